@@ -0,0 +1,211 @@
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+"""The comparisons of hyperdrive-vis-gen-diff, for Python (e.g. notebooks).
+
+This wraps the C interface of the pawsey_hyperdrive_checks library (see
+src/ffi.rs), so files are judged by exactly the same tolerance logic as the
+checker. Build the library with `cargo build --release`; it is looked for in
+$HYPERDRIVE_CHECKS_LIB, then in ../target/release and ../target/debug.
+
+    import hyperdrive_checks as hc
+    report = hc.compare_dirs("baseline", "current", tolerance=1e-3, ulps=16,
+                             rules=[hc.Rule("*band01.bin", tolerance=1e-2)])
+    print(report.passed, report.worst, report.differences.abs)
+"""
+
+import ctypes
+import json
+import os
+import sys
+from dataclasses import dataclass
+from typing import List, Optional
+
+__all__ = [
+    "compare_dirs",
+    "compare_files",
+    "ComparisonReport",
+    "FileReport",
+    "HyperdriveChecksError",
+    "MaxDifferences",
+    "Rule",
+]
+
+
+@dataclass
+class Rule:
+    """Tolerances for the files matching `pattern`, overriding the others."""
+
+    pattern: str
+    tolerance: Optional[float] = None
+    rel_tolerance: Optional[float] = None
+    ulps: Optional[int] = None
+    nan: Optional[str] = None
+
+
+@dataclass
+class MaxDifferences:
+    """The largest differences between the floats of two files. `rel` is
+    None if it's infinite, i.e. a baseline float is zero but the current float
+    isn't."""
+
+    abs: Optional[float]
+    rel: Optional[float]
+    ulps: int
+    sigmas: Optional[float]
+    violations: int
+    nan_failures: int
+
+
+@dataclass
+class FileReport:
+    name: str
+    differences: MaxDifferences
+    passed: bool
+
+
+@dataclass
+class ComparisonReport:
+    files: List[FileReport]
+    differences: MaxDifferences
+    worst: str
+    passed: bool
+
+
+class HyperdriveChecksError(Exception):
+    """A comparison couldn't be done; `exit_code` is the checker's exit code
+    for the problem (e.g. 2 for missing files)."""
+
+    def __init__(self, message, exit_code):
+        super().__init__(message)
+        self.exit_code = exit_code
+
+
+def _load():
+    name = {
+        "darwin": "libpawsey_hyperdrive_checks.dylib",
+        "win32": "pawsey_hyperdrive_checks.dll",
+    }.get(sys.platform, "libpawsey_hyperdrive_checks.so")
+    here = os.path.dirname(os.path.abspath(__file__))
+    candidates = [os.environ.get("HYPERDRIVE_CHECKS_LIB")] + [
+        os.path.join(here, "..", "target", profile, name) for profile in ("release", "debug")
+    ]
+    for path in filter(None, candidates):
+        if os.path.exists(path):
+            lib = ctypes.CDLL(path)
+            break
+    else:
+        raise ImportError("Couldn't find {}; build it with `cargo build --release`".format(name))
+    for f in (lib.hyperdrive_checks_compare_files, lib.hyperdrive_checks_compare_dirs):
+        f.argtypes = [ctypes.c_char_p] * 3
+        # Not c_char_p, which would copy the string and lose the pointer to
+        # free.
+        f.restype = ctypes.c_void_p
+    lib.hyperdrive_checks_free.argtypes = [ctypes.c_void_p]
+    return lib
+
+
+_lib = _load()
+
+
+def _options(
+    tolerance, rel_tolerance, ulps, nan, rules, max_sigma, outputs, dtype, endian, allow_subset
+):
+    lines = []
+
+    def add(name, value):
+        if value is not None:
+            lines.append("{}={}".format(name, value))
+
+    add("tolerance", tolerance)
+    add("rel-tolerance", rel_tolerance)
+    add("ulps", ulps)
+    add("nan", nan)
+    add("max-sigma", max_sigma)
+    add("dtype", dtype)
+    add("endian", endian)
+    add("allow-subset", "true" if allow_subset else None)
+    for output in outputs or []:
+        add("output", output)
+    for rule in rules or []:
+        fields = [rule.pattern] + [
+            "{}={}".format(name, value)
+            for name, value in (
+                ("tolerance", rule.tolerance),
+                ("rel-tolerance", rule.rel_tolerance),
+                ("ulps", rule.ulps),
+                ("nan", rule.nan),
+            )
+            if value is not None
+        ]
+        add("rule", ",".join(fields))
+    return "\n".join(lines)
+
+
+def _call(f, baseline, current, options):
+    pointer = f(os.fsencode(baseline), os.fsencode(current), options.encode())
+    try:
+        result = json.loads(ctypes.string_at(pointer).decode())
+    finally:
+        _lib.hyperdrive_checks_free(pointer)
+    if "error" in result:
+        raise HyperdriveChecksError(result["error"], result["exit_code"])
+    return result
+
+
+def _file_report(r):
+    return FileReport(r["name"], MaxDifferences(**r["differences"]), r["passed"])
+
+
+def compare_files(
+    baseline,
+    current,
+    *,
+    tolerance=None,
+    rel_tolerance=None,
+    ulps=None,
+    nan=None,
+    rules=None,
+    max_sigma=None,
+    dtype=None,
+    endian=None
+):
+    """Compare the file `current` against the file `baseline`, like
+    hyperdrive-vis-gen-diff with the same options. Options that aren't given
+    have the checker's defaults."""
+    options = _options(
+        tolerance, rel_tolerance, ulps, nan, rules, max_sigma, None, dtype, endian, False
+    )
+    return _file_report(_call(_lib.hyperdrive_checks_compare_files, baseline, current, options))
+
+
+def compare_dirs(
+    baseline,
+    current,
+    *,
+    tolerance=None,
+    rel_tolerance=None,
+    ulps=None,
+    nan=None,
+    rules=None,
+    max_sigma=None,
+    outputs=None,
+    dtype=None,
+    endian=None,
+    allow_subset=False
+):
+    """Compare each of the hyperdrive outputs (by default, those matching
+    hyperdrive_band??.bin and hyperdrive_band???.bin, or else the glob patterns
+    of `outputs`) in the directory `current` against the file of the same name
+    in the directory `baseline`."""
+    options = _options(
+        tolerance, rel_tolerance, ulps, nan, rules, max_sigma, outputs, dtype, endian, allow_subset
+    )
+    r = _call(_lib.hyperdrive_checks_compare_dirs, baseline, current, options)
+    return ComparisonReport(
+        [_file_report(f) for f in r["files"]],
+        MaxDifferences(**r["differences"]),
+        r["worst"],
+        r["passed"],
+    )
@@ -0,0 +1,19 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Command-line handling shared by the executables (included by each with
+//! `#[path]`, as it's no business of the library's).
+
+use pawsey_hyperdrive_checks::exit_code;
+
+/// Report a command-line parsing error as clap would, but exit with
+/// [exit_code::ERROR] rather than clap's 1, which would read as differences
+/// that are too large. Help and version requests exit with 0, as usual.
+pub(crate) fn exit_with_usage_error(e: structopt::clap::Error) -> ! {
+    if e.use_stderr() {
+        eprintln!("{}", e.message);
+        std::process::exit(exit_code::ERROR);
+    }
+    e.exit()
+}
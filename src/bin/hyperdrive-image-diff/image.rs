@@ -0,0 +1,73 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the FITS images wsclean (or anything else) makes of hyperdrive's
+//! outputs: a primary HDU of RA and Dec, usually with degenerate frequency and
+//! Stokes axes after them, each combination of which is a plane.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::fits::Fits;
+
+pub(crate) struct Image {
+    pub(crate) path: PathBuf,
+    /// The lengths of the first two axes (RA and Dec) [pixels].
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    /// The lengths of the other axes, e.g. [1, 1] for one frequency and
+    /// Stokes I.
+    pub(crate) other_axes: Vec<usize>,
+    /// The pixels of each plane in turn, RA varying fastest.
+    pub(crate) pixels: Vec<f64>,
+    /// The units of the pixels, e.g. "JY/BEAM".
+    pub(crate) bunit: Option<String>,
+    /// The CRVAL, CDELT and CRPIX of the first two axes, in the units of the
+    /// image (usually degrees and pixels).
+    pub(crate) wcs: [(f64, f64, f64); 2],
+}
+
+impl Image {
+    pub(crate) fn read(path: &Path) -> Result<Image, anyhow::Error> {
+        let fits = Fits::read(path)?;
+        let primary = &fits.hdus[0];
+        let axes = primary.axes();
+        if axes.len() < 2 || axes[0] == 0 || axes[1] == 0 {
+            bail!(
+                "{:?} isn't an image: its primary HDU has axes {:?}",
+                path,
+                axes
+            );
+        }
+        let wcs_of = |n: usize| {
+            let value = |key: &str| primary.float(&format!("{}{}", key, n)).unwrap_or(0.0);
+            (value("CRVAL"), value("CDELT"), value("CRPIX"))
+        };
+        Ok(Image {
+            path: path.to_path_buf(),
+            width: axes[0],
+            height: axes[1],
+            other_axes: axes[2..].to_vec(),
+            pixels: fits.image(primary)?,
+            bunit: primary.value("BUNIT").map(|u| u.to_string()),
+            wcs: [wcs_of(1), wcs_of(2)],
+        })
+    }
+
+    /// The number of planes, e.g. frequencies times Stokes parameters.
+    pub(crate) fn planes(&self) -> usize {
+        self.other_axes.iter().product()
+    }
+
+    /// The pixels of plane `plane`.
+    pub(crate) fn plane(&self, plane: usize) -> &[f64] {
+        let len = self.width * self.height;
+        &self.pixels[plane * len..(plane + 1) * len]
+    }
+
+    /// The units of the pixels, for printing.
+    pub(crate) fn units(&self) -> &str {
+        self.bunit.as_deref().unwrap_or("")
+    }
+}
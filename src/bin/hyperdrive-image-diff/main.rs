@@ -0,0 +1,456 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable compares FITS images (e.g. made by wsclean from
+    hyperdrive's outputs, at the end of an end-to-end regression pipeline) in
+    the present working directory against those in the "baseline" directory.
+    Reports whether any pixel differs by more than some tolerance, and whether
+    the brightest pixel has moved or changed in brightness, optionally only
+    within a box at the centre of the images.
+*/
+
+mod image;
+#[path = "../common/usage.rs"]
+mod usage;
+
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::{exit_code, exit_code_of, matching_files, Error};
+use structopt::StructOpt;
+
+use image::Image;
+
+/// The images wsclean writes, less the PSF, model and residual images.
+const DEFAULT_OUTPUTS: &[&str] = &["*-image.fits", "*-dirty.fits"];
+/// Reference values and pixel sizes closer than this are the same [usually
+/// degrees].
+const WCS_TOLERANCE: f64 = 1e-9;
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+struct Opt {
+    /// The directory containing the baseline images.
+    #[structopt(name = "BASELINE_DIR", default_value = "baseline", parse(from_os_str))]
+    baseline_dir: PathBuf,
+
+    /// The names of the images to compare, rather than wsclean's *-image.fits
+    /// and *-dirty.fits. Glob patterns are allowed.
+    #[structopt(long, use_delimiter = true)]
+    outputs: Vec<String>,
+
+    /// The largest acceptable difference between two pixels, in the units of
+    /// the images (usually Jy/beam).
+    #[structopt(short, long, default_value = "0.001")]
+    tolerance: f64,
+
+    /// The largest acceptable RMS of the differences between the pixels, in
+    /// the units of the images. Not checked if not given.
+    #[structopt(long)]
+    rms_tolerance: Option<f64>,
+
+    /// The largest acceptable fractional difference between the brightest
+    /// pixels of two images.
+    #[structopt(long, default_value = "0.001")]
+    peak_tolerance: f64,
+
+    /// The largest acceptable distance between the brightest pixels of two
+    /// images, in pixels.
+    #[structopt(long, default_value = "0")]
+    peak_offset: f64,
+
+    /// Only compare a square this many pixels wide at the centre of the
+    /// images, e.g. to avoid the edges of the primary beam, where
+    /// deconvolution is least stable.
+    #[structopt(long)]
+    central_box: Option<usize>,
+
+    /// Don't fail if files in the baseline are missing from the present
+    /// working directory, for intentionally partial runs.
+    #[structopt(long)]
+    allow_subset: bool,
+
+    /// Don't print anything; only set the exit code.
+    #[structopt(short, long)]
+    quiet: bool,
+}
+
+/// The brightest pixel of a plane.
+#[derive(Clone, Copy)]
+struct Peak {
+    value: f64,
+    x: usize,
+    y: usize,
+}
+
+impl Peak {
+    fn distance(self, other: Peak) -> f64 {
+        let dx = self.x as f64 - other.x as f64;
+        let dy = self.y as f64 - other.y as f64;
+        dx.hypot(dy)
+    }
+}
+
+/// How a plane of two images differs.
+#[derive(Default)]
+struct Differences {
+    /// The number of pixels compared, i.e. those not NaN in either image.
+    pixels: usize,
+    max_diff: f64,
+    /// The (0-indexed) pixel with the largest difference.
+    worst: Option<(usize, usize)>,
+    /// Of the differences (current - baseline).
+    sum: f64,
+    sum_squares: f64,
+    /// Of the baseline pixels, to tell how big the differences are.
+    baseline_sum_squares: f64,
+    /// Pixels that are NaN in only one image.
+    nan_mismatches: usize,
+    current_peak: Option<Peak>,
+    baseline_peak: Option<Peak>,
+}
+
+impl Differences {
+    fn mean(&self) -> f64 {
+        self.sum / self.pixels.max(1) as f64
+    }
+
+    fn rms(&self) -> f64 {
+        (self.sum_squares / self.pixels.max(1) as f64).sqrt()
+    }
+
+    fn baseline_rms(&self) -> f64 {
+        (self.baseline_sum_squares / self.pixels.max(1) as f64).sqrt()
+    }
+
+    /// The fractional difference of the peaks and the distance between them
+    /// [pixels], if both images have any pixels that aren't NaN.
+    fn peak_differences(&self) -> Option<(f64, f64)> {
+        let (c, b) = (self.current_peak?, self.baseline_peak?);
+        let diff = (c.value - b.value).abs();
+        let fraction = if b.value == 0.0 {
+            diff
+        } else {
+            diff / b.value.abs()
+        };
+        Some((fraction, c.distance(b)))
+    }
+
+    fn failed(&self, options: &Opt) -> bool {
+        let peak_failed = self.peak_differences().is_some_and(|(fraction, offset)| {
+            fraction > options.peak_tolerance || offset > options.peak_offset
+        });
+        self.max_diff > options.tolerance
+            || options.rms_tolerance.is_some_and(|t| self.rms() > t)
+            || self.nan_mismatches > 0
+            || peak_failed
+    }
+}
+
+/// The pixel columns and rows to compare: all of them, or those of a square
+/// `central_box` pixels wide at the centre.
+fn region(
+    image: &Image,
+    central_box: Option<usize>,
+) -> Result<(Range<usize>, Range<usize>), anyhow::Error> {
+    match central_box {
+        None => Ok((0..image.width, 0..image.height)),
+        Some(n) if n == 0 || n > image.width || n > image.height => bail!(
+            "--central-box {} doesn't fit in {:?}, which is {}x{} pixels",
+            n,
+            image.path,
+            image.width,
+            image.height
+        ),
+        Some(n) => {
+            let x = (image.width - n) / 2;
+            let y = (image.height - n) / 2;
+            Ok((x..x + n, y..y + n))
+        }
+    }
+}
+
+/// How the units, reference values and pixel sizes of two images disagree.
+fn compare_metadata(current: &Image, baseline: &Image) -> Vec<String> {
+    let mut problems = vec![];
+    if current.bunit != baseline.bunit {
+        problems.push(format!(
+            "the pixels are in {:?}, but the baseline's are in {:?}",
+            current.units(),
+            baseline.units()
+        ));
+    }
+    for (axis, (c, b)) in current.wcs.iter().zip(&baseline.wcs).enumerate() {
+        if (c.0 - b.0).abs() > WCS_TOLERANCE || (c.2 - b.2).abs() > WCS_TOLERANCE {
+            problems.push(format!(
+                "axis {} has CRVAL {} at pixel {}, but the baseline's has {} at pixel {}",
+                axis + 1,
+                c.0,
+                c.2,
+                b.0,
+                b.2
+            ));
+        }
+        if (c.1 - b.1).abs() > WCS_TOLERANCE {
+            problems.push(format!(
+                "axis {} has pixels {} wide, but the baseline's are {} wide",
+                axis + 1,
+                c.1,
+                b.1
+            ));
+        }
+    }
+    problems
+}
+
+/// Compare the pixels of plane `plane` in `columns` and `rows`.
+fn compare(
+    current: &Image,
+    baseline: &Image,
+    plane: usize,
+    (columns, rows): (Range<usize>, Range<usize>),
+) -> Differences {
+    let mut d = Differences::default();
+    let (c_plane, b_plane) = (current.plane(plane), baseline.plane(plane));
+    let brighter = |peak: Option<Peak>, value: f64| peak.is_none_or(|p| value > p.value);
+    for y in rows {
+        for x in columns.clone() {
+            let (c, b) = (
+                c_plane[y * current.width + x],
+                b_plane[y * current.width + x],
+            );
+            if c.is_nan() != b.is_nan() {
+                d.nan_mismatches += 1;
+                continue;
+            }
+            if c.is_nan() {
+                continue;
+            }
+            d.pixels += 1;
+            let diff = c - b;
+            d.sum += diff;
+            d.sum_squares += diff * diff;
+            d.baseline_sum_squares += b * b;
+            if diff.abs() > d.max_diff {
+                d.max_diff = diff.abs();
+                d.worst = Some((x, y));
+            }
+            if brighter(d.current_peak, c) {
+                d.current_peak = Some(Peak { value: c, x, y });
+            }
+            if brighter(d.baseline_peak, b) {
+                d.baseline_peak = Some(Peak { value: b, x, y });
+            }
+        }
+    }
+    d
+}
+
+fn print_differences(d: &Differences, units: &str, prefix: &str) {
+    println!("{}Pixels compared: {}", prefix, d.pixels);
+    println!(
+        "{}Max. pixel difference: {:e} {}{}",
+        prefix,
+        d.max_diff,
+        units,
+        d.worst
+            .map(|(x, y)| format!(" (pixel {}, {})", x, y))
+            .unwrap_or_default()
+    );
+    println!(
+        "{}Mean difference: {:e}; RMS: {:e}; baseline RMS: {:e}",
+        prefix,
+        d.mean(),
+        d.rms(),
+        d.baseline_rms()
+    );
+    if let (Some(c), Some(b), Some((fraction, offset))) =
+        (d.current_peak, d.baseline_peak, d.peak_differences())
+    {
+        println!(
+            "{}Peak: {:e} {} at pixel {}, {}; the baseline's is {:e} at pixel {}, {} (fractional difference {:e}, {} pixels apart)",
+            prefix, c.value, units, c.x, c.y, b.value, b.x, b.y, fraction, offset
+        );
+    }
+    if d.nan_mismatches > 0 {
+        println!(
+            "{}Pixels NaN in only one image: {}",
+            prefix, d.nan_mismatches
+        );
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code_of(e.chain()));
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let options = Opt::from_iter_safe(std::env::args_os())
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let patterns = if options.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.outputs.clone()
+    };
+
+    let mut failed = false;
+    for name in matching_files(
+        &options.baseline_dir,
+        &PathBuf::from("."),
+        &patterns,
+        options.allow_subset,
+    )? {
+        if !options.quiet {
+            println!("Checking {:?} ...", name);
+        }
+        let current = Image::read(&name)?;
+        let baseline = Image::read(&options.baseline_dir.join(&name))?;
+        if (current.width, current.height, &current.other_axes)
+            != (baseline.width, baseline.height, &baseline.other_axes)
+        {
+            let axes = |i: &Image| {
+                let mut axes = vec![i.width, i.height];
+                axes.extend(&i.other_axes);
+                axes
+            };
+            bail!(Error::Shape(format!(
+                "{:?} has axes {:?}, but {:?} has axes {:?}",
+                current.path,
+                axes(&current),
+                baseline.path,
+                axes(&baseline)
+            )));
+        }
+        let region = region(&current, options.central_box)?;
+
+        let metadata = compare_metadata(&current, &baseline);
+        let planes: Vec<Differences> = (0..current.planes())
+            .map(|p| compare(&current, &baseline, p, region.clone()))
+            .collect();
+        let file_failed = !metadata.is_empty() || planes.iter().any(|d| d.failed(&options));
+        failed |= file_failed;
+        if options.quiet {
+            continue;
+        }
+
+        for problem in &metadata {
+            println!("{:?}: {}", current.path, problem);
+        }
+        if let Some(n) = options.central_box {
+            println!("Comparing the central {}x{} pixels", n, n);
+        }
+        for (p, d) in planes.iter().enumerate() {
+            // Most images have only one plane, which needn't be labelled.
+            let prefix = if planes.len() > 1 {
+                format!("Plane {}: ", p)
+            } else {
+                String::new()
+            };
+            print_differences(d, current.units(), &prefix);
+        }
+        println!(
+            "{:?}: {}",
+            name,
+            if file_failed { "FAILED" } else { "passed" }
+        );
+    }
+
+    if failed {
+        if !options.quiet {
+            println!("Difference is too large; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x3 image with one plane.
+    fn image(pixels: &[f64]) -> Image {
+        Image {
+            path: PathBuf::from("test-image.fits"),
+            width: 4,
+            height: 3,
+            other_axes: vec![1, 1],
+            pixels: pixels.to_vec(),
+            bunit: Some("JY/BEAM".to_string()),
+            wcs: [(10.0, -0.01, 2.0), (-27.0, 0.01, 2.0)],
+        }
+    }
+
+    fn options(args: &[&str]) -> Opt {
+        Opt::from_iter(std::iter::once("hyperdrive-image-diff").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn pixels_are_compared_within_the_region() {
+        let baseline = image(
+            &[
+                [0.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.5, 0.0],
+                [0.0, 0.0, 0.0, f64::NAN],
+            ]
+            .concat(),
+        );
+        let current = image(
+            &[
+                [0.0, 0.0, 0.0, 0.0],
+                [0.0, 0.9, 1.2, 0.0],
+                [0.0, 0.0, f64::NAN, f64::NAN],
+            ]
+            .concat(),
+        );
+        let whole = region(&current, None).unwrap();
+        let d = compare(&current, &baseline, 0, whole);
+        assert_eq!((d.pixels, d.nan_mismatches), (10, 1));
+        assert!((d.max_diff - 0.7).abs() < 1e-12);
+        assert_eq!(d.worst, Some((2, 1)));
+        let (fraction, offset) = d.peak_differences().unwrap();
+        assert!((fraction - 0.2).abs() < 1e-12);
+        assert_eq!(offset, 1.0);
+        assert!(d.failed(&options(&["--tolerance", "1", "--peak-offset", "1"])));
+
+        // The central 2x2 pixels leave out the NaNs.
+        let centre = region(&current, Some(2)).unwrap();
+        assert_eq!(centre, (1..3, 0..2));
+        let d = compare(&current, &baseline, 0, centre);
+        assert_eq!((d.pixels, d.nan_mismatches), (4, 0));
+        let lenient = options(&[
+            "--tolerance",
+            "1",
+            "--peak-tolerance",
+            "0.25",
+            "--peak-offset",
+            "1",
+        ]);
+        assert!(!d.failed(&lenient));
+        assert!(d.failed(&options(&["--tolerance", "1", "--peak-tolerance", "0.25"])));
+
+        assert!(region(&current, Some(4)).is_err());
+        assert!(region(&current, Some(0)).is_err());
+    }
+
+    #[test]
+    fn metadata_mismatches_are_reported() {
+        let baseline = image(&[0.0; 12]);
+        let mut current = image(&[0.0; 12]);
+        assert!(compare_metadata(&current, &baseline).is_empty());
+        current.bunit = None;
+        current.wcs[1].1 = 0.02;
+        assert_eq!(
+            compare_metadata(&current, &baseline),
+            [
+                "the pixels are in \"\", but the baseline's are in \"JY/BEAM\"",
+                "axis 2 has pixels 0.02 wide, but the baseline's are 0.01 wide",
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable compares the measurement sets written by hyperdrive in the
+    present working directory against those in the "baseline" directory.
+    Reports whether the DATA or WEIGHT of any row differs by more than some
+    tolerance, whether any FLAGs differ, and whether the sets' shapes, rows,
+    frequencies, antennas and phase centres agree. The measurement sets are
+    read with python-casacore, which must be installed.
+*/
+
+mod ms;
+#[path = "../common/usage.rs"]
+mod usage;
+
+use std::path::PathBuf;
+
+use pawsey_hyperdrive_checks::{
+    exit_code, exit_code_of, matching_files, max_differences, MaxDifferences, Tolerances,
+};
+use structopt::StructOpt;
+
+use ms::Ms;
+
+/// The numeric subtable columns compared, and their default tolerances.
+const COLUMN_TOLERANCES: &[(&str, f64)] = &[
+    // SPECTRAL_WINDOW [Hz].
+    ("CHAN_FREQ", 1.0),
+    // FIELD [rad]; about 2 milliarcseconds.
+    ("PHASE_DIR", 1e-8),
+    // ANTENNA [m].
+    ("POSITION", 1e-3),
+];
+
+/// Parse a column tolerance like "PHASE_DIR=1e-6".
+fn parse_column_tolerance(s: &str) -> Result<(String, f64), String> {
+    let (column, tolerance) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected COLUMN=TOLERANCE, not \"{}\"", s))?;
+    let column = column.trim().to_uppercase();
+    if !COLUMN_TOLERANCES.iter().any(|(c, _)| *c == column) {
+        let columns: Vec<&str> = COLUMN_TOLERANCES.iter().map(|(c, _)| *c).collect();
+        return Err(format!(
+            "Unknown column \"{}\"; expected one of {}",
+            column,
+            columns.join(", ")
+        ));
+    }
+    let tolerance = tolerance
+        .parse()
+        .map_err(|_| format!("Couldn't parse \"{}\" as a number", tolerance))?;
+    Ok((column, tolerance))
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+struct Opt {
+    /// The directory containing the baseline measurement sets.
+    #[structopt(name = "BASELINE_DIR", default_value = "baseline", parse(from_os_str))]
+    baseline_dir: PathBuf,
+
+    /// The names of the measurement sets to compare, rather than all of them.
+    /// Glob patterns are allowed.
+    #[structopt(long, use_delimiter = true)]
+    outputs: Vec<String>,
+
+    /// The largest acceptable difference between the real or imaginary parts
+    /// of two visibilities.
+    #[structopt(short, long, default_value = "0.001")]
+    tolerance: f32,
+
+    /// The largest acceptable difference between two weights.
+    #[structopt(long, default_value = "1e-6")]
+    weight_tolerance: f32,
+
+    /// The largest acceptable differences in the subtables' numeric columns,
+    /// e.g. "PHASE_DIR=1e-6": CHAN_FREQ of SPECTRAL_WINDOW (in Hz; 1 by
+    /// default), PHASE_DIR of FIELD (in radians; 1e-8) and POSITION of ANTENNA
+    /// (in metres; 1e-3).
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_column_tolerance))]
+    column_tolerance: Vec<(String, f64)>,
+
+    /// The Python with python-casacore installed.
+    #[structopt(long, default_value = "python3")]
+    python: String,
+
+    /// Don't fail if files in the baseline are missing from the present
+    /// working directory, for intentionally partial runs.
+    #[structopt(long)]
+    allow_subset: bool,
+
+    /// Don't print anything; only set the exit code.
+    #[structopt(short, long)]
+    quiet: bool,
+}
+
+impl Opt {
+    /// The tolerance of a column of [COLUMN_TOLERANCES].
+    fn column_tolerance(&self, column: &str) -> f64 {
+        // The last given wins.
+        match self
+            .column_tolerance
+            .iter()
+            .rev()
+            .find(|(c, _)| c == column)
+        {
+            Some((_, t)) => *t,
+            None => COLUMN_TOLERANCES
+                .iter()
+                .find(|(c, _)| *c == column)
+                .map_or(0.0, |(_, t)| *t),
+        }
+    }
+}
+
+/// How two measurement sets differ.
+#[derive(Default)]
+struct Differences {
+    data: MaxDifferences,
+    weights: MaxDifferences,
+    flag_mismatches: usize,
+    /// How the shapes, rows, frequencies and antennas disagree.
+    metadata: Vec<String>,
+}
+
+impl Differences {
+    fn failed(&self) -> bool {
+        !self.data.passed()
+            || !self.weights.passed()
+            || self.flag_mismatches > 0
+            || !self.metadata.is_empty()
+    }
+}
+
+/// How the shapes, rows, frequencies, antennas and phase centres of two
+/// measurement sets disagree. If their shapes differ, nothing else is checked.
+fn compare_metadata(current: &Ms, baseline: &Ms, options: &Opt) -> Vec<String> {
+    let shape = |m: &Ms| (m.rows, m.channels, m.pols);
+    if shape(current) != shape(baseline) {
+        return vec![format!(
+            "(rows, channels, polarisations) is {:?}, but {:?} in the baseline",
+            shape(current),
+            shape(baseline)
+        )];
+    }
+
+    let mut problems = vec![];
+    let rows = (0..current.rows)
+        .filter(|&r| {
+            current.times[r] != baseline.times[r]
+                || current.antenna1[r] != baseline.antenna1[r]
+                || current.antenna2[r] != baseline.antenna2[r]
+        })
+        .count();
+    if rows > 0 {
+        problems.push(format!(
+            "{} rows have a different TIME, ANTENNA1 or ANTENNA2 than in the baseline",
+            rows
+        ));
+    }
+    if let Some((i, (c, b))) = current
+        .freqs
+        .iter()
+        .zip(&baseline.freqs)
+        .enumerate()
+        .find(|(_, (c, b))| (*c - *b).abs() > options.column_tolerance("CHAN_FREQ"))
+    {
+        problems.push(format!(
+            "channel {} is at {} Hz, but {} Hz in the baseline",
+            i, c, b
+        ));
+    }
+    if current.corr_types != baseline.corr_types {
+        problems.push(format!(
+            "correlation types {:?}, but {:?} in the baseline",
+            current.corr_types, baseline.corr_types
+        ));
+    }
+    if current.antenna_names != baseline.antenna_names {
+        problems.push(format!(
+            "antennas {}, but {} in the baseline",
+            current.antenna_names.join(","),
+            baseline.antenna_names.join(",")
+        ));
+    } else {
+        let tolerance = options.column_tolerance("POSITION");
+        for (name, (c, b)) in current.antenna_names.iter().zip(
+            current
+                .antenna_positions
+                .iter()
+                .zip(&baseline.antenna_positions),
+        ) {
+            if c.iter().zip(b).any(|(c, b)| (c - b).abs() > tolerance) {
+                problems.push(format!(
+                    "antenna {} is at {:?} m, but {:?} m in the baseline",
+                    name, c, b
+                ));
+            }
+        }
+    }
+    if current.phase_dirs.len() != baseline.phase_dirs.len() {
+        problems.push(format!(
+            "{} fields, but {} in the baseline",
+            current.phase_dirs.len(),
+            baseline.phase_dirs.len()
+        ));
+    } else {
+        let tolerance = options.column_tolerance("PHASE_DIR");
+        for (i, (c, b)) in current
+            .phase_dirs
+            .iter()
+            .zip(&baseline.phase_dirs)
+            .enumerate()
+        {
+            if c.iter().zip(b).any(|(c, b)| (c - b).abs() > tolerance) {
+                problems.push(format!(
+                    "field {}'s phase centre is at {:?} rad, but {:?} rad in the baseline",
+                    i, c, b
+                ));
+            }
+        }
+    }
+    problems
+}
+
+fn compare(current: &Ms, baseline: &Ms, options: &Opt) -> Result<Differences, anyhow::Error> {
+    let mut d = Differences {
+        metadata: compare_metadata(current, baseline, options),
+        ..Differences::default()
+    };
+    if current.data.len() != baseline.data.len() || current.data.is_empty() {
+        return Ok(d);
+    }
+    d.data = max_differences(
+        &current.data,
+        &baseline.data,
+        &Tolerances::absolute(options.tolerance),
+    )?;
+    d.weights = max_differences(
+        &current.weights,
+        &baseline.weights,
+        &Tolerances::absolute(options.weight_tolerance),
+    )?;
+    d.flag_mismatches = current
+        .flags
+        .iter()
+        .zip(&baseline.flags)
+        .filter(|(c, b)| c != b)
+        .count();
+    Ok(d)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code_of(e.chain()));
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let options = Opt::from_iter_safe(std::env::args_os())
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let patterns = if options.outputs.is_empty() {
+        vec!["*.ms".to_string()]
+    } else {
+        options.outputs.clone()
+    };
+
+    let mut failed = false;
+    for name in matching_files(
+        &options.baseline_dir,
+        &PathBuf::from("."),
+        &patterns,
+        options.allow_subset,
+    )? {
+        if !options.quiet {
+            println!("Checking {:?} ...", name);
+        }
+        let current = Ms::read(&name, &options.python)?;
+        let baseline = Ms::read(&options.baseline_dir.join(&name), &options.python)?;
+        let d = compare(&current, &baseline, &options)?;
+        let file_failed = d.failed();
+        failed |= file_failed;
+        if options.quiet {
+            continue;
+        }
+
+        for problem in &d.metadata {
+            println!("{:?}: {}", current.path, problem);
+        }
+        println!(
+            "Max. DATA difference: {:e} (relative {:e})",
+            d.data.abs, d.data.rel
+        );
+        println!(
+            "Max. WEIGHT difference: {:e} (relative {:e})",
+            d.weights.abs, d.weights.rel
+        );
+        let nans = d.data.nan_failures + d.weights.nan_failures;
+        if nans > 0 {
+            println!("DATA or WEIGHTs that are NaN: {}", nans);
+        }
+        if d.flag_mismatches > 0 {
+            println!("FLAGs that differ: {}", d.flag_mismatches);
+        }
+        println!(
+            "{:?}: {}",
+            name,
+            if file_failed { "FAILED" } else { "passed" }
+        );
+    }
+
+    if failed {
+        if !options.quiet {
+            println!("Difference is too large; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A measurement set of 2 rows, 1 channel and 1 polarisation.
+    fn ms() -> Ms {
+        Ms {
+            path: PathBuf::from("test.ms"),
+            rows: 2,
+            channels: 1,
+            pols: 1,
+            antenna_names: vec!["Tile011".to_string(), "Tile012".to_string()],
+            times: vec![4.0e9; 2],
+            antenna1: vec![0, 0],
+            antenna2: vec![0, 1],
+            data: vec![1.0, 0.0, 2.0, -1.0],
+            weights: vec![1.0, 1.0],
+            flags: vec![false, false],
+            freqs: vec![1.8e8],
+            corr_types: vec![9],
+            phase_dirs: vec![[0.0, -0.47]],
+            antenna_positions: vec![[-2.5e6, 5.0e6, -2.8e6], [-2.5e6, 5.0e6, -2.8e6]],
+        }
+    }
+
+    fn options(args: &[&str]) -> Opt {
+        Opt::from_iter(std::iter::once("hyperdrive-ms-diff").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn column_tolerances_are_parsed() {
+        assert_eq!(
+            parse_column_tolerance(" phase_dir=1e-6"),
+            Ok(("PHASE_DIR".to_string(), 1e-6))
+        );
+        assert!(parse_column_tolerance("TIME=1").is_err());
+        assert!(parse_column_tolerance("POSITION").is_err());
+        assert!(parse_column_tolerance("POSITION=far").is_err());
+
+        let o = options(&["--column-tolerance", "POSITION=1,POSITION=2"]);
+        assert_eq!(o.column_tolerance("POSITION"), 2.0);
+        assert_eq!(o.column_tolerance("CHAN_FREQ"), 1.0);
+    }
+
+    #[test]
+    fn differences_are_found() {
+        let baseline = ms();
+        let mut current = ms();
+        let o = options(&[]);
+        assert!(!compare(&current, &baseline, &o).unwrap().failed());
+
+        current.data[3] = -1.01;
+        current.flags[1] = true;
+        current.freqs[0] += 2.0;
+        current.antenna_positions[1][2] += 1e-4;
+        current.phase_dirs[0][0] = 1e-7;
+        let d = compare(&current, &baseline, &o).unwrap();
+        assert!((d.data.abs - 0.01).abs() < 1e-6);
+        assert_eq!((d.flag_mismatches, d.weights.abs), (1, 0.0));
+        assert_eq!(
+            d.metadata,
+            [
+                "channel 0 is at 180000002 Hz, but 180000000 Hz in the baseline",
+                "field 0's phase centre is at [1e-7, -0.47] rad, but [0.0, -0.47] rad in the baseline",
+            ]
+        );
+        let lenient = options(&[
+            "-t",
+            "0.1",
+            "--column-tolerance",
+            "CHAN_FREQ=5,PHASE_DIR=1e-6",
+        ]);
+        let d = compare(&current, &baseline, &lenient).unwrap();
+        assert!(d.metadata.is_empty() && d.data.passed());
+
+        // Nothing but the shapes are compared if they differ.
+        current.rows = 1;
+        let d = compare(&current, &baseline, &o).unwrap();
+        assert_eq!(
+            d.metadata,
+            ["(rows, channels, polarisations) is (1, 1, 1), but (2, 1, 1) in the baseline"]
+        );
+    }
+}
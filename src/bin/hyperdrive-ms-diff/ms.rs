@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the columns of a CASA measurement set that matter for a
+//! comparison. There's no Rust reader of CASA tables, so python-casacore
+//! dumps them: a line of "ROWS CHANNELS POLS FIELDS", a line of
+//! comma-separated antenna names, then the little-endian arrays listed in
+//! [Ms], in order.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Reads a measurement set (the first argument) with python-casacore and
+/// writes it to stdout.
+const DUMP: &str = r#"
+import sys
+import numpy as np
+from casacore.tables import table
+
+path = sys.argv[1]
+ms = table(path, ack=False)
+data = ms.getcol("DATA")
+rows, chans, pols = data.shape
+sub = lambda name: table(path + "/" + name, ack=False)
+names = sub("ANTENNA").getcol("NAME")
+# The phase centre of each field, without any polynomial terms.
+phase_dirs = sub("FIELD").getcol("PHASE_DIR")[:, 0, :]
+out = sys.stdout.buffer
+out.write(("%d %d %d %d\n%s\n" % (rows, chans, pols, len(phase_dirs), ",".join(names))).encode())
+for a in (
+    ms.getcol("TIME").astype("<f8"),
+    ms.getcol("ANTENNA1").astype("<i4"),
+    ms.getcol("ANTENNA2").astype("<i4"),
+    data.astype("<c8"),
+    ms.getcol("WEIGHT").astype("<f4"),
+    ms.getcol("FLAG").astype("u1"),
+    sub("SPECTRAL_WINDOW").getcol("CHAN_FREQ")[0].astype("<f8"),
+    sub("POLARIZATION").getcol("CORR_TYPE")[0].astype("<i4"),
+    phase_dirs.astype("<f8"),
+    sub("ANTENNA").getcol("POSITION").astype("<f8"),
+):
+    out.write(np.ascontiguousarray(a).tobytes())
+"#;
+
+/// The main table and metadata of a measurement set.
+pub(crate) struct Ms {
+    pub(crate) path: PathBuf,
+    pub(crate) rows: usize,
+    pub(crate) channels: usize,
+    pub(crate) pols: usize,
+    pub(crate) antenna_names: Vec<String>,
+    /// The TIME of each row [MJD seconds].
+    pub(crate) times: Vec<f64>,
+    pub(crate) antenna1: Vec<i32>,
+    pub(crate) antenna2: Vec<i32>,
+    /// The DATA column: real and imaginary parts, polarisation fastest, then
+    /// channel, then row.
+    pub(crate) data: Vec<f32>,
+    /// The WEIGHT column: one per polarisation per row.
+    pub(crate) weights: Vec<f32>,
+    /// The FLAG column, shaped like DATA but without the imaginary parts.
+    pub(crate) flags: Vec<bool>,
+    /// The CHAN_FREQ of the first spectral window [Hz].
+    pub(crate) freqs: Vec<f64>,
+    /// The CORR_TYPE of each polarisation, e.g. 9 for XX.
+    pub(crate) corr_types: Vec<i32>,
+    /// The PHASE_DIR (right ascension, declination) of each field [rad].
+    pub(crate) phase_dirs: Vec<[f64; 2]>,
+    /// The POSITION (ITRF x, y, z) of each antenna [m].
+    pub(crate) antenna_positions: Vec<[f64; 3]>,
+}
+
+impl Ms {
+    pub(crate) fn read(path: &Path, python: &str) -> Result<Ms, anyhow::Error> {
+        let output = Command::new(python)
+            .arg("-c")
+            .arg(DUMP)
+            .arg(path)
+            .output()
+            .map_err(|e| anyhow!("Couldn't run {}: {}", python, e))?;
+        if !output.status.success() {
+            bail!(
+                "Couldn't read {:?} with python-casacore ({}):\n{}",
+                path,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+        parse(path, &output.stdout)
+            .ok_or_else(|| anyhow!("python-casacore's dump of {:?} is truncated", path))
+    }
+}
+
+fn parse(path: &Path, bytes: &[u8]) -> Option<Ms> {
+    let mut lines = bytes.splitn(3, |&b| b == b'\n');
+    let counts: Vec<usize> = std::str::from_utf8(lines.next()?)
+        .ok()?
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let (rows, channels, pols, fields) = match counts.as_slice() {
+        [r, c, p, f] => (*r, *c, *p, *f),
+        _ => return None,
+    };
+    let antenna_names: Vec<String> = String::from_utf8_lossy(lines.next()?)
+        .split(',')
+        .map(|n| n.to_string())
+        .collect();
+
+    let mut rest = lines.next()?;
+    let mut take = |len: usize| -> Option<&[u8]> {
+        let (taken, left) = (rest.get(..len)?, rest.get(len..)?);
+        rest = left;
+        Some(taken)
+    };
+    let samples = rows * channels * pols;
+    let times = take(8 * rows)?
+        .chunks_exact(8)
+        .map(LittleEndian::read_f64)
+        .collect();
+    let antenna1 = take(4 * rows)?
+        .chunks_exact(4)
+        .map(LittleEndian::read_i32)
+        .collect();
+    let antenna2 = take(4 * rows)?
+        .chunks_exact(4)
+        .map(LittleEndian::read_i32)
+        .collect();
+    let data = take(8 * samples)?
+        .chunks_exact(4)
+        .map(LittleEndian::read_f32)
+        .collect();
+    let weights = take(4 * rows * pols)?
+        .chunks_exact(4)
+        .map(LittleEndian::read_f32)
+        .collect();
+    let flags = take(samples)?.iter().map(|&f| f != 0).collect();
+    let freqs = take(8 * channels)?
+        .chunks_exact(8)
+        .map(LittleEndian::read_f64)
+        .collect();
+    let corr_types = take(4 * pols)?
+        .chunks_exact(4)
+        .map(LittleEndian::read_i32)
+        .collect();
+    let phase_dirs = take(16 * fields)?
+        .chunks_exact(16)
+        .map(|d| [LittleEndian::read_f64(d), LittleEndian::read_f64(&d[8..])])
+        .collect();
+    let antenna_positions = take(24 * antenna_names.len())?
+        .chunks_exact(24)
+        .map(|p| {
+            [
+                LittleEndian::read_f64(p),
+                LittleEndian::read_f64(&p[8..]),
+                LittleEndian::read_f64(&p[16..]),
+            ]
+        })
+        .collect();
+    Some(Ms {
+        path: path.to_path_buf(),
+        rows,
+        channels,
+        pols,
+        antenna_names,
+        times,
+        antenna1,
+        antenna2,
+        data,
+        weights,
+        flags,
+        freqs,
+        corr_types,
+        phase_dirs,
+        antenna_positions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What DUMP writes for a measurement set of 2 rows, 1 channel, 2
+    /// polarisations, 1 field and 2 antennas.
+    fn dump() -> Vec<u8> {
+        let mut bytes = b"2 1 2 1\nTile011,Tile012\n".to_vec();
+        for t in [4.0e9f64, 4.0e9 + 8.0] {
+            bytes.extend(t.to_le_bytes());
+        }
+        for a in [0i32, 0, 0, 1] {
+            bytes.extend(a.to_le_bytes());
+        }
+        for v in 0..8 {
+            bytes.extend((v as f32).to_le_bytes());
+        }
+        for w in [1.0f32, 1.0, 0.5, 0.5] {
+            bytes.extend(w.to_le_bytes());
+        }
+        bytes.extend([0, 1, 0, 0]);
+        bytes.extend(1.8e8f64.to_le_bytes());
+        for c in [9i32, 12] {
+            bytes.extend(c.to_le_bytes());
+        }
+        for d in [0.0f64, -0.47, -2.5e6, 5.0e6, -2.8e6, -2.5e6, 5.0e6, -2.8e6] {
+            bytes.extend(d.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn dumps_are_parsed() {
+        let ms = parse(Path::new("test.ms"), &dump()).unwrap();
+        assert_eq!((ms.rows, ms.channels, ms.pols), (2, 1, 2));
+        assert_eq!(ms.antenna_names, ["Tile011", "Tile012"]);
+        assert_eq!(ms.times, [4.0e9, 4.0e9 + 8.0]);
+        assert_eq!((ms.antenna1, ms.antenna2), (vec![0, 0], vec![0, 1]));
+        assert_eq!(ms.data, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(ms.weights, [1.0, 1.0, 0.5, 0.5]);
+        assert_eq!(ms.flags, [false, true, false, false]);
+        assert_eq!((ms.freqs, ms.corr_types), (vec![1.8e8], vec![9, 12]));
+        assert_eq!(ms.phase_dirs, [[0.0, -0.47]]);
+        assert_eq!(ms.antenna_positions[1], [-2.5e6, 5.0e6, -2.8e6]);
+    }
+
+    #[test]
+    fn truncated_dumps_are_refused() {
+        let dump = dump();
+        assert!(parse(Path::new("test.ms"), &dump[..dump.len() - 1]).is_none());
+        assert!(parse(Path::new("test.ms"), b"2 1 2\nTile011\n").is_none());
+        assert!(parse(Path::new("test.ms"), b"").is_none());
+    }
+}
@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable compares the calibration solutions written by `hyperdrive
+    di-calibrate` (FITS, or André Offringa's binary format) in the present
+    working directory against those in the "baseline" directory. Tiles are
+    matched by name and channels by frequency when the files say what they
+    are. Reports whether the amplitudes or phases of any Jones matrix element
+    differ by more than some tolerance.
+*/
+
+#[path = "../common/usage.rs"]
+mod usage;
+
+use std::path::PathBuf;
+
+use pawsey_hyperdrive_checks::solutions::{self, SolutionDiffs, Solutions, POLS};
+use pawsey_hyperdrive_checks::{exit_code, exit_code_of, matching_files};
+use structopt::StructOpt;
+
+/// The files that `hyperdrive di-calibrate` writes by default.
+const DEFAULT_OUTPUTS: &[&str] = &["hyperdrive_solutions.fits", "hyperdrive_solutions.bin"];
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+struct Opt {
+    /// The directory containing the baseline solutions.
+    #[structopt(name = "BASELINE_DIR", default_value = "baseline", parse(from_os_str))]
+    baseline_dir: PathBuf,
+
+    /// The names of the solutions files to compare, rather than
+    /// hyperdrive_solutions.fits and hyperdrive_solutions.bin. Glob patterns
+    /// are allowed.
+    #[structopt(long, use_delimiter = true)]
+    outputs: Vec<String>,
+
+    /// The largest acceptable difference between the amplitudes of two Jones
+    /// matrix elements.
+    #[structopt(long, default_value = "1e-4")]
+    amp_tolerance: f64,
+
+    /// The largest acceptable difference between the phases of two Jones
+    /// matrix elements, in degrees.
+    #[structopt(long, default_value = "0.01")]
+    phase_tolerance: f64,
+
+    /// The index of the reference tile (in the current solutions); by
+    /// default, the last tile that isn't flagged.
+    #[structopt(long)]
+    ref_tile: Option<usize>,
+
+    /// Don't reference the solutions to any tile.
+    #[structopt(long, conflicts_with = "ref-tile")]
+    no_ref_tile: bool,
+
+    /// Don't fail if files in the baseline are missing from the present
+    /// working directory, for intentionally partial runs.
+    #[structopt(long)]
+    allow_subset: bool,
+
+    /// Don't print anything; only set the exit code.
+    #[structopt(short, long)]
+    quiet: bool,
+}
+
+/// The tile to reference the solutions to, if any.
+fn ref_tile(options: &Opt, current: &Solutions) -> Option<usize> {
+    match (options.ref_tile, options.no_ref_tile) {
+        (_, true) => None,
+        (Some(r), _) => Some(r),
+        (None, false) => solutions::default_ref_tile(current),
+    }
+}
+
+/// Do two sets of solutions differ by too much?
+fn failed_by(d: &SolutionDiffs, options: &Opt) -> bool {
+    d.mismatched()
+        || d.max_amp.iter().any(|&a| a > options.amp_tolerance)
+        || d.max_phase.iter().any(|&p| p > options.phase_tolerance)
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code_of(e.chain()));
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let options = Opt::from_iter_safe(std::env::args_os())
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let patterns = if options.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.outputs.clone()
+    };
+
+    let mut failed = false;
+    for name in matching_files(
+        &options.baseline_dir,
+        &PathBuf::from("."),
+        &patterns,
+        options.allow_subset,
+    )? {
+        if !options.quiet {
+            println!("Checking {:?} ...", name);
+        }
+        let current = solutions::read(&name)?;
+        let baseline = solutions::read(&options.baseline_dir.join(&name))?;
+        let ref_tile = ref_tile(&options, &current);
+        let d = solutions::compare(&current, &baseline, ref_tile)?;
+        let file_failed = failed_by(&d, &options);
+        failed |= file_failed;
+        if options.quiet {
+            continue;
+        }
+
+        match ref_tile {
+            Some(r) => println!("Solutions referenced to tile {}:", r),
+            None => println!("Solutions without a reference tile:"),
+        }
+        println!(
+            "{:<4} {:>14} {:>20}",
+            "pol", "max amp diff", "max phase diff (deg)"
+        );
+        for (pol, name) in POLS.iter().enumerate() {
+            println!(
+                "{:<4} {:>14.3e} {:>20.3e}",
+                name, d.max_amp[pol], d.max_phase[pol]
+            );
+        }
+        if !d.flagged_tiles.is_empty() {
+            println!(
+                "Tiles flagged in both (not compared): {}",
+                d.flagged_tiles.join(", ")
+            );
+        }
+        if !d.tile_flag_mismatches.is_empty() {
+            println!(
+                "Tiles flagged in only one: {}",
+                d.tile_flag_mismatches.join(", ")
+            );
+        }
+        if d.flag_mismatches > 0 {
+            println!("Values flagged in only one: {}", d.flag_mismatches);
+        }
+        if !d.only_in_a.is_empty() {
+            println!("Only in the current solutions: {}", d.only_in_a.join(", "));
+        }
+        if !d.only_in_b.is_empty() {
+            println!("Only in the baseline solutions: {}", d.only_in_b.join(", "));
+        }
+        println!(
+            "{:?}: {}",
+            name,
+            if file_failed { "FAILED" } else { "passed" }
+        );
+    }
+
+    if failed {
+        if !options.quiet {
+            println!("Difference is too large; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Opt {
+        Opt::from_iter(std::iter::once("hyperdrive-solutions-diff").chain(args.iter().copied()))
+    }
+
+    /// Solutions for one interval and channel of 3 tiles, the last flagged, as
+    /// an MWAOCAL binary file.
+    fn solutions() -> Solutions {
+        let path = std::env::temp_dir().join(format!("solutions-{}.bin", std::process::id()));
+        let mut bytes = b"MWAOCAL\0".to_vec();
+        for n in [0u32, 0, 1, 3, 1, 4] {
+            bytes.extend(n.to_le_bytes());
+        }
+        bytes.extend([0; 16]);
+        for tile in 0..3 {
+            let jones = match tile {
+                2 => [f64::NAN; 8],
+                _ => [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+            };
+            for f in jones {
+                bytes.extend(f.to_le_bytes());
+            }
+        }
+        std::fs::write(&path, bytes).unwrap();
+        let s = solutions::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        s
+    }
+
+    #[test]
+    fn the_last_unflagged_tile_is_the_reference() {
+        let s = solutions();
+        assert_eq!(ref_tile(&options(&[]), &s), Some(1));
+        assert_eq!(ref_tile(&options(&["--ref-tile", "0"]), &s), Some(0));
+        assert_eq!(ref_tile(&options(&["--no-ref-tile"]), &s), None);
+        assert!(Opt::from_iter_safe(&["-", "--ref-tile", "0", "--no-ref-tile"]).is_err());
+    }
+
+    #[test]
+    fn amplitudes_and_phases_have_their_own_tolerances() {
+        let o = options(&["--amp-tolerance", "1e-3", "--phase-tolerance", "0.1"]);
+        let mut d = SolutionDiffs::default();
+        d.max_amp[3] = 1e-3;
+        d.max_phase[1] = 0.1;
+        assert!(!failed_by(&d, &o));
+        d.max_phase[1] = 0.2;
+        assert!(failed_by(&d, &o));
+
+        let d = SolutionDiffs {
+            only_in_b: vec!["Tile011".to_string()],
+            ..SolutionDiffs::default()
+        };
+        assert!(failed_by(&d, &o));
+    }
+}
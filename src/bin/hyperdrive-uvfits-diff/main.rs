@@ -0,0 +1,457 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable compares the uvfits files written by hyperdrive in the
+    present working directory against those in the "baseline" directory. Rows
+    are matched by baseline and time, so the files needn't be written in the
+    same order. Reports whether any visibility or weight differs by more than
+    some tolerance, and whether the files' frequencies, antennas and header
+    cards agree.
+*/
+
+#[path = "../common/usage.rs"]
+mod usage;
+mod uvfits;
+
+use std::path::PathBuf;
+
+use glob::Pattern;
+use pawsey_hyperdrive_checks::{exit_code, exit_code_of, matching_files};
+use structopt::StructOpt;
+
+use uvfits::{Key, Uvfits};
+
+/// Frequencies closer than this are the same [Hz].
+const FREQ_TOLERANCE: f64 = 1.0;
+/// Antenna positions closer than this are the same [m].
+const POSITION_TOLERANCE: f64 = 1e-3;
+/// Header cards that change with every run, or with the software that wrote
+/// the file, rather than with what's in it.
+const IGNORED_CARDS: &[&str] = &["DATE", "ORIGIN", "SOFTWARE", "SOFTVER", "GIT*"];
+
+/// Parse a card tolerance like "CRVAL4=1".
+fn parse_card_tolerance(s: &str) -> Result<(String, f64), String> {
+    let (key, tolerance) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected KEY=TOLERANCE, not \"{}\"", s))?;
+    let tolerance = tolerance
+        .parse()
+        .map_err(|_| format!("Couldn't parse \"{}\" as a number", tolerance))?;
+    Ok((key.trim().to_uppercase(), tolerance))
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+struct Opt {
+    /// The directory containing the baseline uvfits files.
+    #[structopt(name = "BASELINE_DIR", default_value = "baseline", parse(from_os_str))]
+    baseline_dir: PathBuf,
+
+    /// The names of the uvfits files to compare, rather than all of them.
+    /// Glob patterns are allowed.
+    #[structopt(long, use_delimiter = true)]
+    outputs: Vec<String>,
+
+    /// The largest acceptable difference between the real or imaginary parts
+    /// of two visibilities.
+    #[structopt(short, long, default_value = "0.001")]
+    tolerance: f32,
+
+    /// The largest acceptable difference between two weights.
+    #[structopt(long, default_value = "1e-6")]
+    weight_tolerance: f32,
+
+    /// Header cards not to compare, in any HDU, as well as DATE, ORIGIN,
+    /// SOFTWARE, SOFTVER and GIT*. Glob patterns are allowed, e.g. "OBS*".
+    #[structopt(long, use_delimiter = true, parse(try_from_str = Pattern::new))]
+    ignore_card: Vec<Pattern>,
+
+    /// The largest acceptable difference between the values of a numeric
+    /// header card, e.g. "CRVAL4=1" for the first frequency in Hz. May be
+    /// given more than once. Other cards must have equal values.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_card_tolerance))]
+    card_tolerance: Vec<(String, f64)>,
+
+    /// Don't fail if files in the baseline are missing from the present
+    /// working directory, for intentionally partial runs.
+    #[structopt(long)]
+    allow_subset: bool,
+
+    /// Don't print anything; only set the exit code.
+    #[structopt(short, long)]
+    quiet: bool,
+}
+
+/// How two uvfits files differ.
+#[derive(Default)]
+struct Differences {
+    /// The number of rows in both files.
+    rows: usize,
+    max_vis: f32,
+    /// The row with the largest visibility difference.
+    worst: Option<Key>,
+    max_weight: f32,
+    /// Values that are NaN in only one file.
+    nan_mismatches: usize,
+    only_current: Vec<Key>,
+    only_baseline: Vec<Key>,
+    /// How the frequency and antenna tables disagree.
+    metadata: Vec<String>,
+    /// How the header cards disagree.
+    cards: Vec<String>,
+}
+
+impl Differences {
+    fn failed(&self, options: &Opt) -> bool {
+        self.max_vis > options.tolerance
+            || self.max_weight > options.weight_tolerance
+            || self.nan_mismatches > 0
+            || !self.only_current.is_empty()
+            || !self.only_baseline.is_empty()
+            || !self.metadata.is_empty()
+            || !self.cards.is_empty()
+    }
+}
+
+/// How the frequencies, polarisations and antennas of two files disagree.
+fn compare_metadata(current: &Uvfits, baseline: &Uvfits) -> Vec<String> {
+    let mut problems = vec![];
+    if current.freqs.len() != baseline.freqs.len() {
+        problems.push(format!(
+            "{} fine channels, but the baseline has {}",
+            current.freqs.len(),
+            baseline.freqs.len()
+        ));
+    } else if let Some((i, (c, b))) = current
+        .freqs
+        .iter()
+        .zip(&baseline.freqs)
+        .enumerate()
+        .find(|(_, (c, b))| (*c - *b).abs() > FREQ_TOLERANCE)
+    {
+        problems.push(format!(
+            "fine channel {} is at {} Hz, but {} Hz in the baseline",
+            i, c, b
+        ));
+    }
+    if current.pols != baseline.pols {
+        problems.push(format!(
+            "polarisations {:?}, but the baseline has {:?}",
+            current.pols, baseline.pols
+        ));
+    }
+    if current.antenna_names != baseline.antenna_names {
+        problems.push(format!(
+            "{} antennas ({}), but the baseline has {} ({})",
+            current.antenna_names.len(),
+            current.antenna_names.join(", "),
+            baseline.antenna_names.len(),
+            baseline.antenna_names.join(", ")
+        ));
+    } else {
+        for (name, (c, b)) in current.antenna_names.iter().zip(
+            current
+                .antenna_positions
+                .iter()
+                .zip(&baseline.antenna_positions),
+        ) {
+            if c.len() != b.len()
+                || c.iter()
+                    .zip(b)
+                    .any(|(c, b)| (c - b).abs() > POSITION_TOLERANCE)
+            {
+                problems.push(format!(
+                    "antenna {} is at {:?}, but {:?} in the baseline",
+                    name, c, b
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// How the header cards of two files disagree: the cards of each HDU are
+/// compared with those of the baseline HDU of the same name. Numbers are
+/// compared as numbers, within any tolerance given for their card.
+fn compare_cards(current: &Uvfits, baseline: &Uvfits, options: &Opt) -> Vec<String> {
+    let ignored = |key: &str| {
+        IGNORED_CARDS
+            .iter()
+            .any(|p| Pattern::new(p).unwrap().matches(key))
+            || options.ignore_card.iter().any(|p| p.matches(key))
+    };
+    let baseline_headers: Vec<_> = baseline.headers().collect();
+    let mut problems = vec![];
+    for (hdu, c_cards) in current.headers() {
+        let b_cards = match baseline_headers.iter().find(|(name, _)| *name == hdu) {
+            Some((_, cards)) => cards,
+            None => {
+                problems.push(format!("the {} HDU isn't in the baseline", hdu));
+                continue;
+            }
+        };
+        let find = |cards: &[(String, String)], key: &str| {
+            cards.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+        };
+        for (key, c) in c_cards.iter().filter(|(k, _)| !ignored(k)) {
+            let b = match find(b_cards, key) {
+                Some(b) => b,
+                None => {
+                    problems.push(format!("{} card {} isn't in the baseline", hdu, key));
+                    continue;
+                }
+            };
+            let number = |v: &str| v.replace('D', "E").parse::<f64>().ok();
+            let equal = match (number(c), number(&b)) {
+                (Some(c), Some(b)) => {
+                    let tolerance = options
+                        .card_tolerance
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map_or(0.0, |(_, t)| *t);
+                    (c - b).abs() <= tolerance
+                }
+                _ => *c == b,
+            };
+            if !equal {
+                problems.push(format!(
+                    "{} card {} is {}, but {} in the baseline",
+                    hdu, key, c, b
+                ));
+            }
+        }
+        for (key, _) in b_cards.iter().filter(|(k, _)| !ignored(k)) {
+            if find(c_cards, key).is_none() {
+                problems.push(format!("{} card {} is only in the baseline", hdu, key));
+            }
+        }
+    }
+    for (hdu, _) in baseline_headers {
+        if !current.headers().any(|(name, _)| name == hdu) {
+            problems.push(format!("the {} HDU is only in the baseline", hdu));
+        }
+    }
+    problems
+}
+
+fn compare(current: &Uvfits, baseline: &Uvfits, options: &Opt) -> Differences {
+    let mut d = Differences {
+        metadata: compare_metadata(current, baseline),
+        cards: compare_cards(current, baseline, options),
+        ..Differences::default()
+    };
+    d.only_baseline = baseline
+        .rows
+        .keys()
+        .filter(|k| !current.rows.contains_key(k))
+        .copied()
+        .collect();
+    d.only_baseline.sort_unstable();
+    // Without the same channels and polarisations, there's nothing to match
+    // the values up with.
+    let comparable =
+        current.freqs.len() == baseline.freqs.len() && current.pols.len() == baseline.pols.len();
+
+    let mut keys: Vec<Key> = current.rows.keys().copied().collect();
+    keys.sort_unstable();
+    for key in keys {
+        let b = match baseline.rows.get(&key) {
+            Some(&b) => b,
+            None => {
+                d.only_current.push(key);
+                continue;
+            }
+        };
+        d.rows += 1;
+        if !comparable {
+            continue;
+        }
+        let c = current.values(current.rows[&key]);
+        let b = baseline.values(b);
+        for (i, (c, b)) in c.iter().zip(&b).enumerate() {
+            if c.is_nan() != b.is_nan() {
+                d.nan_mismatches += 1;
+                continue;
+            }
+            let diff = if c == b || c.is_nan() {
+                0.0
+            } else {
+                (c - b).abs()
+            };
+            // Each visibility is a real, imaginary and weight.
+            if i % 3 == 2 {
+                d.max_weight = d.max_weight.max(diff);
+            } else if diff > d.max_vis {
+                d.max_vis = diff;
+                d.worst = Some(key);
+            }
+        }
+    }
+    d
+}
+
+/// Print up to a few rows, and how many more there are.
+fn print_rows(what: &str, rows: &[Key]) {
+    const SHOWN: usize = 5;
+    if rows.is_empty() {
+        return;
+    }
+    println!("{} rows only in the {} file:", rows.len(), what);
+    for r in rows.iter().take(SHOWN) {
+        println!("  {}", r.describe());
+    }
+    if rows.len() > SHOWN {
+        println!("  ... and {} more", rows.len() - SHOWN);
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code_of(e.chain()));
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let options = Opt::from_iter_safe(std::env::args_os())
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let patterns = if options.outputs.is_empty() {
+        vec!["*.uvfits".to_string()]
+    } else {
+        options.outputs.clone()
+    };
+
+    let mut failed = false;
+    for name in matching_files(
+        &options.baseline_dir,
+        &PathBuf::from("."),
+        &patterns,
+        options.allow_subset,
+    )? {
+        if !options.quiet {
+            println!("Checking {:?} ...", name);
+        }
+        let current = Uvfits::read(&name)?;
+        let baseline = Uvfits::read(&options.baseline_dir.join(&name))?;
+        let d = compare(&current, &baseline, &options);
+        let file_failed = d.failed(&options);
+        failed |= file_failed;
+        if options.quiet {
+            continue;
+        }
+
+        for problem in d.metadata.iter().chain(&d.cards) {
+            println!("{:?}: {}", current.path, problem);
+        }
+        println!("Rows compared: {}", d.rows);
+        println!(
+            "Max. visibility difference: {:e}{}",
+            d.max_vis,
+            d.worst
+                .map(|w| format!(" ({})", w.describe()))
+                .unwrap_or_default()
+        );
+        println!("Max. weight difference: {:e}", d.max_weight);
+        if d.nan_mismatches > 0 {
+            println!("Values NaN in only one file: {}", d.nan_mismatches);
+        }
+        print_rows("current", &d.only_current);
+        print_rows("baseline", &d.only_baseline);
+        println!(
+            "{:?}: {}",
+            name,
+            if file_failed { "FAILED" } else { "passed" }
+        );
+    }
+
+    if failed {
+        if !options.quiet {
+            println!("Difference is too large; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uvfits::tests::uvfits;
+
+    fn options(args: &[&str]) -> Opt {
+        Opt::from_iter_safe(std::iter::once("hyperdrive-uvfits-diff").chain(args.iter().copied()))
+            .unwrap()
+    }
+
+    #[test]
+    fn card_tolerances_are_parsed() {
+        assert_eq!(
+            parse_card_tolerance(" crval4=1.5").unwrap(),
+            ("CRVAL4".to_string(), 1.5)
+        );
+        assert!(parse_card_tolerance("CRVAL4").is_err());
+        assert!(parse_card_tolerance("CRVAL4=x").is_err());
+    }
+
+    #[test]
+    fn rows_are_matched_by_baseline_and_time() {
+        let baseline = uvfits(
+            "uvfits-diff-baseline",
+            &[
+                (257, 0.0, [1.0, 2.0, 1.0]),
+                (258, 0.0, [3.0, 4.0, 1.0]),
+                (258, 0.5, [5.0, 6.0, 1.0]),
+            ],
+            &[("DATE", "'2024-01-01'"), ("OBSID", "1"), ("CRVAL5", "2.0")],
+        );
+        // In another order, and a row with other times.
+        let current = uvfits(
+            "uvfits-diff-current",
+            &[
+                (258, 0.0, [3.0, 4.0005, 1.0]),
+                (257, 0.0, [1.0, 2.0, 1.0]),
+                (258, 0.25, [5.0, 6.0, 1.0]),
+            ],
+            &[("DATE", "'2025-06-30'"), ("OBSID", "2"), ("CRVAL5", "2.5")],
+        );
+
+        let d = compare(&current, &baseline, &options(&[]));
+        assert_eq!(d.rows, 2);
+        assert!((d.max_vis - 0.0005).abs() < 1e-6);
+        assert_eq!(d.worst.unwrap().baseline, 258);
+        assert_eq!(d.max_weight, 0.0);
+        assert_eq!(d.nan_mismatches, 0);
+        assert_eq!(d.only_current.len(), 1);
+        assert_eq!(d.only_baseline.len(), 1);
+        assert!(d.metadata.is_empty());
+        // DATE changes with every run, so isn't compared.
+        assert_eq!(
+            d.cards,
+            [
+                "primary card OBSID is 2, but 1 in the baseline",
+                "primary card CRVAL5 is 2.5, but 2.0 in the baseline"
+            ]
+        );
+        assert!(d.failed(&options(&[])));
+
+        let lenient = options(&[
+            "--ignore-card",
+            "OBS*",
+            "--card-tolerance",
+            "CRVAL5=0.5",
+            "--tolerance",
+            "1e-3",
+        ]);
+        let d = compare(&current, &baseline, &lenient);
+        assert!(d.cards.is_empty());
+        assert!(d.failed(&lenient));
+        let d = Differences {
+            only_current: vec![],
+            only_baseline: vec![],
+            ..d
+        };
+        assert!(!d.failed(&lenient));
+        assert!(d.failed(&options(&["--tolerance", "1e-4"])));
+    }
+}
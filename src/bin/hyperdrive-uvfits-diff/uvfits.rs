@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading the uvfits files hyperdrive writes: random groups with (u, v, w,
+//! baseline, date) parameters and a (real, imaginary, weight) triple per
+//! polarisation per fine channel, followed by an "AIPS AN" antenna table.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use byteorder::{BigEndian, ByteOrder};
+use pawsey_hyperdrive_checks::fits::Fits;
+
+/// A row of visibilities is identified by its baseline and time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Key {
+    /// The time, in tenths of a second since JD 0. Times within 0.05 s of each
+    /// other are the same.
+    pub(crate) time: i64,
+    /// The BASELINE parameter, encoding the two antennas.
+    pub(crate) baseline: u32,
+}
+
+impl Key {
+    /// The (1-indexed) antennas of the baseline. Numbers larger than 65535
+    /// use the encoding for more than 255 antennas.
+    pub(crate) fn antennas(self) -> (u32, u32) {
+        let b = self.baseline;
+        if b > 65535 {
+            ((b - 65536) / 2048, b % 2048)
+        } else {
+            (b / 256, b % 256)
+        }
+    }
+
+    pub(crate) fn describe(self) -> String {
+        let (a, b) = self.antennas();
+        format!(
+            "baseline {}-{} at JD {:.6}",
+            a,
+            b,
+            self.time as f64 / 864000.0
+        )
+    }
+}
+
+pub(crate) struct Uvfits {
+    pub(crate) path: PathBuf,
+    fits: Fits,
+    /// The centre frequency of each fine channel [Hz].
+    pub(crate) freqs: Vec<f64>,
+    /// The AIPS code of each polarisation, e.g. -5 for XX.
+    pub(crate) pols: Vec<i64>,
+    pub(crate) antenna_names: Vec<String>,
+    /// The STABXYZ of each antenna [m].
+    pub(crate) antenna_positions: Vec<Vec<f64>>,
+    /// The group of each row.
+    pub(crate) rows: HashMap<Key, usize>,
+    bitpix: i64,
+    /// The PSCAL and PZERO of each parameter before each group's values.
+    scales: Vec<(f64, f64)>,
+}
+
+impl Uvfits {
+    pub(crate) fn read(path: &Path) -> Result<Uvfits, anyhow::Error> {
+        let fits = Fits::read(path)?;
+        let bad = |what: String| {
+            anyhow!(
+                "{:?} isn't a uvfits file hyperdrive would write: {}",
+                path,
+                what
+            )
+        };
+
+        let primary = &fits.hdus[0];
+        if primary.value("GROUPS") != Some("T") {
+            bail!(bad("it doesn't have random groups".to_string()));
+        }
+        let axes = primary.axes();
+        for (n, name) in [(2, "COMPLEX"), (3, "STOKES"), (4, "FREQ")] {
+            let ctype = primary.value(&format!("CTYPE{}", n));
+            if ctype != Some(name) {
+                bail!(bad(format!("axis {} is {:?}, not {}", n, ctype, name)));
+            }
+        }
+        if axes.len() < 4 || axes[1] != 3 {
+            bail!(bad(format!(
+                "it has {:?} values per visibility, not a real, imaginary and weight",
+                axes.get(1)
+            )));
+        }
+        let bitpix = primary.int("BITPIX").unwrap_or(0);
+        if bitpix != -32 && bitpix != -64 {
+            bail!(bad(format!("its BITPIX is {}, not -32 or -64", bitpix)));
+        }
+        let axis = |n: usize| -> Vec<f64> {
+            let value = |key: &str| primary.float(&format!("{}{}", key, n)).unwrap_or(0.0);
+            let (crval, cdelt, crpix) = (value("CRVAL"), value("CDELT"), value("CRPIX"));
+            (0..axes[n - 1])
+                .map(|i| crval + (i as f64 + 1.0 - crpix) * cdelt)
+                .collect()
+        };
+        let pols = axis(3).iter().map(|p| p.round() as i64).collect();
+        let freqs = axis(4);
+
+        let antennas = fits.table(fits.hdu("AIPS AN")?)?;
+        let antenna_names = antennas
+            .strings("ANNAME")
+            .ok_or_else(|| bad("the antenna table has no ANNAME".to_string()))?;
+        let antenna_positions = antennas
+            .f64_rows("STABXYZ")
+            .ok_or_else(|| bad("the antenna table has no STABXYZ".to_string()))?;
+
+        let pcount = primary.int("PCOUNT").unwrap_or(0) as usize;
+        let scales = (1..=pcount)
+            .map(|p| {
+                (
+                    primary.float(&format!("PSCAL{}", p)).unwrap_or(1.0),
+                    primary.float(&format!("PZERO{}", p)).unwrap_or(0.0),
+                )
+            })
+            .collect();
+        let mut uvfits = Uvfits {
+            path: path.to_path_buf(),
+            freqs,
+            pols,
+            antenna_names,
+            antenna_positions,
+            rows: HashMap::new(),
+            bitpix,
+            scales,
+            fits,
+        };
+
+        // Dates may be split over several parameters (to keep their
+        // precision), which add up.
+        let param = |name: &str| -> Vec<usize> {
+            (1..=pcount)
+                .filter(|p| uvfits.fits.hdus[0].value(&format!("PTYPE{}", p)) == Some(name))
+                .map(|p| p - 1)
+                .collect()
+        };
+        let baseline = match param("BASELINE").as_slice() {
+            [b] => *b,
+            _ => bail!(bad("it doesn't have one BASELINE parameter".to_string())),
+        };
+        let dates = param("DATE");
+        if dates.is_empty() {
+            bail!(bad("it doesn't have a DATE parameter".to_string()));
+        }
+        let groups = uvfits.fits.hdus[0].int("GCOUNT").unwrap_or(0) as usize;
+        let mut rows = HashMap::with_capacity(groups);
+        for g in 0..groups {
+            let time: f64 = dates.iter().map(|&d| uvfits.param(g, d)).sum();
+            let key = Key {
+                time: (time * 864000.0).round() as i64,
+                baseline: uvfits.param(g, baseline).round() as u32,
+            };
+            if rows.insert(key, g).is_some() {
+                bail!("{:?} has more than one row for {}", path, key.describe());
+            }
+        }
+        uvfits.rows = rows;
+        Ok(uvfits)
+    }
+
+    /// The header cards of each HDU, named by its EXTNAME (or "primary" for
+    /// the first), in order.
+    pub(crate) fn headers(&self) -> impl Iterator<Item = (&str, &[(String, String)])> {
+        self.fits.hdus.iter().enumerate().map(|(i, hdu)| {
+            let name = match (i, hdu.value("EXTNAME")) {
+                (0, _) => "primary",
+                (_, Some(name)) => name,
+                (_, None) => "unnamed",
+            };
+            (name, hdu.header.as_slice())
+        })
+    }
+
+    /// The number of values in each group: a real, imaginary and weight per
+    /// polarisation per fine channel.
+    fn group_len(&self) -> usize {
+        3 * self.pols.len() * self.freqs.len()
+    }
+
+    /// The raw number at `index` of group `group`, counting its parameters.
+    fn raw(&self, group: usize, index: usize) -> f64 {
+        let size = self.bitpix.unsigned_abs() as usize / 8;
+        let start = (group * (self.scales.len() + self.group_len()) + index) * size;
+        let bytes = &self.fits.data(&self.fits.hdus[0])[start..];
+        match self.bitpix {
+            -64 => BigEndian::read_f64(bytes),
+            _ => BigEndian::read_f32(bytes) as f64,
+        }
+    }
+
+    /// The (0-indexed) parameter `p` of group `group`, scaled.
+    fn param(&self, group: usize, p: usize) -> f64 {
+        let (scale, zero) = self.scales[p];
+        self.raw(group, p) * scale + zero
+    }
+
+    /// The (real, imaginary, weight) triples of group `group`, polarisation
+    /// fastest, then fine channel.
+    pub(crate) fn values(&self, group: usize) -> Vec<f32> {
+        (0..self.group_len())
+            .map(|i| self.raw(group, self.scales.len() + i) as f32)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Pad FITS bytes to whole 2880-byte blocks.
+    fn padded(mut bytes: Vec<u8>, fill: u8) -> Vec<u8> {
+        bytes.resize(bytes.len().div_ceil(2880) * 2880, fill);
+        bytes
+    }
+
+    /// A header of `cards` (keys and values), padded to whole blocks.
+    fn header(cards: &[(&str, &str)]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = cards
+            .iter()
+            .flat_map(|(key, value)| {
+                match value.starts_with('\'') {
+                    true => format!("{:<8}= {:<70}", key, value),
+                    false => format!("{:<8}= {:>20}{:50}", key, value, ""),
+                }
+                .into_bytes()
+            })
+            .collect();
+        bytes.extend(format!("{:<80}", "END").into_bytes());
+        padded(bytes, b' ')
+    }
+
+    /// Write and read back a uvfits file `name` of 1 fine channel and 1
+    /// polarisation, with a (baseline, days since JD 2459000.5, [real,
+    /// imaginary, weight]) row for each of `rows`, and the `extra` cards in
+    /// its primary header.
+    pub(crate) fn uvfits(
+        name: &str,
+        rows: &[(u32, f32, [f32; 3])],
+        extra: &[(&str, &str)],
+    ) -> Uvfits {
+        let gcount = rows.len().to_string();
+        let mut cards = vec![
+            ("SIMPLE", "T"),
+            ("BITPIX", "-32"),
+            ("NAXIS", "6"),
+            ("NAXIS1", "0"),
+            ("NAXIS2", "3"),
+            ("NAXIS3", "1"),
+            ("NAXIS4", "1"),
+            ("NAXIS5", "1"),
+            ("NAXIS6", "1"),
+            ("GROUPS", "T"),
+            ("PCOUNT", "2"),
+            ("GCOUNT", &gcount),
+            ("PTYPE1", "'BASELINE'"),
+            ("PTYPE2", "'DATE    '"),
+            ("PZERO2", "2459000.5"),
+            ("CTYPE2", "'COMPLEX '"),
+            ("CTYPE3", "'STOKES  '"),
+            ("CRVAL3", "-5"),
+            ("CRPIX3", "1"),
+            ("CDELT3", "-1"),
+            ("CTYPE4", "'FREQ    '"),
+            ("CRVAL4", "1.8E8"),
+            ("CRPIX4", "1"),
+            ("CDELT4", "4E4"),
+        ];
+        cards.extend(extra);
+        let mut bytes = header(&cards);
+        let groups = rows
+            .iter()
+            .flat_map(|(baseline, date, values)| {
+                [*baseline as f32, *date]
+                    .iter()
+                    .chain(values)
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .flat_map(|f| f.to_be_bytes().to_vec())
+            .collect();
+        bytes.extend(padded(groups, 0));
+        bytes.extend(header(&[
+            ("XTENSION", "'BINTABLE'"),
+            ("BITPIX", "8"),
+            ("NAXIS", "2"),
+            ("NAXIS1", "32"),
+            ("NAXIS2", "2"),
+            ("PCOUNT", "0"),
+            ("GCOUNT", "1"),
+            ("TFIELDS", "2"),
+            ("TTYPE1", "'ANNAME  '"),
+            ("TFORM1", "'8A      '"),
+            ("TTYPE2", "'STABXYZ '"),
+            ("TFORM2", "'3D      '"),
+            ("EXTNAME", "'AIPS AN '"),
+        ]));
+        let mut table = vec![];
+        for &(name, x) in &[(b"Tile011\0", 1.0f64), (b"Tile012\0", 2.0)] {
+            table.extend_from_slice(name);
+            table.extend([x, 0.0, 0.0].iter().flat_map(|p| p.to_be_bytes().to_vec()));
+        }
+        bytes.extend(padded(table, 0));
+
+        let path = std::env::temp_dir().join(format!("{}-{}.uvfits", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let uvfits = Uvfits::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        uvfits.unwrap()
+    }
+
+    #[test]
+    fn rows_are_keyed_by_baseline_and_time() {
+        let u = uvfits(
+            "uvfits-rows",
+            &[(258, 0.0, [1.0, 2.0, 1.0]), (257, 0.5, [3.0, -4.0, 0.5])],
+            &[],
+        );
+        assert_eq!(
+            (u.freqs.as_slice(), u.pols.as_slice()),
+            (&[1.8e8][..], &[-5][..])
+        );
+        assert_eq!(u.antenna_names, ["Tile011", "Tile012"]);
+        assert_eq!(u.antenna_positions[1], [2.0, 0.0, 0.0]);
+        let later = Key {
+            time: (2459001.0f64 * 864000.0).round() as i64,
+            baseline: 257,
+        };
+        assert_eq!(u.values(u.rows[&later]), [3.0, -4.0, 0.5]);
+        assert_eq!(later.antennas(), (1, 1));
+        assert_eq!(later.describe(), "baseline 1-1 at JD 2459001.000000");
+        assert_eq!(u.rows.len(), 2);
+
+        // More than 255 antennas.
+        let key = Key {
+            time: 0,
+            baseline: 65536 + 2048 * 300 + 5,
+        };
+        assert_eq!(key.antennas(), (300, 5));
+    }
+}
@@ -0,0 +1,390 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable is a one-command regression test: it runs `hyperdrive
+    simulate-vis` (or another hyperdrive subcommand) in a scratch directory,
+    keeping its output and exit code, then compares what it wrote against a
+    baseline directory with hyperdrive-vis-gen-diff, along with how long
+    hyperdrive took (see --max-slowdown in --diff-args).
+
+    hyperdrive's arguments are given after "--", or in a TOML file like
+
+    ```toml
+    subcommand = "simulate-vis"
+
+    [args]
+    metafits = "1065880128.metafits"
+    source-list = "srclist_pumav3_EoR0aegean_EoR1pietro+ForA_1065880128_100.yaml"
+    num-timesteps = 10
+    cpu = true
+    ```
+
+    where each argument becomes an option (e.g. "--num-timesteps 10"), and
+    `true` an option on its own.
+
+    With --slurm, hyperdrive is run as a SLURM job, which is waited for before
+    its outputs are compared.
+*/
+
+mod slurm;
+#[path = "../common/usage.rs"]
+mod usage;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::{exit_code, exit_code_of};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+struct Opt {
+    /// The arguments to give hyperdrive, after the subcommand.
+    #[structopt(name = "HYPERDRIVE_ARGS", last = true)]
+    hyperdrive_args: Vec<String>,
+
+    /// Read hyperdrive's subcommand and arguments from this TOML file, rather
+    /// than the command line.
+    #[structopt(long, conflicts_with = "HYPERDRIVE_ARGS", parse(from_os_str))]
+    args_file: Option<PathBuf>,
+
+    /// The hyperdrive subcommand to run, unless the --args-file says.
+    #[structopt(long, default_value = "simulate-vis")]
+    subcommand: String,
+
+    /// The hyperdrive executable.
+    #[structopt(long, default_value = "hyperdrive")]
+    hyperdrive: String,
+
+    /// The directory containing the baseline files.
+    #[structopt(long, default_value = "baseline", parse(from_os_str))]
+    baseline_dir: PathBuf,
+
+    /// The directory to run hyperdrive in. It's emptied first, and kept
+    /// afterwards, along with hyperdrive's stdout and stderr.
+    #[structopt(long, default_value = "hyperdrive-check", parse(from_os_str))]
+    scratch_dir: PathBuf,
+
+    /// Options for hyperdrive-vis-gen-diff, e.g. "--tolerance 1e-4
+    /// --fine-channels 32".
+    #[structopt(long, default_value = "", allow_hyphen_values = true)]
+    diff_args: String,
+
+    /// Run hyperdrive as a SLURM job (submitted with sbatch), wait for it to
+    /// finish, then compare its outputs. The scratch directory must be on a
+    /// filesystem the compute nodes share.
+    #[structopt(long)]
+    slurm: bool,
+
+    /// The SLURM partition to run hyperdrive in, e.g. "gpuq".
+    #[structopt(long, requires = "slurm")]
+    partition: Option<String>,
+
+    /// The SLURM account to charge the job to.
+    #[structopt(long, requires = "slurm")]
+    account: Option<String>,
+
+    /// The number of GPUs to ask SLURM for.
+    #[structopt(long, requires = "slurm")]
+    gpus: Option<u32>,
+
+    /// The SLURM job's time limit, e.g. "01:00:00".
+    #[structopt(long, requires = "slurm")]
+    time: Option<String>,
+
+    /// Other sbatch options for the job, e.g. "--mem=64G --cpus-per-task=8".
+    #[structopt(long, requires = "slurm", allow_hyphen_values = true)]
+    sbatch_args: Option<String>,
+
+    /// How often to check on the SLURM job [seconds].
+    #[structopt(long, default_value = "30")]
+    poll_interval: u64,
+
+    /// Don't print anything; only set the exit code.
+    #[structopt(short, long)]
+    quiet: bool,
+}
+
+/// hyperdrive's subcommand and arguments, from an --args-file. Only a subset
+/// of TOML is read: top-level `subcommand = "..."`, and an `[args]` table of
+/// strings, numbers and booleans.
+fn read_args_file(path: &Path) -> Result<(Option<String>, Vec<String>), anyhow::Error> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("Couldn't read {:?}: {}", path, e))?;
+    let mut subcommand = None;
+    let mut args = vec![];
+    let mut in_args = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[args]" {
+            in_args = true;
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => bail!("{:?} line {}: expected KEY = VALUE", path, i + 1),
+        };
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(s) => s.to_string(),
+            None => value.to_string(),
+        };
+        match (in_args, key) {
+            (false, "subcommand") => subcommand = Some(value),
+            (false, _) => bail!("{:?} line {}: unknown key \"{}\"", path, i + 1, key),
+            (true, _) if value == "true" => args.push(format!("--{}", key)),
+            (true, _) if value == "false" => (),
+            (true, _) => {
+                args.push(format!("--{}", key));
+                args.push(value);
+            }
+        }
+    }
+    Ok((subcommand, args))
+}
+
+/// hyperdrive runs in the scratch directory, so arguments naming files that
+/// exist here are made absolute.
+fn absolutise(args: Vec<String>, dir: &Path) -> Vec<String> {
+    args.into_iter()
+        .map(|a| {
+            let path = dir.join(&a);
+            if !a.starts_with('-') && Path::new(&a).is_relative() && path.exists() {
+                path.display().to_string()
+            } else {
+                a
+            }
+        })
+        .collect()
+}
+
+/// hyperdrive-vis-gen-diff: preferably the one beside this executable, so that
+/// the two are the same version.
+fn diff_exe() -> PathBuf {
+    let name = "hyperdrive-vis-gen-diff";
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| Some(exe.parent()?.join(name)))
+        .filter(|exe| exe.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// The last few lines of `text`, for an error message.
+fn tail(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(10)..].join("\n")
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit_code_of(e.chain()));
+    }
+}
+
+fn run() -> Result<(), anyhow::Error> {
+    let options = Opt::from_iter_safe(std::env::args_os())
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let here = std::env::current_dir()?;
+    let (subcommand, args) = match &options.args_file {
+        Some(path) => {
+            let (subcommand, args) = read_args_file(path)?;
+            // Arguments in the file are relative to the file.
+            let dir = here.join(path.parent().unwrap_or_else(|| Path::new("")));
+            (
+                subcommand.unwrap_or_else(|| options.subcommand.clone()),
+                absolutise(args, &dir),
+            )
+        }
+        None => (
+            options.subcommand.clone(),
+            absolutise(options.hyperdrive_args.clone(), &here),
+        ),
+    };
+    let baseline = here.join(&options.baseline_dir);
+    if !baseline.is_dir() {
+        bail!("Baseline directory {:?} does not exist!", baseline);
+    }
+
+    let scratch = here.join(&options.scratch_dir);
+    if scratch.exists() {
+        std::fs::remove_dir_all(&scratch)
+            .map_err(|e| anyhow!("Couldn't empty {:?}: {}", scratch, e))?;
+    }
+    std::fs::create_dir_all(&scratch)?;
+
+    if !options.quiet {
+        println!(
+            "Running {} {} {} in {:?} ...",
+            options.hyperdrive,
+            subcommand,
+            args.join(" "),
+            scratch
+        );
+    }
+    // A relative path to hyperdrive would otherwise be relative to the
+    // scratch directory.
+    let hyperdrive = if options.hyperdrive.contains('/') {
+        here.join(&options.hyperdrive)
+    } else {
+        PathBuf::from(&options.hyperdrive)
+    };
+    let (succeeded, status, wall_time) = if options.slurm {
+        let mut command = vec![hyperdrive.display().to_string(), subcommand];
+        command.extend(args);
+        let resources = slurm::Resources {
+            partition: options.partition.as_deref(),
+            account: options.account.as_deref(),
+            gpus: options.gpus,
+            time: options.time.as_deref(),
+            extra: options
+                .sbatch_args
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect(),
+        };
+        let job = slurm::run(
+            &command,
+            &scratch,
+            &resources,
+            Duration::from_secs(options.poll_interval),
+            options.quiet,
+        )?;
+        (
+            job.succeeded(),
+            format!(
+                "SLURM job {} {}, exit code {}",
+                job.id, job.state, job.exit_code
+            ),
+            job.elapsed,
+        )
+    } else {
+        let started = Instant::now();
+        let output = Command::new(&hyperdrive)
+            .arg(&subcommand)
+            .args(&args)
+            .current_dir(&scratch)
+            .output()
+            .map_err(|e| anyhow!("Couldn't run {}: {}", options.hyperdrive, e))?;
+        let wall_time = started.elapsed();
+        std::fs::write(scratch.join("hyperdrive.stdout"), &output.stdout)?;
+        std::fs::write(scratch.join("hyperdrive.stderr"), &output.stderr)?;
+        (
+            output.status.success(),
+            output.status.to_string(),
+            wall_time,
+        )
+    };
+    if !succeeded {
+        // SLURM may not have written any stderr, e.g. if the job was
+        // cancelled before it started.
+        let stderr = std::fs::read(scratch.join("hyperdrive.stderr")).unwrap_or_default();
+        bail!(
+            "hyperdrive failed ({}); its output is in {:?}. The end of its stderr:\n{}",
+            status,
+            scratch,
+            tail(&String::from_utf8_lossy(&stderr))
+        );
+    }
+
+    let mut diff = Command::new(diff_exe());
+    // hyperdrive's timings are checked against the baseline's too.
+    diff.arg(&baseline)
+        .arg("--hyperdrive-log=hyperdrive.stdout")
+        .arg("--hyperdrive-log=hyperdrive.stderr")
+        .arg(format!("--wall-time={}", wall_time.as_secs_f64()))
+        .args(options.diff_args.split_whitespace())
+        .current_dir(&scratch);
+    if options.quiet {
+        diff.arg("--quiet");
+    }
+    let status = diff
+        .status()
+        .map_err(|e| anyhow!("Couldn't run hyperdrive-vis-gen-diff: {}", e))?;
+    std::process::exit(status.code().unwrap_or(exit_code::ERROR));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_files_are_read_as_arguments() {
+        let dir = std::env::temp_dir().join(format!("vis-gen-check-args-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("args.toml");
+        std::fs::write(
+            &path,
+            "# The usual run\n\
+             subcommand = \"vis-simulate\"\n\
+             \n\
+             [args]\n\
+             metafits = \"1090008640.metafits\"\n\
+             num-sources = 10\n\
+             no-beam = true\n\
+             cpu = false\n",
+        )
+        .unwrap();
+        let (subcommand, args) = read_args_file(&path).unwrap();
+        assert_eq!(subcommand.as_deref(), Some("vis-simulate"));
+        assert_eq!(
+            args,
+            [
+                "--metafits",
+                "1090008640.metafits",
+                "--num-sources",
+                "10",
+                "--no-beam"
+            ]
+        );
+
+        std::fs::write(&path, "tolerance = 1\n").unwrap();
+        let e = read_args_file(&path).unwrap_err().to_string();
+        assert!(e.ends_with("line 1: unknown key \"tolerance\""), "{}", e);
+        std::fs::write(&path, "[args]\nno-beam\n").unwrap();
+        let e = read_args_file(&path).unwrap_err().to_string();
+        assert!(e.ends_with("line 2: expected KEY = VALUE"), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_existing_files_are_made_absolute() {
+        let dir = std::env::temp_dir().join(format!("vis-gen-check-abs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("srclist.yaml"), "").unwrap();
+        let args = [
+            "--source-list",
+            "srclist.yaml",
+            "--num-sources",
+            "10",
+            "/a.fits",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        assert_eq!(
+            absolutise(args, &dir),
+            [
+                "--source-list".to_string(),
+                dir.join("srclist.yaml").display().to_string(),
+                "--num-sources".to_string(),
+                "10".to_string(),
+                "/a.fits".to_string()
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tails_are_the_last_ten_lines() {
+        let text: String = (1..=12).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(tail(&text), "3\n4\n5\n6\n7\n8\n9\n10\n11\n12");
+        assert_eq!(tail("only\n"), "only");
+    }
+}
@@ -0,0 +1,237 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Running hyperdrive as a SLURM job, as it must be on e.g. Garrawarla. A
+//! batch script is written to the scratch directory and submitted with
+//! sbatch, and the job is polled with sacct until it finishes. The scratch
+//! directory must be on a filesystem the compute nodes share.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+
+/// What the batch script asks of SLURM.
+pub(crate) struct Resources<'a> {
+    pub(crate) partition: Option<&'a str>,
+    pub(crate) account: Option<&'a str>,
+    pub(crate) gpus: Option<u32>,
+    /// The time limit, e.g. "01:00:00".
+    pub(crate) time: Option<&'a str>,
+    /// Any other sbatch options.
+    pub(crate) extra: Vec<&'a str>,
+}
+
+/// How a finished job went.
+pub(crate) struct Job {
+    pub(crate) id: String,
+    /// The final state, e.g. "COMPLETED" or "TIMEOUT".
+    pub(crate) state: String,
+    pub(crate) exit_code: String,
+    pub(crate) elapsed: Duration,
+}
+
+impl Job {
+    pub(crate) fn succeeded(&self) -> bool {
+        self.state == "COMPLETED" && self.exit_code == "0:0"
+    }
+}
+
+/// The states of a job that hasn't finished yet.
+const UNFINISHED: &[&str] = &[
+    "",
+    "PENDING",
+    "CONFIGURING",
+    "RUNNING",
+    "COMPLETING",
+    "SUSPENDED",
+    "REQUEUED",
+    "RESIZING",
+];
+
+/// Quote `s` for bash.
+fn quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// The batch script running `command` in `scratch`, with its stdout and
+/// stderr written to hyperdrive.stdout and hyperdrive.stderr there.
+fn script(command: &[String], scratch: &Path, resources: &Resources) -> String {
+    let mut script = String::from("#!/bin/bash\n#SBATCH --job-name=hyperdrive-check\n");
+    let mut directive = |d: String| script.push_str(&format!("#SBATCH {}\n", d));
+    directive(format!("--chdir={}", scratch.display()));
+    directive("--output=hyperdrive.stdout".to_string());
+    directive("--error=hyperdrive.stderr".to_string());
+    if let Some(p) = resources.partition {
+        directive(format!("--partition={}", p));
+    }
+    if let Some(a) = resources.account {
+        directive(format!("--account={}", a));
+    }
+    if let Some(g) = resources.gpus {
+        directive(format!("--gres=gpu:{}", g));
+    }
+    if let Some(t) = resources.time {
+        directive(format!("--time={}", t));
+    }
+    for e in &resources.extra {
+        directive(e.to_string());
+    }
+    let words: Vec<String> = command.iter().map(|w| quote(w)).collect();
+    script.push_str(&format!("\n{}\n", words.join(" ")));
+    script
+}
+
+/// Submit `command` as a job running in `scratch`, and wait for it to
+/// finish, checking on it every `poll`.
+pub(crate) fn run(
+    command: &[String],
+    scratch: &Path,
+    resources: &Resources,
+    poll: Duration,
+    quiet: bool,
+) -> Result<Job, anyhow::Error> {
+    let path = scratch.join("hyperdrive.sbatch");
+    std::fs::write(&path, script(command, scratch, resources))?;
+    let output = Command::new("sbatch")
+        .arg("--parsable")
+        .arg(&path)
+        .output()
+        .map_err(|e| anyhow!("Couldn't run sbatch: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "sbatch couldn't submit {:?}:\n{}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    // --parsable prints "ID" or "ID;CLUSTER".
+    let id = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    if id.is_empty() {
+        bail!("sbatch didn't say what job {:?} was submitted as", path);
+    }
+    if !quiet {
+        println!("Submitted SLURM job {}; waiting for it to finish ...", id);
+    }
+
+    let mut last_state = String::new();
+    loop {
+        std::thread::sleep(poll);
+        let job = query(&id)?;
+        if job.state != last_state && !quiet {
+            println!("SLURM job {} is {}", id, job.state);
+        }
+        if !UNFINISHED.contains(&job.state.as_str()) {
+            return Ok(job);
+        }
+        last_state = job.state;
+    }
+}
+
+/// Ask sacct how job `id` is going. Just after submission, sacct may not
+/// know of it; its state is then "".
+fn query(id: &str) -> Result<Job, anyhow::Error> {
+    let output = Command::new("sacct")
+        .args([
+            "-n",
+            "-P",
+            "-X",
+            "-o",
+            "State,ExitCode,ElapsedRaw",
+            "-j",
+            id,
+        ])
+        .output()
+        .map_err(|e| anyhow!("Couldn't run sacct: {}", e))?;
+    if !output.status.success() {
+        bail!(
+            "sacct couldn't find job {}:\n{}",
+            id,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split('|')
+        .collect();
+    let field = |i: usize| fields.get(i).copied().unwrap_or_default().trim();
+    Ok(Job {
+        id: id.to_string(),
+        // e.g. "CANCELLED by 1234"
+        state: field(0).split(' ').next().unwrap_or_default().to_string(),
+        exit_code: field(1).to_string(),
+        elapsed: Duration::from_secs(field(2).parse().unwrap_or(0)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_quoted_only_when_needed() {
+        assert_eq!(quote("--num-sources=10"), "--num-sources=10");
+        assert_eq!(quote("/scratch/a.fits"), "/scratch/a.fits");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+        assert_eq!(quote("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn scripts_ask_for_the_resources() {
+        let command = ["hyperdrive", "vis-simulate", "-m", "a b.metafits"].map(String::from);
+        let resources = Resources {
+            partition: Some("gpuq"),
+            account: None,
+            gpus: Some(1),
+            time: Some("01:00:00"),
+            extra: vec!["--mem=10G"],
+        };
+        assert_eq!(
+            script(&command, Path::new("/scratch/check"), &resources),
+            "#!/bin/bash\n\
+             #SBATCH --job-name=hyperdrive-check\n\
+             #SBATCH --chdir=/scratch/check\n\
+             #SBATCH --output=hyperdrive.stdout\n\
+             #SBATCH --error=hyperdrive.stderr\n\
+             #SBATCH --partition=gpuq\n\
+             #SBATCH --gres=gpu:1\n\
+             #SBATCH --time=01:00:00\n\
+             #SBATCH --mem=10G\n\
+             \n\
+             hyperdrive vis-simulate -m 'a b.metafits'\n"
+        );
+    }
+
+    #[test]
+    fn only_completed_jobs_exiting_0_succeeded() {
+        let job = |state: &str, exit_code: &str| Job {
+            id: "1".to_string(),
+            state: state.to_string(),
+            exit_code: exit_code.to_string(),
+            elapsed: Duration::from_secs(1),
+        };
+        assert!(job("COMPLETED", "0:0").succeeded());
+        assert!(!job("COMPLETED", "1:0").succeeded());
+        assert!(!job("FAILED", "0:0").succeeded());
+        assert!(!job("TIMEOUT", "0:15").succeeded());
+    }
+}
@@ -0,0 +1,263 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Bundle a baseline directory, with its manifest, into a single tar.zst to
+//! move it between filesystems, and restore it. The manifest's sizes and
+//! XXH64s of the files are checked both before archiving and after unpacking,
+//! so a baseline that was damaged on the way is never restored.
+//!
+//! The tarball is made and unpacked by piping through tar and zstd.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::Error;
+
+use crate::manifest::{Manifest, MANIFEST};
+
+/// The default name of the archive of the baseline directory `dir`.
+pub(crate) fn default_name(dir: &Path) -> PathBuf {
+    let name = std::fs::canonicalize(dir)
+        .ok()
+        .and_then(|d| d.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "baseline".to_string());
+    PathBuf::from(format!("{}.tar.zst", name))
+}
+
+/// Write the baseline directory `dir` (its manifest and the files it lists)
+/// into the tar.zst `output`, having checked the files against the manifest.
+pub(crate) fn archive(dir: &Path, output: &Path, quiet: bool) -> Result<(), anyhow::Error> {
+    let manifest = Manifest::read(dir)?.ok_or_else(|| {
+        Error::Missing(format!(
+            "{} has no {}, so its files can't be checksummed; make it with the snapshot subcommand",
+            dir.display(),
+            MANIFEST
+        ))
+    })?;
+    manifest
+        .verify(dir)
+        .map_err(|e| e.context(format!("Refusing to archive {}", dir.display())))?;
+
+    // Write somewhere else first, so that an interrupted archive is never
+    // mistaken for a complete one.
+    let partial = PathBuf::from(format!("{}.partial", output.display()));
+    let mut tar = Command::new("tar");
+    tar.arg("-C")
+        .arg(dir)
+        .args(["-cf", "-", MANIFEST])
+        .args(manifest.files.iter().map(|f| &f.name));
+    let mut zstd = Command::new("zstd");
+    zstd.args(["-q", "-f", "-T0", "-o"]).arg(&partial);
+    if let Err(e) = pipe(tar, zstd) {
+        let _ = std::fs::remove_file(&partial);
+        bail!("Couldn't archive {}: {}", dir.display(), e);
+    }
+    std::fs::rename(&partial, output).map_err(|e| anyhow!("Couldn't write {:?}: {}", output, e))?;
+
+    if !quiet {
+        println!(
+            "Archived {} files ({} bytes) of {} into {}",
+            manifest.files.len(),
+            manifest.files.iter().map(|f| f.bytes).sum::<u64>(),
+            dir.display(),
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// Unpack the tar.zst `archive` into the baseline directory `dir`, which must
+/// not already have anything in it, if every file matches the manifest. If
+/// `verify_only` is set, only check the archive; `dir` isn't touched.
+pub(crate) fn restore(
+    archive: &Path,
+    dir: &Path,
+    verify_only: bool,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    if !archive.is_file() {
+        bail!(Error::Missing(format!(
+            "{} doesn't exist",
+            archive.display()
+        )));
+    }
+    let partial = if verify_only {
+        std::env::temp_dir().join(format!("hyperdrive-checks-verify.{}", std::process::id()))
+    } else {
+        if std::fs::read_dir(dir).is_ok_and(|mut d| d.next().is_some()) {
+            bail!(
+                "{} already has files in it; restore into a new directory",
+                dir.display()
+            );
+        }
+        PathBuf::from(format!("{}.partial", dir.display()))
+    };
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    std::fs::create_dir_all(&partial)
+        .map_err(|e| anyhow!("Couldn't create {:?}: {}", partial, e))?;
+
+    let unpacked = unpack(archive, &partial);
+    if unpacked.is_err() || verify_only {
+        let _ = std::fs::remove_dir_all(&partial);
+    }
+    let manifest =
+        unpacked.map_err(|e| e.context(format!("Refusing to restore {}", archive.display())))?;
+
+    if verify_only {
+        if !quiet {
+            println!(
+                "{} is intact: {} files, {}",
+                archive.display(),
+                manifest.files.len(),
+                manifest.describe()
+            );
+        }
+        return Ok(());
+    }
+    if dir.exists() {
+        // Empty, as checked above.
+        std::fs::remove_dir(dir)?;
+    }
+    std::fs::rename(&partial, dir).map_err(|e| anyhow!("Couldn't create {:?}: {}", dir, e))?;
+    if !quiet {
+        println!(
+            "Restored {} files into {}; the baseline was {}",
+            manifest.files.len(),
+            dir.display(),
+            manifest.describe()
+        );
+    }
+    Ok(())
+}
+
+/// Unpack `archive` into the directory `into` and check it against the
+/// manifest it holds.
+fn unpack(archive: &Path, into: &Path) -> Result<Manifest, anyhow::Error> {
+    let mut zstd = Command::new("zstd");
+    zstd.args(["-q", "-d", "-c"]).arg(archive);
+    let mut tar = Command::new("tar");
+    tar.arg("-C").arg(into).args(["-xf", "-"]);
+    // Neither fails on a whole archive.
+    pipe(zstd, tar).map_err(|e| Error::Shape(format!("it's corrupt: {}", e)))?;
+    let manifest = Manifest::read(into)?
+        .ok_or_else(|| Error::Missing(format!("it doesn't have a {}", MANIFEST)))?;
+    manifest.verify(into)?;
+    Ok(manifest)
+}
+
+/// Run `from | to`, failing if either fails.
+fn pipe(mut from: Command, mut to: Command) -> Result<(), anyhow::Error> {
+    let mut first = from
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Couldn't run {:?}: {}", from.get_program(), e))?;
+    // Guaranteed to be present, as it was piped.
+    let output = to
+        .stdin(first.stdout.take().unwrap())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| anyhow!("Couldn't run {:?}: {}", to.get_program(), e))?;
+    let first_output = first.wait_with_output()?;
+    for (command, output) in [(&from, &first_output), (&to, &output)] {
+        if !output.status.success() {
+            bail!(
+                "{:?} failed: {}",
+                command.get_program(),
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash;
+    use crate::manifest::ManifestFile;
+
+    /// A baseline directory `name`, with a manifest listing its one file.
+    fn baseline(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hyperdrive_band01.bin");
+        std::fs::write(&path, 1.5f32.to_le_bytes()).unwrap();
+        let (bytes, xxh64) = hash::xxh64_file(&path).unwrap();
+        let manifest = Manifest {
+            hyperdrive_version: "0.2.0".to_string(),
+            files: vec![ManifestFile {
+                name: PathBuf::from("hyperdrive_band01.bin"),
+                bytes,
+                xxh64,
+            }],
+            ..Manifest::default()
+        };
+        manifest.write(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn baselines_are_restored_as_archived() {
+        let dir = baseline("archive");
+        let output = dir.with_extension("tar.zst");
+        let restored = dir.with_extension("restored");
+        archive(&dir, &output, true).unwrap();
+        restore(&output, &restored, true, true).unwrap();
+        assert!(!restored.exists());
+        restore(&output, &restored, false, true).unwrap();
+        let read = |d: &Path| std::fs::read(d.join("hyperdrive_band01.bin")).unwrap();
+        assert_eq!(read(&restored), read(&dir));
+
+        // Nothing is restored over an existing baseline.
+        let e = restore(&output, &restored, false, true).unwrap_err();
+        assert!(e.to_string().contains("already has files in it"), "{}", e);
+
+        // Nor from a damaged archive.
+        let mut bytes = std::fs::read(&output).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        std::fs::write(&output, bytes).unwrap();
+        let e = restore(&output, &dir.with_extension("damaged"), false, true).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Shape(_))), "{:?}", e);
+        assert!(!dir.with_extension("damaged").exists());
+
+        for path in [&dir, &restored] {
+            std::fs::remove_dir_all(path).unwrap();
+        }
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn damaged_baselines_are_not_archived() {
+        let dir = baseline("archive-damaged");
+        std::fs::write(dir.join("hyperdrive_band01.bin"), 2.5f32.to_le_bytes()).unwrap();
+        let output = dir.with_extension("tar.zst");
+        let e = archive(&dir, &output, true).unwrap_err();
+        assert!(e.to_string().starts_with("Refusing to archive"), "{}", e);
+        assert!(!output.exists());
+
+        std::fs::remove_file(dir.join(MANIFEST)).unwrap();
+        let e = archive(&dir, &output, true).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Missing(_))), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let e = restore(&output, &dir, false, true).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Missing(_))), "{}", e);
+    }
+
+    #[test]
+    fn archives_are_named_after_the_directory() {
+        let dir = baseline("archive-name");
+        let name = format!("archive-name-{}.tar.zst", std::process::id());
+        assert_eq!(default_name(&dir), PathBuf::from(name));
+        assert_eq!(
+            default_name(Path::new("/nonexistent/baseline")),
+            PathBuf::from("baseline.tar.zst")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! shields.io "endpoint" badges summarising a run.
+//!
+//! See <https://shields.io/endpoint> for the format.
+
+use std::path::Path;
+
+use crate::json::json_string;
+
+/// Write a badge JSON file saying whether the run passed.
+pub(crate) fn write_badge(path: &Path, label: &str, passed: bool) -> std::io::Result<()> {
+    let (message, color) = if passed {
+        ("passing", "brightgreen")
+    } else {
+        ("failing", "red")
+    };
+    std::fs::write(
+        path,
+        format!(
+            "{{\"schemaVersion\": 1, \"label\": {}, \"message\": \"{}\", \"color\": \"{}\"}}\n",
+            json_string(label),
+            message,
+            color
+        ),
+    )
+}
@@ -0,0 +1,123 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Compare outputs against several baselines in one run, each with its own
+//! tolerance, e.g. a CPU reference held to 1e-6 and a GPU baseline held to
+//! 1e-3, with a verdict for each.
+
+use std::path::PathBuf;
+
+use pawsey_hyperdrive_checks::exit_code;
+
+use crate::read::Reader;
+use crate::{
+    band_file_pairs, compare_pair, container, parse_number, preflight, split_bands, summary_value,
+    DiffOpt, Differences, Verbosity,
+};
+
+/// Parse a baseline set given like "DIR:TOLERANCE", e.g. "gpu-baseline:1e-3".
+/// The tolerance is after the last colon, so the directory may be an
+/// "apptainer://" one.
+pub(crate) fn parse(s: &str) -> Result<(PathBuf, f32), String> {
+    let (dir, tolerance) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected a baseline like \"DIR:TOLERANCE\", not \"{}\"", s))?;
+    if dir.is_empty() {
+        return Err(format!("\"{}\" doesn't name a baseline directory", s));
+    }
+    Ok((PathBuf::from(dir), parse_number(tolerance)?))
+}
+
+/// The result of comparing against one baseline set.
+struct Verdict<'a> {
+    dir: &'a PathBuf,
+    tolerance: f32,
+    overall: Differences,
+    /// The name of the file (or band) with the largest difference.
+    worst: String,
+    files: usize,
+    failures: usize,
+}
+
+pub(crate) fn compare_sets(options: &DiffOpt, reader: &Reader) -> Result<(), anyhow::Error> {
+    let mut comparison = options.compare.comparison()?;
+    let mut verdicts = vec![];
+    for (i, (dir, tolerance)) in options.baseline_sets.iter().enumerate() {
+        let baseline = container::resolve(dir, options.compare.quiet)?;
+        let pairs = band_file_pairs(options, &baseline, i == 0)?;
+        preflight(&pairs, &options.compare)?;
+        let pairs = match options.compare.bands {
+            Some(bands) => split_bands(pairs, bands, options.compare.dtype)?,
+            None => pairs,
+        };
+        comparison.tolerance = *tolerance;
+        let mut verdict = Verdict {
+            dir,
+            tolerance: *tolerance,
+            overall: Differences::default(),
+            worst: String::new(),
+            files: pairs.len(),
+            failures: 0,
+        };
+        for pair in &pairs {
+            // Nothing is printed when quiet.
+            let d = compare_pair(
+                pair,
+                &comparison,
+                reader,
+                Verbosity::Quiet,
+                &mut String::new(),
+            )?;
+            if options.compare.failed_at(&d, *tolerance) {
+                verdict.failures += 1;
+            }
+            if verdict.worst.is_empty() || d.max_abs > verdict.overall.max_abs {
+                verdict.worst = pair.name.display().to_string();
+            }
+            verdict.overall = verdict.overall.max(d);
+        }
+        verdicts.push(verdict);
+    }
+
+    if !options.compare.quiet {
+        println!(
+            "Compared against {} baseline sets:",
+            options.baseline_sets.len()
+        );
+        println!(
+            "{:<30} {:>10} {:>14} {:>9} {:>6}",
+            "baseline", "tolerance", "max diff", "failures", "result"
+        );
+        for v in &verdicts {
+            println!(
+                "{:<30} {:>10e} {:>14e} {:>9} {:>6}",
+                v.dir.display(),
+                v.tolerance,
+                v.overall.max_abs,
+                format!("{}/{}", v.failures, v.files),
+                if v.failures > 0 { "FAIL" } else { "pass" }
+            );
+        }
+        // Last, so that scripts scanning logs can find the verdicts easily.
+        for v in &verdicts {
+            println!(
+                "HYPERDRIVE-CHECKS baseline={} result={} max_diff={:e} worst={} files={} tolerance={:e}",
+                summary_value(&v.dir.display().to_string()),
+                if v.failures > 0 { "FAIL" } else { "PASS" },
+                v.overall.max_abs,
+                summary_value(&v.worst),
+                v.files,
+                v.tolerance
+            );
+        }
+    }
+
+    if verdicts.iter().any(|v| v.failures > 0) {
+        if !options.compare.quiet {
+            println!("Difference is too large for some baselines; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
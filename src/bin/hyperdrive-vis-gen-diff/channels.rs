@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Treating fine channels differently depending on where they are in their
+//! coarse band.
+//!
+//! Each file (or band, with --bands) is taken to be a single coarse band, in
+//! which the fine channel varies slower than the polarisation and real/imag
+//! parts, but faster than everything else (e.g. timestep and baseline).
+
+#[derive(Debug)]
+pub(crate) struct Channels {
+    /// The number of fine channels per coarse band.
+    pub(crate) fine_channels: u64,
+    /// The number of consecutive floats belonging to one fine channel, e.g. 8
+    /// for 4 complex polarisations.
+    pub(crate) floats_per_channel: u64,
+    pub(crate) ramp: Ramp,
+    /// How many fine channels at each edge of a coarse band to not compare at
+    /// all.
+    pub(crate) exclude_edges: u64,
+    /// Don't compare the centre (DC) fine channel of each coarse band.
+    pub(crate) exclude_centre: bool,
+    /// Don't compare these fine channels of each coarse band either.
+    pub(crate) ignored: ChannelSet,
+}
+
+/// (N, F) pairs: multiply the tolerance by F for the outer N fine channels at
+/// each edge. Sorted by N, so that the narrowest matching entry wins.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Ramp(Vec<(u64, f32)>);
+
+/// Fine channels of a coarse band, sorted.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChannelSet(Vec<u64>);
+
+impl Channels {
+    /// The number of floats in one coarse band's worth of fine channels.
+    pub(crate) fn floats_per_band(&self) -> u64 {
+        self.fine_channels * self.floats_per_channel
+    }
+
+    /// The fine channel that the float at `index` belongs to.
+    pub(crate) fn channel(&self, index: usize) -> u64 {
+        (index as u64 / self.floats_per_channel) % self.fine_channels
+    }
+
+    /// How far the fine channel of the float at `index` is from the nearest
+    /// edge of its coarse band; 0 for the outermost channels.
+    fn edge_distance(&self, index: usize) -> u64 {
+        let c = self.channel(index);
+        c.min(self.fine_channels - 1 - c)
+    }
+
+    /// Should the float at `index` be left out of the comparison?
+    pub(crate) fn excluded(&self, index: usize) -> bool {
+        self.edge_distance(index) < self.exclude_edges
+            || self.exclude_centre && self.channel(index) == self.fine_channels / 2
+            || self.ignored.0.binary_search(&self.channel(index)).is_ok()
+    }
+
+    /// What to multiply the tolerance by for the float at `index`.
+    pub(crate) fn tolerance_factor(&self, index: usize) -> f32 {
+        let d = self.edge_distance(index);
+        self.ramp
+            .0
+            .iter()
+            .find(|(n, _)| d < *n)
+            .map_or(1.0, |(_, f)| *f)
+    }
+}
+
+/// Parse a tolerance ramp like "1:100,2:10": the outermost fine channel at each
+/// edge gets 100 times the tolerance, and the next one in gets 10 times.
+pub(crate) fn parse_ramp(s: &str) -> Result<Ramp, String> {
+    let mut ramp = vec![];
+    for entry in s.split(',') {
+        let mut parts = entry.splitn(2, ':');
+        let (n, f) = match (parts.next(), parts.next()) {
+            (Some(n), Some(f)) => (n, f),
+            _ => return Err(format!("Expected N:FACTOR, not \"{}\"", entry)),
+        };
+        let n: u64 = crate::parse_number(n)?;
+        let f: f32 = crate::parse_number(f)?;
+        if n == 0 || f <= 0.0 {
+            return Err(format!(
+                "Expected a positive number of channels and factor, not \"{}\"",
+                entry
+            ));
+        }
+        ramp.push((n, f));
+    }
+    ramp.sort_by_key(|&(n, _)| n);
+    Ok(Ramp(ramp))
+}
+
+/// Parse a list of fine channels like "0-1,16,30-31" (ranges are inclusive).
+pub(crate) fn parse_channels(s: &str) -> Result<ChannelSet, String> {
+    let mut channels = vec![];
+    for entry in s.split(',') {
+        let mut parts = entry.splitn(2, '-');
+        let first: u64 = crate::parse_number(parts.next().unwrap_or(""))?;
+        let last: u64 = match parts.next() {
+            Some(last) => crate::parse_number(last)?,
+            None => first,
+        };
+        if last < first {
+            return Err(format!("\"{}\" is an empty range of channels", entry));
+        }
+        channels.extend(first..=last);
+    }
+    channels.sort_unstable();
+    channels.dedup();
+    Ok(ChannelSet(channels))
+}
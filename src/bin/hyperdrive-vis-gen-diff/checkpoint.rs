@@ -0,0 +1,373 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A record of the comparisons completed so far, so that a run killed part of
+//! the way through (e.g. by a node failure or a SLURM time limit) can carry on
+//! where it left off.
+//!
+//! After a header line naming the version of the format and a fingerprint of
+//! the options that affect the results (see `Comparison::fingerprint`), so
+//! that a checkpoint isn't resumed with different ones, each line is a file
+//! (or band) name followed by its results, separated by tabs: max abs, max
+//! rel, max sigmas, max scaled, chi2, samples, RMS growth, sampled blocks,
+//! total blocks, current hash, baseline hash, weighted RMS, max ULPs,
+//! violations, sum of absolute differences, sum of squared differences, count
+//! above the tolerance, max amplitude difference, max scaled amplitude
+//! difference, max phase difference, NaNs in the current and baseline files,
+//! infinities in the current and baseline files, failing NaNs, sum of signed
+//! differences, the median, 95th and 99th percentiles of the absolute
+//! differences, and floats skipped. Floats are hex bit patterns, so they
+//! survive exactly; hashes are hex, or "-" if not known. Lines are only ever
+//! appended, one per completed comparison, so a run killed mid-write leaves at
+//! most a partial last line, which is ignored.
+//!
+//! Version 1 checkpoints gained fields without changing version, so their
+//! lines may stop after any of the fields added since. Versions 1 and 2 don't
+//! record the options' fingerprint. Both are rewritten in the current version
+//! when resumed.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use crate::Differences;
+
+/// The version of the format written, and the number of fields in each of its
+/// lines.
+const VERSION: u32 = 3;
+const FIELDS: usize = RESULT_FIELDS + 1;
+/// The number of fields written by [format_fields].
+pub(crate) const RESULT_FIELDS: usize = 30;
+/// The numbers of fields in the lines of version 1 checkpoints.
+const V1_FIELDS: &[usize] = &[12, 13, 15, 18, 21, 26, 30, 31];
+
+const HEADER: &str = "# hyperdrive-vis-gen-diff checkpoint v";
+
+fn header(fingerprint: u64) -> String {
+    format!("{}{} options {:016x}", HEADER, VERSION, fingerprint)
+}
+
+/// The version of a checkpoint from its header line, and the fingerprint of
+/// the options it was made with if it records one.
+fn parse_header(line: &str) -> Option<(u32, Option<u64>)> {
+    let rest = line.strip_prefix(HEADER)?;
+    match rest.split_once(" options ") {
+        Some((version, fingerprint)) if version == VERSION.to_string() => {
+            Some((VERSION, Some(u64::from_str_radix(fingerprint, 16).ok()?)))
+        }
+        None if rest == "1" || rest == "2" => Some((rest.parse().ok()?, None)),
+        _ => None,
+    }
+}
+
+/// An open checkpoint file, appended to as comparisons complete.
+pub(crate) struct Checkpoint {
+    file: File,
+}
+
+impl Checkpoint {
+    /// Start a new checkpoint at `path` of comparisons made with options of
+    /// this `fingerprint`, replacing any that's already there.
+    pub(crate) fn create(path: &Path, fingerprint: u64) -> Result<Checkpoint, anyhow::Error> {
+        std::fs::write(path, format!("{}\n", header(fingerprint)))
+            .map_err(|e| anyhow!("Couldn't write checkpoint {:?}: {}", path, e))?;
+        Checkpoint::append_to(path)
+    }
+
+    /// Read the comparisons already completed in the checkpoint at `path` and
+    /// carry on appending to it. If there's no checkpoint yet, start one. Fails
+    /// if the checkpoint was made with options of a different `fingerprint`.
+    pub(crate) fn resume(
+        path: &Path,
+        fingerprint: u64,
+    ) -> Result<(Checkpoint, HashMap<String, Differences>), anyhow::Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok((Checkpoint::create(path, fingerprint)?, HashMap::new()))
+            }
+            Err(e) => bail!("Couldn't read checkpoint {:?}: {}", path, e),
+        };
+        // Drop a partial last line, so that new lines start afresh.
+        let complete = &contents[..contents.rfind('\n').map_or(0, |i| i + 1)];
+        let mut lines = complete.lines();
+        let version = match lines.next().and_then(parse_header) {
+            Some((version, Some(recorded))) if recorded == fingerprint => version,
+            Some((_, Some(_))) => bail!(
+                "The checkpoint {:?} was made with different options (e.g. tolerances, --dtype or how NaNs are judged), so its results can't be reused; run without --resume to start again",
+                path
+            ),
+            Some((version, None)) => {
+                eprintln!(
+                    "Warning: the checkpoint {:?} doesn't record the options it was made with, so they can't be checked against these",
+                    path
+                );
+                version
+            }
+            None => bail!("{:?} isn't a hyperdrive-vis-gen-diff checkpoint", path),
+        };
+        let mut done = HashMap::new();
+        for (i, line) in lines.enumerate() {
+            let (name, diffs) = parse_line(line, version)
+                .ok_or_else(|| anyhow!("Malformed line {} in checkpoint {:?}", i + 2, path))?;
+            done.insert(name.to_string(), diffs);
+        }
+        if version != VERSION {
+            let mut checkpoint = Checkpoint::create(path, fingerprint)?;
+            let mut names: Vec<&String> = done.keys().collect();
+            names.sort();
+            for name in names {
+                checkpoint.record(name, &done[name])?;
+            }
+            return Ok((checkpoint, done));
+        }
+        if complete.len() != contents.len() {
+            std::fs::write(path, complete)
+                .map_err(|e| anyhow!("Couldn't write checkpoint {:?}: {}", path, e))?;
+        }
+        Ok((Checkpoint::append_to(path)?, done))
+    }
+
+    fn append_to(path: &Path) -> Result<Checkpoint, anyhow::Error> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Couldn't open checkpoint {:?}: {}", path, e))?;
+        Ok(Checkpoint { file })
+    }
+
+    /// Record that the comparison of `name` completed with `d`.
+    pub(crate) fn record(&mut self, name: &str, d: &Differences) -> Result<(), anyhow::Error> {
+        if name.contains(&['\t', '\n'][..]) {
+            bail!(
+                "Can't checkpoint {:?}; its name contains a tab or newline",
+                name
+            );
+        }
+        // A single write, so that a kill can't leave a line half-written
+        // between two calls.
+        let line = format!("{}\t{}\n", name, format_fields(d));
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Parse a line of a checkpoint of version `version`. Fields missing from
+/// version 1 lines are zero.
+fn parse_line(line: &str, version: u32) -> Option<(&str, Differences)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let layout_known = match version {
+        1 => V1_FIELDS.contains(&fields.len()),
+        _ => fields.len() == FIELDS,
+    };
+    if !layout_known {
+        return None;
+    }
+    Some((fields[0], parse_fields(&fields[1..])?))
+}
+
+/// Parse the fields written by [format_fields]. Any missing from the end (as
+/// in version 1 checkpoints) are zero; callers check that there are as many as
+/// they expect.
+pub(crate) fn parse_fields(fields: &[&str]) -> Option<Differences> {
+    if fields.len() < V1_FIELDS[0] - 1 || fields.len() > RESULT_FIELDS {
+        return None;
+    }
+    let f32_bits = |s: &str| u32::from_str_radix(s, 16).ok().map(f32::from_bits);
+    let f64_bits = |s: &str| u64::from_str_radix(s, 16).ok().map(f64::from_bits);
+    let hash = |s: &str| match s {
+        "-" => Some(None),
+        s => u64::from_str_radix(s, 16).ok().map(Some),
+    };
+    let hashes = match (hash(fields[9])?, hash(fields[10])?) {
+        (Some(c), Some(b)) => Some((c, b)),
+        _ => None,
+    };
+    Some(Differences {
+        max_abs: f32_bits(fields[0])?,
+        max_rel: f32_bits(fields[1])?,
+        max_sigmas: f32_bits(fields[2])?,
+        max_scaled: f32_bits(fields[3])?,
+        chi2: f64_bits(fields[4])?,
+        samples: fields[5].parse().ok()?,
+        rms_growth: f64_bits(fields[6])?,
+        sampled_blocks: fields[7].parse().ok()?,
+        total_blocks: fields[8].parse().ok()?,
+        hashes,
+        weighted_rms: match fields.get(11) {
+            Some(f) => f64_bits(f)?,
+            None => 0.0,
+        },
+        max_ulps: match fields.get(12) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        violations: match fields.get(13) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        sum_abs: match fields.get(14) {
+            Some(f) => f64_bits(f)?,
+            None => 0.0,
+        },
+        sum_sq: match fields.get(15) {
+            Some(f) => f64_bits(f)?,
+            None => 0.0,
+        },
+        above_tolerance: match fields.get(16) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        max_amp: match fields.get(17) {
+            Some(f) => f32_bits(f)?,
+            None => 0.0,
+        },
+        max_amp_scaled: match fields.get(18) {
+            Some(f) => f32_bits(f)?,
+            None => 0.0,
+        },
+        max_phase: match fields.get(19) {
+            Some(f) => f64_bits(f)?,
+            None => 0.0,
+        },
+        current_nans: match fields.get(20) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        baseline_nans: match fields.get(21) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        current_infs: match fields.get(22) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        baseline_infs: match fields.get(23) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        nan_failures: match fields.get(24) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        sum_signed: match fields.get(25) {
+            Some(f) => f64_bits(f)?,
+            None => 0.0,
+        },
+        median: match fields.get(26) {
+            Some(f) => f32_bits(f)?,
+            None => 0.0,
+        },
+        p95: match fields.get(27) {
+            Some(f) => f32_bits(f)?,
+            None => 0.0,
+        },
+        p99: match fields.get(28) {
+            Some(f) => f32_bits(f)?,
+            None => 0.0,
+        },
+        skipped: match fields.get(29) {
+            Some(f) => f.parse().ok()?,
+            None => 0,
+        },
+        ..Differences::default()
+    })
+}
+
+/// The results of a comparison as tab-separated fields, as they follow the name
+/// on each line of a checkpoint (and as workers send them; see
+/// [crate::distributed]).
+pub(crate) fn format_fields(d: &Differences) -> String {
+    let (current, baseline) = match d.hashes {
+        Some((c, b)) => (format!("{:016x}", c), format!("{:016x}", b)),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    format!(
+        "{:08x}\t{:08x}\t{:08x}\t{:08x}\t{:016x}\t{}\t{:016x}\t{}\t{}\t{}\t{}\t{:016x}\t{}\t{}\t{:016x}\t{:016x}\t{}\t{:08x}\t{:08x}\t{:016x}\t{}\t{}\t{}\t{}\t{}\t{:016x}\t{:08x}\t{:08x}\t{:08x}\t{}",
+        d.max_abs.to_bits(),
+        d.max_rel.to_bits(),
+        d.max_sigmas.to_bits(),
+        d.max_scaled.to_bits(),
+        d.chi2.to_bits(),
+        d.samples,
+        d.rms_growth.to_bits(),
+        d.sampled_blocks,
+        d.total_blocks,
+        current,
+        baseline,
+        d.weighted_rms.to_bits(),
+        d.max_ulps,
+        d.violations,
+        d.sum_abs.to_bits(),
+        d.sum_sq.to_bits(),
+        d.above_tolerance,
+        d.max_amp.to_bits(),
+        d.max_amp_scaled.to_bits(),
+        d.max_phase.to_bits(),
+        d.current_nans,
+        d.baseline_nans,
+        d.current_infs,
+        d.baseline_infs,
+        d.nan_failures,
+        d.sum_signed.to_bits(),
+        d.median.to_bits(),
+        d.p95.to_bits(),
+        d.p99.to_bits(),
+        d.skipped
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_counts_are_checked_per_version() {
+        let line = |fields: usize| {
+            let mut line = vec!["hyperdrive_band01.bin"];
+            line.resize(fields, "0");
+            line[10] = "-";
+            line[11] = "-";
+            line.join("\t")
+        };
+        assert!(parse_line(&line(FIELDS), VERSION).is_some());
+        assert!(parse_line(&line(FIELDS - 1), VERSION).is_none());
+        assert!(parse_line(&line(FIELDS + 1), VERSION).is_none());
+        for &fields in V1_FIELDS {
+            assert!(parse_line(&line(fields), 1).is_some(), "{} fields", fields);
+        }
+        assert!(parse_line(&line(14), 1).is_none());
+        assert!(parse_line(&line(FIELDS + 1), 1).is_none());
+    }
+
+    #[test]
+    fn only_the_same_options_are_resumed() {
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.txt", std::process::id()));
+        let d = Differences {
+            max_abs: 0.5,
+            ..Differences::default()
+        };
+        let mut checkpoint = Checkpoint::create(&path, 0xabc).unwrap();
+        checkpoint.record("hyperdrive_band01.bin", &d).unwrap();
+        drop(checkpoint);
+
+        let (_, done) = Checkpoint::resume(&path, 0xabc).unwrap();
+        assert_eq!(done["hyperdrive_band01.bin"].max_abs, 0.5);
+        let e = Checkpoint::resume(&path, 0xabd).err().unwrap().to_string();
+        assert!(e.contains("different options"), "{}", e);
+
+        // Older checkpoints can't be checked, and are rewritten with the
+        // fingerprint.
+        let line = format!("hyperdrive_band02.bin\t{}\n", format_fields(&d));
+        std::fs::write(&path, format!("{}2\n{}", HEADER, line)).unwrap();
+        let (_, done) = Checkpoint::resume(&path, 0xabd).unwrap();
+        assert_eq!(done.len(), 1);
+        let rewritten = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(rewritten.starts_with(&header(0xabd)), "{}", rewritten);
+    }
+}
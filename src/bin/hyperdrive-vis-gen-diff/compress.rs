@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Baseline files stored compressed, e.g. "hyperdrive_band01.bin.zst", to save
+//! scratch quota. They're decompressed as they're read by piping them through
+//! gzip or zstd, so they're never inflated on disk (or, when comparing a chunk
+//! at a time, in memory).
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use anyhow::{anyhow, bail};
+
+/// The extensions of compressed baseline files.
+pub(crate) const EXTENSIONS: &[&str] = &["zst", "gz"];
+
+/// A compression format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub(crate) const NAMES: &'static [&'static str] = &["gzip", "zstd"];
+
+    /// The codec of the file at `path`, if it's compressed.
+    pub(crate) fn of(path: &Path) -> Option<Codec> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Codec::Gzip),
+            "zst" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+        }
+    }
+
+    /// The program that decompresses the codec.
+    pub(crate) fn program(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Codec, String> {
+        match s {
+            "gzip" | "gz" => Ok(Codec::Gzip),
+            "zstd" | "zst" => Ok(Codec::Zstd),
+            _ => Err(format!("Unknown compression \"{}\"", s)),
+        }
+    }
+}
+
+/// The file a baseline `path` is stored in: `path` itself if it exists,
+/// otherwise a compressed copy of it (if there is one).
+pub(crate) fn locate(path: &Path) -> PathBuf {
+    if path.exists() {
+        return path.to_path_buf();
+    }
+    EXTENSIONS
+        .iter()
+        .map(|e| PathBuf::from(format!("{}.{}", path.display(), e)))
+        .find(|p| p.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// The size of the (decompressed) data in the file at `path`.
+pub(crate) fn len(path: &Path) -> std::io::Result<u64> {
+    match Codec::of(path) {
+        None => Ok(std::fs::metadata(path)?.len()),
+        Some(Codec::Zstd) => match zstd_content_size(path)? {
+            Some(len) => Ok(len),
+            None => Stream::open(path)?.finish(),
+        },
+        Some(Codec::Gzip) => match gzip_size(path)? {
+            Some(len) => Ok(len),
+            None => Stream::open(path)?.finish(),
+        },
+    }
+}
+
+/// The decompressed size recorded in the trailer of a gzip file (ISIZE), if
+/// it's certainly the whole size. gzip only records the size modulo 4 GiB, but
+/// as it can't compress by more than a factor of 1032, that's the whole size
+/// of any file compressed to less than 4 MiB. It's the size of the last member
+/// only, but gzip writes a single member when compressing a file.
+fn gzip_size(path: &Path) -> std::io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let compressed = file.metadata()?.len();
+    let mut magic = [0; 2];
+    if compressed < 18 || file.read(&mut magic)? < 2 || magic != [0x1f, 0x8b] {
+        return Ok(None);
+    }
+    if compressed.saturating_mul(1032) >= 1 << 32 {
+        return Ok(None);
+    }
+    let mut isize = [0; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut isize)?;
+    Ok(Some(u32::from_le_bytes(isize) as u64))
+}
+
+/// The decompressed size recorded in the header of a zstd file, if it's a
+/// single frame with one; zstd records it when compressing a file.
+fn zstd_content_size(path: &Path) -> std::io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let mut header = [0; 18];
+    let n = file.read(&mut header)?;
+    if n < 6 || header[..4] != [0x28, 0xb5, 0x2f, 0xfd] {
+        return Ok(None);
+    }
+    let descriptor = header[4];
+    let single_segment = descriptor & 0x20 != 0;
+    let dict_id_len = [0, 1, 2, 4][(descriptor & 0x3) as usize];
+    let size_len = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => return Ok(None),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let start = 5 + usize::from(!single_segment) + dict_id_len;
+    if n < start + size_len {
+        return Ok(None);
+    }
+    let mut size = [0; 8];
+    size[..size_len].copy_from_slice(&header[start..start + size_len]);
+    Ok(Some(
+        u64::from_le_bytes(size) + if size_len == 2 { 256 } else { 0 },
+    ))
+}
+
+/// The decompressed data of a file, read through gzip or zstd.
+pub(crate) struct Stream {
+    child: Child,
+    stdout: ChildStdout,
+    /// How many bytes have been read.
+    pub(crate) position: u64,
+}
+
+impl Stream {
+    pub(crate) fn open(path: &Path) -> std::io::Result<Stream> {
+        let codec = Codec::of(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{:?} isn't compressed", path),
+            )
+        })?;
+        let mut child = Command::new(codec.program())
+            .args(["-d", "-c", "-q"])
+            .arg(path)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                std::io::Error::new(e.kind(), format!("couldn't run {}: {}", codec.program(), e))
+            })?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Stream {
+            child,
+            stdout,
+            position: 0,
+        })
+    }
+
+    /// Skip ahead to `offset`, which mustn't be behind the stream.
+    pub(crate) fn skip_to(&mut self, offset: u64) -> std::io::Result<()> {
+        let skip = offset - self.position;
+        let skipped = std::io::copy(&mut (&mut self.stdout).take(skip), &mut std::io::sink())?;
+        self.position += skipped;
+        if skipped < skip {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "expected at least {} bytes, but there are {}",
+                    offset, self.position
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fill `buf`, stopping early only at the end of the data. Returns the
+    /// number of bytes read.
+    pub(crate) fn read_full(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.stdout.read(&mut buf[read..]) {
+                Ok(0) => {
+                    // The decompressor may have stopped at corrupt data.
+                    self.exited()?;
+                    break;
+                }
+                Ok(n) => read += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+
+    /// Read everything left.
+    pub(crate) fn read_to_end(mut self) -> std::io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.stdout.read_to_end(&mut bytes)?;
+        self.finish()?;
+        Ok(bytes)
+    }
+
+    /// Read everything left and wait for the decompressor, which fails if the
+    /// file was corrupt. Returns the total size of the data.
+    pub(crate) fn finish(mut self) -> std::io::Result<u64> {
+        self.position += std::io::copy(&mut self.stdout, &mut std::io::sink())?;
+        self.exited()?;
+        Ok(self.position)
+    }
+
+    /// Wait for the decompressor to exit, failing if it did (e.g. at corrupt
+    /// data).
+    fn exited(&mut self) -> std::io::Result<()> {
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("decompression failed ({})", status),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // The decompressor may not have been read to the end.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Compress the file `from` into `to`, removing `from`.
+pub(crate) fn compress(codec: Codec, from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    let output = File::create(to).map_err(|e| anyhow!("Couldn't create {:?}: {}", to, e))?;
+    let status = Command::new(codec.program())
+        .args(["-c", "-q"])
+        .arg(from)
+        .stdout(output)
+        .status()
+        .map_err(|e| anyhow!("Couldn't run {}: {}", codec.program(), e))?;
+    if !status.success() {
+        bail!(
+            "Couldn't compress {:?} with {} ({})",
+            from,
+            codec.program(),
+            status
+        );
+    }
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codecs_are_told_by_extension() {
+        let of = |p: &str| Codec::of(Path::new(p));
+        assert_eq!(of("hyperdrive_band01.bin.zst"), Some(Codec::Zstd));
+        assert_eq!(of("baseline/hyperdrive_band01.bin.gz"), Some(Codec::Gzip));
+        assert_eq!(of("hyperdrive_band01.bin"), None);
+        assert_eq!(of("zst"), None);
+        assert_eq!("gz".parse(), Ok(Codec::Gzip));
+        assert_eq!("zstd".parse(), Ok(Codec::Zstd));
+        assert!("xz".parse::<Codec>().is_err());
+    }
+
+    #[test]
+    fn uncompressed_files_are_preferred() {
+        let dir = std::env::temp_dir().join(format!("compress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hyperdrive_band01.bin");
+        assert_eq!(locate(&path), path);
+        std::fs::write(dir.join("hyperdrive_band01.bin.gz"), b"").unwrap();
+        assert_eq!(locate(&path), dir.join("hyperdrive_band01.bin.gz"));
+        std::fs::write(dir.join("hyperdrive_band01.bin.zst"), b"").unwrap();
+        assert_eq!(locate(&path), dir.join("hyperdrive_band01.bin.zst"));
+        std::fs::write(&path, b"").unwrap();
+        assert_eq!(locate(&path), path);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes_are_read_from_headers() {
+        let dir = std::env::temp_dir().join(format!("compress-sizes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let size = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, bytes).unwrap();
+            match Codec::of(&path) {
+                Some(Codec::Zstd) => zstd_content_size(&path).unwrap(),
+                _ => gzip_size(&path).unwrap(),
+            }
+        };
+        let magic = [0x28, 0xb5, 0x2f, 0xfd];
+        // Single segment, with a 1-byte size.
+        let one = [&magic[..], &[0x20, 200]].concat();
+        assert_eq!(size("one.zst", &one), Some(200));
+        // A 2-byte size is offset by 256, after the window descriptor.
+        let two = [&magic[..], &[0x40, 0x58, 0x01, 0x00]].concat();
+        assert_eq!(size("two.zst", &two), Some(256 + 1));
+        // No size recorded.
+        let none = [&magic[..], &[0x00, 0x58]].concat();
+        assert_eq!(size("none.zst", &none), None);
+        assert_eq!(size("not.zst", b"not zstd at all"), None);
+
+        let mut gzip = vec![0x1f, 0x8b];
+        gzip.resize(14, 0);
+        gzip.extend(1234u32.to_le_bytes());
+        assert_eq!(size("a.gz", &gzip), Some(1234));
+        assert_eq!(size("short.gz", &gzip[..10]), None);
+        gzip[0] = 0;
+        assert_eq!(size("not.gz", &gzip), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tolerances for particular files (or bands), e.g. looser ones for the noisy
+//! bands at the edges of the observing band, read from a TOML file like
+//!
+//! ```toml
+//! [[files]]
+//! pattern = "hyperdrive_band0[1-2].bin"
+//! tolerance = 1e-2
+//! rel_tolerance = 1e-4
+//! nan = "equal"
+//! ```
+//!
+//! The first `[[files]]` table whose pattern matches a file's name applies to
+//! it; tolerances it doesn't give, and files no table matches, use those given
+//! on the command line.
+//!
+//! Known failures can be listed too, like `--xfail`, optionally with a date
+//! after which they count again:
+//!
+//! ```toml
+//! [[expected-failures]]
+//! pattern = "hyperdrive_band03.bin"
+//! reason = "https://github.com/.../issues/12"
+//! expires = "2020-06-30"
+//! ```
+//!
+//! The key that signs the --report can be given too (relative to the config
+//! file), though --sign-key and HYPERDRIVE_CHECKS_SIGN_KEY override it:
+//!
+//! ```toml
+//! [report]
+//! sign_key = "keys/checks.key"
+//! ```
+//!
+//! Only this subset of TOML is read.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use glob::Pattern;
+
+use pawsey_hyperdrive_checks::{NanPolicy, Rule};
+
+use crate::exempt::Xfail;
+use crate::manifest::{toml_string, unquote};
+
+/// The headers of the tables a config file can have.
+const FILES: &str = "[[files]]";
+const EXPECTED_FAILURES: &str = "[[expected-failures]]";
+const REPORT: &str = "[report]";
+
+/// The (line number, key, value) lines of a table.
+type Table<'a> = Vec<(usize, &'a str, &'a str)>;
+
+/// Read the rules in the config file at `path`.
+pub(crate) fn read(path: &Path) -> Result<Vec<Rule>, anyhow::Error> {
+    read_tables(path, FILES, rule)
+}
+
+/// Read the expected failures in the config file at `path`.
+pub(crate) fn read_expected_failures(path: &Path) -> Result<Vec<Xfail>, anyhow::Error> {
+    read_tables(path, EXPECTED_FAILURES, expected_failure)
+}
+
+/// Read the key that signs the report, if the config file at `path` gives
+/// one.
+pub(crate) fn read_sign_key(path: &Path) -> Result<Option<PathBuf>, anyhow::Error> {
+    let keys = read_tables(path, REPORT, sign_key)?;
+    if keys.len() > 1 {
+        bail!("Config {:?}: more than one {} table", path, REPORT);
+    }
+    let root = path.parent().unwrap_or_else(|| Path::new("."));
+    Ok(keys.into_iter().flatten().next().map(|k| root.join(k)))
+}
+
+/// Make something out of each table headed `header` in the config file at
+/// `path`.
+fn read_tables<T>(
+    path: &Path,
+    header: &str,
+    make: fn(&Table) -> Result<T, anyhow::Error>,
+) -> Result<Vec<T>, anyhow::Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Couldn't read config {:?}: {}", path, e))?;
+    parse(&text)
+        .and_then(|tables| {
+            tables
+                .iter()
+                .filter(|(h, _)| *h == header)
+                .map(|(_, lines)| make(lines))
+                .collect()
+        })
+        .map_err(|e| anyhow!("Config {:?}: {}", path, e))
+}
+
+/// Split a config file into its tables, each with its header.
+fn parse(text: &str) -> Result<Vec<(&str, Table<'_>)>, anyhow::Error> {
+    let mut tables: Vec<(&str, Table<'_>)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == FILES || line == EXPECTED_FAILURES || line == REPORT {
+            tables.push((line, vec![]));
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => bail!("line {}: expected KEY = VALUE", i + 1),
+        };
+        match tables.last_mut() {
+            Some((_, lines)) => lines.push((i + 1, key, value)),
+            None => bail!(
+                "line {}: \"{}\" isn't in a {}, {} or {} table",
+                i + 1,
+                key,
+                FILES,
+                EXPECTED_FAILURES,
+                REPORT
+            ),
+        }
+    }
+    Ok(tables)
+}
+
+/// Write a config file to `path` holding each file (or band) to its own
+/// tolerances: (name, absolute tolerance, relative tolerance if any).
+/// `comment` heads the file.
+pub(crate) fn write(
+    path: &Path,
+    comment: &str,
+    files: &[(String, f32, Option<f32>)],
+) -> Result<(), anyhow::Error> {
+    let mut text = format!("# {}\n", comment);
+    for (name, tolerance, rel_tolerance) in files {
+        text.push_str("\n[[files]]\n");
+        text.push_str(&format!(
+            "pattern = {}\n",
+            toml_string(&Pattern::escape(name))
+        ));
+        text.push_str(&format!("tolerance = {:e}\n", tolerance));
+        if let Some(r) = rel_tolerance {
+            text.push_str(&format!("rel_tolerance = {:e}\n", r));
+        }
+    }
+    std::fs::write(path, text).map_err(|e| anyhow!("Couldn't write config {:?}: {}", path, e))
+}
+
+/// The pattern of a table.
+fn pattern(lines: &Table, header: &str) -> Result<Pattern, anyhow::Error> {
+    match lines.iter().find(|(_, key, _)| *key == "pattern") {
+        Some((n, _, value)) => {
+            let pattern = unquote(value).ok_or_else(|| anyhow!("line {}: expected a string", n))?;
+            Pattern::new(&pattern)
+                .map_err(|e| anyhow!("line {}: bad pattern \"{}\": {}", n, pattern, e))
+        }
+        None => bail!("a {} table has no pattern", header),
+    }
+}
+
+/// Make a rule out of a [[files]] table.
+fn rule(lines: &Table) -> Result<Rule, anyhow::Error> {
+    let mut rule = Rule::new(pattern(lines, FILES)?);
+    for &(n, key, value) in lines {
+        let number = || {
+            value
+                .parse::<f32>()
+                .map_err(|_| anyhow!("line {}: expected a number, not {}", n, value))
+        };
+        match key {
+            "pattern" => (),
+            "tolerance" => rule.tolerance = Some(number()?),
+            "rel_tolerance" => rule.rel_tolerance = Some(number()?),
+            "ulps" => {
+                rule.ulps = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: expected an integer, not {}", n, value))?,
+                )
+            }
+            "nan" => {
+                rule.nan = Some(
+                    unquote(value)
+                        .and_then(|v| v.parse::<NanPolicy>().ok())
+                        .ok_or_else(|| {
+                            anyhow!("line {}: expected \"fail\", \"equal\" or \"allow\"", n)
+                        })?,
+                )
+            }
+            _ => bail!("line {}: unknown key \"{}\"", n, key),
+        }
+    }
+    Ok(rule)
+}
+
+/// Make an expected failure out of an [[expected-failures]] table.
+fn expected_failure(lines: &Table) -> Result<Xfail, anyhow::Error> {
+    let pattern = pattern(lines, EXPECTED_FAILURES)?;
+    let (mut reason, mut expires) = (None, None);
+    for &(n, key, value) in lines {
+        let string = || unquote(value).ok_or_else(|| anyhow!("line {}: expected a string", n));
+        match key {
+            "pattern" => (),
+            "reason" => reason = Some(string()?),
+            "expires" => expires = Some((n, string()?)),
+            _ => bail!("line {}: unknown key \"{}\"", n, key),
+        }
+    }
+    let reason = match reason {
+        Some(r) if !r.trim().is_empty() => r,
+        _ => bail!(
+            "the {} table for \"{}\" has no reason",
+            EXPECTED_FAILURES,
+            pattern.as_str()
+        ),
+    };
+    // Only a bad expiry date can fail.
+    Xfail::new(pattern, &reason, expires.as_ref().map(|(_, d)| d.as_str())).ok_or_else(|| {
+        let (n, date) = expires.unwrap_or_default();
+        anyhow!(
+            "line {}: expected a date like \"2020-06-30\", not \"{}\"",
+            n,
+            date
+        )
+    })
+}
+
+/// The key that signs the report, from a [report] table.
+fn sign_key(lines: &Table) -> Result<Option<String>, anyhow::Error> {
+    let mut key = None;
+    for &(n, k, value) in lines {
+        match k {
+            "sign_key" => {
+                key = Some(unquote(value).ok_or_else(|| anyhow!("line {}: expected a string", n))?)
+            }
+            _ => bail!("line {}: unknown key \"{}\"", n, k),
+        }
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pawsey_hyperdrive_checks::{tolerances, Tolerances};
+
+    use super::*;
+
+    fn temp(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()))
+    }
+
+    #[test]
+    fn written_tolerances_are_read_back() {
+        let path = temp("config-round-trip");
+        // Glob metacharacters in names must be escaped.
+        let files = [
+            ("hyperdrive_band01.bin".to_string(), 1e-2, Some(1e-4)),
+            ("odd[1].bin".to_string(), 2.5e-3, None),
+        ];
+        write(&path, "suggested", &files).unwrap();
+        let rules = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let defaults = Tolerances {
+            ulps: Some(4),
+            ..Tolerances::absolute(1e-3)
+        };
+        for (name, tolerance, rel_tolerance) in &files {
+            let t = tolerances(&rules, Path::new(name), defaults).unwrap();
+            assert_eq!(t.tolerance, *tolerance, "{}", name);
+            assert_eq!(t.rel_tolerance, *rel_tolerance, "{}", name);
+            assert_eq!(t.ulps, Some(4), "{}", name);
+            assert_eq!(t.nan, NanPolicy::Fail, "{}", name);
+        }
+        assert!(tolerances(&rules, Path::new("odd1.bin"), defaults).is_none());
+    }
+
+    #[test]
+    fn every_key_is_read() {
+        let path = temp("config-keys");
+        std::fs::write(
+            &path,
+            r#"
+# Comments and blank lines are skipped.
+[[files]]
+pattern = "hyperdrive_band0[1-2].bin"
+tolerance = 1e-2
+ulps = 16
+nan = "equal"
+
+[[expected-failures]]
+pattern = "hyperdrive_band03.bin"
+reason = "issue 12"
+expires = "2020-06-30"
+
+[report]
+sign_key = "keys/checks.key"
+"#,
+        )
+        .unwrap();
+        let rules = read(&path).unwrap();
+        let xfails = read_expected_failures(&path).unwrap();
+        let sign_key = read_sign_key(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let t = tolerances(
+            &rules,
+            Path::new("hyperdrive_band02.bin"),
+            Tolerances::absolute(1e-3),
+        )
+        .unwrap();
+        assert_eq!(
+            (t.tolerance, t.rel_tolerance, t.ulps, t.nan),
+            (1e-2, None, Some(16), NanPolicy::Equal)
+        );
+        assert_eq!(xfails.len(), 1);
+        assert_eq!(xfails[0].pattern(), "hyperdrive_band03.bin");
+        assert_eq!(xfails[0].reason, "issue 12");
+        assert_eq!(
+            xfails[0].expires,
+            Some(("2020-06-30".to_string(), 1_593_561_600))
+        );
+        // Relative to the config file.
+        assert_eq!(
+            sign_key,
+            Some(path.parent().unwrap().join("keys/checks.key"))
+        );
+    }
+
+    #[test]
+    fn mistakes_are_rejected() {
+        for text in &[
+            "tolerance = 1",
+            "[[files]]\npattern = \"a\"\ntolerance = lots",
+            "[[files]]\npattern = \"a\"\nnan = \"sometimes\"",
+            "[[files]]\npattern = \"a\"\ntolerence = 1",
+            "[[files]]\ntolerance = 1",
+            "[[expected-failures]]\npattern = \"a\"",
+            "[[expected-failures]]\npattern = \"a\"\nreason = \"b\"\nexpires = \"June\"",
+            "[report]\nsign_key = keys/checks.key",
+            "[report]\nkey = \"checks.key\"",
+            "[report]\nsign_key = \"a.key\"\n[report]\nsign_key = \"b.key\"",
+        ] {
+            let path = temp("config-mistakes");
+            std::fs::write(&path, text).unwrap();
+            let read = (
+                read(&path),
+                read_expected_failures(&path),
+                read_sign_key(&path),
+            );
+            std::fs::remove_file(&path).unwrap();
+            assert!(
+                read.0.is_err() || read.1.is_err() || read.2.is_err(),
+                "{:?}",
+                text
+            );
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Baselines distributed inside Apptainer (Singularity) images, alongside the
+//! hyperdrive builds they were made with.
+//!
+//! A baseline like "apptainer://hyperdrive.sif:/opt/baseline" is copied out of
+//! the image once, into a cache directory, and compared from there. The cache
+//! is keyed by the image's path, size and modification time, so a rebuilt
+//! image is extracted afresh.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail};
+
+use crate::hash;
+
+const PREFIX: &str = "apptainer://";
+
+/// Where the baseline directory is bind-mounted inside the container.
+const MOUNT: &str = "/hyperdrive-checks-extract";
+
+/// If `baseline` names a directory inside a container image, extract it (if
+/// it hasn't been already) and return the extracted directory. Otherwise,
+/// return `baseline` as it is.
+pub(crate) fn resolve(baseline: &Path, quiet: bool) -> Result<PathBuf, anyhow::Error> {
+    let spec = match baseline.to_str().and_then(|s| s.strip_prefix(PREFIX)) {
+        Some(spec) => spec,
+        None => return Ok(baseline.to_path_buf()),
+    };
+    let (image, inside) = match spec.find(":/") {
+        Some(i) => (Path::new(&spec[..i]), &spec[i + 1..]),
+        None => bail!(
+            "Expected a baseline like {}IMAGE.sif:/path/inside, not \"{}\"",
+            PREFIX,
+            baseline.display()
+        ),
+    };
+    let image = image
+        .canonicalize()
+        .map_err(|e| anyhow!("Couldn't find the image {:?}: {}", image, e))?;
+    let metadata = std::fs::metadata(&image)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let key = format!(
+        "{}\0{}\0{}\0{}",
+        image.display(),
+        metadata.len(),
+        modified,
+        inside
+    );
+    let dir = cache_dir("apptainer")?.join(format!("{:016x}", hash::xxh64(key.as_bytes(), 0)));
+    if dir.is_dir() {
+        return Ok(dir);
+    }
+
+    if !quiet {
+        println!("Extracting {} from {} ...", inside, image.display());
+    }
+    // Extract somewhere else first, so that an interrupted extraction is never
+    // mistaken for a complete one.
+    let partial = dir.with_extension("partial");
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    std::fs::create_dir_all(&partial)?;
+    let copy = format!("{}/.", inside.trim_end_matches('/'));
+    let run = |exe: &str| {
+        Command::new(exe)
+            .arg("exec")
+            .arg("--bind")
+            .arg(format!("{}:{}", partial.display(), MOUNT))
+            .arg(&image)
+            .args(["cp", "-r", &copy, MOUNT])
+            .status()
+    };
+    // Older installations only have singularity.
+    let status = run("apptainer")
+        .or_else(|_| run("singularity"))
+        .map_err(|e| anyhow!("Couldn't run apptainer or singularity: {}", e))?;
+    if !status.success() {
+        bail!(
+            "Couldn't extract {} from {} ({})",
+            inside,
+            image.display(),
+            status
+        );
+    }
+    std::fs::rename(&partial, &dir)?;
+    Ok(dir)
+}
+
+/// The directory that baselines of some `kind` (e.g. those extracted from
+/// images) are cached in.
+pub(crate) fn cache_dir(kind: &str) -> Result<PathBuf, anyhow::Error> {
+    let base = match (std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => PathBuf::from(home).join(".cache"),
+        (None, None) => bail!("Can't find a cache directory; set XDG_CACHE_HOME"),
+    };
+    Ok(base.join("hyperdrive-checks").join(kind))
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Render history files into a single, self-contained static HTML page with a
+//! trend plot per test case.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::history::{self, HistoryRow};
+
+const PLOT_WIDTH: f64 = 800.0;
+const PLOT_HEIGHT: f64 = 240.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 10.0;
+const MARGIN_BOTTOM: f64 = 30.0;
+
+/// Write a dashboard to `output`, with one section per history file. Each
+/// history file is treated as a test case, named after the file's stem. If a
+/// history file has runs of several observations, each gets its own section.
+pub(crate) fn write_dashboard(history_files: &[&Path], output: &Path) -> Result<(), anyhow::Error> {
+    let mut html = String::new();
+    html.push_str(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>hyperdrive checks</title>\n<style>\n\
+         body { font-family: sans-serif; margin: 2em; }\n\
+         table { border-collapse: collapse; }\n\
+         td, th { padding: 0.2em 0.8em; text-align: left; }\n\
+         .pass { color: #2a7d2a; } .fail { color: #c0392b; }\n\
+         </style>\n</head>\n<body>\n<h1>hyperdrive checks</h1>\n",
+    );
+    writeln!(
+        html,
+        "<p>Generated {}</p>",
+        history::utc_timestamp(std::time::SystemTime::now())
+    )?;
+
+    for file in history_files {
+        let case = file
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| file.display().to_string());
+        let rows = history::read(file)?;
+        // Keep the observations in the order they first appear.
+        let mut obsids: Vec<Option<u64>> = vec![];
+        for row in &rows {
+            if !obsids.contains(&row.obsid) {
+                obsids.push(row.obsid);
+            }
+        }
+        if obsids.len() <= 1 {
+            write_case(&mut html, &case, &rows)?;
+            continue;
+        }
+        for obsid in obsids {
+            let rows: Vec<HistoryRow> = rows.iter().filter(|r| r.obsid == obsid).cloned().collect();
+            let title = match obsid {
+                Some(o) => format!("{} (obsid {})", case, o),
+                None => format!("{} (unknown obsid)", case),
+            };
+            write_case(&mut html, &title, &rows)?;
+        }
+    }
+    html.push_str("</body>\n</html>\n");
+
+    std::fs::write(output, html)?;
+    Ok(())
+}
+
+/// Add a section for one test case to `html`.
+fn write_case(html: &mut String, case: &str, rows: &[HistoryRow]) -> Result<(), anyhow::Error> {
+    writeln!(html, "<h2>{}</h2>", escape(case))?;
+    match rows.last() {
+        None => html.push_str("<p>No runs recorded.</p>\n"),
+        Some(latest) => {
+            html.push_str(
+                "<table>\n<tr><th>Latest run</th><th>hyperdrive</th>\
+                 <th>Worst file</th><th>Maximum difference</th><th>Verdict</th></tr>\n",
+            );
+            writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:e}</td><td class=\"{v}\">{v}</td></tr>\n</table>",
+                escape(&latest.timestamp),
+                escape(&latest.hyperdrive_version),
+                escape(&latest.worst_file),
+                latest.max_diff,
+                v = if latest.passed { "pass" } else { "fail" },
+            )?;
+            html.push_str(&trend_plot(rows));
+        }
+    }
+    Ok(())
+}
+
+/// An SVG plot of the maximum difference over time, on a log scale. Zero
+/// differences are drawn on the bottom axis.
+fn trend_plot(rows: &[HistoryRow]) -> String {
+    let points: Vec<(i64, &HistoryRow)> = rows
+        .iter()
+        .filter_map(|r| history::parse_utc_timestamp(&r.timestamp).map(|t| (t, r)))
+        .collect();
+    if points.is_empty() {
+        return "<p>No plottable runs.</p>\n".to_string();
+    }
+
+    let t_min = points.iter().map(|p| p.0).min().unwrap();
+    let t_max = points.iter().map(|p| p.0).max().unwrap();
+    let positive = points.iter().map(|p| p.1.max_diff).filter(|&d| d > 0.0);
+    let (log_min, log_max) = positive.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), d| {
+        let l = (d as f64).log10();
+        (lo.min(l.floor()), hi.max(l.ceil()))
+    });
+    let (log_min, log_max) = if log_min.is_finite() {
+        (log_min, log_max.max(log_min + 1.0))
+    } else {
+        (-1.0, 0.0)
+    };
+
+    let inner_w = PLOT_WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let inner_h = PLOT_HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+    let x = |t: i64| {
+        if t_max == t_min {
+            MARGIN_LEFT + inner_w / 2.0
+        } else {
+            MARGIN_LEFT + inner_w * (t - t_min) as f64 / (t_max - t_min) as f64
+        }
+    };
+    let y = |d: f32| {
+        let l = if d > 0.0 { (d as f64).log10() } else { log_min };
+        MARGIN_TOP + inner_h * (log_max - l) / (log_max - log_min)
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        PLOT_WIDTH, PLOT_HEIGHT
+    );
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#888\"/>",
+        MARGIN_LEFT, MARGIN_TOP, inner_w, inner_h
+    );
+    let mut decade = log_min as i32;
+    while decade as f64 <= log_max {
+        let ty = y(10f32.powi(decade));
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{}\" y1=\"{ty:.1}\" x2=\"{}\" y2=\"{ty:.1}\" stroke=\"#ddd\"/>\
+             <text x=\"{}\" y=\"{:.1}\" font-size=\"11\" text-anchor=\"end\">1e{}</text>",
+            MARGIN_LEFT,
+            MARGIN_LEFT + inner_w,
+            MARGIN_LEFT - 5.0,
+            ty + 4.0,
+            decade,
+            ty = ty,
+        );
+        decade += 1;
+    }
+    for (t, anchor) in &[(t_min, "start"), (t_max, "end")] {
+        let label = points
+            .iter()
+            .find(|p| p.0 == *t)
+            .map(|p| p.1.timestamp.as_str())
+            .unwrap_or("");
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"11\" text-anchor=\"{}\">{}</text>",
+            if *anchor == "start" {
+                MARGIN_LEFT
+            } else {
+                MARGIN_LEFT + inner_w
+            },
+            PLOT_HEIGHT - 10.0,
+            anchor,
+            escape(label)
+        );
+    }
+
+    let path: Vec<String> = points
+        .iter()
+        .map(|(t, r)| format!("{:.1},{:.1}", x(*t), y(r.max_diff)))
+        .collect();
+    let _ = writeln!(
+        svg,
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"#3465a4\"/>",
+        path.join(" ")
+    );
+    for (t, r) in &points {
+        let _ = writeln!(
+            svg,
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"><title>{}: {:e} ({})</title></circle>",
+            x(*t),
+            y(r.max_diff),
+            if r.passed { "#2a7d2a" } else { "#c0392b" },
+            escape(&r.timestamp),
+            r.max_diff,
+            escape(&r.hyperdrive_version)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
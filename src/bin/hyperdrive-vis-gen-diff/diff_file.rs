@@ -0,0 +1,91 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Files of the element-wise differences (current minus baseline) of each
+//! file (or band), for plotting where a check went wrong. Each is a file of
+//! little-endian f32s laid out like the inputs, with a JSON sidecar giving its
+//! shape, so that in numpy
+//!
+//! ```python
+//! meta = json.load(open("hyperdrive_band01.bin.diff.json"))
+//! diff = np.fromfile(meta["file"], dtype=meta["dtype"]).reshape(meta["shape"])
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::json::json_string;
+
+/// How a file's floats are arranged, from the slowest-varying axis to the
+/// fastest, e.g. `[("timestep", 10), ("baseline", 8128), ("fine channel",
+/// 32), ("float", 8)]`.
+pub(crate) type Shape = Vec<(&'static str, u64)>;
+
+/// Writes the differences of a pair of files as they're compared.
+pub(crate) struct DiffFile {
+    path: PathBuf,
+    name: String,
+    writer: BufWriter<File>,
+    floats: u64,
+}
+
+impl DiffFile {
+    /// Start writing the differences of the pair of files called `name` in
+    /// `dir`.
+    pub(crate) fn create(dir: &Path, name: &Path) -> Result<DiffFile, anyhow::Error> {
+        std::fs::create_dir_all(dir).map_err(|e| anyhow!("Couldn't create {:?}: {}", dir, e))?;
+        // e.g. "hyperdrive_band01.bin band 02" with --bands
+        let name = name.display().to_string();
+        let path = dir.join(format!("{}.diff.bin", name.replace(['/', ' '], "_")));
+        let file = File::create(&path).map_err(|e| anyhow!("Couldn't create {:?}: {}", path, e))?;
+        Ok(DiffFile {
+            path,
+            name,
+            writer: BufWriter::new(file),
+            floats: 0,
+        })
+    }
+
+    /// Write the differences of the next floats of the pair.
+    pub(crate) fn write(&mut self, current: &[f32], baseline: &[f32]) -> std::io::Result<()> {
+        for (c, b) in current.iter().zip(baseline) {
+            self.writer.write_all(&(c - b).to_le_bytes())?;
+        }
+        self.floats += current.len().min(baseline.len()) as u64;
+        Ok(())
+    }
+
+    /// Write `n` differences of zero, for floats known to be identical.
+    pub(crate) fn write_zeros(&mut self, n: u64) -> std::io::Result<()> {
+        for _ in 0..n {
+            self.writer.write_all(&0f32.to_le_bytes())?;
+        }
+        self.floats += n;
+        Ok(())
+    }
+
+    /// Finish the file, and write its sidecar. `shape` is used if it fits
+    /// the number of floats written; otherwise the file is one-dimensional.
+    pub(crate) fn finish(mut self, shape: Shape) -> Result<(), anyhow::Error> {
+        self.writer.flush()?;
+        let shape = if shape.iter().map(|(_, n)| n).product::<u64>() == self.floats {
+            shape
+        } else {
+            vec![("float", self.floats)]
+        };
+        let list = |items: Vec<String>| format!("[{}]", items.join(", "));
+        let sidecar = format!(
+            "{{\n  \"name\": {},\n  \"file\": {},\n  \"dtype\": \"<f4\",\n  \"difference\": \"current - baseline\",\n  \"shape\": {},\n  \"axes\": {}\n}}\n",
+            json_string(&self.name),
+            json_string(&self.path.file_name().unwrap_or_default().to_string_lossy()),
+            list(shape.iter().map(|(_, n)| n.to_string()).collect()),
+            list(shape.iter().map(|(axis, _)| json_string(axis)).collect()),
+        );
+        let path = self.path.with_extension("json");
+        std::fs::write(&path, sidecar).map_err(|e| anyhow!("Couldn't write {:?}: {}", path, e))
+    }
+}
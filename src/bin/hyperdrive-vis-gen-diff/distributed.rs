@@ -0,0 +1,608 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Spread comparisons over many nodes. A coordinator hands out file pairs to
+//! workers (e.g. launched with `srun`) over TCP and merges their results.
+//!
+//! The protocol is line based, with fields separated by tabs. A worker says
+//! `HELLO <host>`, and the coordinator replies with
+//! `OPTIONS <directory>\t<argument>...`: its working directory and
+//! command-line arguments, from which the worker makes the same comparison as
+//! the coordinator would (tolerances, --dtype, NaN policy and all). Then the
+//! coordinator sends `JOB <index>\t<current>\t<baseline>` or `DONE`. The
+//! worker answers each job with
+//! `RESULT <index>\t<results>\t<io ns>\t<decode ns>\t<metrics ns>`, the
+//! results as a checkpoint records them (see [crate::checkpoint]), or
+//! `ERROR <index>\t<message>`, and then waits for its next job. All paths must
+//! be valid on every node.
+//!
+//! Results are only printed once every job is done, in the same order as the
+//! pairs, so that the output doesn't depend on which worker finished first.
+//! Messages about workers, which do, go to stderr.
+//!
+//! A worker that goes away has its job handed to another. If no worker is
+//! connected for too long while there are jobs left (e.g. every worker died,
+//! or none started), the coordinator gives up.
+
+use std::collections::VecDeque;
+use std::io::{prelude::*, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+
+use crate::checkpoint::{self, Checkpoint};
+use crate::read::Reader;
+use crate::{
+    compare_pair, comparison_from_args, email::hostname, Differences, FilePair, Timings, Verbosity,
+};
+
+struct State {
+    /// Indices of the pairs not yet handed out.
+    queue: VecDeque<usize>,
+    results: Vec<Option<Differences>>,
+    /// The first error reported by a worker, if any.
+    error: Option<String>,
+    /// How many workers are connected and haven't yet been told that there's
+    /// nothing left to do.
+    connected: usize,
+    /// When the last worker went away, or the coordinator started, if no
+    /// worker is connected.
+    idle_since: Option<Instant>,
+    /// Where to record results as they arrive, if anywhere.
+    checkpoint: Option<Checkpoint>,
+}
+
+/// Listen on `address` (e.g. "0.0.0.0:7777") and have connecting workers
+/// compare `pairs` as the command-line arguments `args` say, returning their
+/// results in the same order as `pairs`. Fails if no worker is connected for
+/// `worker_timeout` while there are comparisons left.
+pub(crate) fn coordinate(
+    pairs: &[&FilePair],
+    address: &str,
+    args: &[String],
+    checkpoint: Option<Checkpoint>,
+    worker_timeout: Duration,
+    quiet: bool,
+) -> Result<Vec<Differences>, anyhow::Error> {
+    let listener =
+        TcpListener::bind(address).map_err(|e| anyhow!("Couldn't listen on {}: {}", address, e))?;
+    coordinate_on(listener, pairs, args, checkpoint, worker_timeout, quiet)
+}
+
+fn coordinate_on(
+    listener: TcpListener,
+    pairs: &[&FilePair],
+    args: &[String],
+    checkpoint: Option<Checkpoint>,
+    worker_timeout: Duration,
+    quiet: bool,
+) -> Result<Vec<Differences>, anyhow::Error> {
+    let dir = std::env::current_dir()?;
+    let dir = dir
+        .to_str()
+        .ok_or_else(|| anyhow!("Can't distribute from {:?}; it isn't unicode", dir))?;
+    if let Some(a) = std::iter::once(dir)
+        .chain(args.iter().map(|a| a.as_str()))
+        .find(|a| a.contains(&['\t', '\n'][..]))
+    {
+        bail!(
+            "Can't distribute with the argument {:?}; it contains a tab or newline",
+            a
+        );
+    }
+    let options = Arc::new(format!("OPTIONS {}\t{}", dir, args.join("\t")));
+
+    // Workers may be running in other directories, so give them absolute
+    // paths.
+    let jobs: Vec<(PathBuf, PathBuf)> = pairs
+        .iter()
+        .map(|p| Ok((p.current.canonicalize()?, p.baseline.canonicalize()?)))
+        .collect::<Result<_, std::io::Error>>()?;
+    for (c, b) in &jobs {
+        for path in &[c, b] {
+            if path.to_string_lossy().contains(&['\t', '\n'][..]) {
+                bail!(
+                    "Can't distribute {:?}; its path contains a tab or newline",
+                    path
+                );
+            }
+        }
+    }
+
+    if !quiet {
+        println!(
+            "Coordinating {} comparisons on {}; waiting for workers ...",
+            pairs.len(),
+            listener.local_addr()?
+        );
+    }
+
+    let state = Arc::new((
+        Mutex::new(State {
+            queue: (0..pairs.len()).collect(),
+            results: vec![None; pairs.len()],
+            error: None,
+            connected: 0,
+            idle_since: Some(Instant::now()),
+            checkpoint,
+        }),
+        Condvar::new(),
+    ));
+    let jobs = Arc::new(jobs);
+    let names: Arc<Vec<String>> =
+        Arc::new(pairs.iter().map(|p| p.name.display().to_string()).collect());
+
+    {
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let state = Arc::clone(&state);
+                let jobs = Arc::clone(&jobs);
+                let names = Arc::clone(&names);
+                let options = Arc::clone(&options);
+                std::thread::spawn(move || {
+                    serve_worker(stream, &state, &jobs, &names, &options, quiet)
+                });
+            }
+        });
+    }
+
+    let (lock, cvar) = &*state;
+    let mut s = lock.lock().expect("coordinator lock poisoned");
+    loop {
+        if let Some(e) = &s.error {
+            bail!("{}", e);
+        }
+        // Let every worker hear that we're done before exiting.
+        if s.results.iter().all(|r| r.is_some()) && s.connected == 0 {
+            let results: Vec<Differences> = s.results.iter().map(|r| r.unwrap()).collect();
+            if !quiet {
+                for (pair, diffs) in pairs.iter().zip(results.iter()) {
+                    if let Some((current, baseline)) = diffs.hashes {
+                        println!(
+                            "XXH3 for {:?}: current {:016x}, baseline {:016x}",
+                            pair.name, current, baseline
+                        );
+                    }
+                    println!("Biggest difference for {:?}: {}", pair.name, diffs.max_abs);
+                }
+            }
+            return Ok(results);
+        }
+        let mut wait = worker_timeout;
+        if let Some(since) = s.idle_since {
+            let idle = since.elapsed();
+            if idle >= worker_timeout {
+                bail!(
+                    "No worker has been connected for {:.0} s, with {} comparisons left; did the workers die?",
+                    idle.as_secs_f64(),
+                    s.results.iter().filter(|r| r.is_none()).count()
+                );
+            }
+            wait = worker_timeout - idle;
+        }
+        s = cvar
+            .wait_timeout(s, wait)
+            .expect("coordinator lock poisoned")
+            .0;
+    }
+}
+
+/// Hand out jobs to one worker until there are none left. If the worker goes
+/// away mid-job, its job is put back in the queue for someone else.
+fn serve_worker(
+    stream: TcpStream,
+    state: &(Mutex<State>, Condvar),
+    jobs: &[(PathBuf, PathBuf)],
+    names: &[String],
+    options: &str,
+    quiet: bool,
+) {
+    let (lock, cvar) = state;
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return,
+    };
+    let mut writer = stream;
+    let mut line = String::new();
+    let worker = match reader.read_line(&mut line) {
+        Ok(n) if n > 0 && line.starts_with("HELLO ") => line[6..].trim().to_string(),
+        _ => return,
+    };
+    if writeln!(writer, "{}", options).is_err() {
+        return;
+    }
+    if !quiet {
+        eprintln!("Worker {} connected", worker);
+    }
+    {
+        let mut s = lock.lock().expect("coordinator lock poisoned");
+        s.connected += 1;
+        s.idle_since = None;
+    }
+    hand_out_jobs(&mut reader, &mut writer, state, jobs, names, &worker, quiet);
+    {
+        let mut s = lock.lock().expect("coordinator lock poisoned");
+        s.connected -= 1;
+        if s.connected == 0 {
+            s.idle_since = Some(Instant::now());
+        }
+    }
+    cvar.notify_all();
+}
+
+fn hand_out_jobs(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut TcpStream,
+    state: &(Mutex<State>, Condvar),
+    jobs: &[(PathBuf, PathBuf)],
+    names: &[String],
+    worker: &str,
+    quiet: bool,
+) {
+    let (lock, cvar) = state;
+    let mut line = String::new();
+    loop {
+        let index = lock
+            .lock()
+            .expect("coordinator lock poisoned")
+            .queue
+            .pop_front();
+        let index = match index {
+            Some(i) => i,
+            None => {
+                let _ = writeln!(writer, "DONE");
+                return;
+            }
+        };
+        let (current, baseline) = &jobs[index];
+        let sent = writeln!(
+            writer,
+            "JOB {}\t{}\t{}",
+            index,
+            current.display(),
+            baseline.display()
+        );
+
+        line.clear();
+        let reply = match sent.and_then(|_| reader.read_line(&mut line)) {
+            Ok(n) if n > 0 => parse_reply(&line, index),
+            _ => Err(None),
+        };
+        let mut s = lock.lock().expect("coordinator lock poisoned");
+        match reply {
+            Ok(diffs) => {
+                s.results[index] = Some(diffs);
+                let recorded = match &mut s.checkpoint {
+                    Some(c) => c.record(&names[index], &diffs),
+                    None => Ok(()),
+                };
+                if let Err(e) = recorded {
+                    s.error.get_or_insert(e.to_string());
+                }
+                cvar.notify_all();
+            }
+            Err(Some(e)) => {
+                s.error.get_or_insert(format!(
+                    "Worker {} failed on {:?}: {}",
+                    worker, names[index], e
+                ));
+                cvar.notify_all();
+                return;
+            }
+            Err(None) => {
+                if !quiet {
+                    eprintln!(
+                        "Warning: lost worker {} while it compared {:?}; requeueing",
+                        worker, names[index]
+                    );
+                }
+                s.queue.push_back(index);
+                return;
+            }
+        }
+    }
+}
+
+/// Parse a worker's reply to job `index`. `Err(None)` means the reply was
+/// garbled, and the job should be retried elsewhere.
+fn parse_reply(line: &str, index: usize) -> Result<Differences, Option<String>> {
+    let line = line.trim_end_matches('\n');
+    if let Some(rest) = line.strip_prefix("ERROR ") {
+        let mut fields = rest.splitn(2, '\t');
+        let _ = fields.next();
+        return Err(Some(fields.next().unwrap_or("unknown error").to_string()));
+    }
+    let rest = line.strip_prefix("RESULT ").ok_or(None)?;
+    let fields: Vec<&str> = rest.split('\t').collect();
+    if fields.len() != checkpoint::RESULT_FIELDS + 4
+        || fields[0].parse::<usize>().ok() != Some(index)
+    {
+        return Err(None);
+    }
+    let (results, timings) = fields[1..].split_at(checkpoint::RESULT_FIELDS);
+    let nanos = |s: &str| s.parse().map(Duration::from_nanos).map_err(|_| None);
+    Ok(Differences {
+        timings: Timings {
+            io: nanos(timings[0])?,
+            decode: nanos(timings[1])?,
+            metrics: nanos(timings[2])?,
+        },
+        ..checkpoint::parse_fields(results).ok_or(None)?
+    })
+}
+
+/// Connect to the coordinator at `address` and compare whatever it asks for
+/// until it says there's nothing left to do.
+pub(crate) fn work(
+    address: &str,
+    reader: &Reader,
+    verbosity: Verbosity,
+) -> Result<(), anyhow::Error> {
+    let stream = TcpStream::connect(address)
+        .map_err(|e| anyhow!("Couldn't connect to coordinator {}: {}", address, e))?;
+    let mut lines = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writeln!(writer, "HELLO {}", hostname())?;
+
+    let mut line = String::new();
+    if lines.read_line(&mut line)? == 0 {
+        bail!("The coordinator closed the connection unexpectedly");
+    }
+    let fields: Vec<String> = line
+        .trim_end_matches('\n')
+        .strip_prefix("OPTIONS ")
+        .ok_or_else(|| {
+            anyhow!(
+                "Unexpected message from the coordinator: {}",
+                line.trim_end()
+            )
+        })?
+        .split('\t')
+        .map(String::from)
+        .collect();
+    // Relative paths in the arguments are relative to the coordinator's
+    // directory.
+    let dir = Path::new(&fields[0]);
+    std::env::set_current_dir(dir).map_err(|e| {
+        anyhow!(
+            "Couldn't enter the coordinator's directory {:?} (all paths must be valid on every node): {}",
+            dir,
+            e
+        )
+    })?;
+    let comparison = comparison_from_args(&fields[1..])
+        .map_err(|e| anyhow!("Couldn't use the coordinator's options: {}", e))?;
+
+    loop {
+        line.clear();
+        if lines.read_line(&mut line)? == 0 {
+            bail!("The coordinator closed the connection unexpectedly");
+        }
+        let line = line.trim_end_matches('\n');
+        if line == "DONE" {
+            return Ok(());
+        }
+        let job = line
+            .strip_prefix("JOB ")
+            .ok_or_else(|| anyhow!("Unexpected message from the coordinator: {}", line))?;
+        let fields: Vec<&str> = job.split('\t').collect();
+        if fields.len() != 3 {
+            bail!("Malformed job from the coordinator: {}", line);
+        }
+        let index = fields[0];
+        let current = PathBuf::from(fields[1]);
+        let pair = FilePair {
+            name: current.file_name().map(PathBuf::from).unwrap_or_default(),
+            current,
+            baseline: PathBuf::from(fields[2]),
+            band: None,
+        };
+        let mut out = String::new();
+        let result = compare_pair(&pair, &comparison, reader, verbosity, &mut out);
+        print!("{}", out);
+        match result {
+            Ok(d) => writeln!(
+                writer,
+                "RESULT {}\t{}\t{}\t{}\t{}",
+                index,
+                checkpoint::format_fields(&d),
+                d.timings.io.as_nanos(),
+                d.timings.decode.as_nanos(),
+                d.timings.metrics.as_nanos()
+            )?,
+            Err(e) => writeln!(
+                writer,
+                "ERROR {}\t{}",
+                index,
+                e.to_string().replace(&['\t', '\n'][..], " ")
+            )?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    fn write_f64s(path: &Path, values: &[f64]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn quiet_reader() -> Reader {
+        Reader {
+            retries: 0,
+            backoff: Duration::default(),
+            stripe_size: None,
+            threads: None,
+            direct_io: false,
+            mmap: false,
+            throttle: None,
+            used: AtomicU32::new(0),
+            quiet: true,
+            streams: Default::default(),
+        }
+    }
+
+    /// A pair of identical files in a new directory `name`.
+    fn identical_pair(name: &str) -> FilePair {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pair = FilePair {
+            name: PathBuf::from("band.bin"),
+            current: dir.join("current.bin"),
+            baseline: dir.join("baseline.bin"),
+            band: None,
+        };
+        write_f64s(&pair.current, &[1.0, 2.0]);
+        write_f64s(&pair.baseline, &[1.0, 2.0]);
+        pair
+    }
+
+    /// Coordinate the comparison of `pair` on another thread, returning the
+    /// address to connect workers to.
+    fn coordinate_pair(
+        pair: FilePair,
+    ) -> (
+        String,
+        std::thread::JoinHandle<Result<Vec<Differences>, anyhow::Error>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let coordinator = std::thread::spawn(move || {
+            let args = vec!["hyperdrive-vis-gen-diff".to_string()];
+            let results = coordinate_on(
+                listener,
+                &[&pair],
+                &args,
+                None,
+                Duration::from_secs(60),
+                true,
+            );
+            std::fs::remove_dir_all(pair.current.parent().unwrap()).unwrap();
+            results
+        });
+        (address, coordinator)
+    }
+
+    /// Connect to the coordinator at `address` as a worker, and take its first
+    /// job.
+    fn take_job(address: &str) -> (BufReader<TcpStream>, TcpStream) {
+        let mut writer = TcpStream::connect(address).unwrap();
+        writeln!(writer, "HELLO test").unwrap();
+        let mut reader = BufReader::new(writer.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("OPTIONS "), "{:?}", line);
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("JOB 0\t"), "{:?}", line);
+        (reader, writer)
+    }
+
+    #[test]
+    fn lost_jobs_are_handed_to_another_worker() {
+        let (address, coordinator) = coordinate_pair(identical_pair("distributed-lost"));
+        let (mut reader, mut writer) = take_job(&address);
+        writeln!(writer, "RESULT 0\tcut short").unwrap();
+        // The coordinator hangs up once the job is back in the queue.
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).unwrap(), 0);
+
+        work(&address, &quiet_reader(), Verbosity::Quiet).unwrap();
+        let results = coordinator.join().unwrap().unwrap();
+        let d = results[0];
+        assert_eq!(d.max_abs, 0.0);
+        assert!(matches!(d.hashes, Some((c, b)) if c == b));
+    }
+
+    #[test]
+    fn worker_errors_fail_the_comparison() {
+        let (address, coordinator) = coordinate_pair(identical_pair("distributed-error"));
+        let (_reader, mut writer) = take_job(&address);
+        writeln!(writer, "ERROR 0\tcouldn't read").unwrap();
+        let e = coordinator.join().unwrap().unwrap_err().to_string();
+        assert_eq!(e, "Worker test failed on \"band.bin\": couldn't read");
+    }
+
+    #[test]
+    fn workers_compare_as_the_coordinator_would() {
+        let dir = std::env::temp_dir().join(format!("distributed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pair = FilePair {
+            name: PathBuf::from("band.bin"),
+            current: dir.join("current.bin"),
+            baseline: dir.join("baseline.bin"),
+            band: None,
+        };
+        // Read as f32s, these would be nothing like each other.
+        write_f64s(&pair.current, &[1.0, f64::NAN, 2.5, -4.0]);
+        write_f64s(&pair.baseline, &[1.0, 1.0, 2.0, -4.0]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let worker = std::thread::spawn(move || work(&address, &quiet_reader(), Verbosity::Quiet));
+        let args: Vec<String> = [
+            "hyperdrive-vis-gen-diff",
+            "--dtype",
+            "f64",
+            "--tolerance",
+            "0.1",
+            "--coordinator",
+            "127.0.0.1:0",
+        ]
+        .iter()
+        .map(|a| a.to_string())
+        .collect();
+        let results = coordinate_on(
+            listener,
+            &[&pair],
+            &args,
+            None,
+            Duration::from_secs(60),
+            true,
+        );
+        let worked = worker.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        worked.unwrap();
+
+        let d = results.unwrap()[0];
+        assert_eq!(d.max_abs, 0.5);
+        assert_eq!((d.current_nans, d.baseline_nans), (1, 0));
+        // NaNs fail by default, so the coordinator must hear of them.
+        assert_eq!(d.nan_failures, 1);
+        assert_eq!((d.samples, d.above_tolerance), (3, 1));
+        assert!(d.hashes.is_some());
+    }
+
+    #[test]
+    fn garbled_results_are_retried() {
+        let d = Differences {
+            max_abs: 0.25,
+            nan_failures: 2,
+            timings: Timings {
+                io: Duration::from_nanos(7),
+                ..Timings::default()
+            },
+            ..Differences::default()
+        };
+        let line = format!("RESULT 3\t{}\t7\t0\t0\n", checkpoint::format_fields(&d));
+        let parsed = parse_reply(&line, 3).unwrap();
+        assert_eq!((parsed.max_abs, parsed.nan_failures), (0.25, 2));
+        assert_eq!(parsed.timings.io, Duration::from_nanos(7));
+        // Another job's result, or one cut short, is garbled.
+        assert!(matches!(parse_reply(&line, 4), Err(None)));
+        assert!(matches!(parse_reply("RESULT 3\t0\t0\n", 3), Err(None)));
+        assert!(matches!(
+            parse_reply("ERROR 3\tcouldn't read\n", 3),
+            Err(Some(e)) if e == "couldn't read"
+        ));
+    }
+}
@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Send plain-text email notifications through an SMTP relay.
+//!
+//! Only unauthenticated, unencrypted SMTP is spoken, which is what site relays
+//! on HPC login nodes typically accept from inside the facility.
+
+use std::io::{prelude::*, BufReader};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+
+use crate::history;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct Email<'a> {
+    /// The relay to talk to, e.g. "smtp.pawsey.org.au" or "localhost:25".
+    pub(crate) server: &'a str,
+    pub(crate) from: &'a str,
+    pub(crate) to: &'a [String],
+    pub(crate) subject: &'a str,
+    pub(crate) body: &'a str,
+}
+
+/// The name of this machine, for the default sender address and the SMTP
+/// greeting.
+pub(crate) fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+pub(crate) fn send(email: &Email) -> Result<(), anyhow::Error> {
+    let address = if email.server.contains(':') {
+        email.server.to_string()
+    } else {
+        format!("{}:25", email.server)
+    };
+    let stream = TcpStream::connect(&address)
+        .map_err(|e| anyhow!("Couldn't connect to SMTP server {}: {}", address, e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    expect(&mut reader, 220)?;
+    command(
+        &mut writer,
+        &mut reader,
+        &format!("HELO {}", hostname()),
+        250,
+    )?;
+    command(
+        &mut writer,
+        &mut reader,
+        &format!("MAIL FROM:<{}>", email.from),
+        250,
+    )?;
+    for to in email.to {
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", to), 250)?;
+    }
+    command(&mut writer, &mut reader, "DATA", 354)?;
+
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\r\n",
+        email.from,
+        email.to.join(", "),
+        email.subject,
+        rfc2822_now()
+    );
+    for line in email.body.lines() {
+        // "Dot-stuff" lines so that none of them end the message early.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    writer.write_all(message.as_bytes())?;
+    expect(&mut reader, 250)?;
+
+    command(&mut writer, &mut reader, "QUIT", 221)?;
+    Ok(())
+}
+
+fn command(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    line: &str,
+    code: u32,
+) -> Result<(), anyhow::Error> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    expect(reader, code)
+}
+
+/// Read a (possibly multi-line) SMTP reply and check its status code.
+fn expect(reader: &mut BufReader<TcpStream>, code: u32) -> Result<(), anyhow::Error> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("SMTP server closed the connection unexpectedly");
+        }
+        let status: u32 = line
+            .get(0..3)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| anyhow!("Malformed SMTP reply: {}", line.trim()))?;
+        // A '-' after the code means more lines of this reply follow.
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if status != code {
+            bail!("SMTP server replied \"{}\"; expected {}", line.trim(), code);
+        }
+        return Ok(());
+    }
+}
+
+/// The current time formatted for an email's Date header, e.g.
+/// "Tue, 09 Jun 2020 03:14:15 +0000".
+fn rfc2822_now() -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    let (year, month, day) = history::civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        // 1970-01-01 was a Thursday.
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,238 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Compare the outputs of several repeated, identical runs, to tell GPU
+//! non-determinism apart from a genuine regression. The scatter between the
+//! runs shows how much a single comparison can flap; a file (or band) whose
+//! difference from the baseline is above the tolerance still passes if that
+//! difference is in line with the scatter.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::{exit_code, matching_files_with, Error, DEFAULT_OUTPUTS};
+
+use crate::read::Reader;
+use crate::{
+    compare_pair, compress, container, preflight, remote, split_bands, summary_value, DiffOpt,
+    Differences, FilePair, Verbosity,
+};
+
+/// The spread of the runs and their differences from the baseline, for one
+/// file (or band).
+struct Scatter {
+    name: PathBuf,
+    /// The differences between each pair of runs, combined.
+    between_runs: Differences,
+    /// The difference of each run from the baseline.
+    from_baseline: Vec<Differences>,
+}
+
+impl Scatter {
+    fn smallest_from_baseline(&self) -> f32 {
+        self.from_baseline
+            .iter()
+            .map(|d| d.max_abs)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    fn largest_from_baseline(&self) -> f32 {
+        self.from_baseline
+            .iter()
+            .map(|d| d.max_abs)
+            .fold(0.0, f32::max)
+    }
+}
+
+/// How a file (or band) fared.
+#[derive(Clone, Copy, PartialEq)]
+enum Verdict {
+    /// Every run is within the tolerance of the baseline.
+    Pass,
+    /// Some runs aren't, but no more than the runs differ from each other.
+    Scatter,
+    Fail,
+}
+
+impl Verdict {
+    fn name(self) -> &'static str {
+        match self {
+            Verdict::Pass => "pass",
+            Verdict::Scatter => "scatter",
+            Verdict::Fail => "FAIL",
+        }
+    }
+}
+
+pub(crate) fn compare_runs(options: &DiffOpt, reader: &Reader) -> Result<(), anyhow::Error> {
+    let runs = &options.runs;
+    let baseline = match &options.baseline_url {
+        Some(url) => remote::fetch(url, options.compare.quiet)?,
+        None => container::resolve(&options.baseline_dir, options.compare.quiet)?,
+    };
+    let patterns: Vec<String> = if options.compare.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.compare.outputs.clone()
+    };
+    // Every run must have written the same files as the first.
+    let names = matching_files_with(
+        &baseline,
+        &runs[0],
+        &patterns,
+        compress::EXTENSIONS,
+        options.compare.allow_subset,
+    )?;
+    for run in &runs[1..] {
+        for name in &names {
+            if !run.join(name).is_file() {
+                bail!(Error::Missing(format!(
+                    "{:?} is missing from {}!",
+                    name,
+                    run.display()
+                )));
+            }
+        }
+    }
+
+    let pairs = |current: &Path, baseline: &Path| -> Result<Vec<FilePair>, anyhow::Error> {
+        let pairs: Vec<FilePair> = names
+            .iter()
+            .map(|name| FilePair {
+                name: name.clone(),
+                current: current.join(name),
+                baseline: compress::locate(&baseline.join(name)),
+                band: None,
+            })
+            .collect();
+        preflight(&pairs, &options.compare)?;
+        Ok(match options.compare.bands {
+            Some(bands) => split_bands(pairs, bands, options.compare.dtype)?,
+            None => pairs,
+        })
+    };
+    let comparison = options.compare.comparison()?;
+    let compare_all = |pairs: &[FilePair]| -> Result<Vec<Differences>, anyhow::Error> {
+        pairs
+            .iter()
+            // Nothing is printed when quiet.
+            .map(|pair| {
+                compare_pair(
+                    pair,
+                    &comparison,
+                    reader,
+                    Verbosity::Quiet,
+                    &mut String::new(),
+                )
+            })
+            .collect()
+    };
+
+    let first = pairs(&runs[0], &baseline)?;
+    let mut scatters: Vec<Scatter> = first
+        .iter()
+        .map(|pair| Scatter {
+            name: pair.name.clone(),
+            between_runs: Differences::default(),
+            from_baseline: vec![],
+        })
+        .collect();
+    for (i, run) in runs.iter().enumerate() {
+        if !options.compare.quiet {
+            println!(
+                "Comparing {} against the baseline and the runs before it ...",
+                run.display()
+            );
+        }
+        let against_baseline = if i == 0 {
+            compare_all(&first)?
+        } else {
+            compare_all(&pairs(run, &baseline)?)?
+        };
+        for (s, d) in scatters.iter_mut().zip(against_baseline) {
+            s.from_baseline.push(d);
+        }
+        for earlier in &runs[..i] {
+            for (s, d) in scatters.iter_mut().zip(compare_all(&pairs(run, earlier)?)?) {
+                s.between_runs = s.between_runs.max(d);
+            }
+        }
+    }
+
+    let verdicts: Vec<Verdict> = scatters
+        .iter()
+        .map(|s| {
+            if !s.from_baseline.iter().any(|d| options.compare.failed(d)) {
+                Verdict::Pass
+            } else if s
+                .from_baseline
+                .iter()
+                .any(|d| d.corrupt || d.nan_failures > 0)
+            {
+                Verdict::Fail
+            } else if s.largest_from_baseline() <= options.scatter_factor * s.between_runs.max_abs {
+                Verdict::Scatter
+            } else {
+                Verdict::Fail
+            }
+        })
+        .collect();
+
+    if !options.compare.quiet {
+        println!(
+            "Scatter between {} runs, and their differences from the baseline:",
+            runs.len()
+        );
+        println!(
+            "{:<30} {:>14} {:>14} {:>14} {:>14} {:>8}",
+            "file", "max scatter", "RMS scatter", "min vs base", "max vs base", "result"
+        );
+        for (s, v) in scatters.iter().zip(&verdicts) {
+            println!(
+                "{:<30} {:>14e} {:>14e} {:>14e} {:>14e} {:>8}",
+                s.name.display(),
+                s.between_runs.max_abs,
+                s.between_runs.rms() as f32,
+                s.smallest_from_baseline(),
+                s.largest_from_baseline(),
+                v.name()
+            );
+        }
+        if verdicts.contains(&Verdict::Scatter) {
+            println!(
+                "Differences marked \"scatter\" exceed the tolerance, but are within {} times the run-to-run scatter.",
+                options.scatter_factor
+            );
+        }
+    }
+
+    let failed = verdicts.contains(&Verdict::Fail);
+    if failed && !options.compare.quiet {
+        println!("Difference is too large; exiting with code 1.");
+    }
+    if !options.compare.quiet {
+        let worst = scatters.iter().max_by(|a, b| {
+            a.largest_from_baseline()
+                .total_cmp(&b.largest_from_baseline())
+        });
+        // Last, so that scripts scanning logs can find the verdict easily.
+        println!(
+            "HYPERDRIVE-CHECKS result={} max_diff={:e} worst={} files={} tolerance={:e} runs={} max_scatter={:e}",
+            if failed { "FAIL" } else { "PASS" },
+            worst.map_or(0.0, |s| s.largest_from_baseline()),
+            summary_value(&worst.map(|s| s.name.display().to_string()).unwrap_or_default()),
+            scatters.len(),
+            options.compare.tolerance,
+            runs.len(),
+            scatters
+                .iter()
+                .map(|s| s.between_runs.max_abs)
+                .fold(0.0, f32::max)
+        );
+    }
+    if failed {
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! What the system looked like when the checks ran, so that a failing report
+//! can answer "what changed on the system?" by itself. Anything that can't be
+//! found (e.g. CUDA on a CPU-only node) is left out.
+
+use std::process::Command;
+
+/// The software environment of a run.
+#[derive(Debug, Default)]
+pub(crate) struct Environment {
+    /// The environment modules loaded, from LOADEDMODULES, e.g.
+    /// "cuda/12.2.0".
+    pub(crate) modules: Vec<String>,
+    /// The NVIDIA driver version, from nvidia-smi (see [nvidia_driver]).
+    pub(crate) cuda_driver: Option<String>,
+    /// The CUDA toolkit release, from nvcc.
+    pub(crate) cuda_runtime: Option<String>,
+    /// `git describe` of the working directory, if it's in a git repository.
+    pub(crate) git_describe: Option<String>,
+}
+
+impl Environment {
+    pub(crate) fn capture() -> Environment {
+        Environment {
+            modules: std::env::var("LOADEDMODULES")
+                .map(|m| {
+                    m.split(':')
+                        .filter(|m| !m.is_empty())
+                        .map(|m| m.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            cuda_driver: nvidia_driver().map(|d| d.version),
+            cuda_runtime: run("nvcc", &["--version"]).and_then(|s| {
+                let rest = &s[s.find("release ")? + "release ".len()..];
+                rest.split(',').next().map(|v| v.trim().to_string())
+            }),
+            git_describe: run("git", &["describe", "--always", "--dirty", "--tags"])
+                .map(|s| s.trim().to_string()),
+        }
+    }
+
+    /// The fields of the environment that were found, as (key, value) pairs.
+    pub(crate) fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![];
+        if !self.modules.is_empty() {
+            fields.push(("modules", self.modules.join(" ")));
+        }
+        for (key, value) in [
+            ("cuda_driver", &self.cuda_driver),
+            ("cuda_runtime", &self.cuda_runtime),
+            ("git_describe", &self.git_describe),
+        ] {
+            if let Some(v) = value {
+                fields.push((key, v.clone()));
+            }
+        }
+        fields
+    }
+}
+
+/// What `hyperdrive --version` of the hyperdrive on the PATH says, if there is
+/// one.
+pub(crate) fn hyperdrive_version() -> Option<String> {
+    run("hyperdrive", &["--version"]).map(|s| s.trim().to_string())
+}
+
+/// The git commit that hyperdrive was built from, if `hyperdrive --version`
+/// says.
+pub(crate) fn hyperdrive_commit() -> Option<String> {
+    commit_of(&hyperdrive_version()?)
+}
+
+fn commit_of(version: &str) -> Option<String> {
+    version
+        .lines()
+        .find(|l| l.to_lowercase().contains("commit"))
+        .and_then(|l| l.split_whitespace().last())
+        .map(|c| c.to_string())
+}
+
+/// This node's NVIDIA driver, as the header of nvidia-smi's output describes
+/// it, e.g. "Driver Version: 535.104.05   CUDA Version: 12.2".
+pub(crate) struct NvidiaDriver {
+    pub(crate) version: String,
+    /// The newest CUDA release the driver supports.
+    pub(crate) cuda: Option<String>,
+}
+
+pub(crate) fn nvidia_driver() -> Option<NvidiaDriver> {
+    parse_nvidia_smi(&run("nvidia-smi", &[])?)
+}
+
+fn parse_nvidia_smi(output: &str) -> Option<NvidiaDriver> {
+    let after = |label: &str| {
+        let rest = &output[output.find(label)? + label.len()..];
+        rest.split_whitespace().next().map(|v| v.to_string())
+    };
+    Some(NvidiaDriver {
+        version: after("Driver Version:")?,
+        cuda: after("CUDA Version:"),
+    })
+}
+
+/// The stdout of a command, if it ran successfully and printed anything.
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if stdout.trim().is_empty() {
+        None
+    } else {
+        Some(stdout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_found_in_tool_output() {
+        let smi = "\
++---------------------------------------------------------------------------------------+
+| NVIDIA-SMI 535.104.05             Driver Version: 535.104.05   CUDA Version: 12.2     |
+|-----------------------------------------+----------------------+----------------------+
+";
+        let driver = parse_nvidia_smi(smi).unwrap();
+        assert_eq!(driver.version, "535.104.05");
+        assert_eq!(driver.cuda.as_deref(), Some("12.2"));
+        assert!(parse_nvidia_smi("No devices were found").is_none());
+
+        assert_eq!(
+            commit_of("hyperdrive 0.3.0\nCompiled on git commit hash: 8f1c2e4").as_deref(),
+            Some("8f1c2e4")
+        );
+        assert_eq!(commit_of("hyperdrive 0.3.0"), None);
+    }
+}
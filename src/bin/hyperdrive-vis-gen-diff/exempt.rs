@@ -0,0 +1,151 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Files (or bands) that are still compared and reported, but whose results
+//! don't decide the overall verdict.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use glob::Pattern;
+
+use crate::history;
+
+/// A known regression: files whose names match `pattern` are expected to fail,
+/// optionally only until the end of an expiry date.
+#[derive(Debug)]
+pub(crate) struct Xfail {
+    pattern: Pattern,
+    /// Why, e.g. a link to the issue tracking the regression.
+    pub(crate) reason: String,
+    /// Like "2020-06-30", and the end of that date in seconds since the Unix
+    /// epoch.
+    pub(crate) expires: Option<(String, i64)>,
+}
+
+impl Xfail {
+    /// An xfail from a config file, where `expires` is like "2020-06-30".
+    pub(crate) fn new(pattern: Pattern, reason: &str, expires: Option<&str>) -> Option<Xfail> {
+        let expires = match expires {
+            Some(date) => Some((date.to_string(), parse_date(date)? + 86400)),
+            None => None,
+        };
+        Some(Xfail {
+            pattern,
+            reason: reason.to_string(),
+            expires,
+        })
+    }
+
+    pub(crate) fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub(crate) fn matches(&self, name: &Path) -> bool {
+        self.pattern.matches_path(name)
+    }
+
+    pub(crate) fn expired(&self, now: SystemTime) -> bool {
+        self.expires
+            .as_ref()
+            .is_some_and(|&(_, end)| seconds(now) >= end)
+    }
+}
+
+/// Parse an xfail like "hyperdrive_band0[12].bin=https://github.com/...".
+pub(crate) fn parse_xfail(s: &str) -> Result<Xfail, String> {
+    let (pattern, reason) = split_spec(s, "PATTERN=REASON")?;
+    Ok(Xfail {
+        pattern,
+        reason: reason.to_string(),
+        expires: None,
+    })
+}
+
+/// Split "PATTERN=VALUE" at the first '='.
+fn split_spec<'a>(s: &'a str, form: &str) -> Result<(Pattern, &'a str), String> {
+    let (pattern, value) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("Expected {}, not \"{}\"", form, s)),
+    };
+    if value.trim().is_empty() {
+        return Err(format!("Expected {}, not \"{}\"", form, s));
+    }
+    let pattern =
+        Pattern::new(pattern.trim()).map_err(|e| format!("Bad pattern \"{}\": {}", pattern, e))?;
+    Ok((pattern, value.trim()))
+}
+
+/// A flaky file (or band), excluded from the verdict until the end of its
+/// expiry date.
+pub(crate) struct Quarantine {
+    pattern: Pattern,
+    /// Like "2020-06-30".
+    pub(crate) expires: String,
+    /// The end of the expiry date, in seconds since the Unix epoch.
+    end: i64,
+}
+
+impl Quarantine {
+    pub(crate) fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub(crate) fn matches(&self, name: &Path) -> bool {
+        self.pattern.matches_path(name)
+    }
+
+    pub(crate) fn expired(&self, now: SystemTime) -> bool {
+        seconds(now) >= self.end
+    }
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// The start of a date like "2020-06-30", in seconds since the Unix epoch.
+pub(crate) fn parse_date(date: &str) -> Option<i64> {
+    // Reject dates like "2020-02-31" by checking that they survive a round
+    // trip.
+    history::parse_utc_timestamp(&format!("{}T00:00:00Z", date)).filter(|&s| {
+        s >= 0
+            && history::utc_timestamp(UNIX_EPOCH + Duration::from_secs(s as u64)).starts_with(date)
+    })
+}
+
+/// Read a quarantine list. Each line is an expiry date followed by a pattern,
+/// e.g. "2020-06-30 hyperdrive_band1?.bin". Blank lines and lines starting with
+/// '#' are ignored.
+pub(crate) fn read_quarantine(path: &Path) -> Result<Vec<Quarantine>, anyhow::Error> {
+    let mut entries = vec![];
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (date, pattern) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let (start, pattern) = match (parse_date(date), Pattern::new(pattern)) {
+            (Some(start), Ok(pattern)) if !pattern.as_str().is_empty() => (start, pattern),
+            _ => bail!(
+                "{}:{}: expected \"YYYY-MM-DD PATTERN\", not \"{}\"",
+                path.display(),
+                i + 1,
+                line
+            ),
+        };
+        entries.push(Quarantine {
+            pattern,
+            expires: date.to_string(),
+            end: start + 86400,
+        });
+    }
+    Ok(entries)
+}
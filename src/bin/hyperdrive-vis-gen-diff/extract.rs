@@ -0,0 +1,199 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Cut a small piece out of a failing pair of files, to attach to a bug report
+//! instead of pointing at hundreds of GB on /scratch.
+//!
+//! The data are in timestep, baseline, fine channel order. The piece is
+//! written as `<dir>/<name>` and `<dir>/baseline/<name>`, so running the
+//! checker in `<dir>` compares it, along with a "subset.txt" describing where
+//! it came from and its shape. The piece is always written as little-endian
+//! f32s, whatever the type of the files it's cut from.
+
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use pawsey_hyperdrive_checks::{Dtype, Endian};
+
+use crate::read::Reader;
+use crate::{hash, history};
+
+/// The layout of a file.
+pub(crate) struct Shape {
+    pub(crate) timesteps: usize,
+    pub(crate) fine_channels: usize,
+    pub(crate) floats_per_channel: usize,
+    pub(crate) dtype: Dtype,
+    pub(crate) endian: Endian,
+}
+
+/// Which part of the file to cut out. Unspecified ranges of timesteps and
+/// baselines default to just the one holding the largest difference; of fine
+/// channels, to all of them.
+pub(crate) struct Selection {
+    pub(crate) timesteps: Option<Range<usize>>,
+    pub(crate) baselines: Option<Range<usize>>,
+    pub(crate) channels: Option<Range<usize>>,
+}
+
+/// Parse a range of indices like "3", "2:5" or "2..5" (from 2 up to, but not
+/// including, 5).
+pub(crate) fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let bad = || format!("Expected an index or START:END, not \"{}\"", s);
+    let (start, end) = match s.split_once("..").or_else(|| s.split_once(':')) {
+        Some((start, end)) => (
+            start.trim().parse().map_err(|_| bad())?,
+            end.trim().parse().map_err(|_| bad())?,
+        ),
+        None => {
+            let i: usize = s.trim().parse().map_err(|_| bad())?;
+            (i, i + 1)
+        }
+    };
+    if start >= end {
+        return Err(format!("The range \"{}\" is empty", s));
+    }
+    Ok(start..end)
+}
+
+pub(crate) fn extract(
+    current: &Path,
+    baseline: &Path,
+    output: &Path,
+    shape: &Shape,
+    selection: &Selection,
+    reader: &Reader,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let (c_bytes, b_bytes) = (reader.read_bytes(current)?, reader.read_bytes(baseline)?);
+    let (c, b) = (
+        shape.dtype.to_f32s(&c_bytes, shape.endian, current)?,
+        shape.dtype.to_f32s(&b_bytes, shape.endian, baseline)?,
+    );
+    if c.len() != b.len() {
+        bail!(
+            "{:?} and {:?} have different amounts of data",
+            current,
+            baseline
+        );
+    }
+    let channel_len = shape.floats_per_channel;
+    let row_len = shape.fine_channels * channel_len;
+    if row_len == 0 || shape.timesteps == 0 || !c.len().is_multiple_of(shape.timesteps * row_len) {
+        bail!(
+            "{:?} has {} floats, which isn't a whole number of {} timesteps of {} fine channels of {} floats",
+            current,
+            c.len(),
+            shape.timesteps,
+            shape.fine_channels,
+            channel_len
+        );
+    }
+    let baselines = c.len() / (shape.timesteps * row_len);
+
+    // Where's the largest difference?
+    let worst = c
+        .iter()
+        .zip(b.iter())
+        .map(|(c, b)| (c - b).abs())
+        .enumerate()
+        .fold(
+            (0, 0.0),
+            |(wi, wd), (i, d)| if d > wd { (i, d) } else { (wi, wd) },
+        )
+        .0;
+    let worst_row = worst / row_len;
+    let timesteps = selection.timesteps.clone().unwrap_or_else(|| {
+        let t = worst_row / baselines;
+        t..t + 1
+    });
+    let baseline_range = selection.baselines.clone().unwrap_or_else(|| {
+        let b = worst_row % baselines;
+        b..b + 1
+    });
+    let channels = selection.channels.clone().unwrap_or(0..shape.fine_channels);
+    for (what, range, len) in &[
+        ("timesteps", &timesteps, shape.timesteps),
+        ("baselines", &baseline_range, baselines),
+        ("fine channels", &channels, shape.fine_channels),
+    ] {
+        if range.end > *len {
+            bail!(
+                "Can't extract {} {}..{}; there are only {}",
+                what,
+                range.start,
+                range.end,
+                len
+            );
+        }
+    }
+
+    let cut = |data: &[f32]| {
+        let mut out = vec![];
+        for t in timesteps.clone() {
+            for bl in baseline_range.clone() {
+                let row = (t * baselines + bl) * row_len;
+                out.extend_from_slice(
+                    &data[row + channels.start * channel_len..row + channels.end * channel_len],
+                );
+            }
+        }
+        out
+    };
+    let to_bytes =
+        |floats: Vec<f32>| -> Vec<u8> { floats.iter().flat_map(|f| f.to_le_bytes()).collect() };
+
+    let name = current
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} isn't a file", current))?;
+    std::fs::create_dir_all(output.join("baseline"))?;
+    std::fs::write(output.join(name), to_bytes(cut(&c)))?;
+    std::fs::write(output.join("baseline").join(name), to_bytes(cut(&b)))?;
+
+    let sidecar = format!(
+        "# A subset extracted by {} {} on {}\n\
+         current: {}\ncurrent_xxh3: {:016x}\nbaseline: {}\nbaseline_xxh3: {:016x}\n\
+         source_timesteps: {}\nsource_baselines: {}\nsource_fine_channels: {}\n\
+         floats_per_channel: {}\n\
+         timesteps: {}..{}\nbaselines: {}..{}\nfine_channels: {}..{}\n\
+         # Compare with: {} --fine-channels {} --floats-per-channel {} --timesteps {}\n",
+        env!("CARGO_BIN_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        history::utc_timestamp(std::time::SystemTime::now()),
+        current.display(),
+        hash::xxh3_64(&c_bytes),
+        baseline.display(),
+        hash::xxh3_64(&b_bytes),
+        shape.timesteps,
+        baselines,
+        shape.fine_channels,
+        channel_len,
+        timesteps.start,
+        timesteps.end,
+        baseline_range.start,
+        baseline_range.end,
+        channels.start,
+        channels.end,
+        env!("CARGO_BIN_NAME"),
+        channels.len(),
+        channel_len,
+        timesteps.len()
+    );
+    std::fs::write(output.join("subset.txt"), sidecar)?;
+    if !quiet {
+        println!(
+            "Extracted timesteps {}..{}, baselines {}..{} and fine channels {}..{} into {}",
+            timesteps.start,
+            timesteps.end,
+            baseline_range.start,
+            baseline_range.end,
+            channels.start,
+            channels.end,
+            output.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,497 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Fast non-cryptographic hashes, for identifying the exact data that a report
+//! was made from. See
+//! <https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md>.
+//!
+//! Reports identify the files they compared by XXH3 (the 64-bit variant with
+//! the default secret and a seed of 0, i.e. `xxhsum -H3`). XXH64 is kept for
+//! everything whose format already records it: baseline manifests (and so
+//! remote and archived baselines), cache keys, and the manifest and
+//! configuration hashes in a report's provenance.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::anyhow;
+
+const PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+pub(crate) fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut hasher = Xxh64::new(seed);
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// The size and XXH64 hash (with a seed of 0) of the file at `path`.
+pub(crate) fn xxh64_file(path: &Path) -> Result<(u64, u64), anyhow::Error> {
+    let mut file = File::open(path).map_err(|e| anyhow!("Couldn't open {:?}: {}", path, e))?;
+    let mut hasher = Xxh64::new(0);
+    let mut buf = vec![0; 1 << 20];
+    let mut bytes = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        bytes += n as u64;
+    }
+    Ok((bytes, hasher.finish()))
+}
+
+/// XXH64 of data arriving in pieces, e.g. a file read in chunks.
+pub(crate) struct Xxh64 {
+    seed: u64,
+    acc: [u64; 4],
+    /// Bytes not yet making up a whole 32-byte stripe.
+    pending: Vec<u8>,
+    len: u64,
+}
+
+impl Xxh64 {
+    pub(crate) fn new(seed: u64) -> Xxh64 {
+        Xxh64 {
+            seed,
+            acc: [
+                seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+                seed.wrapping_add(PRIME_2),
+                seed,
+                seed.wrapping_sub(PRIME_1),
+            ],
+            pending: Vec::with_capacity(32),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.pending.is_empty() {
+            let needed = (32 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+            if self.pending.len() < 32 {
+                return;
+            }
+            let stripe = std::mem::take(&mut self.pending);
+            self.stripe(&stripe);
+        }
+        let mut chunks = data.chunks_exact(32);
+        for chunk in &mut chunks {
+            self.stripe(chunk);
+        }
+        self.pending.extend_from_slice(chunks.remainder());
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (a, lane) in self.acc.iter_mut().zip(stripe.chunks_exact(8)) {
+            *a = round(*a, read_u64(lane));
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        let mut hash = if self.len >= 32 {
+            let acc = &self.acc;
+            let mut hash = acc[0]
+                .rotate_left(1)
+                .wrapping_add(acc[1].rotate_left(7))
+                .wrapping_add(acc[2].rotate_left(12))
+                .wrapping_add(acc[3].rotate_left(18));
+            for a in acc {
+                hash = (hash ^ round(0, *a))
+                    .wrapping_mul(PRIME_1)
+                    .wrapping_add(PRIME_4);
+            }
+            hash
+        } else {
+            self.seed.wrapping_add(PRIME_5)
+        };
+        hash = hash.wrapping_add(self.len);
+
+        let mut rest = &self.pending[..];
+        while rest.len() >= 8 {
+            hash ^= round(0, read_u64(&rest[..8]));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME_1)
+                .wrapping_add(PRIME_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let lane = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as u64;
+            hash ^= lane.wrapping_mul(PRIME_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME_2)
+                .wrapping_add(PRIME_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            hash ^= (byte as u64).wrapping_mul(PRIME_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME_1);
+        }
+
+        xxh64_avalanche(hash)
+    }
+}
+
+/// XXH3's 64-bit hash, with the default secret and a seed of 0.
+pub(crate) fn xxh3_64(data: &[u8]) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+const PRIME32_1: u64 = 0x9E37_79B1;
+const PRIME32_2: u64 = 0x85EB_CA77;
+const PRIME32_3: u64 = 0xC2B2_AE3D;
+const PRIME_MX1: u64 = 0x1656_6791_9E37_79F9;
+const PRIME_MX2: u64 = 0x9FB2_1C65_1E98_DF25;
+
+/// XXH3's default secret.
+const SECRET: [u8; 192] = [
+    0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+    0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+    0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+    0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+    0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+    0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+    0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+    0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+    0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+    0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+    0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+    0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const STRIPE: usize = 64;
+/// Stripes per block, after each of which the accumulators are scrambled.
+const STRIPES_PER_BLOCK: usize = (SECRET.len() - STRIPE) / 8;
+/// Inputs up to this long are hashed without the accumulators.
+const MID_SIZE_MAX: usize = 240;
+/// How much input [Xxh3] holds before consuming it; at least [MID_SIZE_MAX],
+/// so short inputs are still whole when finished.
+const BUFFER: usize = 4 * STRIPE;
+
+/// XXH3-64 of data arriving in pieces, e.g. a file read in chunks.
+#[derive(Clone)]
+pub(crate) struct Xxh3 {
+    acc: [u64; 8],
+    /// Stripes consumed in the current block.
+    stripes: usize,
+    /// Input not yet consumed. A stripe is only consumed once more input
+    /// follows it, as the last stripe is treated differently.
+    buffer: [u8; BUFFER],
+    buffered: usize,
+    /// The last stripe consumed, as the last stripe of the input can overlap
+    /// it.
+    last_stripe: [u8; STRIPE],
+    len: u64,
+}
+
+impl Xxh3 {
+    pub(crate) fn new() -> Xxh3 {
+        Xxh3 {
+            acc: [
+                PRIME32_3, PRIME_1, PRIME_2, PRIME_3, PRIME_4, PRIME32_2, PRIME_5, PRIME32_1,
+            ],
+            stripes: 0,
+            buffer: [0; BUFFER],
+            buffered: 0,
+            last_stripe: [0; STRIPE],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if self.buffered + data.len() <= BUFFER {
+            self.buffer[self.buffered..self.buffered + data.len()].copy_from_slice(data);
+            self.buffered += data.len();
+            return;
+        }
+        if self.buffered > 0 {
+            let needed = BUFFER - self.buffered;
+            self.buffer[self.buffered..].copy_from_slice(&data[..needed]);
+            data = &data[needed..];
+            let buffer = self.buffer;
+            self.consume(&buffer);
+            self.buffered = 0;
+        }
+        if data.len() > BUFFER {
+            let whole = (data.len() - 1) / STRIPE * STRIPE;
+            self.consume(&data[..whole]);
+            data = &data[whole..];
+        }
+        self.buffer[..data.len()].copy_from_slice(data);
+        self.buffered = data.len();
+    }
+
+    /// Accumulate whole stripes, scrambling after each block.
+    fn consume(&mut self, stripes: &[u8]) {
+        for stripe in stripes.chunks_exact(STRIPE) {
+            accumulate(&mut self.acc, stripe, &SECRET[self.stripes * 8..]);
+            self.stripes += 1;
+            if self.stripes == STRIPES_PER_BLOCK {
+                scramble(&mut self.acc, &SECRET[SECRET.len() - STRIPE..]);
+                self.stripes = 0;
+            }
+        }
+        self.last_stripe
+            .copy_from_slice(&stripes[stripes.len() - STRIPE..]);
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        let len = self.len as usize;
+        if len <= MID_SIZE_MAX {
+            return xxh3_short(&self.buffer[..len]);
+        }
+
+        let mut state = self.clone();
+        let whole = (self.buffered.max(1) - 1) / STRIPE * STRIPE;
+        if whole > 0 {
+            state.consume(&self.buffer[..whole]);
+        }
+        let mut last = [0; STRIPE];
+        if self.buffered >= STRIPE {
+            last.copy_from_slice(&self.buffer[self.buffered - STRIPE..self.buffered]);
+        } else {
+            let from_before = STRIPE - self.buffered;
+            last[..from_before].copy_from_slice(&self.last_stripe[self.buffered..]);
+            last[from_before..].copy_from_slice(&self.buffer[..self.buffered]);
+        }
+        accumulate(&mut state.acc, &last, &SECRET[SECRET.len() - STRIPE - 7..]);
+
+        let mut hash = self.len.wrapping_mul(PRIME_1);
+        for (i, pair) in state.acc.chunks_exact(2).enumerate() {
+            let secret = &SECRET[11 + 16 * i..];
+            hash = hash.wrapping_add(mul_fold(
+                pair[0] ^ read_u64(&secret[..8]),
+                pair[1] ^ read_u64(&secret[8..16]),
+            ));
+        }
+        xxh3_avalanche(hash)
+    }
+}
+
+/// XXH3 of inputs of at most [MID_SIZE_MAX] bytes.
+fn xxh3_short(data: &[u8]) -> u64 {
+    let len = data.len();
+    let secret = |offset: usize| read_u64(&SECRET[offset..offset + 8]);
+    match len {
+        0 => xxh64_avalanche(secret(56) ^ secret(64)),
+        1..=3 => {
+            let combined = (data[0] as u64) << 16
+                | (data[len >> 1] as u64) << 24
+                | data[len - 1] as u64
+                | (len as u64) << 8;
+            let flip = (read_u32(&SECRET[..4]) ^ read_u32(&SECRET[4..8])) as u64;
+            xxh64_avalanche(combined ^ flip)
+        }
+        4..=8 => {
+            let input =
+                (read_u32(&data[len - 4..]) as u64).wrapping_add((read_u32(data) as u64) << 32);
+            rrmxmx(input ^ (secret(8) ^ secret(16)), len as u64)
+        }
+        9..=16 => {
+            let low = read_u64(data) ^ (secret(24) ^ secret(32));
+            let high = read_u64(&data[len - 8..]) ^ (secret(40) ^ secret(48));
+            xxh3_avalanche(
+                (len as u64)
+                    .wrapping_add(low.swap_bytes())
+                    .wrapping_add(high)
+                    .wrapping_add(mul_fold(low, high)),
+            )
+        }
+        17..=128 => {
+            let mut hash = (len as u64).wrapping_mul(PRIME_1);
+            let pairs = (len - 1) / 32;
+            for i in (0..=pairs).rev() {
+                hash = hash
+                    .wrapping_add(mix16(&data[16 * i..], 32 * i))
+                    .wrapping_add(mix16(&data[len - 16 * (i + 1)..], 32 * i + 16));
+            }
+            xxh3_avalanche(hash)
+        }
+        _ => {
+            let mut hash = (len as u64).wrapping_mul(PRIME_1);
+            for i in 0..8 {
+                hash = hash.wrapping_add(mix16(&data[16 * i..], 16 * i));
+            }
+            hash = xxh3_avalanche(hash);
+            for i in 8..len / 16 {
+                hash = hash.wrapping_add(mix16(&data[16 * i..], 16 * (i - 8) + 3));
+            }
+            hash = hash.wrapping_add(mix16(&data[len - 16..], 136 - 17));
+            xxh3_avalanche(hash)
+        }
+    }
+}
+
+fn mix16(data: &[u8], secret: usize) -> u64 {
+    mul_fold(
+        read_u64(&data[..8]) ^ read_u64(&SECRET[secret..secret + 8]),
+        read_u64(&data[8..16]) ^ read_u64(&SECRET[secret + 8..secret + 16]),
+    )
+}
+
+fn accumulate(acc: &mut [u64; 8], stripe: &[u8], secret: &[u8]) {
+    for i in 0..8 {
+        let value = read_u64(&stripe[8 * i..8 * i + 8]);
+        let key = value ^ read_u64(&secret[8 * i..8 * i + 8]);
+        acc[i ^ 1] = acc[i ^ 1].wrapping_add(value);
+        acc[i] = acc[i].wrapping_add((key & 0xFFFF_FFFF).wrapping_mul(key >> 32));
+    }
+}
+
+fn scramble(acc: &mut [u64; 8], secret: &[u8]) {
+    for (i, a) in acc.iter_mut().enumerate() {
+        *a ^= *a >> 47;
+        *a ^= read_u64(&secret[8 * i..8 * i + 8]);
+        *a = a.wrapping_mul(PRIME32_1);
+    }
+}
+
+/// The 128-bit product of `a` and `b`, with its halves XORed together.
+fn mul_fold(a: u64, b: u64) -> u64 {
+    let product = a as u128 * b as u128;
+    product as u64 ^ (product >> 64) as u64
+}
+
+fn xxh64_avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+fn xxh3_avalanche(mut hash: u64) -> u64 {
+    hash ^= hash >> 37;
+    hash = hash.wrapping_mul(PRIME_MX1);
+    hash ^ (hash >> 32)
+}
+
+fn rrmxmx(mut hash: u64, len: u64) -> u64 {
+    hash ^= hash.rotate_left(49) ^ hash.rotate_left(24);
+    hash = hash.wrapping_mul(PRIME_MX2);
+    hash ^= (hash >> 35).wrapping_add(len);
+    hash = hash.wrapping_mul(PRIME_MX2);
+    hash ^ (hash >> 28)
+}
+
+fn round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME_1)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(b)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        const PRIME32: u64 = 2_654_435_761;
+        for &(data, seed, expected) in &[
+            (&b""[..], 0, 0xEF46_DB37_51D8_E999),
+            (b"", PRIME32, 0xAC75_FDA2_929B_17EF),
+            (b"a", 0, 0xD24E_C4F1_A98C_6E5B),
+            (b"abc", 0, 0x44BC_2CF5_AD77_0999),
+            // An 8-byte lane and single bytes.
+            (b"hello world", 0, 0x45AB_6734_B21E_6968),
+            // A whole stripe, a 4-byte lane and single bytes.
+            (
+                b"Nobody inspects the spammish repetition",
+                0,
+                0xFBCE_A83C_8A37_8BF1,
+            ),
+        ] {
+            assert_eq!(xxh64(data, seed), expected, "{:?}, seed {}", data, seed);
+        }
+    }
+
+    #[test]
+    fn pieces_hash_like_the_whole() {
+        let data: Vec<u8> = (0..222u32).map(|i| (i * 7 + 3) as u8).collect();
+        for &piece in &[1, 5, 31, 32, 33, 100] {
+            let mut hasher = Xxh64::new(7);
+            for chunk in data.chunks(piece) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finish(), xxh64(&data, 7), "pieces of {}", piece);
+        }
+    }
+
+    /// Answers from the reference implementation (libxxhash 0.8.1's
+    /// XXH3_64bits) for inputs of every length class.
+    #[test]
+    fn xxh3_known_answers() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 + 3) as u8).collect();
+        for &(len, expected) in &[
+            (0, 0x2D06_8005_38D3_94C2),
+            (1, 0x13E6_08BC_156D_EFED),
+            (3, 0xA908_8DDA_485B_481C),
+            (4, 0x6D92_53B1_6C8B_1ED3),
+            (8, 0x6053_9DB6_3047_1163),
+            (9, 0xFEFF_6683_61D7_23A8),
+            (16, 0xB8C8_59B0_F030_B585),
+            (17, 0x714A_0440_8E79_B80F),
+            (32, 0x19FF_4EE1_D6BA_1A55),
+            (33, 0x3E44_983A_D216_79C8),
+            (64, 0x287E_B1FA_9E4B_E2C1),
+            (65, 0x8292_18DE_4D79_8646),
+            (96, 0xF084_E7CF_BC62_4743),
+            (97, 0x1DAA_8327_1A8E_7B7C),
+            (128, 0x6742_5A03_6502_61BF),
+            (129, 0xC664_BF33_11C6_ABC4),
+            (200, 0x746C_D002_5327_BF5B),
+            (240, 0x6455_6DC6_B462_A6CF),
+            (241, 0x8BEA_DD3A_8874_FE17),
+            (1024, 0x9B81_661C_641C_72B1),
+            (1025, 0x806C_2072_ED71_3576),
+            (2048, 0xABE6_0481_3BA6_2ED1),
+            (4096, 0xD742_8746_842B_E37E),
+            (100_000, 0x0C05_6F6F_CC34_0974),
+        ] {
+            assert_eq!(xxh3_64(&data[..len]), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn xxh3_pieces_hash_like_the_whole() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 13 + 1) as u8).collect();
+        for &len in &[100, 241, 256, 257, 1024, 1025, 5000] {
+            for &piece in &[1, 7, 63, 64, 65, 255, 256, 257, 1000] {
+                let mut hasher = Xxh3::new();
+                for chunk in data[..len].chunks(piece) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(
+                    hasher.finish(),
+                    xxh3_64(&data[..len]),
+                    "{} bytes in pieces of {}",
+                    len,
+                    piece
+                );
+            }
+        }
+    }
+}
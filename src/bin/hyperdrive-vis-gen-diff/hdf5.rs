@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Comparing the datasets of HDF5 files, e.g. hyperdrive outputs converted by
+//! downstream tools, or beam files. The hdf5 crate needs libhdf5 to build, so
+//! instead h5py dumps each dataset as little-endian f32s (complex values as
+//! interleaved real and imaginary parts), like python-casacore does for
+//! measurement sets in hyperdrive-ms-diff. The datasets of a pair of files
+//! must have the same shapes, and each is then compared like a pair of raw
+//! output files, named like "beam.h5:/coeffs".
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::{Dtype, Endian, Error};
+
+use crate::FilePair;
+
+/// The extensions of HDF5 files.
+pub(crate) const EXTENSIONS: &[&str] = &["h5", "hdf5"];
+
+/// Dumps an HDF5 file (the first argument) into a directory (the second): the
+/// datasets named in the remaining arguments, or every numeric dataset. Each
+/// is written to "INDEX.bin", and listed on stdout as a line of "NAME\tSHAPE",
+/// the shape comma-separated.
+const DUMP: &str = r#"
+import sys
+import numpy as np
+import h5py
+
+path, out, names = sys.argv[1], sys.argv[2], sys.argv[3:]
+f = h5py.File(path, "r")
+if not names:
+    found = []
+    f.visititems(lambda n, o: found.append("/" + n)
+                 if isinstance(o, h5py.Dataset) and o.dtype.kind in "biufc" else None)
+    names = found
+for i, name in enumerate(names):
+    if name not in f:
+        continue
+    a = np.asarray(f[name][()])
+    shape = list(a.shape)
+    if a.dtype.kind == "c":
+        a = np.stack([a.real, a.imag], axis=-1)
+        shape.append(2)
+    a.astype("<f4").tofile("%s/%d.bin" % (out, i))
+    print("%s\t%s" % (name, ",".join(str(s) for s in shape)))
+"#;
+
+/// Whether the file at `path` is an HDF5 file, by its extension.
+pub(crate) fn is_hdf5(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXTENSIONS.contains(&e))
+}
+
+/// The directory the datasets are dumped into, removed when dropped.
+pub(crate) struct Dumps {
+    dir: PathBuf,
+}
+
+impl Drop for Dumps {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A dataset dumped from an HDF5 file.
+struct Dataset {
+    name: String,
+    shape: Vec<usize>,
+    path: PathBuf,
+}
+
+/// Replace each pair of HDF5 files with a pair for each of their datasets
+/// named in `datasets` (or each of their numeric datasets), dumped with
+/// `python`. The dumps are removed when the returned [Dumps] is dropped.
+pub(crate) fn expand(
+    pairs: Vec<FilePair>,
+    datasets: &[String],
+    python: &str,
+    dtype: Dtype,
+    endian: Endian,
+) -> Result<(Vec<FilePair>, Dumps), anyhow::Error> {
+    if dtype != Dtype::F32 || endian == Endian::Big {
+        bail!("HDF5 datasets are compared as f32s; --dtype and --endian don't apply to them");
+    }
+    let dumps = Dumps {
+        dir: std::env::temp_dir().join(format!("hyperdrive-checks-hdf5.{}", std::process::id())),
+    };
+    let mut expanded = vec![];
+    for (i, pair) in pairs.into_iter().enumerate() {
+        let dump = |path: &Path, which: &str| {
+            let dir = dumps.dir.join(format!("{}.{}", i, which));
+            std::fs::create_dir_all(&dir)?;
+            read(path, &dir, datasets, python)
+        };
+        let current = dump(&pair.current, "current")?;
+        let baseline = dump(&pair.baseline, "baseline")?;
+        let names =
+            |sets: &[Dataset]| -> Vec<String> { sets.iter().map(|d| d.name.clone()).collect() };
+        let expected = if datasets.is_empty() {
+            let mut all = names(&current);
+            all.extend(names(&baseline));
+            all.sort();
+            all.dedup();
+            all
+        } else {
+            datasets.to_vec()
+        };
+        let mut missing = vec![];
+        for name in &expected {
+            for (sets, path) in [(&current, &pair.current), (&baseline, &pair.baseline)] {
+                if !sets.iter().any(|d| &d.name == name) {
+                    missing.push(format!("{} from {}", name, path.display()));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            bail!(Error::Missing(format!(
+                "Datasets are missing: {}",
+                missing.join(", ")
+            )));
+        }
+        if expected.is_empty() {
+            bail!(Error::Missing(format!(
+                "{} doesn't have any numeric datasets",
+                pair.current.display()
+            )));
+        }
+        for name in expected {
+            // Both are there, as checked above.
+            let c = current.iter().find(|d| d.name == name).unwrap();
+            let b = baseline.iter().find(|d| d.name == name).unwrap();
+            if c.shape != b.shape {
+                bail!(Error::Shape(format!(
+                    "Dataset {} of {} has shape {:?}, but that of {} has shape {:?}",
+                    name,
+                    pair.current.display(),
+                    c.shape,
+                    pair.baseline.display(),
+                    b.shape
+                )));
+            }
+            expanded.push(FilePair {
+                name: PathBuf::from(format!("{}:{}", pair.name.display(), name)),
+                current: c.path.clone(),
+                baseline: b.path.clone(),
+                band: None,
+            });
+        }
+    }
+    Ok((expanded, dumps))
+}
+
+/// Dump the datasets of the HDF5 file at `path` into the directory `dir`.
+fn read(
+    path: &Path,
+    dir: &Path,
+    datasets: &[String],
+    python: &str,
+) -> Result<Vec<Dataset>, anyhow::Error> {
+    if !path.is_file() {
+        bail!(Error::Missing(format!("{} doesn't exist", path.display())));
+    }
+    let output = Command::new(python)
+        .arg("-c")
+        .arg(DUMP)
+        .arg(path)
+        .arg(dir)
+        .args(datasets)
+        .output()
+        .map_err(|e| anyhow!("Couldn't run {}: {}", python, e))?;
+    if !output.status.success() {
+        bail!(
+            "Couldn't read {:?} with h5py ({}):\n{}",
+            path,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let mut sets = vec![];
+    for (i, line) in String::from_utf8_lossy(&output.stdout).lines().enumerate() {
+        let (name, shape) = line
+            .split_once('\t')
+            .ok_or_else(|| anyhow!("h5py's listing of {:?} is malformed: {}", path, line))?;
+        let shape = shape
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|e| anyhow!("h5py's listing of {:?} is malformed: {}", path, e))?;
+        // Datasets that weren't found aren't listed, so the index of each
+        // dump comes from its position among those asked for.
+        let index = if datasets.is_empty() {
+            i
+        } else {
+            datasets.iter().position(|d| d == name).unwrap_or(i)
+        };
+        sets.push(Dataset {
+            name: name.to_string(),
+            shape,
+            path: dir.join(format!("{}.bin", index)),
+        });
+    }
+    Ok(sets)
+}
@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Heatmaps of the largest absolute difference in each (baseline, fine
+//! channel) cell of a file (or band), over all of its timesteps, for quick
+//! triage. Baselines run down the image and fine channels across it. Cells
+//! with no difference are white, those within the tolerance blue (darker for
+//! bigger differences, on a log scale), those above it orange to red (up to
+//! 1000 times the tolerance), and those with a NaN or infinite difference
+//! magenta.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+
+use crate::png;
+
+/// The image is scaled up to be about this big, if it would be smaller.
+const TARGET_SIZE: usize = 512;
+
+/// The largest absolute differences of each cell of a file (or band).
+pub(crate) struct Heatmap {
+    baselines: u64,
+    fine_channels: u64,
+    floats_per_channel: u64,
+    cells: Vec<f32>,
+}
+
+impl Heatmap {
+    pub(crate) fn new(baselines: u64, fine_channels: u64, floats_per_channel: u64) -> Heatmap {
+        Heatmap {
+            baselines,
+            fine_channels,
+            floats_per_channel,
+            cells: vec![0.0; (baselines * fine_channels) as usize],
+        }
+    }
+
+    /// Add the differences of the floats of the pair from `offset`.
+    pub(crate) fn add(&mut self, offset: usize, current: &[f32], baseline: &[f32]) {
+        let per_row = self.fine_channels * self.floats_per_channel;
+        for (i, (c, b)) in current.iter().zip(baseline).enumerate() {
+            let index = (offset + i) as u64;
+            let row = index / per_row % self.baselines;
+            let channel = index / self.floats_per_channel % self.fine_channels;
+            let d = (c - b).abs();
+            let d = if d.is_nan() && c.to_bits() != b.to_bits() {
+                f32::INFINITY
+            } else {
+                d
+            };
+            let cell = &mut self.cells[(row * self.fine_channels + channel) as usize];
+            if d > *cell || d.is_infinite() {
+                *cell = d;
+            }
+        }
+    }
+
+    /// Render the heatmap to the PNG file `path`, highlighting the cells with
+    /// differences above `tolerance`.
+    pub(crate) fn write(&self, path: &Path, tolerance: f32) -> Result<(), anyhow::Error> {
+        let (rows, columns) = (self.baselines as usize, self.fine_channels as usize);
+        let scale = |n: usize| (TARGET_SIZE / n.max(1)).clamp(1, 16);
+        let (width, height) = (columns * scale(columns), rows * scale(rows));
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let row = y * rows / height;
+            for x in 0..width {
+                let column = x * columns / width;
+                pixels.extend(colour(self.cells[row * columns + column], tolerance));
+            }
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| anyhow!("Couldn't create {:?}: {}", dir, e))?;
+        }
+        png::write_rgb(path, width, height, &pixels)
+    }
+}
+
+fn colour(d: f32, tolerance: f32) -> [u8; 3] {
+    let mix = |from: [f32; 3], to: [f32; 3], t: f32| {
+        let t = t.clamp(0.0, 1.0);
+        [0, 1, 2].map(|i| (from[i] + (to[i] - from[i]) * t) as u8)
+    };
+    if d == 0.0 {
+        [255, 255, 255]
+    } else if !d.is_finite() {
+        [255, 0, 255]
+    } else if d <= tolerance {
+        // From a millionth of the tolerance, up to it.
+        let t = (d / tolerance).log10() / 6.0 + 1.0;
+        mix([200.0, 220.0, 255.0], [0.0, 0.0, 140.0], t)
+    } else {
+        let t = (d / tolerance).log10() / 3.0;
+        mix([255.0, 170.0, 0.0], [200.0, 0.0, 0.0], t)
+    }
+}
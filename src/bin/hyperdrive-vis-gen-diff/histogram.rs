@@ -0,0 +1,68 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The distribution of the absolute differences of a file, for its median and
+//! percentiles, without keeping every difference.
+//!
+//! The bits of non-negative floats are ordered like the floats, so each bin
+//! holds the floats sharing their top 16 bits: the exponent and the top 7 bits
+//! of the mantissa. Percentiles are therefore accurate to better than 1%,
+//! whatever the magnitude of the differences.
+
+/// The number of bins: one for each top 16 bits of a float without a sign.
+const BINS: usize = 1 << 15;
+
+/// Counts of absolute differences.
+#[derive(Default)]
+pub(crate) struct Histogram {
+    /// Empty until the first difference is added.
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    /// Count an absolute difference (which mustn't be NaN).
+    pub(crate) fn add(&mut self, diff: f32) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; BINS];
+        }
+        self.counts[(diff.abs().to_bits() >> 16) as usize] += 1;
+        self.total += 1;
+    }
+
+    pub(crate) fn merge(mut self, other: Histogram) -> Histogram {
+        if self.counts.is_empty() {
+            return other;
+        }
+        for (c, o) in self.counts.iter_mut().zip(other.counts) {
+            *c += o;
+        }
+        self.total += other.total;
+        self
+    }
+
+    /// The difference that a fraction `q` (e.g. 0.5 for the median) of the
+    /// differences are no bigger than, rounded up to the top of its bin (but
+    /// no bigger than `max`, the largest difference). 0 if there are no
+    /// differences.
+    pub(crate) fn percentile(&self, q: f64, max: f32) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        let rank = ((q * self.total as f64).ceil() as u64).clamp(1, self.total);
+        let mut seen = 0;
+        for (bin, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                // The bottom bin starts at zero, which is worth reporting
+                // exactly.
+                if bin == 0 {
+                    return 0.0;
+                }
+                return f32::from_bits(((bin as u32) + 1) << 16).min(max);
+            }
+        }
+        max
+    }
+}
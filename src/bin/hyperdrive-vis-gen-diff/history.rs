@@ -0,0 +1,297 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A long-lived CSV file with one summary row per run, so that results can be
+//! tracked over time without any infrastructure.
+
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail};
+
+const HEADER: &str = "timestamp,hyperdrive_version,worst_file,max_diff,verdict,obsid";
+/// The header of history files written before the obsid was recorded. Their
+/// rows have no obsid field.
+const OLD_HEADER: &str = "timestamp,hyperdrive_version,worst_file,max_diff,verdict";
+
+/// A summary of a single run.
+#[derive(Clone)]
+pub(crate) struct HistoryRow {
+    /// UTC, formatted like "2020-06-09T03:14:15Z".
+    pub(crate) timestamp: String,
+    pub(crate) hyperdrive_version: String,
+    /// The file with the largest difference.
+    pub(crate) worst_file: String,
+    pub(crate) max_diff: f32,
+    pub(crate) passed: bool,
+    /// The observation checked, if known.
+    pub(crate) obsid: Option<u64>,
+}
+
+/// Append `row` to the CSV file at `path`, creating it (with a header) if it
+/// doesn't exist.
+pub(crate) fn append(path: &Path, row: &HistoryRow) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", HEADER)?;
+    }
+    writeln!(
+        file,
+        "{},{},{},{:e},{},{}",
+        row.timestamp,
+        csv_field(&row.hyperdrive_version),
+        csv_field(&row.worst_file),
+        row.max_diff,
+        if row.passed { "pass" } else { "fail" },
+        row.obsid.map(|o| o.to_string()).unwrap_or_default()
+    )
+}
+
+/// The header of the per-file statistics CSV files written with --csv.
+const FILES_HEADER: &str = "timestamp,hyperdrive_version,obsid,file,max_diff,rms,verdict";
+
+/// The statistics of one file (or band) of a run.
+pub(crate) struct FileRow<'a> {
+    pub(crate) file: &'a str,
+    pub(crate) max_diff: f32,
+    pub(crate) rms: f32,
+    /// e.g. "pass", "fail" or "xfail".
+    pub(crate) verdict: String,
+}
+
+/// Append a row for each of `files` to the CSV file at `path`, all with the
+/// same timestamp, hyperdrive version and obsid, creating it (with a header)
+/// if it doesn't exist.
+pub(crate) fn append_files(
+    path: &Path,
+    timestamp: &str,
+    hyperdrive_version: &str,
+    obsid: Option<u64>,
+    files: &[FileRow],
+) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Couldn't open {:?}: {}", path, e))?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", FILES_HEADER)?;
+    } else {
+        let mut header = String::new();
+        BufReader::new(&file).read_line(&mut header)?;
+        if header.trim_end() != FILES_HEADER {
+            bail!(
+                "{:?} isn't a --csv file; its header is \"{}\", not \"{}\"",
+                path,
+                header.trim_end(),
+                FILES_HEADER
+            );
+        }
+    }
+    let mut rows = String::new();
+    for f in files {
+        rows.push_str(&format!(
+            "{},{},{},{},{:e},{:e},{}\n",
+            timestamp,
+            csv_field(hyperdrive_version),
+            obsid.map(|o| o.to_string()).unwrap_or_default(),
+            csv_field(f.file),
+            f.max_diff,
+            f.rms,
+            f.verdict
+        ));
+    }
+    // All at once, so that concurrent runs don't interleave their rows.
+    file.write_all(rows.as_bytes())?;
+    Ok(())
+}
+
+/// A row of a CSV file written with --csv.
+pub(crate) struct FileStats {
+    pub(crate) obsid: Option<u64>,
+    pub(crate) file: String,
+    pub(crate) max_diff: f32,
+    pub(crate) verdict: String,
+}
+
+/// Is the file at `path` a CSV file written with --csv, rather than with
+/// --history?
+pub(crate) fn is_files_csv(path: &Path) -> Result<bool, anyhow::Error> {
+    let mut header = String::new();
+    BufReader::new(File::open(path).map_err(|e| anyhow!("Couldn't open {:?}: {}", path, e))?)
+        .read_line(&mut header)?;
+    Ok(header.trim_end() == FILES_HEADER)
+}
+
+/// Read each row of a CSV file written with --csv.
+pub(crate) fn read_files(path: &Path) -> Result<Vec<FileStats>, anyhow::Error> {
+    let file =
+        BufReader::new(File::open(path).map_err(|e| anyhow!("Couldn't open {:?}: {}", path, e))?);
+    let mut rows = vec![];
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if i == 0 && line == FILES_HEADER || line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = split_csv_line(&line);
+        if fields.len() != 7 {
+            bail!(
+                "{}:{}: expected 7 fields, but found {}",
+                path.display(),
+                i + 1,
+                fields.len()
+            );
+        }
+        let max_diff = fields[4]
+            .parse()
+            .map_err(|e| anyhow!("{}:{}: bad max_diff: {}", path.display(), i + 1, e))?;
+        let obsid = match fields[2].as_str() {
+            "" => None,
+            o => Some(
+                o.parse()
+                    .map_err(|e| anyhow!("{}:{}: bad obsid: {}", path.display(), i + 1, e))?,
+            ),
+        };
+        rows.push(FileStats {
+            obsid,
+            file: std::mem::take(&mut fields[3]),
+            max_diff,
+            verdict: std::mem::take(&mut fields[6]),
+        });
+    }
+    Ok(rows)
+}
+
+/// Read all of the rows from the CSV file at `path`.
+pub(crate) fn read(path: &Path) -> Result<Vec<HistoryRow>, anyhow::Error> {
+    let file = BufReader::new(File::open(path)?);
+    let mut rows = vec![];
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if i == 0 && (line == HEADER || line == OLD_HEADER) || line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_csv_line(&line);
+        if fields.len() != 5 && fields.len() != 6 {
+            bail!(
+                "{}:{}: expected 5 or 6 fields, but found {}",
+                path.display(),
+                i + 1,
+                fields.len()
+            );
+        }
+        rows.push(HistoryRow {
+            timestamp: fields[0].clone(),
+            hyperdrive_version: fields[1].clone(),
+            worst_file: fields[2].clone(),
+            max_diff: fields[3]
+                .parse()
+                .map_err(|e| anyhow!("{}:{}: bad max_diff: {}", path.display(), i + 1, e))?,
+            passed: match fields[4].as_str() {
+                "pass" => true,
+                "fail" => false,
+                v => bail!("{}:{}: bad verdict \"{}\"", path.display(), i + 1, v),
+            },
+            obsid: match fields.get(5).map(|f| f.as_str()) {
+                None | Some("") => None,
+                Some(o) => Some(
+                    o.parse()
+                        .map_err(|e| anyhow!("{}:{}: bad obsid: {}", path.display(), i + 1, e))?,
+                ),
+            },
+        });
+    }
+    Ok(rows)
+}
+
+/// Split a line of CSV into its (unquoted) fields.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Quote a CSV field if it contains anything that would otherwise break the
+/// row.
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Parse a timestamp written by [utc_timestamp] back into seconds since the
+/// Unix epoch.
+pub(crate) fn parse_utc_timestamp(s: &str) -> Option<i64> {
+    let s = s.strip_suffix('Z')?;
+    let (date, time) = s.split_at(s.find('T')?);
+    let mut date = date.split('-').map(|x| x.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time[1..].split(':').map(|x| x.parse::<i64>());
+    let (h, m, sec) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // The inverse of the algorithm in civil_from_days.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86400 + h * 3600 + m * 60 + sec)
+}
+
+/// Convert days since the Unix epoch into a (year, month, day) civil date
+/// (Howard Hinnant's algorithm).
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format a time as an ISO 8601 UTC timestamp, e.g. "2020-06-09T03:14:15Z".
+pub(crate) fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
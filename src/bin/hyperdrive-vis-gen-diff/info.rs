@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Describe what this build (and the programs and plugins on the PATH) can
+//! do, as JSON, so that scripts can check for a capability before relying on
+//! it.
+
+use std::process::{Command, Stdio};
+
+use pawsey_hyperdrive_checks::{Dtype, Endian};
+
+use crate::compress::Codec;
+use crate::json::json_string;
+use crate::presets::PRESETS;
+use crate::{plugin, remote, DEFAULT_OUTPUTS};
+
+/// Render the description of this build. HDF5 files are only listed as
+/// readable if `python` can import h5py.
+pub(crate) fn info(python: &str) -> String {
+    let list = |names: &[String]| names.join(", ");
+    let available = |pairs: &[(&str, bool)]| -> String {
+        pairs
+            .iter()
+            .map(|(name, ok)| format!("{}: {}", json_string(name), ok))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let formats: Vec<String> = formats(has_h5py(python))
+        .iter()
+        .map(|f| json_string(f))
+        .collect();
+    // Compressed baselines are decompressed by piping them through the
+    // codec's program, and remote baselines are downloaded by curl or aws.
+    let compressors: Vec<(&str, bool)> = Codec::NAMES
+        .iter()
+        .map(|name| {
+            let codec: Codec = name.parse().unwrap();
+            (*name, plugin::on_path(codec.program()))
+        })
+        .collect();
+    let schemes: Vec<(&str, bool)> = remote::SCHEMES
+        .iter()
+        .map(|(scheme, program)| (*scheme, plugin::on_path(program)))
+        .collect();
+    let presets: Vec<String> = PRESETS.iter().map(|p| json_string(p.name)).collect();
+    let plugins: Vec<String> = plugin::discover().iter().map(|p| json_string(p)).collect();
+    // The outputs as they would be given to --outputs.
+    let outputs = DEFAULT_OUTPUTS.join(",");
+    let defaults = [
+        ("baseline_dir", "./baseline"),
+        ("outputs", &outputs),
+        ("tolerance", "0.001"),
+        ("dashboard_output", "index.html"),
+    ];
+    let defaults: Vec<String> = defaults
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json_string(k), json_string(v)))
+        .collect();
+    format!(
+        "{{\"name\": {}, \"version\": {}, \"formats\": [{}], \"compressors\": {{{}}}, \"remote_schemes\": {{{}}}, \"presets\": [{}], \"plugins\": [{}], \"defaults\": {{{}}}}}",
+        json_string(env!("CARGO_BIN_NAME")),
+        json_string(env!("CARGO_PKG_VERSION")),
+        list(&formats),
+        available(&compressors),
+        available(&schemes),
+        list(&presets),
+        list(&plugins),
+        list(&defaults)
+    )
+}
+
+/// The formats that can be read: raw outputs of every --dtype and --endian,
+/// e.g. "raw-f32-little", the formats with built-in readers, and HDF5 if h5py
+/// is available.
+fn formats(h5py: bool) -> Vec<String> {
+    let mut formats: Vec<String> = Dtype::NAMES
+        .iter()
+        .flat_map(|dtype| {
+            Endian::NAMES
+                .iter()
+                .map(move |endian| format!("raw-{}-{}", dtype, endian))
+        })
+        .collect();
+    // For --mwaf-dir and --apply-solutions.
+    formats.push("mwaf".to_string());
+    formats.push("mwaocal-solutions".to_string());
+    if h5py {
+        formats.push("hdf5".to_string());
+    }
+    formats
+}
+
+fn has_h5py(python: &str) -> bool {
+    Command::new(python)
+        .args(["-c", "import h5py"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn only_real_capabilities_are_described() {
+        let readable = formats(false);
+        assert_eq!(readable.len(), Dtype::NAMES.len() * Endian::NAMES.len() + 2);
+        assert!(readable.contains(&"raw-f32-little".to_string()));
+        assert!(readable.contains(&"raw-i16-big".to_string()));
+        assert!(!readable.contains(&"hdf5".to_string()));
+        assert!(formats(true).contains(&"hdf5".to_string()));
+
+        let info = json::parse(&info("/nonexistent/python")).unwrap();
+        assert!(info.get("features").is_none());
+        for key in &["compressors", "remote_schemes"] {
+            match info.get(key) {
+                Some(json::Value::Object(fields)) => assert!(!fields.is_empty(), "{}", key),
+                v => panic!("{}: {:?}", key, v),
+            }
+        }
+    }
+}
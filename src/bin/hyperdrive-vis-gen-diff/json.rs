@@ -0,0 +1,592 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A machine-readable report of a run, for dashboards to ingest, and reading
+//! reports back, whichever schema version they were written with.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::Dtype;
+
+use crate::presets::Preset;
+use crate::report::Provenance;
+use crate::{Differences, FilePair, Timings};
+
+/// The version of the report's layout. Bumped whenever a field is removed,
+/// renamed or changes meaning, so that dashboards can tell which they have;
+/// adding fields doesn't change it.
+const SCHEMA_VERSION: u32 = 3;
+
+/// Everything in the report.
+pub(crate) struct Run<'a> {
+    pub(crate) provenance: &'a Provenance,
+    /// The type of the values in the files.
+    pub(crate) dtype: Dtype,
+    /// The options used, as a JSON object.
+    pub(crate) options: String,
+    pub(crate) passed: bool,
+    pub(crate) worst: &'a str,
+    pub(crate) overall: &'a Differences,
+    pub(crate) pairs: &'a [FilePair],
+    pub(crate) diffs: &'a [Differences],
+    /// The result of each file, e.g. "pass" or "XFAIL".
+    pub(crate) statuses: &'a [&'a str],
+    /// How many transient IO errors were retried (see --io-retries).
+    pub(crate) io_retries: u32,
+    /// The --preset, if any.
+    pub(crate) preset: Option<&'a Preset>,
+    /// How long writing the other reports (history, badge, text report,
+    /// JUnit, email) took.
+    pub(crate) reports: Duration,
+}
+
+/// Render a string as a JSON string, quoted and escaped.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Render a float, or null if JSON can't represent it.
+fn number(f: f64) -> String {
+    if f.is_finite() {
+        format!("{:e}", f)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Render a string, or null if there isn't one.
+fn optional(s: &Option<String>) -> String {
+    s.as_deref().map_or("null".to_string(), json_string)
+}
+
+fn object(fields: &[(&str, String)]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json_string(k), v))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// The time spent on a file, or on the whole run with the time spent writing
+/// `reports`.
+fn timings(t: &Timings, reports: Option<Duration>) -> String {
+    let secs = |d: Duration| number(d.as_secs_f64());
+    let mut fields = vec![
+        ("io", secs(t.io)),
+        ("decode", secs(t.decode)),
+        ("metrics", secs(t.metrics)),
+    ];
+    if let Some(reports) = reports {
+        fields.push(("reports", secs(reports)));
+    }
+    object(&fields)
+}
+
+/// The statistics of a file, or of every file together if `overall`. Only a
+/// file's percentiles are known, so every file's are the largest of them.
+fn statistics(d: &Differences, overall: bool) -> Vec<(&'static str, String)> {
+    let percentiles = if overall {
+        ["max_file_median", "max_file_p95", "max_file_p99"]
+    } else {
+        ["median", "p95", "p99"]
+    };
+    vec![
+        ("max_diff", number(d.max_abs as f64)),
+        ("max_rel_diff", number(d.max_rel as f64)),
+        ("mean_diff", number(d.mean_abs())),
+        ("rms", number(d.rms())),
+        ("mean_signed_diff", number(d.mean_signed())),
+        ("std_dev", number(d.std_dev())),
+        (percentiles[0], number(d.median as f64)),
+        (percentiles[1], number(d.p95 as f64)),
+        (percentiles[2], number(d.p99 as f64)),
+        ("frac_exceeding", number(d.frac_exceeding())),
+        ("above_tolerance", d.above_tolerance.to_string()),
+        ("samples", d.samples.to_string()),
+        ("current_nans", d.current_nans.to_string()),
+        ("baseline_nans", d.baseline_nans.to_string()),
+        ("current_infs", d.current_infs.to_string()),
+        ("baseline_infs", d.baseline_infs.to_string()),
+        ("nan_failures", d.nan_failures.to_string()),
+        ("skipped", d.skipped.to_string()),
+        ("timings", timings(&d.timings, None)),
+    ]
+}
+
+pub(crate) fn render(run: &Run) -> Result<String, anyhow::Error> {
+    let mut files = vec![];
+    for ((pair, d), status) in run.pairs.iter().zip(run.diffs).zip(run.statuses) {
+        let bytes = |path| -> Result<String, anyhow::Error> {
+            Ok(pair.bytes(path, run.dtype)?.to_string())
+        };
+        let hash = |h: Option<u64>| h.map_or("null".to_string(), |h| format!("\"{:016x}\"", h));
+        let mut fields = vec![
+            ("name", json_string(&pair.name.display().to_string())),
+            ("status", json_string(status)),
+            ("current", json_string(&pair.current.display().to_string())),
+            (
+                "baseline",
+                json_string(&pair.baseline.display().to_string()),
+            ),
+            ("current_bytes", bytes(&pair.current)?),
+            ("baseline_bytes", bytes(&pair.baseline)?),
+            ("current_xxh3", hash(d.hashes.map(|h| h.0))),
+            ("baseline_xxh3", hash(d.hashes.map(|h| h.1))),
+        ];
+        fields.append(&mut statistics(d, false));
+        files.push(object(&fields));
+    }
+
+    let p = run.provenance;
+    let mut fields = vec![
+        ("schema_version", SCHEMA_VERSION.to_string()),
+        (
+            "checker",
+            object(&[
+                ("name", json_string(env!("CARGO_BIN_NAME"))),
+                ("version", json_string(env!("CARGO_PKG_VERSION"))),
+            ]),
+        ),
+        ("hyperdrive_version", json_string(&p.hyperdrive_version)),
+        ("host", json_string(&p.host)),
+        ("time", json_string(&p.timestamp)),
+        (
+            "obsid",
+            p.obsid.map_or("null".to_string(), |o| o.to_string()),
+        ),
+        (
+            "baseline_manifest_xxh64",
+            format!("\"{:016x}\"", p.baseline_manifest),
+        ),
+        ("config_xxh64", format!("\"{:016x}\"", p.config)),
+        (
+            "baseline_provenance",
+            p.baseline.as_ref().map_or("null".to_string(), |m| {
+                let fields: Vec<(&str, String)> = m
+                    .fields()
+                    .into_iter()
+                    .map(|(k, v)| (k, json_string(&v)))
+                    .collect();
+                object(&fields)
+            }),
+        ),
+        (
+            "environment",
+            object(&[
+                (
+                    "modules",
+                    format!(
+                        "[{}]",
+                        p.environment
+                            .modules
+                            .iter()
+                            .map(|m| json_string(m))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ),
+                ("cuda_driver", optional(&p.environment.cuda_driver)),
+                ("cuda_runtime", optional(&p.environment.cuda_runtime)),
+                ("git_describe", optional(&p.environment.git_describe)),
+            ]),
+        ),
+        ("options", run.options.clone()),
+        ("passed", run.passed.to_string()),
+        ("worst", json_string(run.worst)),
+        ("io_retries", run.io_retries.to_string()),
+        (
+            "preset",
+            run.preset.map_or("null".to_string(), |p| {
+                object(&[
+                    ("name", json_string(p.name)),
+                    ("rationale", json_string(p.rationale)),
+                ])
+            }),
+        ),
+    ];
+    fields.append(&mut statistics(run.overall, true));
+    for (_, t) in fields.iter_mut().filter(|(k, _)| *k == "timings") {
+        *t = timings(&run.overall.timings, Some(run.reports));
+    }
+    fields.push(("files", format!("[{}]", files.join(", "))));
+    Ok(format!("{}\n", object(&fields)))
+}
+
+/// A value in a report being read back. Numbers keep their text, so that
+/// rewriting a report doesn't round them.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        match self {
+            Value::Object(fields) => fields.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Rename the field `from` of an object, if it has one, to `to`.
+    fn rename(&mut self, from: &str, to: &str) {
+        if let Value::Object(fields) = self {
+            for (k, _) in fields.iter_mut().filter(|(k, _)| k == from) {
+                *k = to.to_string();
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Value) {
+        if let Value::Object(fields) = self {
+            match fields.iter_mut().find(|(k, _)| k == key) {
+                Some((_, v)) => *v = value,
+                None => fields.push((key.to_string(), value)),
+            }
+        }
+    }
+
+    /// Render the value as the report is written.
+    pub(crate) fn render(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Number(n) => n.clone(),
+            Value::String(s) => json_string(s),
+            Value::Array(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(Value::render)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Object(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", json_string(k), v.render()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// Upgrade a report from the schema version before each to the next, i.e.
+/// `UPGRADES[0]` upgrades version 1 to 2.
+const UPGRADES: &[fn(&mut Value)] = &[
+    // The overall percentiles are the largest of each file's.
+    |report| {
+        report.rename("median", "max_file_median");
+        report.rename("p95", "max_file_p95");
+        report.rename("p99", "max_file_p99");
+    },
+    // Files are identified by XXH3 rather than XXH64. The XXH64s can't be
+    // converted, so they're kept alongside.
+    |report| {
+        if let Some(Value::Array(files)) = report.get_mut("files") {
+            for file in files {
+                file.set("current_xxh3", Value::Null);
+                file.set("baseline_xxh3", Value::Null);
+            }
+        }
+    },
+];
+
+/// Upgrade a report of any schema version to the current one, so that
+/// reports written by older checkers can be read like new ones.
+pub(crate) fn upgrade(mut report: Value) -> Result<Value, anyhow::Error> {
+    let version = match report.get("schema_version") {
+        Some(Value::Number(n)) => n
+            .parse::<u32>()
+            .map_err(|_| anyhow!("the schema_version {} isn't a version", n))?,
+        _ => bail!("it has no schema_version, so it isn't a JSON report"),
+    };
+    if version == 0 || version > SCHEMA_VERSION {
+        bail!(
+            "its schema_version is {}, but this checker only reads versions 1 to {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    for upgrade in &UPGRADES[version as usize - 1..] {
+        upgrade(&mut report);
+    }
+    report.set("schema_version", Value::Number(SCHEMA_VERSION.to_string()));
+    Ok(report)
+}
+
+/// Read the JSON report at `path`, upgraded to the current schema version.
+pub(crate) fn read(path: &Path) -> Result<Value, anyhow::Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Couldn't read the report {:?}: {}", path, e))?;
+    parse(&text)
+        .and_then(upgrade)
+        .map_err(|e| anyhow!("Report {:?}: {}", path, e))
+}
+
+/// Parse JSON text.
+pub(crate) fn parse(text: &str) -> Result<Value, anyhow::Error> {
+    let mut parser = Parser { text, position: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position < text.len() {
+        bail!("unexpected text at byte {}", parser.position);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    /// The byte offset of the next character.
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.text[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), anyhow::Error> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => bail!(
+                "expected '{}', not '{}' at byte {}",
+                expected,
+                c,
+                self.position - c.len_utf8()
+            ),
+            None => bail!("expected '{}', not the end", expected),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, anyhow::Error> {
+        self.skip_whitespace();
+        let start = self.position;
+        match self.peek() {
+            Some('{') => {
+                self.next();
+                let mut fields = vec![];
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.next();
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => (),
+                        Some('}') => return Ok(Value::Object(fields)),
+                        _ => bail!("expected ',' or '}}' after byte {}", start),
+                    }
+                }
+            }
+            Some('[') => {
+                self.next();
+                let mut values = vec![];
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.next();
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => (),
+                        Some(']') => return Ok(Value::Array(values)),
+                        _ => bail!("expected ',' or ']' after byte {}", start),
+                    }
+                }
+            }
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                while self
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+                {
+                    self.position += 1;
+                }
+                let number = &self.text[start..self.position];
+                match number.parse::<f64>() {
+                    Ok(_) => Ok(Value::Number(number.to_string())),
+                    Err(_) => bail!("bad number {} at byte {}", number, start),
+                }
+            }
+            _ => {
+                for (word, value) in &[
+                    ("null", Value::Null),
+                    ("true", Value::Bool(true)),
+                    ("false", Value::Bool(false)),
+                ] {
+                    if self.text[start..].starts_with(word) {
+                        self.position += word.len();
+                        return Ok(value.clone());
+                    }
+                }
+                bail!("expected a value at byte {}", start)
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, anyhow::Error> {
+        let start = self.position;
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let mut code = self.hex4()?;
+                        // A character outside the basic plane is a pair of
+                        // surrogates.
+                        if (0xD800..0xDC00).contains(&code)
+                            && self.text[self.position..].starts_with("\\u")
+                        {
+                            self.position += 2;
+                            let low = self.hex4()?;
+                            code = 0x10000
+                                + ((code - 0xD800) << 10)
+                                + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => bail!("bad escape in the string at byte {}", start),
+                },
+                Some(c) => out.push(c),
+                None => bail!("unterminated string at byte {}", start),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, anyhow::Error> {
+        let hex = self
+            .text
+            .get(self.position..self.position + 4)
+            .unwrap_or("");
+        let code = u32::from_str_radix(hex, 16)
+            .map_err(|_| anyhow!("bad \\u escape at byte {}", self.position))?;
+        self.position += 4;
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_read_back_as_written() {
+        let text = r#"{"a": [1e-3, -2, 0.5e10], "b": {"c": null, "d": true, "e": false}, "f": "tab\t\"quote\" é 😀", "g": [], "h": {}}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(
+            value.get("f"),
+            Some(&Value::String("tab\t\"quote\" é 😀".to_string()))
+        );
+        assert_eq!(parse(&value.render()).unwrap(), value);
+        for bad in &["", "{", "[1,]", "{\"a\" 1}", "\"abc", "01x", "nul", "{} {}"] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn only_the_run_has_report_timings() {
+        let t = Timings {
+            io: Duration::from_millis(250),
+            ..Timings::default()
+        };
+        let file = parse(&timings(&t, None)).unwrap();
+        assert_eq!(file.get("io"), Some(&Value::Number("2.5e-1".to_string())));
+        assert_eq!(file.get("reports"), None);
+        let run = parse(&timings(&t, Some(Duration::from_millis(1500)))).unwrap();
+        assert_eq!(
+            run.get("reports"),
+            Some(&Value::Number("1.5e0".to_string()))
+        );
+    }
+
+    #[test]
+    fn old_reports_are_upgraded() {
+        let v1 = r#"{"schema_version": 1, "passed": true, "median": 1e-4, "p95": 2e-4, "p99": 3e-4, "files": [{"name": "a.bin", "current_xxh64": "0123456789abcdef", "median": 1e-4, "p95": 2e-4, "p99": 3e-4}]}"#;
+        let report = upgrade(parse(v1).unwrap()).unwrap();
+        assert_eq!(
+            report.get("schema_version"),
+            Some(&Value::Number(SCHEMA_VERSION.to_string()))
+        );
+        assert_eq!(report.get("median"), None);
+        assert_eq!(
+            report.get("max_file_p95"),
+            Some(&Value::Number("2e-4".to_string()))
+        );
+        // Each file's percentiles are its own.
+        match report.get("files") {
+            Some(Value::Array(files)) => {
+                assert!(files[0].get("p95").is_some());
+                assert_eq!(files[0].get("current_xxh3"), Some(&Value::Null));
+                assert!(files[0].get("current_xxh64").is_some());
+            }
+            f => panic!("{:?}", f),
+        }
+
+        // The current version is left alone, and unknown versions refused.
+        let current = upgrade(report.clone()).unwrap();
+        assert_eq!(current, report);
+        for v in &["0", "4", "\"1\""] {
+            let text = format!("{{\"schema_version\": {}}}", v);
+            assert!(upgrade(parse(&text).unwrap()).is_err(), "{}", v);
+        }
+        assert!(upgrade(parse("{}").unwrap()).is_err());
+    }
+}
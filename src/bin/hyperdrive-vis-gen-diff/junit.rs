@@ -0,0 +1,124 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! JUnit XML reports, which CI systems (e.g. GitLab) render natively, with one
+//! test case per file (or band).
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+/// The result of one file (or band).
+pub(crate) struct Case {
+    pub(crate) name: String,
+    /// As in the summary table, e.g. "pass", "FAIL" or "XFAIL".
+    pub(crate) status: &'static str,
+    /// What was measured and what it was compared against, e.g. "max diff
+    /// 2e-3, tolerance 1e-3".
+    pub(crate) message: String,
+    pub(crate) time: Duration,
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Not allowed in XML 1.0 at all.
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => (),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Write a test suite named `suite` of `cases`, with `properties` (e.g. the
+/// tolerance and obsid), to `path`. Unexpected passes are failures if
+/// `strict_xfail`.
+pub(crate) fn write(
+    path: &Path,
+    suite: &str,
+    timestamp: &str,
+    properties: &[(&str, String)],
+    cases: &[Case],
+    strict_xfail: bool,
+) -> Result<(), anyhow::Error> {
+    let count = |statuses: &[&str]| {
+        cases
+            .iter()
+            .filter(|c| statuses.contains(&c.status))
+            .count()
+    };
+    let time: Duration = cases.iter().map(|c| c.time).sum();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <testsuites>\n\
+         <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">\n",
+        escape(suite),
+        cases.len(),
+        count(if strict_xfail {
+            &["FAIL", "XPASS"]
+        } else {
+            &["FAIL"]
+        }),
+        count(&["CORRUPT"]),
+        count(&["XFAIL", "QUAR"]),
+        time.as_secs_f64(),
+        escape(timestamp)
+    );
+    xml.push_str("<properties>\n");
+    for (name, value) in properties {
+        xml.push_str(&format!(
+            "<property name=\"{}\" value=\"{}\"/>\n",
+            escape(name),
+            escape(value)
+        ));
+    }
+    xml.push_str("</properties>\n");
+    for case in cases {
+        xml.push_str(&format!(
+            "<testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n",
+            escape(suite),
+            escape(&case.name),
+            case.time.as_secs_f64()
+        ));
+        let message = escape(&case.message);
+        match case.status {
+            "FAIL" => xml.push_str(&format!(
+                "<failure message=\"{}\" type=\"difference\">{}</failure>\n",
+                message, message
+            )),
+            "CORRUPT" => xml.push_str(&format!(
+                "<error message=\"corrupt file; {}\" type=\"corrupt\"/>\n",
+                message
+            )),
+            "XFAIL" => xml.push_str(&format!(
+                "<skipped message=\"known failure; {}\"/>\n",
+                message
+            )),
+            "XPASS" if strict_xfail => xml.push_str(&format!(
+                "<failure message=\"unexpected pass of a known failure; {}\" type=\"xpass\"/>\n",
+                message
+            )),
+            "QUAR" => xml.push_str(&format!(
+                "<skipped message=\"quarantined; {}\"/>\n",
+                message
+            )),
+            // Passes, including unexpected ones.
+            status => xml.push_str(&format!(
+                "<system-out>{}: {}</system-out>\n",
+                escape(status),
+                message
+            )),
+        }
+        xml.push_str("</testcase>\n");
+    }
+    xml.push_str("</testsuite>\n</testsuites>\n");
+    std::fs::write(path, xml).map_err(|e| anyhow!("Couldn't write {:?}: {}", path, e))
+}
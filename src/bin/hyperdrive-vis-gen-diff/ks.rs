@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A two-sample Kolmogorov–Smirnov test of whether the current and baseline
+//! values of a file (or band) are drawn from the same distribution, to tell
+//! numerical noise (which barely moves the distribution) apart from a subtle
+//! systematic change (which shifts or stretches it), however small either's
+//! largest difference.
+//!
+//! Like [crate::histogram], the values are counted in bins of the top 16 bits
+//! of the floats, ordered by value, so the distributions are resolved to
+//! better than 1% whatever their magnitude, without keeping every value.
+
+/// The number of bins: one for each top 16 bits of a float.
+const BINS: usize = 1 << 16;
+
+/// Counts of the current and baseline values.
+#[derive(Default)]
+pub(crate) struct Distributions {
+    /// Empty until the first values are added.
+    current: Vec<u64>,
+    baseline: Vec<u64>,
+    total: u64,
+}
+
+/// The bin of a float (which mustn't be NaN), such that bins are in the order
+/// of the floats in them.
+fn bin(f: f32) -> usize {
+    // -0 is 0.
+    let bits = if f == 0.0 { 0 } else { f.to_bits() };
+    let ordered = if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    };
+    (ordered >> 16) as usize
+}
+
+impl Distributions {
+    /// Count a pair of current and baseline values (neither of which may be
+    /// NaN).
+    pub(crate) fn add(&mut self, current: f32, baseline: f32) {
+        if self.current.is_empty() {
+            self.current = vec![0; BINS];
+            self.baseline = vec![0; BINS];
+        }
+        self.current[bin(current)] += 1;
+        self.baseline[bin(baseline)] += 1;
+        self.total += 1;
+    }
+
+    pub(crate) fn merge(mut self, other: Distributions) -> Distributions {
+        if self.current.is_empty() {
+            return other;
+        }
+        for (c, o) in self.current.iter_mut().zip(other.current) {
+            *c += o;
+        }
+        for (b, o) in self.baseline.iter_mut().zip(other.baseline) {
+            *b += o;
+        }
+        self.total += other.total;
+        self
+    }
+
+    /// The KS statistic (the largest distance between the cumulative
+    /// distributions of the current and baseline values) and its p-value,
+    /// i.e. the probability of a statistic at least this big if the values
+    /// were drawn from the same distribution. None if nothing was counted.
+    pub(crate) fn test(&self) -> Option<(f64, f64)> {
+        if self.total == 0 {
+            return None;
+        }
+        let n = self.total as f64;
+        let (mut current, mut baseline) = (0, 0);
+        let mut statistic: f64 = 0.0;
+        for (c, b) in self.current.iter().zip(&self.baseline) {
+            current += c;
+            baseline += b;
+            statistic = statistic.max((current as f64 - baseline as f64).abs() / n);
+        }
+        // Both samples have n values.
+        let effective = (n / 2.0).sqrt();
+        let lambda = (effective + 0.12 + 0.11 / effective) * statistic;
+        Some((statistic, kolmogorov(lambda)))
+    }
+}
+
+/// The complementary cumulative Kolmogorov distribution,
+/// Q(λ) = 2 Σ (-1)^(j-1) exp(-2 j² λ²), as in Numerical Recipes.
+fn kolmogorov(lambda: f64) -> f64 {
+    // The series converges too slowly to be summed here, and Q is 1 to well
+    // within the accuracy of the test.
+    if lambda < 0.3 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    for j in 1..=100 {
+        let term = sign * 2.0 * (-2.0 * (j as f64 * lambda).powi(2)).exp();
+        sum += term;
+        if term.abs() < 1e-12 * sum.abs() {
+            break;
+        }
+        sign = -sign;
+    }
+    sum.clamp(0.0, 1.0)
+}
@@ -0,0 +1,2705 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+/*! This executable simply compares each of the "hyperdrive_bandxx.bin" files in
+    the present working directory against those in the "baseline"
+    directory. Reports whether the largest difference between any two floats is
+    larger than some tolerance. Will fall over if the baseline directory doesn't
+    exist, or if there is some kind of mis-match between the hyperdrive files.
+*/
+
+mod archive;
+mod badge;
+mod baseline_sets;
+mod channels;
+mod checkpoint;
+mod compress;
+mod config;
+mod container;
+mod dashboard;
+mod diff_file;
+mod distributed;
+mod email;
+mod ensemble;
+mod environment;
+mod exempt;
+mod extract;
+mod hash;
+mod hdf5;
+mod heatmap;
+mod histogram;
+mod history;
+mod info;
+mod json;
+mod junit;
+mod ks;
+mod manifest;
+mod metafits;
+mod mwaf;
+mod normalise;
+mod notify;
+mod obsid;
+mod options;
+mod parallel;
+mod perf;
+mod plugin;
+mod png;
+mod presets;
+mod progress;
+mod read;
+mod region;
+mod remote;
+mod report;
+mod sample;
+mod snapshot;
+mod suite;
+mod temporal;
+mod top;
+mod trends;
+#[path = "../common/usage.rs"]
+mod usage;
+mod validate;
+mod verdict;
+mod wait;
+mod watch;
+mod weights;
+mod window;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::solutions::{self, Solutions};
+use pawsey_hyperdrive_checks::{
+    abs_difference, baseline_files, estimate_noise, exit_code, exit_code_of, glob_files,
+    matching_files_with, natural_cmp, sort_naturally, tolerances, Dtype, Endian, Error, Judgement,
+    NanPolicy, Rule, Tolerances, DEFAULT_OUTPUTS,
+};
+use structopt::clap::ArgMatches;
+use structopt::StructOpt;
+
+use channels::Channels;
+use checkpoint::Checkpoint;
+use diff_file::DiffFile;
+use heatmap::Heatmap;
+use histogram::Histogram;
+use ks::Distributions;
+use manifest::Manifest;
+use metafits::Metafits;
+use normalise::Normalisation;
+use options::{
+    Command, CompareOpt, DiffDirsOpt, DiffOpt, ExtractOpt, HistoryOpt, Opt, SnapshotOpt,
+    UpgradeReportOpt,
+};
+use progress::Progress;
+use read::{as_f32s, Reader, Stamp};
+use sample::Sampling;
+
+/// Parse a number written the way Rust (and C in the "C" locale) writes them,
+/// e.g. "0.001" or "1e-3", regardless of the user's locale. Commas are rejected
+/// with an explanation rather than misread, because wrapper scripts run under
+/// some locales write "0,001".
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    let trimmed = s.trim();
+    if trimmed.contains(',') {
+        return Err(format!(
+            "\"{}\" isn't a number; use '.' as the decimal separator and no thousands separators (e.g. \"0.001\")",
+            s
+        ));
+    }
+    trimmed
+        .parse()
+        .map_err(|_| format!("Couldn't parse \"{}\" as a number", s))
+}
+
+/// Two files to be compared against one another. `name` is used when
+/// reporting.
+struct FilePair {
+    name: PathBuf,
+    current: PathBuf,
+    baseline: PathBuf,
+    /// If set, only compare one band of files containing several equally sized
+    /// bands: the band's index, and the number of values in each band.
+    band: Option<(u64, u64)>,
+}
+
+impl FilePair {
+    /// The size of the data compared in `path`, one of the pair's files, in
+    /// bytes.
+    fn bytes(&self, path: &Path, dtype: Dtype) -> std::io::Result<u64> {
+        Ok(match self.band {
+            Some((_, len)) => len * dtype.size() as u64,
+            None => compress::len(path)?,
+        })
+    }
+}
+
+/// Find the hyperdrive output files in the PWD and pair them with those in
+/// `baseline_dir`. If `wait` is set (and --wait-for-files was given), first
+/// wait for the files to be written.
+fn band_file_pairs(
+    options: &DiffOpt,
+    baseline_dir: &Path,
+    wait: bool,
+) -> Result<Vec<FilePair>, anyhow::Error> {
+    let baseline_str = &baseline_dir
+        .to_str()
+        .expect("The baseline dir contained invalid unicode");
+    let patterns = if options.compare.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.compare.outputs.clone()
+    };
+    if wait && options.wait_for_files {
+        wait::wait_for_files(
+            baseline_dir,
+            Path::new("."),
+            &patterns,
+            options.timeout,
+            options.poll_interval,
+            options.compare.quiet,
+        )?;
+    }
+    let present_files = if options.compare.keep_going {
+        // Files missing from the baseline (or the PWD) are reported in their
+        // turn.
+        let mut files = vec![];
+        for p in &patterns {
+            files.append(&mut glob_files(Path::new("."), p)?);
+        }
+        sort_naturally(&mut files);
+        if files.is_empty() {
+            bail!(Error::Missing(format!(
+                "PWD does not have any {} files!",
+                patterns.join(" or ")
+            )));
+        }
+        if !options.compare.allow_subset {
+            files.append(&mut baseline_files(
+                baseline_dir,
+                &patterns,
+                compress::EXTENSIONS,
+            )?);
+            sort_naturally(&mut files);
+            files.dedup();
+        }
+        files
+    } else {
+        matching_files_with(
+            baseline_dir,
+            Path::new("."),
+            &patterns,
+            compress::EXTENSIONS,
+            options.compare.allow_subset,
+        )?
+    };
+
+    Ok(present_files
+        .into_iter()
+        .map(|p| {
+            let mut b_file_path = PathBuf::from(baseline_str);
+            b_file_path.push(&p);
+            FilePair {
+                name: p.clone(),
+                current: p,
+                baseline: compress::locate(&b_file_path),
+                band: None,
+            }
+        })
+        .collect())
+}
+
+/// Recursively collect the paths of all regular files underneath `dir`,
+/// relative to `dir`.
+fn walk_regular_files(
+    dir: &Path,
+    relative: &Path,
+    files: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir.join(relative))? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let rel = relative.join(entry.file_name());
+        if file_type.is_dir() {
+            walk_regular_files(dir, &rel, files)?;
+        } else if file_type.is_file() {
+            files.push(rel);
+        }
+    }
+    Ok(())
+}
+
+/// Pair every regular file in `a` with the file of the same relative path in
+/// `b`. Files that only exist in one of the directories are reported, but not
+/// compared.
+fn dir_file_pairs(a: &Path, b: &Path, quiet: bool) -> Result<Vec<FilePair>, anyhow::Error> {
+    for dir in &[a, b] {
+        if !dir.is_dir() {
+            bail!("Directory {:?} does not exist!", dir);
+        }
+    }
+
+    let mut a_files = vec![];
+    walk_regular_files(a, Path::new(""), &mut a_files)?;
+    sort_naturally(&mut a_files);
+    let mut b_files = vec![];
+    walk_regular_files(b, Path::new(""), &mut b_files)?;
+    sort_naturally(&mut b_files);
+    let has = |files: &[PathBuf], f: &Path| files.binary_search_by(|g| natural_cmp(g, f)).is_ok();
+
+    if !quiet {
+        for f in a_files.iter().filter(|f| !has(&b_files, f)) {
+            println!("Skipping {:?}; only present in {:?}", f, a);
+        }
+        for f in b_files.iter().filter(|f| !has(&a_files, f)) {
+            println!("Skipping {:?}; only present in {:?}", f, b);
+        }
+    }
+
+    let pairs: Vec<FilePair> = a_files
+        .into_iter()
+        .filter(|f| has(&b_files, f))
+        .map(|f| FilePair {
+            current: a.join(&f),
+            baseline: b.join(&f),
+            name: f,
+            band: None,
+        })
+        .collect();
+    if pairs.is_empty() {
+        bail!("{:?} and {:?} do not have any files in common!", a, b);
+    }
+    Ok(pairs)
+}
+
+/// The largest differences found between two files.
+#[derive(Clone, Copy, Debug, Default)]
+struct Differences {
+    /// The largest absolute difference between two floats.
+    max_abs: f32,
+    /// The largest absolute difference between two floats, relative to the
+    /// magnitude of the baseline float. Infinite if a baseline float is zero but
+    /// the current float isn't.
+    max_rel: f32,
+    /// The largest absolute difference in units of the estimated noise of the
+    /// baseline data. Only calculated if requested.
+    max_sigmas: f32,
+    /// The largest absolute difference divided by the factor that the
+    /// tolerance is multiplied by for that float (see --edge-tolerance-ramp).
+    /// The same as `max_abs` if the tolerance doesn't vary.
+    max_scaled: f32,
+    /// The sum of the squared differences in units of the residuals' noise, if
+    /// calculated, and the number of floats compared. Both are summed when
+    /// combining.
+    chi2: f64,
+    samples: u64,
+    /// The largest growth of the per-timestep residual RMS over the
+    /// observation, relative to its mean, if calculated.
+    rms_growth: f64,
+    /// The largest weighted residual RMS of a file (or band), if calculated.
+    weighted_rms: f64,
+    /// The Kolmogorov-Smirnov statistic and p-value of a file (or band), if
+    /// calculated. Those with the smallest p-value are kept when combining.
+    ks: Option<(f64, f64)>,
+    /// The sums of the absolute, signed (current minus baseline) and squared
+    /// differences, and the number of floats bigger than the (absolute)
+    /// tolerance. Summed when combining.
+    sum_abs: f64,
+    sum_signed: f64,
+    sum_sq: f64,
+    above_tolerance: u64,
+    /// The median, 95th and 99th percentiles of the absolute differences of a
+    /// file (or band), to within 1%. The largest of them when combining.
+    median: f32,
+    p95: f32,
+    p99: f32,
+    /// The largest distance between two floats in units in the last place.
+    max_ulps: u64,
+    /// With --complex, the largest differences between the amplitudes of
+    /// two visibilities (also divided by the tolerance factor, like
+    /// `max_scaled`), and between their phases, in degrees.
+    max_amp: f32,
+    max_amp_scaled: f32,
+    max_phase: f64,
+    /// The number of floats outside all of the tolerances, if there are
+    /// several. Summed when combining.
+    violations: u64,
+    /// The numbers of NaNs and infinities in the current and baseline data,
+    /// and of NaNs that fail the NaN policy. Summed when combining.
+    current_nans: u64,
+    baseline_nans: u64,
+    current_infs: u64,
+    baseline_infs: u64,
+    nan_failures: u64,
+    /// The number of floats left out as flagged or excluded. Summed when
+    /// combining.
+    skipped: u64,
+    /// When sampling, how many blocks were compared, and how many there are in
+    /// total. These are summed rather than maximised when combining.
+    sampled_blocks: u64,
+    total_blocks: u64,
+    /// The XXH3 hashes of the current and baseline files, so that reports can
+    /// be traced back to the exact data compared. Only known for a single,
+    /// fully-read pair of files.
+    hashes: Option<(u64, u64)>,
+    /// Where the time went. Summed when combining.
+    timings: Timings,
+    /// Whether either file was corrupt (e.g. truncated), so that only the
+    /// valid part of the files could be compared.
+    corrupt: bool,
+    /// The number of pairs of files of different sizes, of which only the
+    /// overlap was compared (with --compare-overlap).
+    size_mismatches: u64,
+    /// If the files couldn't be compared at all (e.g. one was missing), with
+    /// --keep-going, the exit code of the error. The largest is kept when
+    /// combining.
+    unreadable: Option<i32>,
+}
+
+/// How long comparing a pair of files spent in each stage, to tell whether a
+/// slow check is a filesystem problem or a checker problem. Not measured by
+/// distributed workers.
+#[derive(Clone, Copy, Debug, Default)]
+struct Timings {
+    /// Reading the files.
+    io: Duration,
+    /// Converting the bytes to floats, and hashing them.
+    decode: Duration,
+    /// Everything else: noise estimates, flags, differences and so on.
+    metrics: Duration,
+}
+
+impl Timings {
+    fn total(&self) -> Duration {
+        self.io + self.decode + self.metrics
+    }
+
+    fn add(self, other: Timings) -> Timings {
+        Timings {
+            io: self.io + other.io,
+            decode: self.decode + other.decode,
+            metrics: self.metrics + other.metrics,
+        }
+    }
+}
+
+impl Differences {
+    fn reduced_chi2(&self) -> f64 {
+        self.chi2 / self.samples as f64
+    }
+
+    fn mean_abs(&self) -> f64 {
+        self.sum_abs / self.samples as f64
+    }
+
+    fn rms(&self) -> f64 {
+        (self.sum_sq / self.samples as f64).sqrt()
+    }
+
+    /// The mean signed difference, i.e. any systematic bias.
+    fn mean_signed(&self) -> f64 {
+        self.sum_signed / self.samples as f64
+    }
+
+    /// The standard deviation of the signed differences.
+    fn std_dev(&self) -> f64 {
+        (self.sum_sq / self.samples as f64 - self.mean_signed().powi(2))
+            .max(0.0)
+            .sqrt()
+    }
+
+    /// The fraction of floats bigger than the (absolute) tolerance.
+    fn frac_exceeding(&self) -> f64 {
+        self.above_tolerance as f64 / self.samples as f64
+    }
+
+    fn max(self, other: Differences) -> Differences {
+        Differences {
+            max_abs: self.max_abs.max(other.max_abs),
+            max_rel: self.max_rel.max(other.max_rel),
+            max_sigmas: self.max_sigmas.max(other.max_sigmas),
+            max_scaled: self.max_scaled.max(other.max_scaled),
+            chi2: self.chi2 + other.chi2,
+            samples: self.samples + other.samples,
+            rms_growth: self.rms_growth.max(other.rms_growth),
+            weighted_rms: self.weighted_rms.max(other.weighted_rms),
+            ks: match (self.ks, other.ks) {
+                (Some(a), Some(b)) => Some(if b.1 < a.1 { b } else { a }),
+                (a, b) => a.or(b),
+            },
+            sum_abs: self.sum_abs + other.sum_abs,
+            sum_signed: self.sum_signed + other.sum_signed,
+            sum_sq: self.sum_sq + other.sum_sq,
+            above_tolerance: self.above_tolerance + other.above_tolerance,
+            median: self.median.max(other.median),
+            p95: self.p95.max(other.p95),
+            p99: self.p99.max(other.p99),
+            max_ulps: self.max_ulps.max(other.max_ulps),
+            max_amp: self.max_amp.max(other.max_amp),
+            max_amp_scaled: self.max_amp_scaled.max(other.max_amp_scaled),
+            max_phase: self.max_phase.max(other.max_phase),
+            violations: self.violations + other.violations,
+            current_nans: self.current_nans + other.current_nans,
+            baseline_nans: self.baseline_nans + other.baseline_nans,
+            current_infs: self.current_infs + other.current_infs,
+            baseline_infs: self.baseline_infs + other.baseline_infs,
+            nan_failures: self.nan_failures + other.nan_failures,
+            skipped: self.skipped + other.skipped,
+            sampled_blocks: self.sampled_blocks + other.sampled_blocks,
+            total_blocks: self.total_blocks + other.total_blocks,
+            hashes: None,
+            timings: self.timings.add(other.timings),
+            corrupt: self.corrupt || other.corrupt,
+            size_mismatches: self.size_mismatches + other.size_mismatches,
+            unreadable: self.unreadable.max(other.unreadable),
+        }
+    }
+}
+
+/// The differences between the amplitudes of the complex numbers `p` and `b`
+/// (as real and imaginary parts), and between their phases in degrees. The
+/// phase difference is the phase of p times the conjugate of b, so it doesn't
+/// jump by 360 degrees when one phase wraps around and the other doesn't.
+fn complex_differences(p: (f32, f32), b: (f32, f32)) -> (f32, f64) {
+    let amp = |(re, im): (f32, f32)| (re as f64).hypot(im as f64);
+    let (pr, pi, br, bi) = (p.0 as f64, p.1 as f64, b.0 as f64, b.1 as f64);
+    let phase = (pi * br - pr * bi).atan2(pr * br + pi * bi);
+    ((amp(p) - amp(b)).abs() as f32, phase.abs().to_degrees())
+}
+
+/// Before reading any data, check that every pair of files has the same size,
+/// and that those sizes are a whole number of values. Every problem found is
+/// reported at once, as missing files if any are missing.
+fn preflight(pairs: &[FilePair], options: &CompareOpt) -> Result<(), anyhow::Error> {
+    let problems: Vec<Error> = preflight_each(pairs, options)
+        .into_iter()
+        .flatten()
+        .collect();
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "{} problem(s) found before comparing any data:\n  {}",
+        problems.len(),
+        problems
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join("\n  ")
+    );
+    if problems.iter().any(|p| matches!(p, Error::Missing(_))) {
+        bail!(Error::Missing(message));
+    }
+    bail!(Error::Shape(message));
+}
+
+/// The problems [preflight] finds with each pair of files. Those that
+/// --compare-valid-prefix or --compare-overlap can work around are warned
+/// about instead.
+fn preflight_each(pairs: &[FilePair], options: &CompareOpt) -> Vec<Vec<Error>> {
+    let dtype = options.dtype;
+    let element_size = dtype.size() as u64;
+
+    let mut all = vec![];
+    for pair in pairs {
+        let mut problems = vec![];
+        // Problems that --compare-valid-prefix can work around.
+        let mut corruption = vec![];
+        let sizes = [&pair.current, &pair.baseline].map(|path| match compress::len(path) {
+            Ok(len) => Some(len),
+            Err(e) => {
+                problems.push(Error::reading(path, e));
+                None
+            }
+        });
+        for (path, size) in [&pair.current, &pair.baseline].iter().zip(sizes.iter()) {
+            match size {
+                Some(0) => problems.push(Error::Shape(format!("{:?} is empty", path))),
+                Some(s) if s % element_size != 0 => corruption.push(Error::Shape(format!(
+                    "{:?} is corrupt: its {} bytes are {} whole {} values and {} stray bytes, so it was probably truncated mid-write",
+                    path,
+                    s,
+                    s / element_size,
+                    dtype.name(),
+                    s % element_size
+                ))),
+                _ => (),
+            }
+        }
+        if let [Some(c), Some(b)] = sizes {
+            let whole = c % element_size == 0 && b % element_size == 0;
+            if c != b && options.compare_overlap && whole {
+                eprintln!(
+                    "Warning: {:?} has {} values, but {:?} has {}; only the first {} will be compared",
+                    pair.current,
+                    c / element_size,
+                    pair.baseline,
+                    b / element_size,
+                    c.min(b) / element_size
+                );
+            } else if c != b {
+                corruption.push(Error::Shape(format!(
+                    "{:?} is {} bytes, but {:?} is {} bytes; the smaller may have been truncated",
+                    pair.current, c, pair.baseline, b
+                )));
+            }
+        }
+        if options.compare_valid_prefix {
+            for c in &corruption {
+                eprintln!("Warning: {}", c);
+            }
+        } else {
+            problems.append(&mut corruption);
+        }
+        all.push(problems);
+    }
+    all
+}
+
+/// Check that each file (of `bands` equally sized bands, if given) is a whole
+/// number of timesteps of the observation described by its metafits, and that
+/// there's a coarse channel for each band. Every problem found is reported at
+/// once. Should be called after [preflight].
+fn check_layout<'a>(
+    pairs: impl IntoIterator<Item = &'a FilePair>,
+    metafits: &Metafits,
+    comparison: &Comparison,
+    bands: Option<u64>,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let channels = match &comparison.channels {
+        Some(c) if c.floats_per_band() > 0 => c,
+        _ => return Ok(()),
+    };
+    if !quiet {
+        println!("Observation: {}", metafits.describe());
+    }
+    let coarse_channels = metafits.coarse_channels.len() as u64;
+    let element_size = comparison.dtype.size() as u64;
+
+    let mut problems = vec![];
+    // The distinct numbers of timesteps and baselines found.
+    let mut shapes = vec![];
+    for pair in pairs {
+        match bands {
+            Some(b) if b > coarse_channels => problems.push(format!(
+                "{:?} has {} bands, but there are only {} coarse channels",
+                pair.current, b, coarse_channels
+            )),
+            None => match band_number(&pair.name) {
+                Some(n) if n == 0 || n > coarse_channels => problems.push(format!(
+                    "{:?} is for band {}, but there are only {} coarse channels",
+                    pair.current, n, coarse_channels
+                )),
+                _ => (),
+            },
+            _ => (),
+        }
+        // Sizes were checked by the preflight.
+        let floats = match compress::len(&pair.current) {
+            Ok(len) => len / element_size / bands.unwrap_or(1).max(1),
+            Err(_) => continue,
+        };
+        if !floats.is_multiple_of(channels.floats_per_band()) {
+            problems.push(format!(
+                "{:?} has {} floats{}, which isn't a whole number of {} fine channels of {} floats",
+                pair.current,
+                floats,
+                if bands.is_some() { " per band" } else { "" },
+                channels.fine_channels,
+                channels.floats_per_channel
+            ));
+            continue;
+        }
+        let rows = floats / channels.floats_per_band();
+        match comparison.baselines(rows) {
+            Some(b) => {
+                if !shapes.contains(&(rows / b, b)) {
+                    shapes.push((rows / b, b));
+                }
+            }
+            None => {
+                let tiles = metafits.tiles();
+                problems.push(format!(
+                    "{:?} has {} rows of {} fine channels{}, which isn't a whole number of timesteps of {} baselines (or {} with autocorrelations) for {} tiles",
+                    pair.current,
+                    rows,
+                    channels.fine_channels,
+                    if bands.is_some() { " per band" } else { "" },
+                    tiles * tiles.saturating_sub(1) / 2,
+                    tiles * (tiles + 1) / 2,
+                    tiles
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(Error::Shape(format!(
+            "{} problem(s) found checking the data against --metafits:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        )));
+    }
+    if !quiet {
+        for (timesteps, baselines) in shapes {
+            println!(
+                "Data have {} timesteps of {} baselines",
+                timesteps, baselines
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Split each pair of files into `bands` pairs of equally sized bands, to be
+/// compared and reported separately. Should be called after [preflight].
+fn split_bands(
+    pairs: Vec<FilePair>,
+    bands: u64,
+    dtype: Dtype,
+) -> Result<Vec<FilePair>, anyhow::Error> {
+    if bands == 0 {
+        bail!("--bands must be at least 1");
+    }
+    let mut split = vec![];
+    for pair in pairs {
+        let floats = std::fs::metadata(&pair.current)?.len() / dtype.size() as u64;
+        if !floats.is_multiple_of(bands) {
+            bail!(Error::Shape(format!(
+                "{:?} has {} floats, which can't be split into {} equally sized bands",
+                pair.current, floats, bands
+            )));
+        }
+        for band in 0..bands {
+            split.push(FilePair {
+                name: PathBuf::from(format!("{} band {:02}", pair.name.display(), band + 1)),
+                current: pair.current.clone(),
+                baseline: pair.baseline.clone(),
+                band: Some((band, floats / bands)),
+            });
+        }
+    }
+    Ok(split)
+}
+
+/// The number of the coarse band in a name like "hyperdrive_band03.bin".
+fn band_number(name: &Path) -> Option<u64> {
+    let name = name.file_name()?.to_str()?;
+    let digits: String = name[name.rfind("band")? + 4..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
+
+/// How much is printed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    /// Nothing; only the exit code says how the comparison went.
+    Quiet,
+    /// A summary of the comparison.
+    Summary,
+    /// Also the results of each file (or band).
+    Files,
+    /// Also the statistics and timings of each chunk compared.
+    Chunks,
+}
+
+/// How to compare each pair of files.
+#[derive(Default)]
+struct Comparison {
+    /// Also express differences in units of the baseline's estimated noise.
+    estimate_sigma: bool,
+    /// Only compare a random subset of each file.
+    sampling: Option<Sampling>,
+    /// Treat fine channels differently depending on where they are in their
+    /// coarse band.
+    channels: Option<Channels>,
+    /// Only compare these slices of each file (or band).
+    ranges: Vec<region::Slice>,
+    /// Leave out data flagged by the aoflagger mwaf files in this directory.
+    mwaf_dir: Option<PathBuf>,
+    /// Calculate the weighted residual RMS with the weight files in this
+    /// directory.
+    weights_dir: Option<PathBuf>,
+    /// Leave out samples that the weight files flag.
+    mask_weights: bool,
+    /// Bring the data into line before comparing it.
+    normalisation: Option<Normalisation>,
+    /// Calibrate the current data with these solutions (read from this file)
+    /// before comparing it, with or without autocorrelations.
+    solutions: Option<(PathBuf, Solutions)>,
+    autos: bool,
+    /// Rather than failing, compare the valid part of corrupt files.
+    valid_prefix: bool,
+    /// Rather than failing, compare the values that both files of a pair have
+    /// when they have different numbers.
+    overlap: bool,
+    /// Don't compare the floats of bitwise identical files.
+    exact: bool,
+    /// Write the differences of each pair of files into this directory.
+    write_diff: Option<PathBuf>,
+    /// Render heatmaps of the differences into this directory.
+    plot: Option<PathBuf>,
+    /// Print this many of the largest differences, locating them with the
+    /// number of baselines or tiles if known.
+    top: Option<usize>,
+    baselines: Option<u64>,
+    weights: bool,
+    /// The observation's layout, to find the number of baselines and label
+    /// the largest differences.
+    metafits: Option<Metafits>,
+    /// If either a relative or ULP tolerance is given, count the floats
+    /// outside all of the tolerances.
+    tolerance: f32,
+    rel_tolerance: Option<f32>,
+    ulps: Option<u32>,
+    /// The absolute tolerance of the imaginary floats, instead of `tolerance`.
+    imag_tolerance: Option<f32>,
+    /// How to judge NaNs.
+    nan: NanPolicy,
+    /// Tolerances for particular files, overriding those above.
+    rules: Vec<Rule>,
+    /// Also compare the floats as complex numbers.
+    complex: bool,
+    /// Calculate the chi-squared of the residuals, with this noise per float
+    /// if given (otherwise, it's estimated).
+    chi_squared: bool,
+    chi2_sigma: Option<f64>,
+    /// Test whether the current and baseline values have the same
+    /// distribution.
+    ks: bool,
+    /// Report how the residuals evolve over this many timesteps.
+    timesteps: Option<usize>,
+    /// The type and byte order of the values in the files.
+    dtype: Dtype,
+    endian: Endian,
+    /// The number of threads to compare each file with.
+    threads: usize,
+    /// How far through the files the comparison is, shown on a terminal.
+    progress: Option<Progress>,
+}
+
+impl Comparison {
+    /// Whether the baseline's noise has to be estimated, from all of its data.
+    fn needs_noise(&self) -> bool {
+        self.estimate_sigma || self.chi_squared && self.chi2_sigma.is_none()
+    }
+
+    /// A fingerprint of everything that affects the results of comparing a
+    /// pair of files, so that a checkpoint isn't resumed with different
+    /// options. Files (e.g. of solutions) are identified by their paths.
+    fn fingerprint(&self) -> u64 {
+        let description = format!(
+            "{:?}",
+            (
+                (
+                    self.sampling.as_ref().map(|s| (s.fraction, s.seed)),
+                    &self.channels,
+                    &self.ranges,
+                    &self.mwaf_dir,
+                    &self.weights_dir,
+                    self.mask_weights,
+                    &self.normalisation,
+                    self.solutions.as_ref().map(|(path, _)| path),
+                    self.autos,
+                ),
+                (
+                    self.valid_prefix,
+                    self.overlap,
+                    self.exact,
+                    self.estimate_sigma,
+                    self.weights,
+                    self.timesteps,
+                ),
+                (
+                    self.tolerance,
+                    self.rel_tolerance,
+                    self.ulps,
+                    self.imag_tolerance,
+                    self.nan,
+                    &self.rules,
+                ),
+                (
+                    self.complex,
+                    self.chi_squared,
+                    self.chi2_sigma,
+                    self.ks,
+                    self.dtype,
+                    self.endian,
+                ),
+            )
+        );
+        hash::xxh64(description.as_bytes(), 0)
+    }
+
+    /// Whether files are compared a chunk at a time, so that the memory used
+    /// doesn't depend on their size. Otherwise something needs all of the data
+    /// at once.
+    fn streams(&self) -> bool {
+        self.sampling.is_none()
+            && !self.valid_prefix
+            && !self.needs_noise()
+            && self.solutions.is_none()
+            && self.timesteps.is_none()
+            && self.weights_dir.is_none()
+    }
+
+    /// The number of baselines in each timestep of a file (or band) of `rows`
+    /// rows (of all fine channels), if it's known.
+    fn baselines(&self, rows: u64) -> Option<u64> {
+        self.baselines
+            .or_else(
+                || match (self.timesteps, self.metafits.as_ref().map(Metafits::tiles)) {
+                    (Some(t), _) => Some(rows / t as u64),
+                    // With or without autocorrelations, whichever fits.
+                    (None, Some(n)) => [n * n.saturating_sub(1) / 2, n * (n + 1) / 2]
+                        .iter()
+                        .copied()
+                        .find(|&b| b > 0 && rows.is_multiple_of(b)),
+                    (None, None) => None,
+                },
+            )
+            .filter(|&b| b > 0 && rows.is_multiple_of(b))
+    }
+}
+
+/// Files read (or sampled) whole.
+struct Loaded {
+    current: Vec<f32>,
+    baseline: Vec<f32>,
+    sampled_blocks: u64,
+    total_blocks: u64,
+    hashes: Option<(u64, u64)>,
+    /// Whether only the valid prefix of corrupt files was read.
+    corrupt: bool,
+    /// Whether the files have different numbers of values, so only their
+    /// overlap was read.
+    size_mismatch: bool,
+}
+
+/// Read the whole of a pair of files into memory (or just the sampled parts
+/// of them), normalised if asked.
+fn read_whole(
+    pair: &FilePair,
+    comparison: &Comparison,
+    reader: &Reader,
+    timings: &mut Timings,
+) -> Result<Loaded, anyhow::Error> {
+    let sampling = comparison.sampling.as_ref();
+    let size = comparison.dtype.size();
+    let p = &pair.name;
+    // Read in the present and baseline data (or just the sampled parts of
+    // them).
+    let started = Instant::now();
+    let (p_bytes, b_bytes, sampled_blocks, total_blocks) = match sampling {
+        Some(sampling) => {
+            let len = std::fs::metadata(&pair.current)?.len() / size as u64;
+            let total_blocks = len.div_ceil(sample::BLOCK_LEN as u64);
+            let blocks = sampling.choose_blocks(p, total_blocks);
+            let read = |path| reader.read_blocks(path, &blocks, sample::BLOCK_LEN * size);
+            (
+                read(&pair.current)?,
+                read(&pair.baseline)?,
+                blocks.len() as u64,
+                total_blocks,
+            )
+        }
+        None => {
+            let read = |path: &Path| {
+                let read = match pair.band {
+                    Some((band, len)) => reader.read_blocks(path, &[band], len as usize * size),
+                    None => reader.read_bytes(path),
+                };
+                read.or_else(|e| {
+                    let prefix = read::readable_prefix(path);
+                    let what = format!(
+                        "reading failed after {} bytes ({} valid values): {}",
+                        prefix.len(),
+                        prefix.len() / size,
+                        e
+                    );
+                    if !comparison.valid_prefix || pair.band.is_some() {
+                        bail!(Error::Shape(format!(
+                            "{:?} is corrupt or unreadable: {}",
+                            path, what
+                        )));
+                    }
+                    eprintln!("Warning: {:?} is corrupt: {}", path, what);
+                    Ok(prefix)
+                })
+            };
+            (read(&pair.current)?, read(&pair.baseline)?, 0, 0)
+        }
+    };
+    timings.io = started.elapsed();
+
+    // Compare whatever whole floats both files have, if asked to; otherwise,
+    // preflight has already made sure they match.
+    let (mut p_bytes, mut b_bytes) = (p_bytes, b_bytes);
+    let valid = p_bytes.len().min(b_bytes.len()) / size * size;
+    let whole = p_bytes.len() % size == 0 && b_bytes.len() % size == 0;
+    let size_mismatch = comparison.overlap && whole && p_bytes.len() != b_bytes.len();
+    if size_mismatch {
+        p_bytes.truncate(valid);
+        b_bytes.truncate(valid);
+    }
+    let corrupt = comparison.valid_prefix && (valid != p_bytes.len() || valid != b_bytes.len());
+    if corrupt {
+        eprintln!(
+            "Warning: comparing only the first {} valid values of {:?} ({} bytes) and {:?} ({} bytes)",
+            valid / size,
+            pair.current,
+            p_bytes.len(),
+            pair.baseline,
+            b_bytes.len()
+        );
+        p_bytes.truncate(valid);
+        b_bytes.truncate(valid);
+    }
+
+    let started = Instant::now();
+    let hashes = match sampling {
+        Some(_) => None,
+        None => Some((hash::xxh3_64(&p_bytes), hash::xxh3_64(&b_bytes))),
+    };
+    let mut p_data = comparison
+        .dtype
+        .to_f32s(&p_bytes, comparison.endian, &pair.current)?;
+    let mut b_data = comparison
+        .dtype
+        .to_f32s(&b_bytes, comparison.endian, &pair.baseline)?;
+    drop((p_bytes, b_bytes));
+    timings.decode = started.elapsed();
+    if p_data.is_empty() {
+        bail!(Error::Shape(format!(
+            "{:?} didn't contain any data",
+            pair.current
+        )));
+    }
+    if b_data.is_empty() {
+        bail!(Error::Shape(format!(
+            "{:?} didn't contain any data",
+            pair.baseline
+        )));
+    }
+
+    // Check that they have an equal amount of data.
+    if p_data.len() != b_data.len() {
+        bail!(Error::Shape(format!(
+            "{:?} and {:?} have different amounts of data",
+            pair.current, pair.baseline
+        )));
+    }
+    if let Some(n) = &comparison.normalisation {
+        n.apply(&mut p_data, &mut b_data)?;
+    }
+
+    Ok(Loaded {
+        current: p_data,
+        baseline: b_data,
+        sampled_blocks,
+        total_blocks,
+        hashes,
+        corrupt,
+        size_mismatch,
+    })
+}
+
+/// The number of floats of each file compared at a time when streaming.
+const STREAM_FLOATS: usize = 1 << 24;
+
+/// The shape of `floats` floats laid out as hyperdrive writes them, as far as
+/// it's known: by timestep, baseline, fine channel and float within a
+/// channel.
+fn diff_shape(floats: u64, comparison: &Comparison) -> diff_file::Shape {
+    let channels = match &comparison.channels {
+        Some(c) if c.floats_per_band() > 0 => c,
+        _ => return vec![("float", floats)],
+    };
+    let rows = floats / channels.floats_per_band();
+    let mut shape = match comparison.baselines(rows) {
+        Some(b) => vec![("timestep", rows / b), ("baseline", b)],
+        None => vec![("row", rows)],
+    };
+    shape.push(("fine channel", channels.fine_channels));
+    shape.push(("float", channels.floats_per_channel));
+    shape
+}
+
+/// An empty heatmap for a pair of files of `floats` floats, if --plot was
+/// given.
+fn heatmap(
+    pair: &FilePair,
+    floats: u64,
+    comparison: &Comparison,
+) -> Result<Option<Heatmap>, anyhow::Error> {
+    let channels = match (&comparison.plot, &comparison.channels) {
+        (None, _) => return Ok(None),
+        (Some(_), Some(c)) if c.floats_per_band() > 0 => c,
+        (Some(_), _) => bail!("--plot needs --fine-channels or --metafits"),
+    };
+    match comparison.baselines(floats / channels.floats_per_band()) {
+        Some(b) => Ok(Some(Heatmap::new(
+            b,
+            channels.fine_channels,
+            channels.floats_per_channel,
+        ))),
+        None => bail!(
+            "Can't plot {:?} without knowing its number of baselines; see --baselines, --timesteps and --metafits",
+            pair.name
+        ),
+    }
+}
+
+/// Where the heatmap of a pair of files goes.
+fn plot_path(pair: &FilePair, comparison: &Comparison) -> PathBuf {
+    let dir = comparison.plot.clone().unwrap_or_default();
+    // e.g. "hyperdrive_band01.bin band 02" with --bands
+    let name = pair.name.display().to_string().replace(['/', ' '], "_");
+    dir.join(format!("{}.png", name))
+}
+
+/// The XXH3 hash of a pair of files, if they're bitwise identical. The two
+/// files are hashed at once.
+fn identical(
+    pair: &FilePair,
+    reader: &Reader,
+    progress: Option<&Progress>,
+) -> Result<Option<u64>, anyhow::Error> {
+    let len = compress::len(&pair.current)?;
+    if compress::len(&pair.baseline)? != len {
+        return Ok(None);
+    }
+    let chunk = (STREAM_FLOATS * 4) as u64;
+    let hash = |path: &Path| -> Result<u64, anyhow::Error> {
+        let layout = reader.layout(path);
+        let mut hasher = hash::Xxh3::new();
+        for offset in (0..len).step_by(chunk as usize) {
+            let bytes = reader
+                .read_range(path, &layout, offset, chunk.min(len - offset) as usize)
+                .map_err(|e| Error::Shape(format!("{:?} is corrupt or unreadable: {}", path, e)))?;
+            if let Some(progress) = progress {
+                progress.advance(&pair.name.display().to_string(), bytes.len() as u64);
+            }
+            hasher.update(&bytes);
+        }
+        reader
+            .close(path)
+            .map_err(|e| Error::Shape(format!("{:?} is corrupt or unreadable: {}", path, e)))?;
+        Ok(hasher.finish())
+    };
+    let (current, baseline) = std::thread::scope(|s| {
+        let current = s.spawn(|| hash(&pair.current));
+        let baseline = hash(&pair.baseline);
+        (current.join().expect("hashing thread panicked"), baseline)
+    });
+    let (current, baseline) = (current?, baseline?);
+    Ok((current == baseline).then_some(current))
+}
+
+/// With --exact, compare a pair of whole files by their hashes first, returning
+/// no differences if they're identical, or `None` if their floats need
+/// comparing.
+fn compare_identical(
+    pair: &FilePair,
+    comparison: &Comparison,
+    reader: &Reader,
+    verbose: bool,
+    out: &mut String,
+) -> Result<Option<Differences>, anyhow::Error> {
+    let p = &pair.name;
+    let started = Instant::now();
+    let hash = match identical(pair, reader, comparison.progress.as_ref())? {
+        Some(hash) => hash,
+        None => {
+            if verbose {
+                writeln!(
+                    out,
+                    "{:?} isn't identical to the baseline; comparing its floats",
+                    p
+                )?;
+            }
+            return Ok(None);
+        }
+    };
+    if verbose {
+        writeln!(
+            out,
+            "{:?} is identical to the baseline (XXH3 {:016x})",
+            p, hash
+        )?;
+    }
+    let floats = compress::len(&pair.current)? / comparison.dtype.size() as u64;
+    if let Some(dir) = &comparison.write_diff {
+        let mut diff_file = DiffFile::create(dir, &pair.name)?;
+        diff_file.write_zeros(floats)?;
+        diff_file.finish(diff_shape(floats, comparison))?;
+    }
+    if let Some(heatmap) = heatmap(pair, floats, comparison)? {
+        heatmap.write(&plot_path(pair, comparison), comparison.tolerance)?;
+    }
+    Ok(Some(Differences {
+        samples: floats,
+        hashes: Some((hash, hash)),
+        timings: Timings {
+            io: started.elapsed(),
+            ..Timings::default()
+        },
+        ..Differences::default()
+    }))
+}
+
+/// Check that the fine channels given fit a file of `floats` floats.
+fn check_channels(pair: &FilePair, floats: u64, c: &Channels) -> Result<(), anyhow::Error> {
+    if c.floats_per_band() == 0 {
+        bail!("--fine-channels and --floats-per-channel must be at least 1");
+    }
+    if 2 * c.exclude_edges >= c.fine_channels {
+        bail!(
+            "Excluding {} edge channels of {} leaves nothing to compare",
+            c.exclude_edges,
+            c.fine_channels
+        );
+    }
+    if !floats.is_multiple_of(c.floats_per_band()) {
+        bail!(
+            "{:?} has {} floats, which isn't a whole number of {} fine channels of {} floats",
+            pair.current,
+            floats,
+            c.fine_channels,
+            c.floats_per_channel
+        );
+    }
+    Ok(())
+}
+
+/// The coarse band (numbered from 1) of a file, for its `what` (e.g. its
+/// solutions).
+fn coarse_band(pair: &FilePair, what: &str) -> Result<u64, anyhow::Error> {
+    match pair.band {
+        Some((band, _)) => Ok(band + 1),
+        None => band_number(&pair.name).ok_or_else(|| {
+            anyhow!(
+                "Couldn't tell which coarse band {:?} is for its {}",
+                pair.name,
+                what
+            )
+        }),
+    }
+}
+
+/// What comparing some floats found: the biggest differences, the largest
+/// differences (with --top), and the histogram (for percentiles) and
+/// distributions (with --ks) of the differences.
+type Compared = (
+    Differences,
+    Option<Vec<top::Worst>>,
+    Histogram,
+    Option<Distributions>,
+);
+
+/// Compare a pair of files (or bands of them) a chunk at a time with
+/// `compare`, writing the differences to `diff_file` and `heatmap` as they're
+/// found. Returns what was found and the XXH3 hashes of the files.
+#[allow(clippy::too_many_arguments)]
+fn compare_streamed(
+    pair: &FilePair,
+    floats: u64,
+    comparison: &Comparison,
+    reader: &Reader,
+    compare: impl Fn(&[f32], &[f32], usize) -> Compared,
+    diff_file: &mut Option<DiffFile>,
+    heatmap: &mut Option<Heatmap>,
+    timings: &mut Timings,
+    verbosity: Verbosity,
+    out: &mut String,
+) -> Result<(Compared, (u64, u64)), anyhow::Error> {
+    let p = &pair.name;
+    let progress = comparison.progress.as_ref();
+    let name = p.display().to_string();
+    let layouts = (reader.layout(&pair.current), reader.layout(&pair.baseline));
+    let size = comparison.dtype.size();
+    let start = pair.band.map_or(0, |(band, len)| band * len * size as u64);
+    // Normalisation needs whole fine channels, and O_DIRECT whole blocks.
+    let align = comparison
+        .normalisation
+        .as_ref()
+        .map_or(1, |n| n.floats_per_channel.max(1) as usize)
+        * 1024;
+    let chunk = (STREAM_FLOATS / align).max(1) * align;
+    let mut hashers = (hash::Xxh3::new(), hash::Xxh3::new());
+    let mut diffs = Differences::default();
+    let mut histogram = Histogram::default();
+    let mut distributions: Option<Distributions> = None;
+    let mut worst = comparison.top.map(|_| vec![]);
+    // With --mmap, f32s in this machine's byte order are compared where they
+    // lie in the mapped files, rather than being copied out.
+    let mapped = match (comparison.dtype, comparison.endian.is_native()) {
+        (Dtype::F32, true) => match (reader.map(&pair.current)?, reader.map(&pair.baseline)?) {
+            (Some(p), Some(b)) => Some((p, b)),
+            _ => None,
+        },
+        _ => None,
+    };
+    for offset in (0..floats as usize).step_by(chunk) {
+        let len = chunk.min(floats as usize - offset);
+        let range_start = start + (offset * size) as u64;
+        let corrupt = |path: &Path, e: std::io::Error| {
+            Error::Shape(format!("{:?} is corrupt or unreadable: {}", path, e))
+        };
+        let decode_copy = |p_bytes: &[u8], b_bytes: &[u8]| -> Result<_, Error> {
+            Ok((
+                Cow::Owned(
+                    comparison
+                        .dtype
+                        .to_f32s(p_bytes, comparison.endian, &pair.current)?,
+                ),
+                Cow::Owned(
+                    comparison
+                        .dtype
+                        .to_f32s(b_bytes, comparison.endian, &pair.baseline)?,
+                ),
+            ))
+        };
+        let started = Instant::now();
+        let (mut p_data, mut b_data, io, decode): (Cow<[f32]>, Cow<[f32]>, _, _) = match &mapped {
+            Some((p_map, b_map)) => {
+                let p_bytes = p_map
+                    .bytes(range_start, len * size)
+                    .map_err(|e| corrupt(&pair.current, e))?;
+                let b_bytes = b_map
+                    .bytes(range_start, len * size)
+                    .map_err(|e| corrupt(&pair.baseline, e))?;
+                // Hashing faults the pages in, so it's the read.
+                hashers.0.update(p_bytes);
+                hashers.1.update(b_bytes);
+                let io = started.elapsed();
+                let started = Instant::now();
+                let (p_data, b_data) = match (as_f32s(p_bytes), as_f32s(b_bytes)) {
+                    (Some(p), Some(b)) => (Cow::Borrowed(p), Cow::Borrowed(b)),
+                    _ => decode_copy(p_bytes, b_bytes)?,
+                };
+                (p_data, b_data, io, started.elapsed())
+            }
+            None => {
+                let read = |path: &Path, layout| {
+                    reader
+                        .read_range(path, layout, range_start, len * size)
+                        .map_err(|e| corrupt(path, e))
+                };
+                let p_bytes = read(&pair.current, &layouts.0)?;
+                let b_bytes = read(&pair.baseline, &layouts.1)?;
+                let io = started.elapsed();
+                let started = Instant::now();
+                hashers.0.update(&p_bytes);
+                hashers.1.update(&b_bytes);
+                let (p_data, b_data) = decode_copy(&p_bytes, &b_bytes)?;
+                (p_data, b_data, io, started.elapsed())
+            }
+        };
+        timings.io += io;
+        timings.decode += decode;
+        if let Some(progress) = progress {
+            progress.advance(&name, 2 * (len * size) as u64);
+        }
+
+        let started = Instant::now();
+        if let Some(n) = &comparison.normalisation {
+            n.apply(p_data.to_mut(), b_data.to_mut())?;
+        }
+        if let Some(f) = diff_file {
+            f.write(&p_data, &b_data)?;
+        }
+        if let Some(h) = heatmap {
+            h.add(offset, &p_data, &b_data);
+        }
+        let (d, w, h, ks) = compare(&p_data, &b_data, offset);
+        diffs = diffs.max(d);
+        histogram = histogram.merge(h);
+        distributions = match (distributions, ks) {
+            (Some(all), Some(ks)) => Some(all.merge(ks)),
+            (all, ks) => all.or(ks),
+        };
+        if let (Some(all), Some(w), Some(n)) = (&mut worst, w, comparison.top) {
+            *all = top::merge(std::mem::take(all), w, n);
+        }
+        let metrics = started.elapsed();
+        timings.metrics += metrics;
+        if verbosity >= Verbosity::Chunks {
+            writeln!(
+                out,
+                "  Floats {}-{} of {:?}: max diff {:e}, RMS {:e}; read {:.3} s, decode {:.3} s, compare {:.3} s",
+                offset,
+                offset + len - 1,
+                p,
+                d.max_abs,
+                d.rms(),
+                io.as_secs_f64(),
+                decode.as_secs_f64(),
+                metrics.as_secs_f64()
+            )?;
+        }
+    }
+    for path in [&pair.current, &pair.baseline] {
+        reader
+            .close(path)
+            .map_err(|e| Error::Shape(format!("{:?} is corrupt or unreadable: {}", path, e)))?;
+    }
+    Ok((
+        (diffs, worst, histogram, distributions),
+        (hashers.0.finish(), hashers.1.finish()),
+    ))
+}
+
+/// Print the statistics of the differences found in a file, for --verbose.
+fn print_file_statistics(
+    p: &Path,
+    diffs: &Differences,
+    comparison: &Comparison,
+    per_float: bool,
+    out: &mut String,
+) -> Result<(), anyhow::Error> {
+    if let Some((current, baseline)) = diffs.hashes {
+        writeln!(
+            out,
+            "XXH3 for {:?}: current {:016x}, baseline {:016x}",
+            p, current, baseline
+        )?;
+    }
+    if comparison.sampling.is_some() {
+        writeln!(
+            out,
+            "Biggest difference for {:?} (in {} of {} blocks): {}",
+            p, diffs.sampled_blocks, diffs.total_blocks, diffs.max_abs
+        )?;
+    } else {
+        writeln!(out, "Biggest difference for {:?}: {}", p, diffs.max_abs)?;
+    }
+    writeln!(
+        out,
+        "Statistics for {:?}: mean {:e}, RMS {:e}, std dev {:e}, median {:e}, 95th percentile {:e}, 99th percentile {:e}, fraction above tolerance {:e}",
+        p,
+        diffs.mean_signed(),
+        diffs.rms(),
+        diffs.std_dev(),
+        diffs.median,
+        diffs.p95,
+        diffs.p99,
+        diffs.frac_exceeding()
+    )?;
+    if let Some((statistic, p_value)) = diffs.ks {
+        writeln!(
+            out,
+            "Kolmogorov-Smirnov test for {:?}: statistic {:e}, p-value {:e}",
+            p, statistic, p_value
+        )?;
+    }
+    if per_float {
+        writeln!(
+            out,
+            "Biggest relative difference for {:?}: {:e}; in ULPs: {}; floats outside every tolerance: {}",
+            p, diffs.max_rel, diffs.max_ulps, diffs.violations
+        )?;
+    }
+    if diffs.current_nans + diffs.baseline_nans + diffs.current_infs + diffs.baseline_infs > 0 {
+        writeln!(
+            out,
+            "Non-finite floats in {:?}: NaNs {} current, {} baseline; infinities {} current, {} baseline",
+            p, diffs.current_nans, diffs.baseline_nans, diffs.current_infs, diffs.baseline_infs
+        )?;
+    }
+    if diffs.skipped > 0 {
+        writeln!(
+            out,
+            "Floats left out of {:?} as flagged or excluded: {}",
+            p, diffs.skipped
+        )?;
+    }
+    if comparison.complex {
+        writeln!(
+            out,
+            "Biggest amplitude difference for {:?}: {}; phase difference: {} degrees",
+            p, diffs.max_amp, diffs.max_phase
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the largest differences found in a file (see --top), and where they
+/// are.
+fn print_worst(
+    pair: &FilePair,
+    worst: &[top::Worst],
+    floats: u64,
+    comparison: &Comparison,
+    out: &mut String,
+) -> Result<(), anyhow::Error> {
+    let p = &pair.name;
+    let channels = comparison.channels.as_ref();
+    let rows = channels.map(|c| floats / c.floats_per_band());
+    let layout = top::Layout {
+        fine_channels: channels.map(|c| c.fine_channels),
+        floats_per_channel: channels.map_or(0, |c| c.floats_per_channel),
+        weights: comparison.weights,
+        baselines: comparison.baselines.or_else(|| comparison.baselines(rows?)),
+        tile_names: comparison
+            .metafits
+            .as_ref()
+            .map(|m| m.tile_names.as_slice()),
+        frequencies: match (&comparison.metafits, channels) {
+            (Some(m), Some(c)) => coarse_band(pair, "frequencies")
+                .ok()
+                .and_then(|band| (0..c.fine_channels).map(|f| m.frequency(band, f)).collect()),
+            _ => None,
+        },
+    };
+    // Indices are within the whole file, even with --bands.
+    let offset = pair.band.map_or(0, |(band, len)| band * len);
+    writeln!(out, "Largest differences for {:?}:", p)?;
+    for w in worst {
+        let location = layout.describe(w.index);
+        writeln!(
+            out,
+            "  {:>12} {:>14e} (current {:e}, baseline {:e}){}{}",
+            offset + w.index,
+            (w.current - w.baseline).abs(),
+            w.current,
+            w.baseline,
+            if location.is_empty() { "" } else { ": " },
+            location
+        )?;
+    }
+    Ok(())
+}
+
+/// Compare a pair of files as floats, returning the biggest differences found.
+fn compare_pair(
+    pair: &FilePair,
+    comparison: &Comparison,
+    reader: &Reader,
+    verbosity: Verbosity,
+    out: &mut String,
+) -> Result<Differences, anyhow::Error> {
+    let estimate_sigma = comparison.estimate_sigma;
+    let channels = comparison.channels.as_ref();
+    let p = &pair.name;
+    let verbose = verbosity >= Verbosity::Files;
+    if verbose {
+        writeln!(out, "Checking {:?} ...", p)?;
+    }
+    let progress = comparison.progress.as_ref();
+    let name = p.display().to_string();
+    if let Some(progress) = progress {
+        // Both files are read.
+        let bytes = match pair.band {
+            Some((_, len)) => len * comparison.dtype.size() as u64,
+            None => compress::len(&pair.current)?,
+        };
+        progress.start(&name, 2 * bytes);
+    }
+
+    if comparison.exact && pair.band.is_none() {
+        if let Some(diffs) = compare_identical(pair, comparison, reader, verbose, out)? {
+            return Ok(diffs);
+        }
+    }
+
+    let streamed = comparison.streams();
+
+    let mut timings = Timings::default();
+    let stamps = (Stamp::of(&pair.current)?, Stamp::of(&pair.baseline)?);
+    let (mut whole, floats, sampled_blocks, total_blocks, mut hashes, corrupt, size_mismatch) =
+        if streamed {
+            let len = |path: &Path| -> Result<u64, anyhow::Error> {
+                Ok(match pair.band {
+                    Some((_, len)) => len,
+                    None => compress::len(path)? / comparison.dtype.size() as u64,
+                })
+            };
+            let (p_len, b_len) = (len(&pair.current)?, len(&pair.baseline)?);
+            if p_len == 0 {
+                bail!(Error::Shape(format!(
+                    "{:?} didn't contain any data",
+                    pair.current
+                )));
+            }
+            if b_len == 0 {
+                bail!(Error::Shape(format!(
+                    "{:?} didn't contain any data",
+                    pair.baseline
+                )));
+            }
+            if p_len != b_len && !comparison.overlap {
+                bail!(Error::Shape(format!(
+                    "{:?} and {:?} have different amounts of data",
+                    pair.current, pair.baseline
+                )));
+            }
+            (None, p_len.min(b_len), 0, 0, None, false, p_len != b_len)
+        } else {
+            let loaded = read_whole(pair, comparison, reader, &mut timings)?;
+            if let Some(progress) = progress {
+                progress.read_all(&name);
+            }
+            let floats = loaded.current.len() as u64;
+            (
+                Some((loaded.current, loaded.baseline)),
+                floats,
+                loaded.sampled_blocks,
+                loaded.total_blocks,
+                loaded.hashes,
+                loaded.corrupt,
+                loaded.size_mismatch,
+            )
+        };
+    let started = Instant::now();
+    if comparison.complex && !floats.is_multiple_of(2) {
+        bail!(
+            "{:?} has {} floats, which can't be paired into complex numbers",
+            pair.current,
+            floats
+        );
+    }
+
+    let noise = match &whole {
+        Some((_, b_data)) if comparison.needs_noise() => Some(estimate_noise(b_data)),
+        _ => None,
+    };
+    let sigma = if estimate_sigma { noise } else { None };
+    // The residuals are the difference of two noisy data sets, so their noise
+    // is sqrt(2) times that of either.
+    let chi2_sigma = if comparison.chi_squared {
+        comparison
+            .chi2_sigma
+            .or_else(|| noise.map(|n| n as f64 * std::f64::consts::SQRT_2))
+    } else {
+        None
+    };
+
+    if let Some(c) = channels {
+        check_channels(pair, floats, c)?;
+    }
+
+    if let (Some((_, s)), Some(c), Some((p_data, _))) =
+        (&comparison.solutions, channels, &mut whole)
+    {
+        if c.floats_per_channel != 8 {
+            bail!("--apply-solutions needs 4 complex polarisations (8 floats) per fine channel");
+        }
+        let fine_channels = c.fine_channels as usize;
+        // The solutions may be for just this coarse band, or for all of them.
+        let first_channel = if s.channels() == fine_channels {
+            0
+        } else {
+            (coarse_band(pair, "solutions")? as usize - 1) * fine_channels
+        };
+        s.apply(p_data, fine_channels, first_channel, comparison.autos)?;
+    }
+
+    let flags = match (&comparison.mwaf_dir, channels) {
+        (Some(dir), Some(c)) => {
+            let band = coarse_band(pair, "mwaf file")?;
+            let path = mwaf::find(dir, band)?;
+            let flags = mwaf::read(&path)?;
+            let rows = floats / c.floats_per_band();
+            if flags.channels != c.fine_channels || flags.rows != rows {
+                bail!(
+                    "{:?} has {} fine channels and {} rows, but {:?} has {} and {}",
+                    path,
+                    flags.channels,
+                    flags.rows,
+                    pair.current,
+                    c.fine_channels,
+                    rows
+                );
+            }
+            Some(flags)
+        }
+        _ => None,
+    };
+    let weights = match &comparison.weights_dir {
+        Some(dir) => {
+            let path = weights::find(dir, coarse_band(pair, "weight file")?)?;
+            let w = weights::read(&path)?;
+            if 2 * w.len() as u64 != floats {
+                bail!(
+                    "{:?} has {} weights, but {:?} has {} complex visibilities",
+                    path,
+                    w.len(),
+                    pair.current,
+                    floats / 2
+                );
+            }
+            Some(w)
+        }
+        None => None,
+    };
+    let timesteps = match (comparison.timesteps, channels) {
+        (Some(t), _) => Some(t as u64),
+        (None, Some(c)) if c.floats_per_band() > 0 => {
+            let rows = floats / c.floats_per_band();
+            comparison.baselines(rows).map(|b| rows / b)
+        }
+        _ => None,
+    };
+    let region = region::Region::new(&comparison.ranges, floats, timesteps, channels)?;
+    let skip = |i: usize| {
+        if !region.contains(i) {
+            return true;
+        }
+        let excluded = match channels {
+            Some(c) => {
+                c.excluded(i)
+                    || flags
+                        .as_ref()
+                        .is_some_and(|f| f.flagged(i as u64 / c.floats_per_band(), c.channel(i)))
+            }
+            None => false,
+        };
+        excluded || comparison.mask_weights && weights.as_ref().is_some_and(|w| w[i / 2] <= 0.0)
+    };
+
+    let trend = match (comparison.timesteps, &whole) {
+        (Some(timesteps), Some((p_data, b_data))) => {
+            if timesteps == 0 || !p_data.len().is_multiple_of(timesteps) {
+                bail!(
+                    "{:?} has {} floats, which can't be split into {} timesteps",
+                    pair.current,
+                    p_data.len(),
+                    timesteps
+                );
+            }
+            let rms = temporal::rms_per_timestep(p_data, b_data, timesteps, skip);
+            Some(temporal::trend(&rms))
+        }
+        _ => None,
+    };
+
+    let weighted_rms = match (&weights, &whole) {
+        (Some(w), Some((p_data, b_data))) => Some(weights::weighted_rms(p_data, b_data, w, skip)),
+        _ => None,
+    };
+    timings.metrics += started.elapsed();
+
+    // Compare `p_data` and `b_data`, which start `offset` floats into the
+    // data.
+    let defaults = Tolerances {
+        tolerance: comparison.tolerance,
+        rel_tolerance: comparison.rel_tolerance,
+        ulps: comparison.ulps,
+        nan: comparison.nan,
+    };
+    let tolerances = tolerances(&comparison.rules, p, defaults);
+    if let (Some(t), true) = (tolerances, verbose) {
+        writeln!(
+            out,
+            "Tolerances for {:?}: absolute {:e}{}{}",
+            p,
+            t.tolerance,
+            t.rel_tolerance
+                .map_or(String::new(), |r| format!(", relative {:e}", r)),
+            t.ulps.map_or(String::new(), |u| format!(", {} ULPs", u))
+        )?;
+    }
+    let tol = tolerances.unwrap_or(defaults);
+    let per_float = tol.rel_tolerance.is_some()
+        || tol.ulps.is_some()
+        || comparison.imag_tolerance.is_some()
+        || !comparison.rules.is_empty();
+    // The absolute tolerance of the float at an index: imaginary floats are
+    // the second of each visibility.
+    let floats_per_visibility = if comparison.weights { 3 } else { 2 };
+    let absolute = |i: usize| match comparison.imag_tolerance {
+        Some(t) if i % floats_per_visibility == 1 => t,
+        _ => tol.tolerance,
+    };
+    let compare = |p_data: &[f32], b_data: &[f32], offset: usize| {
+        let worst = comparison.top.map(|n| {
+            let mut worst = top::worst(p_data, b_data, n, |i| skip(offset + i));
+            worst.iter_mut().for_each(|w| w.index += offset as u64);
+            worst
+        });
+        let fold = |range: std::ops::Range<usize>| {
+            range.fold(Differences::default(), |acc, i| {
+                if skip(offset + i) {
+                    return acc.max(Differences {
+                        skipped: 1,
+                        ..Differences::default()
+                    });
+                }
+                let (p, b) = (p_data[i], b_data[i]);
+                let factor = channels.map_or(1.0, |c| c.tolerance_factor(offset + i));
+                let absolute = absolute(offset + i) * factor;
+                let non_finite = Differences {
+                    current_nans: p.is_nan() as u64,
+                    baseline_nans: b.is_nan() as u64,
+                    current_infs: p.is_infinite() as u64,
+                    baseline_infs: b.is_infinite() as u64,
+                    ..Differences::default()
+                };
+                // NaNs are left out of the statistics; they're judged by
+                // the NaN policy instead.
+                let (d, passed) = match tol.judge(p, b, absolute) {
+                    Judgement::Nan { fails } => {
+                        return acc.max(Differences {
+                            nan_failures: fails as u64,
+                            ..non_finite
+                        })
+                    }
+                    Judgement::Compared { difference, passed } => (difference, passed),
+                };
+                let diff = d.abs;
+                // Each visibility is compared with its real part.
+                let (amp, phase) = if comparison.complex && (offset + i).is_multiple_of(2) {
+                    complex_differences((p, p_data[i + 1]), (b, b_data[i + 1]))
+                } else {
+                    (0.0, 0.0)
+                };
+                acc.max(Differences {
+                    max_abs: diff,
+                    max_rel: d.rel,
+                    max_scaled: diff / factor,
+                    sum_abs: diff as f64,
+                    sum_signed: if diff == 0.0 { 0.0 } else { (p - b) as f64 },
+                    sum_sq: (diff as f64).powi(2),
+                    above_tolerance: (diff > absolute) as u64,
+                    max_ulps: d.ulps,
+                    max_amp: amp,
+                    max_amp_scaled: amp / factor,
+                    max_phase: phase,
+                    violations: (per_float && !passed) as u64,
+                    chi2: chi2_sigma.map_or(0.0, |s| (diff as f64 / s).powi(2)),
+                    samples: 1,
+                    ..non_finite
+                })
+            })
+        };
+        let diffs = parallel::reduce(p_data.len(), comparison.threads, fold, Differences::max);
+        let histogram = parallel::reduce(
+            p_data.len(),
+            comparison.threads,
+            |range| {
+                range
+                    .filter(|&i| !skip(offset + i))
+                    .filter(|&i| !p_data[i].is_nan() && !b_data[i].is_nan())
+                    .fold(Histogram::default(), |mut h, i| {
+                        h.add(abs_difference(p_data[i], b_data[i]));
+                        h
+                    })
+            },
+            Histogram::merge,
+        );
+        let distributions = comparison.ks.then(|| {
+            parallel::reduce(
+                p_data.len(),
+                comparison.threads,
+                |range| {
+                    range
+                        .filter(|&i| !skip(offset + i))
+                        .filter(|&i| !p_data[i].is_nan() && !b_data[i].is_nan())
+                        .fold(Distributions::default(), |mut d, i| {
+                            d.add(p_data[i], b_data[i]);
+                            d
+                        })
+                },
+                Distributions::merge,
+            )
+        });
+        (diffs, worst, histogram, distributions)
+    };
+
+    let mut diff_file = match &comparison.write_diff {
+        Some(dir) => Some(DiffFile::create(dir, &pair.name)?),
+        None => None,
+    };
+    let mut heatmap = heatmap(pair, floats, comparison)?;
+    let (mut diffs, worst, histogram, distributions) = match &whole {
+        Some((p_data, b_data)) => {
+            let started = Instant::now();
+            if let Some(f) = &mut diff_file {
+                f.write(p_data, b_data)?;
+            }
+            if let Some(h) = &mut heatmap {
+                h.add(0, p_data, b_data);
+            }
+            let compared = compare(p_data, b_data, 0);
+            timings.metrics += started.elapsed();
+            compared
+        }
+        None => {
+            let (compared, streamed) = compare_streamed(
+                pair,
+                floats,
+                comparison,
+                reader,
+                compare,
+                &mut diff_file,
+                &mut heatmap,
+                &mut timings,
+                verbosity,
+                out,
+            )?;
+            hashes = Some(streamed);
+            compared
+        }
+    };
+    if let Some(f) = diff_file {
+        f.finish(diff_shape(floats, comparison))?;
+    }
+    if let Some(h) = heatmap {
+        h.write(&plot_path(pair, comparison), comparison.tolerance)?;
+    }
+    diffs.median = histogram.percentile(0.5, diffs.max_abs);
+    diffs.p95 = histogram.percentile(0.95, diffs.max_abs);
+    diffs.p99 = histogram.percentile(0.99, diffs.max_abs);
+    diffs.ks = distributions.and_then(|d| d.test());
+    stamps.0.check_unchanged(&pair.current)?;
+    stamps.1.check_unchanged(&pair.baseline)?;
+    diffs.sampled_blocks = sampled_blocks;
+    diffs.total_blocks = total_blocks;
+    diffs.hashes = hashes;
+    diffs.corrupt = corrupt;
+    diffs.size_mismatches = size_mismatch as u64;
+    diffs.timings = timings;
+    if verbosity >= Verbosity::Chunks {
+        writeln!(
+            out,
+            "Timings for {:?}: read {:.3} s, decode {:.3} s, compare {:.3} s",
+            p,
+            timings.io.as_secs_f64(),
+            timings.decode.as_secs_f64(),
+            timings.metrics.as_secs_f64()
+        )?;
+    }
+    if verbose {
+        print_file_statistics(p, &diffs, comparison, per_float, out)?;
+    }
+    if let Some(sigma) = sigma {
+        diffs.max_sigmas = if diffs.max_scaled == 0.0 {
+            0.0
+        } else {
+            diffs.max_scaled / sigma
+        };
+        if verbose {
+            writeln!(
+                out,
+                "Estimated noise for {:?}: {} (biggest difference is {} sigma)",
+                p, sigma, diffs.max_sigmas
+            )?;
+        }
+    }
+    if let Some(worst) = worst {
+        print_worst(pair, &worst, floats, comparison, out)?;
+    }
+    if let Some(rms) = weighted_rms {
+        diffs.weighted_rms = rms;
+        if verbose {
+            writeln!(out, "Weighted residual RMS for {:?}: {:e}", p, rms)?;
+        }
+    }
+    if let Some(t) = trend {
+        diffs.rms_growth = t.growth;
+        if verbose {
+            writeln!(
+                out,
+                "Residual RMS over time for {:?}: mean {:e}, slope {:e} per timestep ({:+.1}% over the observation), scatter {:.1}%",
+                p,
+                t.mean,
+                t.slope,
+                100.0 * t.growth,
+                100.0 * t.relative_std
+            )?;
+        }
+    }
+    if let (Some(s), true) = (chi2_sigma, verbose) {
+        writeln!(
+            out,
+            "Reduced chi-squared for {:?}: {} (residual noise {})",
+            p,
+            diffs.reduced_chi2(),
+            s
+        )?;
+    }
+    Ok(diffs)
+}
+
+fn main() {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let plugin = plugin::split(&args);
+    let matches = Opt::clap()
+        .get_matches_from_safe(&args[..plugin.unwrap_or(args.len())])
+        .unwrap_or_else(|e| usage::exit_with_usage_error(e));
+    let options = Opt::from_clap(&matches);
+    // Whether the run ends with a summary line, and if so, on stderr.
+    let summary = options
+        .summarised()
+        .filter(|_| plugin.is_none())
+        .map(|o| o.reports.format == "json");
+    if let Err(e) = run(options, &matches, &args, plugin) {
+        eprintln!("Error: {:?}", e);
+        let code = exit_code_of(e.chain());
+        if let Some(json) = summary {
+            // Only the first line, so the summary stays on one.
+            let message = e.to_string();
+            print_summary(
+                &format!(
+                    "HYPERDRIVE-CHECKS result=ERROR exit_code={} error={}",
+                    code,
+                    summary_value(message.lines().next().unwrap_or_default())
+                ),
+                json,
+            );
+        }
+        std::process::exit(code);
+    }
+}
+
+/// The comparison that the command-line arguments `args` (including the
+/// executable's name) ask for, as a coordinator's workers are sent them.
+fn comparison_from_args(args: &[String]) -> Result<Comparison, anyhow::Error> {
+    let matches = Opt::clap().get_matches_from_safe(args)?;
+    match Opt::from_clap(&matches).into_comparison(&matches) {
+        Some((mut options, matches)) => {
+            apply_implied_options(&mut options, matches)?;
+            options.comparison()
+        }
+        None => bail!("{:?} doesn't compare any files", args),
+    }
+}
+
+/// Apply the options implied by others: those of the --preset, unless given
+/// in `matches`, and --quiet with --format json. Fails if
+/// --suggested-config is given without --suggest-tolerance.
+fn apply_implied_options(
+    options: &mut CompareOpt,
+    matches: &ArgMatches,
+) -> Result<(), anyhow::Error> {
+    if options.suggested_config.is_some() && !options.suggest_tolerance {
+        bail!("--suggested-config needs --suggest-tolerance (or the suggest subcommand)");
+    }
+    if let Some(preset) = options.preset {
+        preset.apply(options, matches)?;
+    }
+    if options.reports.format == "json" {
+        // Only the report goes to stdout.
+        options.quiet = true;
+    }
+    Ok(())
+}
+
+fn run(
+    options: Opt,
+    matches: &ArgMatches,
+    args: &[OsString],
+    plugin: Option<usize>,
+) -> Result<(), anyhow::Error> {
+    let Opt { cmd, diff: mut top } = options;
+    let cmd = match (cmd, plugin) {
+        (_, Some(i)) => {
+            apply_implied_options(&mut top.compare, matches)?;
+            let name = args[i].to_string_lossy();
+            return plugin::run(&name, &args[i + 1..], &top.baseline_dir, &top.compare);
+        }
+        (None, None) => return diff(top, matches, args),
+        (Some(cmd), None) => cmd,
+    };
+    let (name, sub) = matches.subcommand();
+    if let Command::External(args) = cmd {
+        // Only reached when a baseline directory is given first.
+        apply_implied_options(&mut top.compare, matches)?;
+        let rest: Vec<OsString> = args[1..].iter().map(OsString::from).collect();
+        return plugin::run(&args[0], &rest, &top.baseline_dir, &top.compare);
+    }
+    // Otherwise, they'd be silently ignored.
+    if args.get(1).and_then(|a| a.to_str()) != Some(name) {
+        bail!("Options for {} go after it, not before", name);
+    }
+    let sub = sub.expect("clap gives every subcommand its matches");
+    match cmd {
+        Command::Diff(options) => diff(options, sub, args),
+        Command::Suggest(mut options) => {
+            options.compare.suggest_tolerance = true;
+            diff(options, sub, args)
+        }
+        Command::DiffDirs(options) => diff_dirs(options, sub, args),
+        Command::Worker(options) => distributed::work(
+            &options.coordinator,
+            &options.read.reader(options.quiet),
+            options::verbosity(options.quiet, options.verbose),
+        ),
+        Command::Dashboard(options) => {
+            let files: Vec<&Path> = options.history_files.iter().map(|p| p.as_path()).collect();
+            dashboard::write_dashboard(&files, &options.output)?;
+            if !options.quiet {
+                println!("Wrote {}", options.output.display());
+            }
+            Ok(())
+        }
+        Command::History(options) => print_history(&options),
+        Command::Run(options) => {
+            suite::run(&options.suite, options.junit.as_deref(), options.quiet)
+        }
+        Command::Info(options) => {
+            // Always print this; it's the whole point of the command.
+            println!("{}", info::info(&options.python));
+            Ok(())
+        }
+        Command::Extract(options) => extract_piece(&options),
+        Command::Snapshot(options) => snapshot_outputs(&options),
+        Command::Archive(options) => {
+            let output = options
+                .output
+                .clone()
+                .unwrap_or_else(|| archive::default_name(&options.dir));
+            archive::archive(&options.dir, &output, options.quiet)
+        }
+        Command::Restore(options) => archive::restore(
+            &options.archive,
+            &options.dir,
+            options.verify_only,
+            options.quiet,
+        ),
+        Command::UpgradeReport(options) => upgrade_report(&options),
+        Command::External(_) => unreachable!("plugins were run above"),
+    }
+}
+
+/// Print the trends of a --csv or --history file, exiting with 1 if anything
+/// is creeping upward.
+fn print_history(options: &HistoryOpt) -> Result<(), anyhow::Error> {
+    if options.last == 0 {
+        bail!("--last must be at least 1");
+    }
+    if trends::print_trends(&options.file, options.last, options.creep, options.quiet)? {
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+fn extract_piece(options: &ExtractOpt) -> Result<(), anyhow::Error> {
+    extract::extract(
+        &options.current,
+        &options.baseline,
+        &options.output,
+        &extract::Shape {
+            timesteps: options.timesteps,
+            fine_channels: options.fine_channels as usize,
+            floats_per_channel: options.floats_per_channel as usize,
+            dtype: options.dtype,
+            endian: options.endian,
+        },
+        &extract::Selection {
+            timesteps: options.timestep_range.clone(),
+            baselines: options.baseline_range.clone(),
+            channels: options.channel_range.clone(),
+        },
+        &options.read.reader(options.quiet),
+        options.quiet,
+    )
+}
+
+fn snapshot_outputs(options: &SnapshotOpt) -> Result<(), anyhow::Error> {
+    let patterns = if options.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.outputs.clone()
+    };
+    snapshot::snapshot(
+        &options.dir,
+        &patterns,
+        options.verify.then_some((options.dtype, options.endian)),
+        options.compress,
+        snapshot::Producer {
+            hyperdrive_version: options
+                .hyperdrive_version
+                .clone()
+                .or_else(environment::hyperdrive_version)
+                .unwrap_or_else(|| "unknown".to_string()),
+            hyperdrive_commit: options
+                .hyperdrive_commit
+                .clone()
+                .or_else(environment::hyperdrive_commit),
+            cuda_version: options
+                .cuda_version
+                .clone()
+                .or_else(|| environment::nvidia_driver().and_then(|d| d.cuda)),
+            timings: perf::timings(&options.hyperdrive_log, options.wall_time)?,
+        },
+        options.quiet,
+    )
+}
+
+fn upgrade_report(options: &UpgradeReportOpt) -> Result<(), anyhow::Error> {
+    let upgraded = format!("{}\n", json::read(&options.report)?.render());
+    match &options.output {
+        Some(path) => std::fs::write(path, upgraded)
+            .map_err(|e| anyhow!("Couldn't write {:?}: {}", path, e))?,
+        None => print!("{}", upgraded),
+    }
+    Ok(())
+}
+
+/// Compare the outputs in the PWD (or those of the --runs) against the
+/// baseline, or each --baseline. `matches` are those the options were parsed
+/// from, and `args` the command-line arguments.
+fn diff(
+    mut options: DiffOpt,
+    matches: &ArgMatches,
+    args: &[OsString],
+) -> Result<(), anyhow::Error> {
+    apply_implied_options(&mut options.compare, matches)?;
+    if options.watch {
+        return watch::watch(&options, args);
+    }
+    let quiet = options.compare.quiet;
+    let reader = options.compare.read.reader(quiet);
+    if !options.runs.is_empty() {
+        return ensemble::compare_runs(&options, &reader);
+    }
+    if !options.baseline_sets.is_empty() {
+        return baseline_sets::compare_sets(&options, &reader);
+    }
+
+    let baseline = match (&options.baseline_window, &options.baseline_url) {
+        (Some(_), _) => window::choose_baseline(&options, &reader)?,
+        (None, Some(url)) => remote::fetch(url, quiet)?,
+        (None, None) => container::resolve(&options.baseline_dir, quiet)?,
+    };
+    // The provenance of the baseline directory, if it has any.
+    let manifest = Manifest::read(&baseline)?;
+    match &manifest {
+        Some(m) => {
+            if !quiet {
+                println!("Baseline {}", m.describe());
+            }
+            m.check(&options.expect_baseline)?;
+            if options.compare.dry_run {
+                m.verify_sizes(&baseline)?;
+            }
+        }
+        None if !options.expect_baseline.is_empty() => bail!(
+            "{:?} has no {}, so --expect-baseline can't be checked",
+            baseline,
+            manifest::MANIFEST
+        ),
+        None => (),
+    }
+    let pairs = band_file_pairs(&options, &baseline, true)?;
+    compare(
+        pairs,
+        manifest,
+        &options.baseline_dir,
+        &options.compare,
+        args,
+        &reader,
+    )
+}
+
+/// Compare the files of one directory against those of another.
+fn diff_dirs(
+    mut options: DiffDirsOpt,
+    matches: &ArgMatches,
+    args: &[OsString],
+) -> Result<(), anyhow::Error> {
+    apply_implied_options(&mut options.compare, matches)?;
+    let reader = options.compare.read.reader(options.compare.quiet);
+    let pairs = dir_file_pairs(&options.a, &options.b, options.compare.quiet)?;
+    compare(pairs, None, &options.b, &options.compare, args, &reader)
+}
+
+/// Compare each pair of files, then judge and report the differences (or
+/// with --dry-run, --validate-only or --suggest-tolerance, do only that).
+/// `manifest` is that of `baseline_dir`, if it has one, and `args` the
+/// command-line arguments, for a coordinator's workers.
+fn compare(
+    pairs: Vec<FilePair>,
+    manifest: Option<Manifest>,
+    baseline_dir: &Path,
+    options: &CompareOpt,
+    args: &[OsString],
+    reader: &Reader,
+) -> Result<(), anyhow::Error> {
+    // Each dataset of HDF5 files is compared as a pair of files of its own.
+    // The dumps of the datasets are removed when this is dropped.
+    let (pairs, dumps) = expand_hdf5(pairs, options)?;
+    if options.validate_only {
+        return validate(&pairs, options, reader);
+    }
+
+    // Read these before any comparisons, in case they're malformed.
+    let exemptions = verdict::Exemptions::read(options)?;
+    let sign_key = match (&options.reports.sign_key, &options.config) {
+        (Some(key), _) => Some(key.clone()),
+        (None, Some(path)) => config::read_sign_key(path)?,
+        (None, None) => None,
+    };
+
+    let (mut pairs, broken, mut comparison) = prepare(pairs, options)?;
+    if options.dry_run {
+        return print_dry_run(&pairs, options);
+    }
+    let diffs = compare_all(&pairs, &mut comparison, &broken, options, args, reader)?;
+    if diffs.len() < pairs.len() {
+        if !options.quiet {
+            println!(
+                "Stopped at the first failure (--fail-fast); {} of {} files weren't compared.",
+                pairs.len() - diffs.len(),
+                pairs.len()
+            );
+        }
+        pairs.truncate(diffs.len());
+    }
+    let obsid = match (options.obsid, &options.metafits) {
+        (Some(obsid), _) => Some(obsid),
+        (None, Some(metafits)) => Some(obsid::from_metafits(metafits)?),
+        (None, None) => pairs
+            .first()
+            .and_then(|p| obsid::from_path(&p.current).or_else(|| obsid::from_path(&p.baseline))),
+    };
+    if let (Some(obsid), false) = (obsid, options.quiet) {
+        println!("Observation: {}", obsid);
+    }
+    let io_retries = reader.used.load(AtomicOrdering::Relaxed);
+    if io_retries > 0 && !options.quiet {
+        println!("Transient IO errors retried: {}", io_retries);
+    }
+    let overall = diffs
+        .iter()
+        .fold(Differences::default(), |acc, &d| acc.max(d));
+
+    if options.suggest_tolerance {
+        return suggest_tolerances(options, &pairs, &diffs, &overall);
+    }
+
+    verdict::print_statistics(&overall, options);
+    let outcome = verdict::Outcome {
+        pairs: &pairs,
+        diffs: &diffs,
+        overall,
+        obsid,
+        io_retries,
+    };
+    let verdict = verdict::judge(
+        &outcome,
+        &exemptions,
+        manifest.as_ref(),
+        comparison.sampling.is_some(),
+        options,
+    )?;
+    let reports = verdict::write_reports(
+        &outcome,
+        &verdict,
+        baseline_dir,
+        manifest,
+        sign_key.as_deref(),
+        options,
+    )?;
+    if options.timings && !options.quiet {
+        verdict::print_timings(&outcome, reports);
+    }
+    verdict::print_verdict(&outcome, &verdict, options);
+    if verdict.failed {
+        // Exiting doesn't run destructors.
+        drop(dumps);
+        std::process::exit(verdict::exit_code(&overall));
+    }
+    Ok(())
+}
+
+/// With --input-format hdf5 (or if every current file looks like HDF5), dump
+/// each dataset of each pair of files into a pair of raw files of its own,
+/// removed when the returned dumps are dropped.
+fn expand_hdf5(
+    pairs: Vec<FilePair>,
+    options: &CompareOpt,
+) -> Result<(Vec<FilePair>, Option<hdf5::Dumps>), anyhow::Error> {
+    let hdf5 = match options.input_format.as_deref() {
+        Some("hdf5") => true,
+        Some(_) => false,
+        None => !pairs.is_empty() && pairs.iter().all(|p| hdf5::is_hdf5(&p.current)),
+    };
+    if !hdf5 {
+        return Ok((pairs, None));
+    }
+    let (pairs, dumps) = hdf5::expand(
+        pairs,
+        &options.datasets,
+        &options.python,
+        options.dtype,
+        options.endian,
+    )?;
+    Ok((pairs, Some(dumps)))
+}
+
+/// Check that every file, current and baseline, decodes as whole, finite
+/// values (see --validate-only).
+fn validate(
+    pairs: &[FilePair],
+    options: &CompareOpt,
+    reader: &Reader,
+) -> Result<(), anyhow::Error> {
+    let mut files = vec![];
+    for pair in pairs {
+        for path in [&pair.current, &pair.baseline] {
+            if !files.contains(path) {
+                files.push(path.clone());
+            }
+        }
+    }
+    validate::validate_all(
+        &files,
+        reader,
+        options.dtype,
+        options.endian,
+        options.allow_nan || options.nan_equal,
+        options.quiet,
+    )
+}
+
+/// Before reading any data, check the pairs of files (see [preflight]) and
+/// split them into --bands. Returns the pairs, the problems of those that
+/// can't be compared with --keep-going (which are reported, and fail, in
+/// their turn), and the comparison to make.
+#[allow(clippy::type_complexity)]
+fn prepare(
+    pairs: Vec<FilePair>,
+    options: &CompareOpt,
+) -> Result<(Vec<FilePair>, HashMap<PathBuf, Error>, Comparison), anyhow::Error> {
+    let mut broken: HashMap<PathBuf, Error> = HashMap::new();
+    if options.keep_going && !options.dry_run {
+        let problems = preflight_each(&pairs, options);
+        for (pair, mut problems) in pairs.iter().zip(problems) {
+            if !problems.is_empty() {
+                // Reported (and exiting) as the first problem.
+                let message = problems
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                broken.insert(pair.name.clone(), problems.remove(0).with_message(message));
+            }
+        }
+    } else {
+        preflight(&pairs, options)?;
+    }
+    let comparison = options.comparison()?;
+    if let Some(metafits) = &comparison.metafits {
+        check_layout(
+            pairs.iter().filter(|p| !broken.contains_key(&p.name)),
+            metafits,
+            &comparison,
+            options.bands,
+            options.quiet,
+        )?;
+    }
+    let pairs = match options.bands {
+        Some(bands) => {
+            let mut split = vec![];
+            for pair in pairs {
+                // Broken files can't be split.
+                if broken.contains_key(&pair.name) {
+                    split.push(pair);
+                } else {
+                    split.append(&mut split_bands(vec![pair], bands, options.dtype)?);
+                }
+            }
+            split
+        }
+        None => pairs,
+    };
+    Ok((pairs, broken, comparison))
+}
+
+/// List the pairs of files that would be compared (see --dry-run).
+fn print_dry_run(pairs: &[FilePair], options: &CompareOpt) -> Result<(), anyhow::Error> {
+    if options.quiet {
+        return Ok(());
+    }
+    for pair in pairs {
+        println!(
+            "Would compare {:?} with {:?}: {} {} values",
+            pair.name,
+            pair.baseline,
+            pair.bytes(&pair.current, options.dtype)? / options.dtype.size() as u64,
+            options.dtype.name()
+        );
+    }
+    println!(
+        "{} pair(s) of files look sane; nothing was compared.",
+        pairs.len()
+    );
+    Ok(())
+}
+
+/// Compare every pair of files, here or (with --coordinator) by workers,
+/// skipping those already done according to the --checkpoint. Returns the
+/// differences of each pair in turn, stopping after the first failure with
+/// --fail-fast. The pairs in `broken` fail without being compared.
+fn compare_all(
+    pairs: &[FilePair],
+    comparison: &mut Comparison,
+    broken: &HashMap<PathBuf, Error>,
+    options: &CompareOpt,
+    args: &[OsString],
+    reader: &Reader,
+) -> Result<Vec<Differences>, anyhow::Error> {
+    let (checkpoint, mut done) = match &options.checkpoint {
+        Some(path) if options.resume => {
+            let (c, done) = Checkpoint::resume(path, comparison.fingerprint())?;
+            (Some(c), done)
+        }
+        Some(path) => (
+            Some(Checkpoint::create(path, comparison.fingerprint())?),
+            HashMap::new(),
+        ),
+        None => (None, HashMap::new()),
+    };
+    let key = |pair: &FilePair| pair.name.display().to_string();
+    let todo: Vec<&FilePair> = pairs
+        .iter()
+        .filter(|p| !done.contains_key(&key(p)))
+        .collect();
+    if !done.is_empty() && !options.quiet {
+        println!(
+            "Resuming: {} of {} comparisons were already done",
+            pairs.len() - todo.len(),
+            pairs.len()
+        );
+    }
+
+    let jobs = match options.jobs {
+        Some(jobs) => jobs,
+        None if comparison.streams() => parallel::available(),
+        None => {
+            // Each job holds a pair of whole files in memory, both as read and
+            // as f32s.
+            let largest = todo
+                .iter()
+                .filter_map(|p| p.bytes(&p.current, options.dtype).ok())
+                .max()
+                .unwrap_or(0);
+            let floats = largest / options.dtype.size() as u64;
+            parallel::fitting(2 * (largest + floats * 4))
+        }
+    }
+    .clamp(1, todo.len().max(1));
+    // Cores not comparing files of their own help with each file.
+    comparison.threads = (parallel::available() / jobs).max(1);
+    comparison.progress = Progress::new(todo.len(), options.quiet);
+    let new_diffs = match &options.coordinator {
+        Some(address) => distributed::coordinate(
+            &todo,
+            address,
+            &args
+                .iter()
+                .map(|a| {
+                    a.to_str().map(String::from).ok_or_else(|| {
+                        anyhow!(
+                            "Can't distribute with the argument {:?}; it isn't unicode",
+                            a
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            checkpoint,
+            options.worker_timeout,
+            options.quiet,
+        )?,
+        None => compare_here(&todo, jobs, comparison, broken, checkpoint, options, reader)?,
+    };
+    let mut new_diffs = new_diffs.into_iter();
+    Ok(pairs
+        .iter()
+        // Everything not done already was just compared, in order, unless
+        // --fail-fast stopped early.
+        .map_while(|pair| done.remove(&key(pair)).or_else(|| new_diffs.next()))
+        .collect())
+}
+
+/// Compare the pairs of files `todo`, `jobs` at a time, printing what's found
+/// in order and recording each in the `checkpoint`.
+fn compare_here(
+    todo: &[&FilePair],
+    jobs: usize,
+    comparison: &Comparison,
+    broken: &HashMap<PathBuf, Error>,
+    mut checkpoint: Option<Checkpoint>,
+    options: &CompareOpt,
+    reader: &Reader,
+) -> Result<Vec<Differences>, anyhow::Error> {
+    let mut diffs = vec![];
+    let compared = parallel::for_each_ordered(
+        todo,
+        jobs,
+        |pair| {
+            let mut out = String::new();
+            let result = match broken.get(&pair.name) {
+                Some(problem) => Err(problem.clone().into()),
+                None => compare_pair(pair, comparison, reader, options.verbosity(), &mut out),
+            };
+            if let Some(progress) = &comparison.progress {
+                progress.finish(&pair.name.display().to_string());
+            }
+            let d = match result {
+                Ok(d) => d,
+                Err(e) if options.keep_going => {
+                    if !options.quiet {
+                        writeln!(out, "Couldn't compare {:?}: {}", pair.name, e)?;
+                    }
+                    Differences {
+                        unreadable: Some(exit_code_of(e.chain())),
+                        ..Differences::default()
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            Ok((d, out))
+        },
+        |i, (d, out)| {
+            match &comparison.progress {
+                Some(progress) => progress.suspend(|| print!("{}", out)),
+                None => print!("{}", out),
+            }
+            // Corrupt or missing files may have been fixed by the time of a
+            // resume.
+            if let (Some(c), false) = (&mut checkpoint, d.corrupt || d.unreadable.is_some()) {
+                c.record(&todo[i].name.display().to_string(), &d)?;
+            }
+            let stop = options.fail_fast && options.failed(&d);
+            diffs.push(d);
+            Ok(if stop {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            })
+        },
+    );
+    if let Some(progress) = &comparison.progress {
+        progress.clear();
+    }
+    compared?;
+    Ok(diffs)
+}
+
+/// Round `x` up to one significant figure, e.g. 6.4e-4 to 7e-4, for a
+/// tolerance that reads like one chosen by hand.
+fn round_up(x: f32) -> f32 {
+    if !x.is_finite() || x <= 0.0 {
+        return x;
+    }
+    let x = x as f64;
+    let unit = 10f64.powf(x.log10().floor());
+    ((x / unit).ceil() * unit) as f32
+}
+
+/// Report the smallest tolerances at which each file (and all of them) would
+/// pass, and recommend some: the largest differences with a safety margin, or
+/// the largest 99th percentile allowing the worst 1% of floats to exceed it.
+fn suggest_tolerances(
+    options: &CompareOpt,
+    pairs: &[FilePair],
+    diffs: &[Differences],
+    overall: &Differences,
+) -> Result<(), anyhow::Error> {
+    let margin = options.safety_margin;
+    // Relative tolerances are no help where a baseline float is zero.
+    let relative = |d: &Differences| Some(round_up(d.max_rel * margin)).filter(|r| r.is_finite());
+    println!("Minimum tolerances at which the comparison would pass:");
+    println!(
+        "{:<30} {:>14} {:>14} {:>14}",
+        "file", "absolute", "relative", "99th pct"
+    );
+    for (pair, d) in pairs.iter().zip(diffs.iter()) {
+        println!(
+            "{:<30} {:>14e} {:>14e} {:>14e}",
+            pair.name.display(),
+            d.max_scaled,
+            d.max_rel,
+            d.p99
+        );
+    }
+    println!(
+        "{:<30} {:>14e} {:>14e} {:>14e}",
+        "overall", overall.max_scaled, overall.max_rel, overall.p99
+    );
+
+    println!(
+        "Recommended, with a safety margin of {} times the largest differences:",
+        margin
+    );
+    let mut recommended = format!("--tolerance {:e}", round_up(overall.max_scaled * margin));
+    match relative(overall) {
+        Some(r) => recommended.push_str(&format!(" or --rel-tolerance {:e}", r)),
+        None => recommended
+            .push_str(" (some baseline floats are zero, so a relative tolerance won't do)"),
+    }
+    println!("  {}", recommended);
+    println!(
+        "Or, allowing the worst 1% of each file's floats to exceed it (from the 99th percentiles):"
+    );
+    println!(
+        "  --tolerance {:e} --max-frac-exceeding 0.01",
+        round_up(overall.p99 * margin)
+    );
+    if overall.nan_failures > 0 {
+        println!(
+            "Some files have NaNs, which no tolerance passes; see --allow-nan and --nan-equal."
+        );
+    }
+
+    if let Some(path) = &options.suggested_config {
+        let files: Vec<(String, f32, Option<f32>)> = pairs
+            .iter()
+            .zip(diffs.iter())
+            .map(|(pair, d)| {
+                (
+                    pair.name.display().to_string(),
+                    round_up(d.max_scaled * margin),
+                    relative(d),
+                )
+            })
+            .collect();
+        config::write(
+            path,
+            &format!(
+                "Suggested by {} --suggest-tolerance, with a safety margin of {}.",
+                env!("CARGO_BIN_NAME"),
+                margin
+            ),
+            &files,
+        )?;
+        println!(
+            "Wrote the recommended tolerances of each file to {:?}",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Print the summary line of a comparison, even with --quiet; to stderr if
+/// stdout is for the JSON report alone.
+fn print_summary(line: &str, json: bool) {
+    if json {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Quote a value in the summary line if it would otherwise be split.
+fn summary_value(s: &str) -> String {
+    if s.is_empty() || s.contains(char::is_whitespace) || s.contains('"') {
+        format!("\"{}\"", s.replace('"', "\\\""))
+    } else {
+        s.to_string()
+    }
+}
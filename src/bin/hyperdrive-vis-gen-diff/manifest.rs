@@ -0,0 +1,503 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The manifest.toml in a baseline directory, recording when and from what
+//! its files were created, so that a comparison against the wrong baseline
+//! set (e.g. one made by another hyperdrive version or on another GPU) can be
+//! spotted, or refused.
+//!
+//! Only the subset of TOML that is written here is read back: comments,
+//! `key = "string"`, `key = integer` and `key = float` lines, and `[[files]]`
+//! and `[[timings]]` tables.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::Error;
+
+// JSON's string escapes are all valid in TOML basic strings.
+use crate::hash;
+use crate::perf::Timing;
+
+/// The name of the manifest inside a baseline directory.
+pub(crate) const MANIFEST: &str = "manifest.toml";
+
+/// The fields that can be checked with --expect-baseline.
+const FIELDS: &[&str] = &[
+    "created",
+    "host",
+    "source",
+    "hyperdrive_version",
+    "hyperdrive_commit",
+    "cuda_version",
+];
+
+/// Where a set of baseline files came from.
+#[derive(Default)]
+pub(crate) struct Manifest {
+    /// UTC, formatted like "2020-06-09T03:14:15Z".
+    pub(crate) created: String,
+    pub(crate) host: String,
+    /// The directory the files were copied from.
+    pub(crate) source: PathBuf,
+    pub(crate) hyperdrive_version: String,
+    pub(crate) hyperdrive_commit: Option<String>,
+    /// The CUDA version of the node the files were made on, if it had one.
+    pub(crate) cuda_version: Option<String>,
+    pub(crate) files: Vec<ManifestFile>,
+    /// How long hyperdrive took to make the files, if known.
+    pub(crate) timings: Vec<Timing>,
+}
+
+/// A file in the baseline directory.
+#[derive(Default)]
+pub(crate) struct ManifestFile {
+    pub(crate) name: PathBuf,
+    pub(crate) bytes: u64,
+    pub(crate) xxh64: u64,
+}
+
+impl Manifest {
+    /// The manifest's provenance fields, by name, leaving out those that
+    /// aren't known.
+    pub(crate) fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("created", self.created.clone()),
+            ("host", self.host.clone()),
+            ("source", self.source.display().to_string()),
+            ("hyperdrive_version", self.hyperdrive_version.clone()),
+        ];
+        if let Some(c) = &self.hyperdrive_commit {
+            fields.push(("hyperdrive_commit", c.clone()));
+        }
+        if let Some(c) = &self.cuda_version {
+            fields.push(("cuda_version", c.clone()));
+        }
+        fields
+    }
+
+    /// A one-line description, e.g. for printing before a comparison.
+    pub(crate) fn describe(&self) -> String {
+        let mut extra = vec![];
+        if let Some(c) = &self.hyperdrive_commit {
+            extra.push(format!("commit {}", c));
+        }
+        if let Some(c) = &self.cuda_version {
+            extra.push(format!("CUDA {}", c));
+        }
+        format!(
+            "created {} on {} by hyperdrive {}{}, from {}",
+            self.created,
+            self.host,
+            self.hyperdrive_version,
+            if extra.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", extra.join(", "))
+            },
+            self.source.display()
+        )
+    }
+
+    /// Check the manifest's fields against `expected` (field, value) pairs,
+    /// reporting every mismatch at once.
+    pub(crate) fn check(&self, expected: &[(String, String)]) -> Result<(), anyhow::Error> {
+        let fields = self.fields();
+        let mismatches: Vec<String> = expected
+            .iter()
+            .filter_map(|(key, value)| match fields.iter().find(|(k, _)| k == key) {
+                Some((_, v)) if v == value => None,
+                Some((_, v)) => Some(format!("{} is \"{}\", not \"{}\"", key, v, value)),
+                None => Some(format!("{} isn't recorded; expected \"{}\"", key, value)),
+            })
+            .collect();
+        if !mismatches.is_empty() {
+            bail!(
+                "The baseline isn't the one expected:\n  {}",
+                mismatches.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    fn render(&self) -> String {
+        let mut toml = format!(
+            "# Written by {} {}.\n",
+            env!("CARGO_BIN_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        for (key, value) in self.fields() {
+            toml.push_str(&format!("{} = {}\n", key, toml_string(&value)));
+        }
+        for f in &self.files {
+            toml.push_str(&format!(
+                "\n[[files]]\nname = {}\nbytes = {}\nxxh64 = \"{:016x}\"\n",
+                toml_string(&f.name.display().to_string()),
+                f.bytes,
+                f.xxh64
+            ));
+        }
+        for t in &self.timings {
+            toml.push_str(&format!(
+                "\n[[timings]]\nstage = {}\nseconds = {:?}\n",
+                toml_string(&t.stage),
+                t.seconds
+            ));
+        }
+        toml
+    }
+
+    /// Check that every file the manifest lists is in `dir`, with the size
+    /// and XXH64 recorded, reporting every problem at once.
+    pub(crate) fn verify(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        self.check_files(dir, true)
+    }
+
+    /// Like [Manifest::verify], but only checking the sizes of the files, so
+    /// that none of them are read.
+    pub(crate) fn verify_sizes(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        self.check_files(dir, false)
+    }
+
+    fn check_files(&self, dir: &Path, hash: bool) -> Result<(), anyhow::Error> {
+        let mut missing = vec![];
+        let mut mismatches = vec![];
+        for f in &self.files {
+            let path = dir.join(&f.name);
+            if !path.is_file() {
+                missing.push(f.name.display().to_string());
+                continue;
+            }
+            if !hash {
+                let bytes = std::fs::metadata(&path)?.len();
+                if bytes != f.bytes {
+                    mismatches.push(format!(
+                        "{} has {} bytes, but the manifest says {} bytes",
+                        f.name.display(),
+                        bytes,
+                        f.bytes
+                    ));
+                }
+                continue;
+            }
+            let (bytes, xxh64) = hash::xxh64_file(&path)?;
+            if (bytes, xxh64) != (f.bytes, f.xxh64) {
+                mismatches.push(format!(
+                    "{} has {} bytes with XXH64 {:016x}, but the manifest says {} bytes with XXH64 {:016x}",
+                    f.name.display(),
+                    bytes,
+                    xxh64,
+                    f.bytes,
+                    f.xxh64
+                ));
+            }
+        }
+        if !missing.is_empty() {
+            bail!(Error::Missing(format!(
+                "{} file(s) in the manifest are missing: {}",
+                missing.len(),
+                missing.join(", ")
+            )));
+        }
+        if !mismatches.is_empty() {
+            bail!(Error::Shape(format!(
+                "Files don't match the manifest:\n  {}",
+                mismatches.join("\n  ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write the manifest into the baseline directory `dir`.
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        let path = dir.join(MANIFEST);
+        std::fs::write(&path, self.render())
+            .map_err(|e| anyhow!("Couldn't write {:?}: {}", path, e))
+    }
+
+    /// Read the manifest in the baseline directory `dir`, if it has one.
+    pub(crate) fn read(dir: &Path) -> Result<Option<Manifest>, anyhow::Error> {
+        let path = dir.join(MANIFEST);
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("Couldn't read {:?}: {}", path, e),
+        };
+        parse(&text)
+            .map(Some)
+            .map_err(|e| anyhow!("{:?} is malformed: {}", path, e))
+    }
+}
+
+/// The table that lines of a manifest are in.
+enum Table {
+    Top,
+    Files,
+    Timings,
+}
+
+fn parse(text: &str) -> Result<Manifest, String> {
+    let mut manifest = Manifest::default();
+    let mut table = Table::Top;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[files]]" {
+            manifest.files.push(ManifestFile::default());
+            table = Table::Files;
+            continue;
+        }
+        if line == "[[timings]]" {
+            manifest.timings.push(Timing {
+                stage: String::new(),
+                seconds: 0.0,
+            });
+            table = Table::Timings;
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => return Err(format!("line {}: expected KEY = VALUE", i + 1)),
+        };
+        let string = || unquote(value).ok_or(format!("line {}: expected a string", i + 1));
+        let integer = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("line {}: expected an integer", i + 1))
+        };
+        match table {
+            Table::Top => match key {
+                "created" => manifest.created = string()?,
+                "host" => manifest.host = string()?,
+                "source" => manifest.source = PathBuf::from(string()?),
+                "hyperdrive_version" => manifest.hyperdrive_version = string()?,
+                "hyperdrive_commit" => manifest.hyperdrive_commit = Some(string()?),
+                "cuda_version" => manifest.cuda_version = Some(string()?),
+                // Written by a newer version; nothing to do with it here.
+                _ => (),
+            },
+            Table::Files => {
+                // There's a file for each [[files]] table.
+                let f = manifest.files.last_mut().unwrap();
+                match key {
+                    "name" => f.name = PathBuf::from(string()?),
+                    "bytes" => f.bytes = integer()?,
+                    "xxh64" => {
+                        f.xxh64 = u64::from_str_radix(&string()?, 16)
+                            .map_err(|_| format!("line {}: expected a hex hash", i + 1))?
+                    }
+                    _ => (),
+                }
+            }
+            Table::Timings => {
+                let t = manifest.timings.last_mut().unwrap();
+                match key {
+                    "stage" => t.stage = string()?,
+                    "seconds" => {
+                        t.seconds = value
+                            .parse()
+                            .map_err(|_| format!("line {}: expected a number", i + 1))?
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(manifest)
+}
+
+/// Render a string as a TOML basic string, quoted and escaped. Unlike JSON,
+/// TOML doesn't allow DEL unescaped.
+pub(crate) fn toml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() && (c as u32) < 0x80 => {
+                out.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Undo [toml_string].
+pub(crate) fn unquote(s: &str) -> Option<String> {
+    let s = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            'n' => out.push('\n'),
+            't' => out.push('\t'),
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// Parse an expectation of the baseline's manifest like
+/// "hyperdrive_version=0.2.0".
+pub(crate) fn parse_expectation(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if FIELDS.contains(&key.trim()) => {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        (Some(key), Some(_)) => Err(format!(
+            "Unknown manifest field \"{}\"; expected one of {}",
+            key,
+            FIELDS.join(", ")
+        )),
+        _ => Err(format!("Expected FIELD=VALUE, not \"{}\"", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_strings_are_read_back_as_written() {
+        for s in &[
+            "",
+            "plain",
+            "quote \" backslash \\",
+            "tab\tnewline\n",
+            "bell\u{7} del\u{7f} é 😀",
+        ] {
+            let quoted = toml_string(s);
+            assert!(!quoted.contains(&['\n', '\u{7f}'][..]), "{}", quoted);
+            assert_eq!(unquote(&quoted).as_deref(), Some(*s));
+        }
+    }
+
+    fn manifest() -> Manifest {
+        Manifest {
+            created: "2020-06-09T03:14:15Z".to_string(),
+            host: "nid001234".to_string(),
+            source: PathBuf::from("/scratch/run \"1\""),
+            hyperdrive_version: "0.2.0".to_string(),
+            hyperdrive_commit: None,
+            cuda_version: Some("11.1".to_string()),
+            files: vec![ManifestFile {
+                name: PathBuf::from("hyperdrive_band01.bin"),
+                bytes: 8,
+                xxh64: 0x0123_4567_89ab_cdef,
+            }],
+            timings: vec![Timing {
+                stage: "calibration".to_string(),
+                seconds: 1.5,
+            }],
+        }
+    }
+
+    #[test]
+    fn manifests_are_read_back_as_written() {
+        let written = manifest();
+        let read = parse(&written.render()).unwrap();
+        assert_eq!(read.fields(), written.fields());
+        assert_eq!(read.describe(), written.describe());
+        assert_eq!(read.files.len(), 1);
+        let f = &read.files[0];
+        assert_eq!(
+            (&f.name, f.bytes, f.xxh64),
+            (&written.files[0].name, 8, 0x0123_4567_89ab_cdef)
+        );
+        assert_eq!(read.timings[0].stage, "calibration");
+        assert_eq!(read.timings[0].seconds, 1.5);
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored_but_bad_values_refused() {
+        let newer = "created = \"now\"\ngpu = \"A100\"\n\n[[files]]\nname = \"a.bin\"\nmode = 1\n";
+        let read = parse(newer).unwrap();
+        assert_eq!((read.created.as_str(), read.files.len()), ("now", 1));
+
+        assert_eq!(
+            parse("host = nid001234").err().unwrap(),
+            "line 1: expected a string"
+        );
+        assert_eq!(
+            parse("# comment\n[[files]]\nbytes = -1").err().unwrap(),
+            "line 3: expected an integer"
+        );
+        assert_eq!(
+            parse("[files]").err().unwrap(),
+            "line 1: expected KEY = VALUE"
+        );
+    }
+
+    #[test]
+    fn every_mismatch_is_reported() {
+        let m = manifest();
+        let expect = |pairs: &[(&str, &str)]| {
+            let expected: Vec<(String, String)> = pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            m.check(&expected)
+        };
+        assert!(expect(&[("cuda_version", "11.1"), ("host", "nid001234")]).is_ok());
+        let e = expect(&[
+            ("hyperdrive_version", "0.3.0"),
+            ("hyperdrive_commit", "abc"),
+        ])
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            e,
+            "The baseline isn't the one expected:\n  \
+             hyperdrive_version is \"0.2.0\", not \"0.3.0\"\n  \
+             hyperdrive_commit isn't recorded; expected \"abc\""
+        );
+
+        assert_eq!(
+            parse_expectation(" cuda_version = 11.1"),
+            Ok(("cuda_version".to_string(), "11.1".to_string()))
+        );
+        assert!(parse_expectation("gpu=A100").is_err());
+        assert!(parse_expectation("cuda_version").is_err());
+    }
+
+    #[test]
+    fn files_are_verified_against_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hyperdrive_band01.bin");
+        std::fs::write(&path, [1u8; 8]).unwrap();
+        let (bytes, xxh64) = hash::xxh64_file(&path).unwrap();
+        let mut m = manifest();
+        m.files[0].xxh64 = xxh64;
+        assert_eq!(bytes, 8);
+        m.verify(&dir).unwrap();
+
+        // The same size, but different contents.
+        std::fs::write(&path, [2u8; 8]).unwrap();
+        m.verify_sizes(&dir).unwrap();
+        let e = m.verify(&dir).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Shape(_))), "{}", e);
+
+        std::fs::remove_file(&path).unwrap();
+        let e = m.verify_sizes(&dir).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::Missing(_))), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! How an observation's data are laid out, from its metafits file: its tiles,
+//! coarse channels and fine channel resolution. This is what mwalib would
+//! give, but only the little needed here, read with our own FITS reader.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::fits::Fits;
+
+/// The width of an MWA coarse channel, in Hz. Receiver channel n is centred
+/// on n times this.
+const COARSE_CHANNEL_WIDTH: f64 = 1.28e6;
+
+pub(crate) struct Metafits {
+    /// The names of the tiles, in the order their baselines are in (that of
+    /// their antenna numbers). Tiles are numbered if there's no TILEDATA.
+    pub(crate) tile_names: Vec<String>,
+    /// The receiver channel numbers of the coarse channels, in increasing
+    /// frequency, as the band files are numbered.
+    pub(crate) coarse_channels: Vec<u64>,
+    /// The width of each fine channel, in Hz.
+    pub(crate) fine_channel_width: f64,
+}
+
+impl Metafits {
+    pub(crate) fn read(path: &Path) -> Result<Metafits, anyhow::Error> {
+        let fits = Fits::read(path)?;
+        let primary = &fits.hdus[0];
+        let missing = |key: &str| anyhow!("{:?} doesn't have {}", path, key);
+
+        let tile_names = match fits.hdu("TILEDATA") {
+            Ok(hdu) => {
+                let table = fits.table(hdu)?;
+                let antennas = table.f64s("Antenna").ok_or_else(|| missing("Antenna"))?;
+                let names = table
+                    .strings("TileName")
+                    .ok_or_else(|| missing("TileName"))?;
+                // One row for each polarisation of each tile.
+                let mut tiles: Vec<(u64, String)> =
+                    antennas.into_iter().map(|a| a as u64).zip(names).collect();
+                tiles.sort();
+                tiles.dedup_by_key(|(a, _)| *a);
+                tiles.into_iter().map(|(_, name)| name).collect()
+            }
+            Err(_) => {
+                let inputs = primary
+                    .int("NINPUTS")
+                    .and_then(|n| u64::try_from(n / 2).ok())
+                    .ok_or_else(|| missing("NINPUTS or TILEDATA"))?;
+                (0..inputs).map(|t| t.to_string()).collect()
+            }
+        };
+
+        let mut coarse_channels = primary
+            .value("CHANNELS")
+            .ok_or_else(|| missing("CHANNELS"))?
+            .split(',')
+            .map(|c| c.trim().parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("{:?} has malformed CHANNELS: {}", path, e))?;
+        coarse_channels.sort_unstable();
+
+        let fine_channel_width = primary
+            .float("FINECHAN")
+            .ok_or_else(|| missing("FINECHAN"))?
+            * 1e3;
+        if fine_channel_width <= 0.0 || (COARSE_CHANNEL_WIDTH / fine_channel_width).fract() != 0.0 {
+            bail!(
+                "{:?} has a FINECHAN of {} kHz, which doesn't divide a coarse channel",
+                path,
+                fine_channel_width / 1e3
+            );
+        }
+
+        Ok(Metafits {
+            tile_names,
+            coarse_channels,
+            fine_channel_width,
+        })
+    }
+
+    pub(crate) fn tiles(&self) -> u64 {
+        self.tile_names.len() as u64
+    }
+
+    /// The number of fine channels in each coarse channel (so in each band
+    /// file).
+    pub(crate) fn fine_channels(&self) -> u64 {
+        (COARSE_CHANNEL_WIDTH / self.fine_channel_width) as u64
+    }
+
+    /// The centre frequency, in Hz, of fine channel `channel` of coarse band
+    /// `band` (numbered from 1), if there is such a band.
+    pub(crate) fn frequency(&self, band: u64, channel: u64) -> Option<f64> {
+        let coarse = *self
+            .coarse_channels
+            .get(usize::try_from(band).ok()?.checked_sub(1)?)?;
+        let low_edge = coarse as f64 * COARSE_CHANNEL_WIDTH - COARSE_CHANNEL_WIDTH / 2.0;
+        Some(low_edge + (channel as f64 + 0.5) * self.fine_channel_width)
+    }
+
+    /// One line describing the observation's layout.
+    pub(crate) fn describe(&self) -> String {
+        format!(
+            "{} tiles, {} coarse channels ({}-{}) of {} {} kHz fine channels",
+            self.tiles(),
+            self.coarse_channels.len(),
+            self.coarse_channels.first().unwrap_or(&0),
+            self.coarse_channels.last().unwrap_or(&0),
+            self.fine_channels(),
+            self.fine_channel_width / 1e3
+        )
+    }
+}
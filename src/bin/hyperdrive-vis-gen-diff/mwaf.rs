@@ -0,0 +1,71 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading RFI flags from the mwaf files written by aoflagger (via cotter or
+//! Birli), one per coarse band.
+//!
+//! An mwaf file is a FITS file whose first binary table has one row per
+//! (timestep, baseline), timestep-major, with a FLAGS column of one bit per
+//! fine channel.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::fits::Fits;
+
+pub(crate) struct Flags {
+    /// The number of fine channels flagged in each row.
+    pub(crate) channels: u64,
+    /// The number of (timestep, baseline) rows.
+    pub(crate) rows: u64,
+    bytes_per_row: usize,
+    data: Vec<u8>,
+}
+
+impl Flags {
+    pub(crate) fn flagged(&self, row: u64, channel: u64) -> bool {
+        let byte = self.data[row as usize * self.bytes_per_row + channel as usize / 8];
+        // FITS bit arrays are stored most-significant bit first.
+        byte & (0x80 >> (channel % 8)) != 0
+    }
+}
+
+/// Find the mwaf file for coarse band `band` (counting from 1) in `dir`, e.g.
+/// "1065880128_01.mwaf".
+pub(crate) fn find(dir: &Path, band: u64) -> Result<PathBuf, anyhow::Error> {
+    let pattern = format!("{}/*_{:02}.mwaf", dir.display(), band);
+    let mut matches: Vec<PathBuf> = glob::glob(&pattern)?.filter_map(Result::ok).collect();
+    match matches.len() {
+        0 => bail!("Couldn't find an mwaf file matching {}", pattern),
+        1 => Ok(matches.remove(0)),
+        _ => bail!("Several mwaf files match {}: {:?}", pattern, matches),
+    }
+}
+
+pub(crate) fn read(path: &Path) -> Result<Flags, anyhow::Error> {
+    let fits = Fits::read(path)?;
+    let bad = |what: &str| anyhow!("{:?} isn't a valid mwaf file: {}", path, what);
+
+    let table = fits.hdus.get(1).ok_or_else(|| bad("no flags table"))?;
+    if table.value("XTENSION") != Some("BINTABLE") || table.value("TTYPE1") != Some("FLAGS") {
+        bail!(bad("the first extension isn't a binary table of FLAGS"));
+    }
+    let tform = table.value("TFORM1").ok_or_else(|| bad("no TFORM1"))?;
+    let channels: u64 = tform
+        .strip_suffix('X')
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| bad("FLAGS isn't a bit array"))?;
+    let bytes_per_row = table.int("NAXIS1").ok_or_else(|| bad("no NAXIS1"))? as usize;
+    let rows = table.int("NAXIS2").ok_or_else(|| bad("no NAXIS2"))? as u64;
+    if (bytes_per_row as u64) < channels.div_ceil(8) {
+        bail!(bad("rows are too short for their flags"));
+    }
+
+    Ok(Flags {
+        channels,
+        rows,
+        bytes_per_row,
+        data: fits.data(table).to_vec(),
+    })
+}
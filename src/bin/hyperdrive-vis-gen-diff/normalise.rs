@@ -0,0 +1,92 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Bring data written under different, but equivalent, conventions into line
+//! before comparing them.
+//!
+//! Each fine channel (of --floats-per-channel floats) holds a number of
+//! polarisations, each a real and imaginary float, optionally followed by a
+//! weight. A sample is flagged if its weight is negative.
+
+use anyhow::bail;
+
+/// The names of polarisations, when there are four of them.
+const POLS: [&str; 4] = ["XX", "XY", "YX", "YY"];
+
+/// The steps to apply. Changes to "the current data" make it follow the
+/// baseline's conventions.
+#[derive(Debug)]
+pub(crate) struct Normalisation {
+    pub(crate) floats_per_channel: u64,
+    /// Each polarisation is followed by a weight.
+    pub(crate) weights: bool,
+    /// Negate the weights of the current data.
+    pub(crate) flip_weights: bool,
+    /// Zero every sample flagged in either data set, in both.
+    pub(crate) zero_flagged: bool,
+    /// Conjugate these polarisations of the current data.
+    pub(crate) conjugate: Vec<usize>,
+}
+
+impl Normalisation {
+    /// Normalise `current` and `baseline` (which have the same length) in
+    /// place.
+    pub(crate) fn apply(
+        &self,
+        current: &mut [f32],
+        baseline: &mut [f32],
+    ) -> Result<(), anyhow::Error> {
+        let floats_per_pol = if self.weights { 3 } else { 2 };
+        let fpc = self.floats_per_channel as usize;
+        if fpc == 0 || !fpc.is_multiple_of(floats_per_pol) {
+            bail!(
+                "--floats-per-channel {} isn't a whole number of polarisations of {} floats",
+                fpc,
+                floats_per_pol
+            );
+        }
+        let pols = fpc / floats_per_pol;
+        if let Some(&p) = self.conjugate.iter().find(|&&p| p >= pols) {
+            bail!(
+                "Can't conjugate polarisation {}; there are only {}",
+                p,
+                pols
+            );
+        }
+
+        let samples = current
+            .chunks_exact_mut(floats_per_pol)
+            .zip(baseline.chunks_exact_mut(floats_per_pol))
+            .enumerate();
+        for (i, (c, b)) in samples {
+            if self.flip_weights {
+                c[2] = -c[2];
+            }
+            if self.conjugate.contains(&(i % pols)) {
+                c[1] = -c[1];
+            }
+            if self.zero_flagged && (c[2] < 0.0 || b[2] < 0.0) {
+                c.iter_mut().for_each(|f| *f = 0.0);
+                b.iter_mut().for_each(|f| *f = 0.0);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a polarisation, either by name (for four polarisations, e.g. "XY")
+/// or by index.
+pub(crate) fn parse_pol(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    POLS.iter()
+        .position(|p| p.eq_ignore_ascii_case(s))
+        .or_else(|| s.parse().ok())
+        .ok_or_else(|| {
+            format!(
+                "Expected a polarisation ({}) or its index, not \"{}\"",
+                POLS.join(", "),
+                s
+            )
+        })
+}
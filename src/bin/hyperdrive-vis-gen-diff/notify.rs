@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Tell someone when unattended checks (e.g. a nightly suite run by cron)
+//! fail: post to a Slack or Mattermost incoming webhook, or email through an
+//! SMTP relay.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail};
+
+use crate::email;
+use crate::json::json_string;
+
+/// Where to send notifications; nothing is sent if none are given.
+#[derive(Debug, Default)]
+pub(crate) struct Notify {
+    /// An incoming webhook, e.g. "https://hooks.slack.com/services/...".
+    pub(crate) webhook: Option<String>,
+    pub(crate) email_to: Vec<String>,
+    /// The relay to send emails through, needed with `email_to`.
+    pub(crate) smtp_server: Option<String>,
+    /// Defaults to hyperdrive-checks@<hostname>.
+    pub(crate) email_from: Option<String>,
+}
+
+impl Notify {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.webhook.is_none() && self.email_to.is_empty()
+    }
+
+    /// Send `subject` and `body` everywhere configured. `body` is plain text,
+    /// shown in a fixed-width font on the webhook so that tables line up.
+    /// Failing to notify anyone shouldn't hide the result of the checks, so
+    /// failures are only warned about.
+    pub(crate) fn send(&self, subject: &str, body: &str) {
+        if let Some(url) = &self.webhook {
+            let text = format!("**{}**\n```\n{}```", subject, body);
+            if let Err(e) = post(url, &text) {
+                eprintln!("Warning: couldn't post to the webhook: {}", e);
+            }
+        }
+        if !self.email_to.is_empty() {
+            let from = self
+                .email_from
+                .clone()
+                .unwrap_or_else(|| format!("hyperdrive-checks@{}", email::hostname()));
+            let sent = match &self.smtp_server {
+                Some(server) => email::send(&email::Email {
+                    server,
+                    from: &from,
+                    to: &self.email_to,
+                    subject,
+                    body,
+                }),
+                None => Err(anyhow!("no SMTP server was given")),
+            };
+            if let Err(e) = sent {
+                eprintln!("Warning: couldn't send email: {}", e);
+            }
+        }
+    }
+}
+
+/// POST `text` to an incoming webhook as the JSON payload `{"text": ...}`,
+/// which both Slack and Mattermost accept.
+fn post(url: &str, text: &str) -> Result<(), anyhow::Error> {
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        bail!("Expected an https:// webhook URL, not \"{}\"", url);
+    }
+    let payload = format!("{{\"text\": {}}}", json_string(text));
+    let mut child = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--max-time",
+            "30",
+            "--header",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+        ])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Couldn't run curl: {}", e))?;
+    // Guaranteed to be present, as it was piped.
+    child.stdin.take().unwrap().write_all(payload.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim_end());
+    }
+    Ok(())
+}
@@ -0,0 +1,38 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Work out which MWA observation is being checked, so that results from the
+//! many observations in a campaign can be told apart.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use anyhow::anyhow;
+use pawsey_hyperdrive_checks::fits::Fits;
+
+/// Read the obsid (its GPSTIME) from the primary header of a metafits file.
+pub(crate) fn from_metafits(path: &Path) -> Result<u64, anyhow::Error> {
+    Fits::read(path)?.hdus[0]
+        .int("GPSTIME")
+        .and_then(|t| u64::try_from(t).ok())
+        .ok_or_else(|| anyhow!("{:?} doesn't have a GPSTIME", path))
+}
+
+/// Find an obsid in a path following MWA naming conventions, e.g.
+/// "/scratch/mwa/1065880128/hyperdrive". Obsids are GPS times: runs of exactly
+/// 10 digits, starting with 1. The last one in the path wins.
+pub(crate) fn from_path(path: &Path) -> Option<u64> {
+    let path = path.canonicalize().ok()?;
+    let mut found = None;
+    for component in path.iter() {
+        let component = component.to_string_lossy();
+        let mut runs = component
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|run| run.len() == 10 && run.starts_with('1'));
+        if let Some(run) = runs.next_back() {
+            found = run.parse().ok();
+        }
+    }
+    found
+}
@@ -0,0 +1,1336 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The command-line options. Without a subcommand (and with diff and suggest),
+//! the outputs in the PWD are compared against a baseline directory, with the
+//! options of [DiffOpt]: how to find the baseline, and [CompareOpt], how to
+//! compare the files and report the results. Every other subcommand has
+//! options of its own, given after it.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pawsey_hyperdrive_checks::{Dtype, Endian, NanPolicy};
+use structopt::clap::ArgMatches;
+use structopt::StructOpt;
+
+use crate::wait::parse_duration;
+use crate::{
+    baseline_sets, channels, compress, config, exempt, extract, manifest, normalise, parse_number,
+    presets, read, region, sample, solutions, Channels, Comparison, Differences, Metafits,
+    Normalisation, Reader, Sampling, Verbosity,
+};
+
+/// Parse a window like "0.9:1.1".
+fn parse_window(s: &str) -> Result<(f64, f64), String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(low), Some(high)) => {
+            let (low, high) = (parse_number(low)?, parse_number(high)?);
+            if low <= high {
+                Ok((low, high))
+            } else {
+                Err(format!("The window \"{}\" is empty", s))
+            }
+        }
+        _ => Err(format!("Expected LOW:HIGH, not \"{}\"", s)),
+    }
+}
+
+/// This executable simply compares each of the "hyperdrive_bandxx.bin" files in
+/// the present working directory against those in the "baseline"
+/// directory. Reports whether the largest difference between any two floats is
+/// larger than some tolerance. Will fall over if the baseline directory doesn't
+/// exist, or if there is some kind of mis-match between the hyperdrive files.
+#[derive(StructOpt, Debug)]
+#[structopt(author)]
+pub(crate) struct Opt {
+    #[structopt(subcommand)]
+    pub(crate) cmd: Option<Command>,
+
+    #[structopt(flatten)]
+    pub(crate) diff: DiffOpt,
+}
+
+impl Opt {
+    /// The options of the comparison asked for, if any, and the matches they
+    /// were parsed from (those of its subcommand, if it has one).
+    pub(crate) fn into_comparison<'m, 'a>(
+        self,
+        matches: &'m ArgMatches<'a>,
+    ) -> Option<(CompareOpt, &'m ArgMatches<'a>)> {
+        match self.cmd {
+            None => Some((self.diff.compare, matches)),
+            Some(Command::Diff(d)) | Some(Command::Suggest(d)) => {
+                Some((d.compare, matches.subcommand().1?))
+            }
+            Some(Command::DiffDirs(d)) => Some((d.compare, matches.subcommand().1?)),
+            Some(_) => None,
+        }
+    }
+
+    /// The options of the comparison asked for, if files are compared, so
+    /// that the run ends with a summary line (however it ends).
+    pub(crate) fn summarised(&self) -> Option<&CompareOpt> {
+        let (compare, watch) = match &self.cmd {
+            None => (&self.diff.compare, self.diff.watch),
+            Some(Command::Diff(d)) => (&d.compare, d.watch),
+            Some(Command::DiffDirs(d)) => (&d.compare, false),
+            Some(_) => return None,
+        };
+        Some(compare).filter(|c| !(c.dry_run || c.validate_only || c.suggest_tolerance || watch))
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Command {
+    /// Compare the outputs in the PWD against those in BASELINE_DIR, as is
+    /// done when no subcommand is given.
+    Diff(DiffOpt),
+
+    /// Rather than passing or failing, report the smallest tolerances at
+    /// which the outputs in the PWD would pass against those in BASELINE_DIR,
+    /// and recommend tolerances; the same as --suggest-tolerance.
+    Suggest(DiffOpt),
+
+    /// Compare every regular file present in both directories (matched by
+    /// relative path) as raw little-endian floats, regardless of their names.
+    DiffDirs(DiffDirsOpt),
+
+    /// Compare files on behalf of a coordinator (see --coordinator), until it
+    /// has nothing left to compare.
+    Worker(WorkerOpt),
+
+    /// Render history files (from --history) into a static HTML page with a
+    /// trend plot per history file.
+    Dashboard(DashboardOpt),
+
+    /// Print the trend of the maximum difference of each file (or band)
+    /// recorded in a --csv file, or of each run in a --history file, and flag
+    /// (exiting with 1) those that have been creeping upward over the last few
+    /// runs, even if they're still under the tolerance.
+    History(HistoryOpt),
+
+    /// Run every check in a suite file (see the README), each in its own
+    /// working directory, and summarise which passed. --junit writes the
+    /// summary as a JUnit report.
+    Run(RunOpt),
+
+    /// Print the formats this build can read, which compressed and remote
+    /// baselines it can fetch, its presets, plugins and defaults, as JSON.
+    Info(InfoOpt),
+
+    /// Any other subcommand FOO runs the executable "hyperdrive-checks-FOO"
+    /// found on the PATH with the remaining arguments, and the options given
+    /// before FOO as JSON in the HYPERDRIVE_CHECKS_OPTIONS environment
+    /// variable.
+    #[structopt(external_subcommand)]
+    External(Vec<String>),
+
+    /// Cut a small piece out of a pair of files (in timestep, baseline, fine
+    /// channel order) into a directory, to attach to a bug report. By default,
+    /// the piece is the timestep and baseline with the largest difference.
+    Extract(ExtractOpt),
+
+    /// Copy the hyperdrive outputs in the PWD (see --outputs) into the
+    /// baseline directory, creating it if needed and replacing the outputs
+    /// already there, and record when and from what they were created in its
+    /// manifest.toml.
+    Snapshot(SnapshotOpt),
+
+    /// Bundle a baseline directory (its manifest.toml and the files it lists)
+    /// into a single tar.zst, having checked the files against the manifest.
+    Archive(ArchiveOpt),
+
+    /// Unpack an archive made by the archive subcommand into a new baseline
+    /// directory, refusing if any file doesn't match the manifest.
+    Restore(RestoreOpt),
+
+    /// Rewrite a --json report written by an older version of this
+    /// executable in the current schema (see its schema_version), so that
+    /// scripts only need to read the latest one.
+    UpgradeReport(UpgradeReportOpt),
+}
+
+// How to find the baseline to compare the outputs in the PWD against, and
+// then how to compare them. (Not doc comments, on this or the other flattened
+// structs: structopt would take them as the about of the commands.)
+#[derive(StructOpt, Debug)]
+pub(crate) struct DiffOpt {
+    /// The directory containing hyperdrive simulate-vis outputs to compare
+    /// against. A directory inside an Apptainer image can be given like
+    /// "apptainer://hyperdrive.sif:/opt/baseline".
+    #[structopt(
+        name = "BASELINE_DIR",
+        default_value = "./baseline",
+        parse(from_os_str)
+    )]
+    pub(crate) baseline_dir: PathBuf,
+
+    /// Download the baseline from here (an https:// or s3:// URL of a
+    /// directory with a manifest.toml) rather than using BASELINE_DIR. Every
+    /// file is checked against the manifest, and cached for next time.
+    #[structopt(long)]
+    pub(crate) baseline_url: Option<String>,
+
+    /// Refuse to compare unless the baseline directory's manifest.toml (see
+    /// the "snapshot" subcommand) records this value for a field, e.g.
+    /// "hyperdrive_version=0.2.0" or "cuda_version=12.2". Can be given
+    /// several times.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = manifest::parse_expectation))]
+    pub(crate) expect_baseline: Vec<(String, String)>,
+
+    /// A directory of promoted baselines, one per subdirectory, named so that
+    /// they sort oldest to newest (e.g. by date). The outputs are compared
+    /// against the newest --window of them, to tell drifting from every
+    /// baseline apart from only disagreeing with an odd newest one. The
+    /// outputs are then compared as usual against the newest baseline that
+    /// agrees (or the newest, if none does), so this fails only if no baseline
+    /// in the window agrees.
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["coordinator", "sample-fraction", "checkpoint", "dry-run", "validate-only"]
+    )]
+    pub(crate) baseline_window: Option<PathBuf>,
+
+    /// How many of the newest baselines to compare against with
+    /// --baseline-window.
+    #[structopt(long, default_value = "5")]
+    pub(crate) window: usize,
+
+    /// Compare against this baseline directory with its own absolute
+    /// tolerance, given like "DIR:TOLERANCE" (e.g. "cpu-baseline:1e-6"), rather
+    /// than against BASELINE_DIR. Can be given several times, to check against
+    /// e.g. a CPU reference and a looser GPU baseline at once, with a verdict
+    /// for each. Fails if any of them does.
+    #[structopt(
+        long = "baseline",
+        value_name = "DIR:TOLERANCE",
+        number_of_values = 1,
+        conflicts_with_all = &["coordinator", "sample-fraction", "baseline-window", "baseline-url", "dry-run", "validate-only"],
+        parse(try_from_str = baseline_sets::parse)
+    )]
+    pub(crate) baseline_sets: Vec<(PathBuf, f32)>,
+
+    /// The output directories of repeated, identical runs (at least two), to
+    /// compare against the baseline instead of the PWD. The scatter between the
+    /// runs is reported for each file (or band), and one whose difference from
+    /// the baseline is above the tolerance passes if it's in line with the
+    /// scatter (see --scatter-factor), as GPU non-determinism would make it.
+    #[structopt(
+        long,
+        min_values = 2,
+        parse(from_os_str),
+        conflicts_with_all = &["coordinator", "sample-fraction", "baseline-window", "baseline-sets", "dry-run", "validate-only"]
+    )]
+    pub(crate) runs: Vec<PathBuf>,
+
+    /// With --runs, a difference from the baseline above the tolerance passes
+    /// if it's no more than this many times the largest difference between
+    /// the runs.
+    #[structopt(long, default_value = "2", parse(try_from_str = parse_number))]
+    pub(crate) scatter_factor: f32,
+
+    /// Instead of failing when band files are absent, wait for every band file
+    /// in the baseline directory to appear in the PWD (and stop growing). Useful
+    /// when running as a dependent job of the job producing the files.
+    #[structopt(long)]
+    pub(crate) wait_for_files: bool,
+
+    /// Give up waiting for files after this long, e.g. "2h", "30m", "1h30m".
+    /// Without this, --wait-for-files waits indefinitely.
+    #[structopt(long, requires = "wait-for-files", parse(try_from_str = parse_duration))]
+    pub(crate) timeout: Option<Duration>,
+
+    /// How often to check for files when using --wait-for-files.
+    #[structopt(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    pub(crate) poll_interval: Duration,
+
+    /// Compare, then compare again whenever the outputs in the PWD are
+    /// rewritten, printing only the files whose results changed. Runs until
+    /// interrupted.
+    #[structopt(long, conflicts_with_all = &["csv", "coordinator", "checkpoint", "format"])]
+    pub(crate) watch: bool,
+
+    #[structopt(flatten)]
+    pub(crate) compare: CompareOpt,
+}
+
+// How to compare pairs of files, and report the results.
+#[derive(StructOpt, Debug)]
+pub(crate) struct CompareOpt {
+    /// A named bundle of settings: "strict" (a tolerance of 1e-6), "gpu"
+    /// (5e-3 and 1e-5 --max-frac-exceeding, for CUDA against CPU outputs),
+    /// "gpu-vs-cpu" (relative tolerances with absolute floors, for CUDA
+    /// against CPU outputs), "nightly" (0.1 --max-sigma and --nan-equal) or
+    /// "acceptance" (1e-4). Options given explicitly override the preset's,
+    /// e.g. --tolerance replaces the preset's --max-sigma.
+    #[structopt(long, parse(try_from_str = presets::parse_preset))]
+    pub(crate) preset: Option<&'static presets::Preset>,
+
+    /// If the maximum difference between any two files is bigger than this
+    /// number, then fail.
+    #[structopt(short, long, default_value = "0.001", parse(try_from_str = parse_number))]
+    pub(crate) tolerance: f32,
+
+    /// Also let a float pass if its difference is at most this fraction of the
+    /// magnitude of the baseline float (e.g. 1e-5), even if it's bigger than
+    /// --tolerance.
+    #[structopt(long, conflicts_with_all = &["max-sigma", "chi2-window", "tolerances", "coordinator"], parse(try_from_str = parse_number))]
+    pub(crate) rel_tolerance: Option<f32>,
+
+    /// Also let a float pass if it's at most this many units in the last place
+    /// from the baseline float, even if its difference is bigger than
+    /// --tolerance.
+    #[structopt(long, conflicts_with_all = &["max-sigma", "chi2-window", "tolerances", "coordinator"])]
+    pub(crate) ulps: Option<u32>,
+
+    /// The absolute tolerance of the imaginary parts of the visibilities (the
+    /// second float of each; see --weights), instead of --tolerance. Useful
+    /// with --rel-tolerance, as imaginary parts near zero have large relative
+    /// differences.
+    #[structopt(long, conflicts_with_all = &["complex", "max-sigma", "chi2-window", "tolerances", "coordinator"], parse(try_from_str = parse_number))]
+    pub(crate) imag_tolerance: Option<f32>,
+
+    /// A TOML file of tolerances for particular files (or bands), e.g. looser
+    /// ones for the edge bands: [[files]] tables, each with a glob "pattern"
+    /// and any of "tolerance", "rel_tolerance" and "ulps". The first table
+    /// matching a file applies; the options given here fill in the rest.
+    #[structopt(long, conflicts_with_all = &["complex", "max-sigma", "chi2-window", "tolerances", "coordinator"], parse(from_os_str))]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Don't fail because of NaNs. They're still counted and reported, and
+    /// left out of the differences. By default, a NaN in either file fails.
+    #[structopt(long, conflicts_with = "coordinator")]
+    pub(crate) allow_nan: bool,
+
+    /// Treat NaNs in the same place in both files as equal; only NaNs in just
+    /// one of the files fail.
+    #[structopt(long, conflicts_with_all = &["allow-nan", "coordinator"])]
+    pub(crate) nan_equal: bool,
+
+    /// Treat the floats as interleaved real and imaginary parts of complex
+    /// visibilities, and compare their amplitudes (with --tolerance) and
+    /// phases (with --phase-tolerance) rather than the floats themselves, so
+    /// that phases wrapping around +-180 degrees don't look like differences.
+    #[structopt(long, conflicts_with_all = &["rel-tolerance", "ulps", "max-sigma", "chi2-window", "coordinator"])]
+    pub(crate) complex: bool,
+
+    /// The largest acceptable phase difference, in degrees, with --complex.
+    #[structopt(long, default_value = "0.1", parse(try_from_str = parse_number))]
+    pub(crate) phase_tolerance: f64,
+
+    /// Print nothing but the final "HYPERDRIVE-CHECKS" summary line (to stderr
+    /// with --format json), so that the success or failure is determined by
+    /// the exit code or that line.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+
+    /// Print more: once for the results of each file (or band) as well as the
+    /// summary, twice for the statistics and timings of each chunk compared
+    /// too.
+    #[structopt(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    pub(crate) verbose: u64,
+
+    /// Rather than passing or failing, report the smallest absolute and
+    /// relative tolerances at which each file (and all files together) would
+    /// pass, and recommend tolerances with a safety margin.
+    #[structopt(long)]
+    pub(crate) suggest_tolerance: bool,
+
+    /// With --suggest-tolerance (or suggest), recommend tolerances this many times the
+    /// largest differences seen.
+    #[structopt(long, default_value = "2", parse(try_from_str = parse_number))]
+    pub(crate) safety_margin: f32,
+
+    /// With --suggest-tolerance (or suggest), write the recommended tolerances of each file
+    /// (or band) into this config file, for --config.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) suggested_config: Option<PathBuf>,
+
+    /// Also report whether the comparison would pass at each of these
+    /// tolerances (e.g. "1e-5,1e-4,1e-3"), to see how close to the edge it
+    /// is. The verdict still depends only on --tolerance.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with_all = &["max-sigma", "chi2-window", "coordinator"],
+        parse(try_from_str = parse_number)
+    )]
+    pub(crate) tolerances: Vec<f32>,
+
+    /// Instead of using an absolute tolerance, estimate the thermal noise of
+    /// each baseline file and fail if any difference is bigger than this many
+    /// standard deviations of the noise (e.g. 0.01).
+    #[structopt(long, parse(try_from_str = parse_number))]
+    pub(crate) max_sigma: Option<f32>,
+
+    /// The observation being checked, to key the history, report and emails
+    /// by. If not given, it's read from --metafits, or found in the paths of
+    /// the files compared (e.g. "/scratch/mwa/1065880128/...").
+    #[structopt(long)]
+    pub(crate) obsid: Option<u64>,
+
+    /// The metafits file of the observation being checked. The number of fine
+    /// channels and tiles are found from it (unless given), each file's size
+    /// is checked against them, and the largest differences are labelled with
+    /// tile names and frequencies.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) metafits: Option<PathBuf>,
+
+    /// Only compare a random fraction (e.g. 0.01) of each file, in blocks of
+    /// 4096 floats, and report how confident the verdict is. For fast
+    /// screening of huge datasets.
+    #[structopt(long, conflicts_with = "coordinator", parse(try_from_str = sample::parse_fraction))]
+    pub(crate) sample_fraction: Option<f64>,
+
+    /// The seed choosing which blocks are sampled with --sample-fraction; the
+    /// same seed always samples the same blocks.
+    #[structopt(long, default_value = "0")]
+    pub(crate) seed: u64,
+
+    /// The names of the hyperdrive outputs to compare (e.g. as given to
+    /// hyperdrive's --outputs), rather than hyperdrive_band??.bin and
+    /// hyperdrive_band???.bin. Glob patterns are allowed, e.g.
+    /// "--pattern 'obs_*_vis.bin'". Files are sorted with their numbers
+    /// compared as numbers, so band 100 comes after band 99.
+    #[structopt(long, visible_alias = "pattern", use_delimiter = true)]
+    pub(crate) outputs: Vec<String>,
+
+    /// The type of the values in the files, e.g. for simulated outputs
+    /// written in double precision. Values are compared as f32s whatever their
+    /// type.
+    #[structopt(long, default_value = "f32", possible_values = Dtype::NAMES)]
+    pub(crate) dtype: Dtype,
+
+    /// The byte order of the values in the files, e.g. "big" for legacy
+    /// baselines written on big-endian machines.
+    #[structopt(long, default_value = "little", possible_values = Endian::NAMES)]
+    pub(crate) endian: Endian,
+
+    /// How to read the files: "raw" values, or the datasets of "hdf5" files,
+    /// each compared separately. If not given, files ending in .h5 or .hdf5
+    /// are read as HDF5. HDF5 files are read through h5py.
+    #[structopt(long, possible_values = &["raw", "hdf5"])]
+    pub(crate) input_format: Option<String>,
+
+    /// The datasets of HDF5 files to compare, e.g. "/vis,/weights". If not
+    /// given, every numeric dataset is compared.
+    #[structopt(long, use_delimiter = true)]
+    pub(crate) datasets: Vec<String>,
+
+    /// The Python interpreter to read HDF5 files with; it needs h5py.
+    #[structopt(long, default_value = "python3")]
+    pub(crate) python: String,
+
+    /// Each output is a single file containing this many equally sized bands,
+    /// one after another; compare and report each band separately.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"], parse(try_from_str = parse_number))]
+    pub(crate) bands: Option<u64>,
+
+    /// Instead of using an absolute tolerance, fail if the reduced chi-squared
+    /// of the residuals isn't within this window, e.g. "0.9:1.1".
+    #[structopt(long, conflicts_with_all = &["max-sigma", "coordinator"], parse(try_from_str = parse_window))]
+    pub(crate) chi2_window: Option<(f64, f64)>,
+
+    /// The noise (standard deviation) of each residual, for --chi2-window. If
+    /// not given, sqrt(2) times the estimated noise of each baseline file is
+    /// used.
+    #[structopt(long, requires = "chi2-window", parse(try_from_str = parse_number))]
+    pub(crate) chi2_sigma: Option<f64>,
+
+    /// The number of timesteps in each file (or band), with the timestep
+    /// varying slowest. Reports how the per-timestep residual RMS evolves over
+    /// the observation.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"])]
+    pub(crate) timesteps: Option<usize>,
+
+    /// Also fail if the per-timestep residual RMS grows by more than this
+    /// fraction of its mean over the observation (e.g. 0.5 for 50%).
+    #[structopt(long, requires = "timesteps", parse(try_from_str = parse_number))]
+    pub(crate) max_rms_growth: Option<f64>,
+
+    /// Run a two-sample Kolmogorov-Smirnov test of whether the current and
+    /// baseline values of each file (or band) have the same distribution, and
+    /// report its statistic and p-value.
+    #[structopt(long, conflicts_with = "coordinator")]
+    pub(crate) ks_test: bool,
+
+    /// Also fail if the p-value of the Kolmogorov-Smirnov test (see --ks-test)
+    /// of any file (or band) is below this, e.g. 0.01.
+    #[structopt(long, conflicts_with = "coordinator", parse(try_from_str = parse_number))]
+    pub(crate) ks_threshold: Option<f64>,
+
+    /// Mark files (or bands) matching PATTERN as known failures, e.g.
+    /// "hyperdrive_band03.bin=https://github.com/.../issues/12". They are still
+    /// compared and reported, but don't fail the run; if one passes, that is
+    /// reported too. Can be given several times.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = exempt::parse_xfail))]
+    pub(crate) xfail: Vec<exempt::Xfail>,
+
+    /// Fail the run if a known failure (see --xfail) passes, so that stale
+    /// entries can't linger.
+    #[structopt(long)]
+    pub(crate) strict_xfail: bool,
+
+    /// A list of flaky files (or bands) to compare and report, but exclude
+    /// from the verdict. Each line is an expiry date and a glob pattern, e.g.
+    /// "2020-06-30 hyperdrive_band1?.bin"; expired entries count again, with a
+    /// warning.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) quarantine: Option<PathBuf>,
+
+    /// Report how long each file spent being read, decoded and compared, and
+    /// how long writing the reports (history, badge, email) took.
+    #[structopt(long)]
+    pub(crate) timings: bool,
+
+    /// Record each completed comparison in this file as it finishes, so that
+    /// an interrupted run can be carried on with --resume.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) checkpoint: Option<PathBuf>,
+
+    /// Skip the comparisons already recorded in the --checkpoint file, reusing
+    /// their results, and carry on with the rest. Starts afresh if the
+    /// checkpoint doesn't exist yet. The other options should be the same as
+    /// for the interrupted run.
+    #[structopt(long, requires = "checkpoint")]
+    pub(crate) resume: bool,
+
+    /// The number of fine channels in each file (or band, with --bands). Needed
+    /// to treat fine channels differently depending on where they are in their
+    /// coarse band. If not given, it's found from --metafits.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"])]
+    pub(crate) fine_channels: Option<u64>,
+
+    /// The number of consecutive floats belonging to each fine channel; the
+    /// default is for 4 complex polarisations.
+    #[structopt(long, default_value = "8")]
+    pub(crate) floats_per_channel: u64,
+
+    /// Loosen the tolerance for fine channels at the edges of each coarse
+    /// band, e.g. "2:10" multiplies it by 10 for the outer 2 fine channels at
+    /// each edge, and "1:100,2:10" by 100 for the outermost and 10 for the next.
+    #[structopt(long, requires = "fine-channels", parse(try_from_str = channels::parse_ramp))]
+    pub(crate) edge_tolerance_ramp: Option<channels::Ramp>,
+
+    /// Don't compare the first and last N fine channels of each coarse band at
+    /// all, as is standard practice for MWA data downstream.
+    #[structopt(long, requires = "fine-channels")]
+    pub(crate) exclude_edge_channels: Option<u64>,
+
+    /// Don't compare the centre (DC) fine channel of each coarse band, which
+    /// hyperdrive and the correlator have handled inconsistently across
+    /// versions.
+    #[structopt(long, requires = "fine-channels")]
+    pub(crate) exclude_centre_channel: bool,
+
+    /// Don't compare these fine channels of each coarse band, e.g.
+    /// "0-1,16,30-31" (counting from 0; ranges are inclusive).
+    #[structopt(long, requires = "fine-channels", parse(try_from_str = channels::parse_channels))]
+    pub(crate) ignore_channels: Option<channels::ChannelSet>,
+
+    /// Only compare this slice of each file (or band), e.g. to bisect a
+    /// difference: floats "1000..2000", or "timesteps=0..1" or
+    /// "channels=4..8" given the layout of the data (up to, but not
+    /// including, the end). Can be given once for each.
+    #[structopt(long, number_of_values = 1, parse(try_from_str = region::parse))]
+    pub(crate) range: Vec<region::Slice>,
+
+    /// A directory of aoflagger mwaf files, one per coarse band (e.g.
+    /// "1065880128_01.mwaf" for hyperdrive_band01.bin or band 1 with --bands).
+    /// Data they flag as RFI isn't compared. The files must be in timestep,
+    /// baseline, fine channel order.
+    #[structopt(long, requires = "fine-channels", parse(from_os_str))]
+    pub(crate) mwaf_dir: Option<PathBuf>,
+
+    /// A directory of visibility weight files, one per coarse band (e.g.
+    /// "hyperdrive_band01_weights.bin" for hyperdrive_band01.bin or band 1
+    /// with --bands), each with one float per complex visibility. Reports the
+    /// weighted residual RMS of each file (or band).
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"], parse(from_os_str))]
+    pub(crate) weights_dir: Option<PathBuf>,
+
+    /// Leave samples with a weight of zero or less in the --weights-dir files
+    /// (i.e. flagged samples) out of the comparison.
+    #[structopt(long, requires = "weights-dir")]
+    pub(crate) mask_weights: bool,
+
+    /// A log of the hyperdrive run that made the files (e.g. its stdout),
+    /// from which the time of each stage is read. May be given more than
+    /// once. The timings are compared against those in the baseline's
+    /// manifest.toml, or recorded there by `snapshot`.
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pub(crate) hyperdrive_log: Vec<PathBuf>,
+
+    /// How long the hyperdrive run that made the files took, in seconds.
+    /// Treated like the stages of --hyperdrive-log.
+    #[structopt(long, parse(try_from_str = parse_number))]
+    pub(crate) wall_time: Option<f64>,
+
+    /// Fail if hyperdrive was more than this many percent slower than the
+    /// baseline, overall or in any stage, e.g. 20.
+    #[structopt(long, parse(try_from_str = parse_number))]
+    pub(crate) max_slowdown: Option<f64>,
+
+    /// Print the N largest differences of each file (or band), with their
+    /// indices and, if --fine-channels is given, their channels and
+    /// polarisations. Their timesteps and baselines are found from
+    /// --baselines, --timesteps or the tiles in --metafits.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"])]
+    pub(crate) top: Option<usize>,
+
+    /// Compare this many files (or bands) at once. By default, one per core
+    /// (but no more than there are files). Files are compared in chunks, but
+    /// some options need whole files in memory; with those, the default is
+    /// as many as fit in the memory available.
+    #[structopt(long)]
+    pub(crate) jobs: Option<usize>,
+
+    /// The number of baselines in each timestep, for --top.
+    #[structopt(long, requires = "fine-channels")]
+    pub(crate) baselines: Option<u64>,
+
+    /// Also fail if the RMS of the differences of any file (or band) is more
+    /// than this.
+    #[structopt(long, conflicts_with = "coordinator", parse(try_from_str = parse_number))]
+    pub(crate) max_rms: Option<f64>,
+
+    /// Only fail a file (or band) if more than this fraction of its floats
+    /// differ by more than the tolerance (e.g. 1e-4), rather than if any do.
+    #[structopt(
+        long,
+        conflicts_with_all = &["rel-tolerance", "ulps", "config", "complex", "max-sigma", "chi2-window", "coordinator"],
+        parse(try_from_str = parse_number)
+    )]
+    pub(crate) max_frac_exceeding: Option<f64>,
+
+    /// Also fail if the weighted residual RMS of any file (or band) is more
+    /// than this.
+    #[structopt(long, requires = "weights-dir", parse(try_from_str = parse_number))]
+    pub(crate) max_weighted_rms: Option<f64>,
+
+    /// Each polarisation in a fine channel (of --floats-per-channel floats)
+    /// is a real, imaginary and weight float, rather than just a real and
+    /// imaginary float. Samples with negative weights are flagged.
+    #[structopt(long)]
+    pub(crate) weights: bool,
+
+    /// Negate the weights of the current data before comparing, for data
+    /// written with the opposite weight convention to the baseline.
+    #[structopt(long, requires = "weights", conflicts_with = "coordinator")]
+    pub(crate) flip_weights: bool,
+
+    /// Zero out samples flagged in either data set, in both, before
+    /// comparing.
+    #[structopt(long, requires = "weights", conflicts_with = "coordinator")]
+    pub(crate) zero_flagged: bool,
+
+    /// Conjugate these polarisations (e.g. "XY,YX", or indices) of the current
+    /// data before comparing.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with = "coordinator",
+        parse(try_from_str = normalise::parse_pol)
+    )]
+    pub(crate) conjugate_pols: Vec<usize>,
+
+    /// Calibrate the current data with these (MWAOCAL .bin) solutions before
+    /// comparing it, so that uncalibrated outputs can be compared against
+    /// calibrated baselines. The data must be in timestep, baseline, fine
+    /// channel order, with 4 complex polarisations per fine channel.
+    #[structopt(
+        long,
+        requires = "fine-channels",
+        conflicts_with_all = &["coordinator", "sample-fraction"],
+        parse(from_os_str)
+    )]
+    pub(crate) apply_solutions: Option<PathBuf>,
+
+    /// The data to be calibrated with --apply-solutions includes
+    /// autocorrelations.
+    #[structopt(long, requires = "apply-solutions")]
+    pub(crate) autos: bool,
+
+    /// When a file is corrupt (e.g. truncated by a killed job), compare the
+    /// whole floats that both files have, rather than stopping. Corrupt files
+    /// still fail.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction", "bands"])]
+    pub(crate) compare_valid_prefix: bool,
+
+    /// When the files of a pair have different numbers of values (e.g. after
+    /// a change to hyperdrive's padding), compare the values that both have,
+    /// with a warning, rather than stopping. The differing sizes don't fail
+    /// the check by themselves.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction", "bands"])]
+    pub(crate) compare_overlap: bool,
+
+    /// Stop at the first file (or band) that fails, rather than comparing the
+    /// rest. Files already being compared by other jobs aren't reported.
+    #[structopt(long, conflicts_with = "coordinator")]
+    pub(crate) fail_fast: bool,
+
+    /// Report files that are missing from the baseline, unreadable or corrupt
+    /// (e.g. of the wrong size) as failures in their turn, and carry on
+    /// comparing the rest, rather than stopping before comparing anything.
+    #[structopt(long, conflicts_with = "coordinator")]
+    pub(crate) keep_going: bool,
+
+    /// List the pairs of files that would be compared, check their sizes (and
+    /// shapes, with --metafits) and the baseline directory against its
+    /// manifest, then exit without reading any data.
+    #[structopt(long, conflicts_with_all = &["validate-only", "coordinator"])]
+    pub(crate) dry_run: bool,
+
+    /// Only check that every file, current and baseline, decodes as whole,
+    /// finite values of --dtype and --endian, then exit without comparing
+    /// anything.
+    #[structopt(long, conflicts_with = "coordinator")]
+    pub(crate) validate_only: bool,
+
+    /// Don't fail if files in the baseline are missing from the present
+    /// working directory, for intentionally partial runs (e.g. of only some
+    /// bands).
+    #[structopt(long)]
+    pub(crate) allow_subset: bool,
+
+    /// Hash each pair of files first, and only compare the floats of those
+    /// that aren't bitwise identical. Much faster when nothing is expected to
+    /// have changed. Identical files pass, whatever NaNs they have.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction", "bands"])]
+    pub(crate) exact: bool,
+
+    /// Write the differences (current minus baseline) of each file (or band)
+    /// into this directory, as little-endian f32s laid out like the inputs
+    /// (e.g. "hyperdrive_band01.bin.diff.bin"), each with a JSON file of its
+    /// shape for loading into numpy.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"], parse(from_os_str))]
+    pub(crate) write_diff: Option<PathBuf>,
+
+    /// Render a PNG heatmap of the largest absolute difference in each
+    /// (baseline, fine channel) of each file (or band) into this directory
+    /// (e.g. "hyperdrive_band01.bin.png"), highlighting those above the
+    /// tolerance. Needs --fine-channels or --metafits. The number of baselines
+    /// is found from --baselines, --timesteps or the tiles in --metafits.
+    #[structopt(long, conflicts_with_all = &["coordinator", "sample-fraction"], parse(from_os_str))]
+    pub(crate) plot: Option<PathBuf>,
+
+    /// Rather than comparing files here, listen on this address (e.g.
+    /// "0.0.0.0:7777") and hand the comparisons out to workers started with
+    /// the "worker" subcommand, possibly on other nodes.
+    #[structopt(long)]
+    pub(crate) coordinator: Option<String>,
+
+    /// Fail if there are comparisons left but no worker has been connected to
+    /// the coordinator for this long, e.g. because every worker died.
+    #[structopt(long, default_value = "10m", parse(try_from_str = parse_duration))]
+    pub(crate) worker_timeout: Duration,
+
+    #[structopt(flatten)]
+    pub(crate) read: ReadOpt,
+
+    #[structopt(flatten)]
+    pub(crate) reports: ReportOpt,
+}
+
+// How to read files.
+#[derive(StructOpt, Debug)]
+pub(crate) struct ReadOpt {
+    /// How many times to retry opening or reading a file after a transient IO
+    /// error (EIO or ESTALE).
+    #[structopt(long, default_value = "3")]
+    pub(crate) io_retries: u32,
+
+    /// How long to wait before the first retry after a transient IO error; the
+    /// wait doubles for each subsequent retry.
+    #[structopt(long, default_value = "5s", parse(try_from_str = parse_duration))]
+    pub(crate) retry_backoff: Duration,
+
+    /// Read files in parallel chunks of this many bytes, e.g. "1M". By default,
+    /// files on Lustre are read in chunks of their stripe size (as reported by
+    /// `lfs getstripe`), and other files are read sequentially.
+    #[structopt(long, parse(try_from_str = read::parse_size))]
+    pub(crate) stripe_size: Option<u64>,
+
+    /// How many concurrent reads to issue per file when reading in chunks.
+    /// Defaults to the file's Lustre stripe count, or the number of available
+    /// cores.
+    #[structopt(long)]
+    pub(crate) read_threads: Option<usize>,
+
+    /// Read files with O_DIRECT, bypassing the page cache, so that big one-off
+    /// comparisons don't evict the cached data of other jobs on the node.
+    #[structopt(long)]
+    pub(crate) direct_io: bool,
+
+    /// Cap the aggregate rate at which files are read, in bytes per second,
+    /// e.g. "200M" or "1G" (binary prefixes).
+    #[structopt(long, parse(try_from_str = read::parse_rate))]
+    pub(crate) max_read_rate: Option<u64>,
+
+    /// Map files into memory and compare their floats where they lie, rather
+    /// than copying them, when they're f32s in this machine's byte order (and
+    /// aren't compressed). An IO error while comparing a mapped file kills the
+    /// process with SIGBUS rather than being retried (see --io-retries).
+    #[structopt(long, conflicts_with_all = &["direct-io", "max-read-rate"])]
+    pub(crate) mmap: bool,
+}
+
+impl ReadOpt {
+    pub(crate) fn reader(&self, quiet: bool) -> Reader {
+        Reader {
+            retries: self.io_retries,
+            backoff: self.retry_backoff,
+            stripe_size: self.stripe_size,
+            threads: self.read_threads,
+            direct_io: self.direct_io,
+            mmap: self.mmap,
+            throttle: self.max_read_rate.map(read::Throttle::new),
+            used: Default::default(),
+            quiet,
+            streams: Default::default(),
+        }
+    }
+}
+
+// Where to report the results of a comparison, besides stdout.
+#[derive(StructOpt, Debug)]
+pub(crate) struct ReportOpt {
+    /// Append a summary row for this run (timestamp, hyperdrive version, worst
+    /// file, maximum difference and verdict) to this CSV file.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) history: Option<PathBuf>,
+
+    /// Append a row of statistics for each file (or band) of this run
+    /// (timestamp, hyperdrive version, obsid, file, maximum difference, RMS
+    /// and verdict) to this CSV file, for tracking trends.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) csv: Option<PathBuf>,
+
+    /// The version of hyperdrive that produced the files, for the history
+    /// file. If not given, `hyperdrive --version` is used (if available).
+    #[structopt(long)]
+    pub(crate) hyperdrive_version: Option<String>,
+
+    /// Write a machine-readable JSON report of the run to this file: each
+    /// file's statistics and result, the overall verdict and the options
+    /// used.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) json: Option<PathBuf>,
+
+    /// Write a JUnit XML report to this file, with a test case per file (or
+    /// band), for CI systems to render.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) junit: Option<PathBuf>,
+
+    /// "json" prints the JSON report (see --json) to stdout instead of the
+    /// usual output.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    pub(crate) format: String,
+
+    /// Write a summary report of the run, stamped with its provenance (the
+    /// checker and hyperdrive versions, host, and hashes of the baseline data
+    /// and command-line arguments).
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) report: Option<PathBuf>,
+
+    /// Sign the --report with this minisign secret key, writing the signature
+    /// next to it (with a ".minisig" extension). Overrides the sign_key in the
+    /// --config file's [report] table.
+    #[structopt(long, env = "HYPERDRIVE_CHECKS_SIGN_KEY", parse(from_os_str))]
+    pub(crate) sign_key: Option<PathBuf>,
+
+    /// Write a shields.io endpoint badge JSON file saying whether this run
+    /// passed.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) badge: Option<PathBuf>,
+
+    /// The label of the badge written with --badge.
+    #[structopt(long, default_value = "numerical regression")]
+    pub(crate) badge_label: String,
+
+    /// Email a summary of the comparison to these addresses if it fails.
+    #[structopt(long, use_delimiter = true, requires = "smtp-server")]
+    pub(crate) email_to: Vec<String>,
+
+    /// The SMTP relay used to send emails, e.g. "smtp.example.org" or
+    /// "localhost:25".
+    #[structopt(long, env = "HYPERDRIVE_CHECKS_SMTP_SERVER")]
+    pub(crate) smtp_server: Option<String>,
+
+    /// The sender address of emails. Defaults to hyperdrive-checks@<hostname>.
+    #[structopt(long)]
+    pub(crate) email_from: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct DiffDirsOpt {
+    #[structopt(name = "A", parse(from_os_str))]
+    pub(crate) a: PathBuf,
+
+    #[structopt(name = "B", parse(from_os_str))]
+    pub(crate) b: PathBuf,
+
+    #[structopt(flatten)]
+    pub(crate) compare: CompareOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct WorkerOpt {
+    /// The address of the coordinator, e.g. "nid001234:7777".
+    #[structopt(name = "COORDINATOR")]
+    pub(crate) coordinator: String,
+
+    /// Print nothing but errors.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+
+    /// Print the results of each file (or band) compared, once; twice for the
+    /// statistics and timings of each chunk too.
+    #[structopt(short, long, parse(from_occurrences), conflicts_with = "quiet")]
+    pub(crate) verbose: u64,
+
+    #[structopt(flatten)]
+    pub(crate) read: ReadOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct DashboardOpt {
+    /// The history CSV files to plot; each is treated as a separate test
+    /// case, named after the file.
+    #[structopt(name = "HISTORY_FILES", required = true, parse(from_os_str))]
+    pub(crate) history_files: Vec<PathBuf>,
+
+    /// Where to write the HTML page.
+    #[structopt(short, long, default_value = "index.html", parse(from_os_str))]
+    pub(crate) output: PathBuf,
+
+    /// Don't say where the page was written.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct HistoryOpt {
+    /// The --csv or --history file.
+    #[structopt(name = "FILE", parse(from_os_str))]
+    pub(crate) file: PathBuf,
+
+    /// How many of the latest runs to look at.
+    #[structopt(long, default_value = "10", parse(try_from_str = parse_number))]
+    pub(crate) last: usize,
+
+    /// Flag maximum differences whose fitted trend grew by more than this
+    /// fraction of their mean over those runs.
+    #[structopt(long, default_value = "0.5", parse(try_from_str = parse_number))]
+    pub(crate) creep: f64,
+
+    /// Print nothing; only exit with 1 if anything is creeping upward.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct RunOpt {
+    #[structopt(name = "SUITE", parse(from_os_str))]
+    pub(crate) suite: PathBuf,
+
+    /// Write the summary as a JUnit XML report to this file, with a test case
+    /// per check.
+    #[structopt(long, parse(from_os_str))]
+    pub(crate) junit: Option<PathBuf>,
+
+    /// Print nothing but the summary of which checks passed.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct InfoOpt {
+    /// The Python interpreter that would read HDF5 files; whether they can be
+    /// read depends on whether it has h5py.
+    #[structopt(long, default_value = "python3")]
+    pub(crate) python: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct ExtractOpt {
+    #[structopt(name = "CURRENT", parse(from_os_str))]
+    pub(crate) current: PathBuf,
+
+    #[structopt(name = "BASELINE", parse(from_os_str))]
+    pub(crate) baseline: PathBuf,
+
+    /// The directory to write the piece into.
+    #[structopt(short, long, parse(from_os_str))]
+    pub(crate) output: PathBuf,
+
+    /// The number of fine channels in each timestep and baseline of the
+    /// files.
+    #[structopt(long)]
+    pub(crate) fine_channels: u64,
+
+    /// The number of timesteps in the files.
+    #[structopt(long)]
+    pub(crate) timesteps: usize,
+
+    /// The number of consecutive floats belonging to each fine channel; the
+    /// default is for 4 complex polarisations.
+    #[structopt(long, default_value = "8")]
+    pub(crate) floats_per_channel: u64,
+
+    /// The type of the values in the files.
+    #[structopt(long, default_value = "f32", possible_values = Dtype::NAMES)]
+    pub(crate) dtype: Dtype,
+
+    /// The byte order of the values in the files.
+    #[structopt(long, default_value = "little", possible_values = Endian::NAMES)]
+    pub(crate) endian: Endian,
+
+    /// The timesteps to extract, e.g. "3" or "2:5" (up to, but not
+    /// including, 5).
+    #[structopt(long, parse(try_from_str = extract::parse_range))]
+    pub(crate) timestep_range: Option<std::ops::Range<usize>>,
+
+    /// The baselines to extract.
+    #[structopt(long, parse(try_from_str = extract::parse_range))]
+    pub(crate) baseline_range: Option<std::ops::Range<usize>>,
+
+    /// The fine channels to extract; by default, all of them.
+    #[structopt(long, parse(try_from_str = extract::parse_range))]
+    pub(crate) channel_range: Option<std::ops::Range<usize>>,
+
+    /// Don't say what was extracted.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+
+    #[structopt(flatten)]
+    pub(crate) read: ReadOpt,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct SnapshotOpt {
+    /// The baseline directory to write to.
+    #[structopt(name = "DIR", default_value = "./baseline", parse(from_os_str))]
+    pub(crate) dir: PathBuf,
+
+    /// The names of the hyperdrive outputs to copy, rather than
+    /// hyperdrive_band??.bin and hyperdrive_band???.bin. Glob patterns are
+    /// allowed.
+    #[structopt(long, visible_alias = "pattern", use_delimiter = true)]
+    pub(crate) outputs: Vec<String>,
+
+    /// Before replacing anything, check that every file is a whole,
+    /// non-zero number of floats, none of them NaN or infinite.
+    #[structopt(long)]
+    pub(crate) verify: bool,
+
+    /// The type of the values in the files, for --verify.
+    #[structopt(long, default_value = "f32", possible_values = Dtype::NAMES)]
+    pub(crate) dtype: Dtype,
+
+    /// The byte order of the values in the files, for --verify.
+    #[structopt(long, default_value = "little", possible_values = Endian::NAMES)]
+    pub(crate) endian: Endian,
+
+    /// Store the files compressed with "zstd" or "gzip" (e.g. as
+    /// hyperdrive_band01.bin.zst), to save space. They're decompressed as
+    /// they're compared.
+    #[structopt(long, possible_values = compress::Codec::NAMES)]
+    pub(crate) compress: Option<compress::Codec>,
+
+    /// The version of hyperdrive that made the files, for the manifest. If
+    /// not given, `hyperdrive --version` is used (if available).
+    #[structopt(long)]
+    pub(crate) hyperdrive_version: Option<String>,
+
+    /// The git commit of the hyperdrive that made the files, for the
+    /// manifest. If not given, it's found in `hyperdrive --version` (if it
+    /// says).
+    #[structopt(long)]
+    pub(crate) hyperdrive_commit: Option<String>,
+
+    /// The CUDA version used to make the files, for the manifest. If not
+    /// given, it's asked of nvidia-smi (if there is one).
+    #[structopt(long)]
+    pub(crate) cuda_version: Option<String>,
+
+    /// A log of the hyperdrive run that made the files (e.g. its stdout),
+    /// from which the time of each stage is read and recorded in the
+    /// manifest, for later runs to compare against. May be given more than
+    /// once.
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    pub(crate) hyperdrive_log: Vec<PathBuf>,
+
+    /// How long the hyperdrive run that made the files took, in seconds.
+    /// Recorded like the stages of --hyperdrive-log.
+    #[structopt(long, parse(try_from_str = parse_number))]
+    pub(crate) wall_time: Option<f64>,
+
+    /// Don't list the files copied.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct ArchiveOpt {
+    /// The baseline directory to archive.
+    #[structopt(name = "DIR", default_value = "./baseline", parse(from_os_str))]
+    pub(crate) dir: PathBuf,
+
+    /// Where to write the archive; by default, DIR's name with
+    /// ".tar.zst", in the PWD.
+    #[structopt(short, long, parse(from_os_str))]
+    pub(crate) output: Option<PathBuf>,
+
+    /// Don't say what was archived.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct RestoreOpt {
+    #[structopt(name = "ARCHIVE", parse(from_os_str))]
+    pub(crate) archive: PathBuf,
+
+    /// The directory to restore into, which must be empty or not exist.
+    #[structopt(name = "DIR", default_value = "./baseline", parse(from_os_str))]
+    pub(crate) dir: PathBuf,
+
+    /// Only check that the archive is intact; nothing is restored.
+    #[structopt(long)]
+    pub(crate) verify_only: bool,
+
+    /// Don't say what was restored.
+    #[structopt(short, long)]
+    pub(crate) quiet: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct UpgradeReportOpt {
+    #[structopt(name = "REPORT", parse(from_os_str))]
+    pub(crate) report: PathBuf,
+
+    /// Where to write the upgraded report; by default, it's printed.
+    #[structopt(short, long, parse(from_os_str))]
+    pub(crate) output: Option<PathBuf>,
+}
+
+/// How much to print, from --quiet and --verbose.
+pub(crate) fn verbosity(quiet: bool, verbose: u64) -> Verbosity {
+    match (quiet, verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Summary,
+        (false, 1) => Verbosity::Files,
+        (false, _) => Verbosity::Chunks,
+    }
+}
+
+impl CompareOpt {
+    pub(crate) fn verbosity(&self) -> Verbosity {
+        verbosity(self.quiet, self.verbose)
+    }
+
+    pub(crate) fn comparison(&self) -> Result<Comparison, anyhow::Error> {
+        let metafits = match &self.metafits {
+            Some(path) => Some(Metafits::read(path)?),
+            None => None,
+        };
+        // Sampled and distributed comparisons don't know about channels.
+        let fine_channels = self.fine_channels.or_else(|| {
+            metafits
+                .as_ref()
+                .filter(|_| self.coordinator.is_none() && self.sample_fraction.is_none())
+                .map(Metafits::fine_channels)
+        });
+        Ok(Comparison {
+            estimate_sigma: self.max_sigma.is_some(),
+            sampling: self.sample_fraction.map(|fraction| Sampling {
+                fraction,
+                seed: self.seed,
+            }),
+            channels: fine_channels.map(|fine_channels| Channels {
+                fine_channels,
+                floats_per_channel: self.floats_per_channel,
+                ramp: self.edge_tolerance_ramp.clone().unwrap_or_default(),
+                exclude_edges: self.exclude_edge_channels.unwrap_or(0),
+                exclude_centre: self.exclude_centre_channel,
+                ignored: self.ignore_channels.clone().unwrap_or_default(),
+            }),
+            ranges: self.range.clone(),
+            mwaf_dir: self.mwaf_dir.clone(),
+            weights_dir: self.weights_dir.clone(),
+            mask_weights: self.mask_weights,
+            normalisation: if self.flip_weights
+                || self.zero_flagged
+                || !self.conjugate_pols.is_empty()
+            {
+                Some(Normalisation {
+                    floats_per_channel: self.floats_per_channel,
+                    weights: self.weights,
+                    flip_weights: self.flip_weights,
+                    zero_flagged: self.zero_flagged,
+                    conjugate: self.conjugate_pols.clone(),
+                })
+            } else {
+                None
+            },
+            solutions: match &self.apply_solutions {
+                Some(path) => Some((path.clone(), solutions::read(path)?)),
+                None => None,
+            },
+            autos: self.autos,
+            valid_prefix: self.compare_valid_prefix,
+            overlap: self.compare_overlap,
+            exact: self.exact,
+            write_diff: self.write_diff.clone(),
+            plot: self.plot.clone(),
+            top: self.top.filter(|_| !self.quiet),
+            baselines: self.baselines,
+            weights: self.weights,
+            metafits,
+            tolerance: self.tolerance,
+            rel_tolerance: self.rel_tolerance,
+            ulps: self.ulps,
+            imag_tolerance: self.imag_tolerance,
+            nan: match (self.allow_nan, self.nan_equal) {
+                (true, _) => NanPolicy::Allow,
+                (false, true) => NanPolicy::Equal,
+                (false, false) => NanPolicy::Fail,
+            },
+            rules: match &self.config {
+                Some(path) => config::read(path)?,
+                None => vec![],
+            },
+            complex: self.complex,
+            chi_squared: self.chi2_window.is_some(),
+            chi2_sigma: self.chi2_sigma,
+            ks: self.ks_test || self.ks_threshold.is_some(),
+            timesteps: self.timesteps,
+            dtype: self.dtype,
+            endian: self.endian,
+            threads: 1,
+            progress: None,
+        })
+    }
+
+    /// Are these differences too big?
+    pub(crate) fn failed(&self, d: &Differences) -> bool {
+        self.failed_at(d, self.tolerance)
+    }
+
+    /// Are these differences too big, given this (absolute) tolerance?
+    pub(crate) fn failed_at(&self, d: &Differences, tolerance: f32) -> bool {
+        if d.corrupt || d.unreadable.is_some() || d.nan_failures > 0 {
+            return true;
+        }
+        if self.max_rms_growth.is_some_and(|m| d.rms_growth > m) {
+            return true;
+        }
+        if let (Some(t), Some((_, p))) = (self.ks_threshold, d.ks) {
+            if p < t {
+                return true;
+            }
+        }
+        if self.max_weighted_rms.is_some_and(|m| d.weighted_rms > m) {
+            return true;
+        }
+        if self.max_rms.is_some_and(|m| d.rms() > m) {
+            return true;
+        }
+        if let Some(f) = self.max_frac_exceeding {
+            return d.frac_exceeding() > f;
+        }
+        if self.complex {
+            // The amplitudes are held to the tolerance instead of the floats.
+            return d.max_amp_scaled > tolerance || d.max_phase > self.phase_tolerance;
+        }
+        if let Some((low, high)) = self.chi2_window {
+            return !(low..=high).contains(&d.reduced_chi2());
+        }
+        match self.max_sigma {
+            Some(max_sigma) => d.max_sigmas > max_sigma,
+            // Counted while comparing, as a float within any one tolerance
+            // passes.
+            None if self.per_float_tolerances() => d.violations > 0,
+            None => d.max_scaled > tolerance,
+        }
+    }
+
+    /// Are floats checked against several (or per-file) tolerances?
+    pub(crate) fn per_float_tolerances(&self) -> bool {
+        self.rel_tolerance.is_some()
+            || self.ulps.is_some()
+            || self.imag_tolerance.is_some()
+            || self.config.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Opt, ArgMatches<'static>), structopt::clap::Error> {
+        let args: Vec<&str> = std::iter::once("hyperdrive-vis-gen-diff")
+            .chain(args.iter().copied())
+            .collect();
+        let matches = Opt::clap().get_matches_from_safe(args)?;
+        Ok((Opt::from_clap(&matches), matches))
+    }
+
+    #[test]
+    fn subcommands_take_their_own_options() {
+        let (opt, matches) = parse(&["diff", "--tolerance", "0.1", "-q", "old"]).unwrap();
+        let (compare, sub) = opt.into_comparison(&matches).unwrap();
+        assert_eq!(compare.tolerance, 0.1);
+        assert!(compare.quiet);
+        assert_eq!(sub.occurrences_of("tolerance"), 1);
+
+        // Those before the subcommand aren't its own (and are refused when
+        // run).
+        let (opt, matches) = parse(&["--tolerance", "0.1", "diff", "old"]).unwrap();
+        let (compare, sub) = opt.into_comparison(&matches).unwrap();
+        assert_eq!(compare.tolerance, 1e-3);
+        assert_eq!(sub.occurrences_of("tolerance"), 0);
+
+        let (opt, matches) = parse(&["diff-dirs", "a", "b", "--ulps", "4"]).unwrap();
+        assert_eq!(opt.into_comparison(&matches).unwrap().0.ulps, Some(4));
+
+        let (opt, matches) = parse(&["history", "runs.csv"]).unwrap();
+        assert!(opt.into_comparison(&matches).is_none());
+
+        // Only comparisons have comparison options.
+        assert!(parse(&["snapshot", "--tolerance", "0.1"]).is_err());
+        assert!(parse(&["worker", "h:1", "--mmap"]).is_ok());
+    }
+
+    #[test]
+    fn only_comparisons_are_summarised() {
+        let summarised = |args: &[&str]| parse(args).unwrap().0.summarised().is_some();
+        assert!(summarised(&[]));
+        assert!(summarised(&["diff", "old"]));
+        assert!(summarised(&["diff-dirs", "a", "b"]));
+        assert!(!summarised(&["--dry-run"]));
+        assert!(!summarised(&["diff", "--suggest-tolerance"]));
+        assert!(!summarised(&["diff", "--watch"]));
+        assert!(!summarised(&["suggest"]));
+        assert!(!summarised(&["dashboard", "history.json"]));
+    }
+
+    #[test]
+    fn conflicting_baselines_are_refused() {
+        for args in [
+            &["--baseline", "old:1e-3", "--runs", "a", "b"][..],
+            &["--baseline", "old:1e-3", "--baseline-window", "3"],
+            &["--baseline", "old:1e-3", "--dry-run"],
+            &["--runs", "a", "b", "--dry-run"],
+            &["--checkpoint", "c.json", "--baseline-window", "3"],
+            &["diff", "--watch", "--csv", "runs.csv"],
+        ] {
+            assert!(parse(args).is_err(), "{:?} wasn't refused", args);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Comparing several files at once, and each file in several pieces at once,
+//! without the results (or the output) depending on how many threads did it.
+
+use std::collections::BTreeMap;
+use std::ops::{ControlFlow, Range};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+/// The number of floats each thread reduces at a time. Statistics are always
+/// combined in the same order, chunk by chunk, so that sums come out the same
+/// regardless of the number of threads.
+const CHUNK: usize = 1 << 20;
+
+/// The number of threads available.
+pub(crate) fn available() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// How many jobs needing `bytes` of memory each fit in the memory available
+/// (as the kernel reports it), but no more than there are threads available.
+/// 1 if the available memory isn't known.
+pub(crate) fn fitting(bytes: u64) -> usize {
+    let available_memory = std::fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            meminfo.lines().find_map(|line| {
+                let kib = line
+                    .strip_prefix("MemAvailable:")?
+                    .trim()
+                    .strip_suffix("kB")?;
+                kib.trim().parse::<u64>().ok()
+            })
+        })
+        .map(|kib| kib * 1024);
+    match available_memory {
+        Some(memory) => (memory / bytes.max(1)).clamp(1, available() as u64) as usize,
+        None => 1,
+    }
+}
+
+/// Reduce the indices `0..len`: `fold` each chunk of them (on up to `threads`
+/// threads), then `combine` the results in order.
+pub(crate) fn reduce<T, F, C>(len: usize, threads: usize, fold: F, combine: C) -> T
+where
+    T: Send + Default,
+    F: Fn(Range<usize>) -> T + Sync,
+    C: Fn(T, T) -> T,
+{
+    let chunks: Vec<Range<usize>> = (0..len)
+        .step_by(CHUNK)
+        .map(|start| start..(start + CHUNK).min(len))
+        .collect();
+    if threads <= 1 || chunks.len() <= 1 {
+        return chunks.into_iter().map(&fold).fold(T::default(), &combine);
+    }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, T)> = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads.min(chunks.len()))
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match chunks.get(i) {
+                            Some(chunk) => results.push((i, fold(chunk.clone()))),
+                            None => return results,
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("A comparison thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results
+        .into_iter()
+        .map(|(_, t)| t)
+        .fold(T::default(), combine)
+}
+
+/// Run `job` on each of `items` on up to `jobs` threads. As results arrive,
+/// `done` is called (on this thread) with each item's index and result in
+/// order, so that output stays in order. Stops handing out items after an
+/// error, and returns the first, or once `done` breaks.
+pub(crate) fn for_each_ordered<I, T, J, D>(
+    items: &[I],
+    jobs: usize,
+    job: J,
+    mut done: D,
+) -> Result<(), anyhow::Error>
+where
+    I: Sync,
+    T: Send,
+    J: Fn(&I) -> Result<T, anyhow::Error> + Sync,
+    D: FnMut(usize, T) -> Result<ControlFlow<()>, anyhow::Error>,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        for (i, item) in items.iter().enumerate() {
+            if done(i, job(item)?)?.is_break() {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    std::thread::scope(|s| {
+        for _ in 0..jobs.min(items.len()) {
+            let tx = tx.clone();
+            let (next, stop, job) = (&next, &stop, &job);
+            s.spawn(move || loop {
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let item = match items.get(i) {
+                    Some(item) => item,
+                    None => return,
+                };
+                let result = job(item);
+                if result.is_err() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+                if tx.send((i, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(tx);
+
+        // Results that arrived before those of earlier items.
+        let mut waiting = BTreeMap::new();
+        let mut expected = 0;
+        for (i, result) in rx {
+            waiting.insert(i, result);
+            while let Some(result) = waiting.remove(&expected) {
+                match result.and_then(|t| done(expected, t)) {
+                    Ok(ControlFlow::Continue(())) => (),
+                    Ok(ControlFlow::Break(())) => {
+                        // Items already being compared are left unreported.
+                        stop.store(true, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        stop.store(true, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                }
+                expected += 1;
+            }
+        }
+        // Every item before a failing one was handed out before it, so the
+        // failure is always reached in order, above.
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reduce_combines_chunks_in_order() {
+        let len = 5 * CHUNK + 3;
+        let starts = |threads| {
+            reduce(
+                len,
+                threads,
+                |range| vec![range.start],
+                |mut a, b| {
+                    a.extend(b);
+                    a
+                },
+            )
+        };
+        let expected: Vec<usize> = (0..6).map(|i| i * CHUNK).collect();
+        for threads in [1, 2, 4, 16] {
+            assert_eq!(starts(threads), expected);
+        }
+
+        // So float sums don't depend on the number of threads.
+        let sum = |threads| {
+            reduce(
+                len,
+                threads,
+                |range| range.map(|i| 1.0 / (i as f32 + 1.0)).sum::<f32>(),
+                |a, b| a + b,
+            )
+        };
+        assert_eq!(sum(1).to_bits(), sum(7).to_bits());
+        assert_eq!(reduce(0, 4, |r| r.len(), |a, b| a + b), 0);
+    }
+
+    #[test]
+    fn for_each_ordered_reports_in_order() {
+        let items: Vec<u64> = (0..8).collect();
+        let mut seen = vec![];
+        // Later items finish first.
+        for_each_ordered(
+            &items,
+            4,
+            |&i| {
+                std::thread::sleep(std::time::Duration::from_millis(8 - i));
+                Ok(i * 10)
+            },
+            |i, t| {
+                seen.push((i, t));
+                Ok(ControlFlow::Continue(()))
+            },
+        )
+        .unwrap();
+        let expected: Vec<(usize, u64)> = (0..8).map(|i| (i as usize, i * 10)).collect();
+        assert_eq!(seen, expected);
+
+        // Nothing after the first failure or break is reported.
+        for jobs in [1, 4] {
+            let mut seen = vec![];
+            let e = for_each_ordered(
+                &items,
+                jobs,
+                |&i| match i {
+                    3 => Err(anyhow::anyhow!("item 3")),
+                    _ => Ok(i),
+                },
+                |i, _| {
+                    seen.push(i);
+                    Ok(ControlFlow::Continue(()))
+                },
+            )
+            .unwrap_err();
+            assert_eq!((e.to_string(), seen), ("item 3".to_string(), vec![0, 1, 2]));
+
+            let mut seen = vec![];
+            for_each_ordered(
+                &items,
+                jobs,
+                |&i| Ok(i),
+                |i, _| {
+                    seen.push(i);
+                    Ok(if i == 1 {
+                        ControlFlow::Break(())
+                    } else {
+                        ControlFlow::Continue(())
+                    })
+                },
+            )
+            .unwrap();
+            assert_eq!(seen, vec![0, 1]);
+        }
+    }
+}
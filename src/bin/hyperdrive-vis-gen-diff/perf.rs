@@ -0,0 +1,106 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Performance regressions: how long hyperdrive took, overall and in each
+//! stage, against the timings recorded in the baseline's manifest.
+//!
+//! Stage timings are read from hyperdrive's log, from lines like "Sky
+//! modelling took 3.2s", "Finished calibrating in 1m 2.5s" or "Total time:
+//! 82.5 s" (after any "[...]" log prefix). The times of stages logged several
+//! times (e.g. once per coarse band) are added up.
+
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+
+/// The name of the stage for the wall time of the whole run.
+pub(crate) const WALL_TIME: &str = "wall time";
+
+/// How long a stage took.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Timing {
+    pub(crate) stage: String,
+    pub(crate) seconds: f64,
+}
+
+/// A stage timed in both the current run and the baseline.
+pub(crate) struct Slowdown {
+    pub(crate) stage: String,
+    pub(crate) current: f64,
+    pub(crate) baseline: f64,
+}
+
+impl Slowdown {
+    /// How much slower the current run was, as a percentage (negative if it
+    /// was faster).
+    pub(crate) fn percent(&self) -> f64 {
+        100.0 * (self.current / self.baseline - 1.0)
+    }
+}
+
+/// The timings of a hyperdrive run: the stages in its `logs`, and its
+/// `wall_time` in seconds, if known.
+pub(crate) fn timings(
+    logs: &[PathBuf],
+    wall_time: Option<f64>,
+) -> Result<Vec<Timing>, anyhow::Error> {
+    let mut timings: Vec<Timing> = vec![];
+    if let Some(seconds) = wall_time {
+        timings.push(Timing {
+            stage: WALL_TIME.to_string(),
+            seconds,
+        });
+    }
+    for path in logs {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Couldn't read hyperdrive log {:?}: {}", path, e))?;
+        for t in text.lines().filter_map(parse_line) {
+            match timings.iter_mut().find(|e| e.stage == t.stage) {
+                Some(e) => e.seconds += t.seconds,
+                None => timings.push(t),
+            }
+        }
+    }
+    Ok(timings)
+}
+
+/// The stages timed in both `current` and `baseline`, in the order of
+/// `current`.
+pub(crate) fn compare(current: &[Timing], baseline: &[Timing]) -> Vec<Slowdown> {
+    current
+        .iter()
+        .filter_map(|c| {
+            let b = baseline.iter().find(|b| b.stage == c.stage)?;
+            Some(Slowdown {
+                stage: c.stage.clone(),
+                current: c.seconds,
+                baseline: b.seconds,
+            })
+        })
+        .filter(|s| s.baseline > 0.0)
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<Timing> {
+    let line = match line.rfind("] ") {
+        Some(i) => &line[i + 2..],
+        None => line,
+    };
+    let line = line.trim().trim_end_matches('.');
+    [" took ", " in ", ": "].iter().find_map(|sep| {
+        let i = line.rfind(sep)?;
+        let seconds = parse_duration(&line[i + sep.len()..])?;
+        let stage = line[..i].trim().to_lowercase();
+        Some(Timing { stage, seconds }).filter(|t| !t.stage.is_empty())
+    })
+}
+
+/// Parse a duration in a log, in seconds. Unlike on the command line, a bare
+/// number isn't taken to be seconds, as it's as likely to be a count.
+fn parse_duration(s: &str) -> Option<f64> {
+    if !s.trim_end().ends_with(char::is_alphabetic) {
+        return None;
+    }
+    crate::wait::parse_duration(s).ok().map(|d| d.as_secs_f64())
+}
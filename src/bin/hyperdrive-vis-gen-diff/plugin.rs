@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! git-style external subcommands, so that site-specific checks (e.g. of
+//! ingestion into the Pawsey archive) can extend this tool without forking it.
+//!
+//! `hyperdrive-vis-gen-diff [OPTIONS] foo [ARGS]` runs the executable
+//! "hyperdrive-checks-foo" found on the PATH with ARGS. The options given
+//! before "foo" are parsed as usual and passed on as a JSON object in the
+//! HYPERDRIVE_CHECKS_OPTIONS environment variable; HYPERDRIVE_CHECKS is the
+//! path of this executable, so that the plugin can call back into it.
+
+use std::ffi::OsString;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::anyhow;
+use structopt::StructOpt;
+
+use crate::json::json_string;
+use crate::{CompareOpt, Opt};
+
+const PREFIX: &str = "hyperdrive-checks-";
+
+/// The built-in subcommands, which plugins can't replace.
+const BUILT_IN: &[&str] = &[
+    "diff",
+    "suggest",
+    "diff-dirs",
+    "worker",
+    "dashboard",
+    "history",
+    "info",
+    "extract",
+    "snapshot",
+    "run",
+    "archive",
+    "restore",
+    "upgrade-report",
+    "help",
+];
+
+/// Find the plugin executable for subcommand `name` on the PATH.
+pub(crate) fn find(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(format!("{}{}", PREFIX, name)))
+        .find(|exe| is_executable(exe))
+}
+
+/// The names of the plugins on the PATH, sorted, without duplicates.
+pub(crate) fn discover() -> Vec<String> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut names: Vec<String> = std::env::split_paths(&path)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .filter(|entry| is_executable(&entry.path()))
+        .filter_map(|entry| {
+            let name = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(PREFIX)?
+                .to_string();
+            Some(name).filter(|n| !n.is_empty() && !BUILT_IN.contains(&n.as_str()))
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether `program` is an executable on the PATH.
+pub(crate) fn on_path(program: &str) -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path).any(|dir| is_executable(&dir.join(program)))
+}
+
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// If `args` (including the executable's path) run a plugin, return the index
+/// of the plugin's name. This must be decided before the arguments are parsed,
+/// because otherwise the name would be taken as the baseline directory.
+pub(crate) fn split(args: &[OsString]) -> Option<usize> {
+    (1..args.len()).find(|&i| {
+        let name = match args[i].to_str() {
+            Some(name) => name,
+            None => return false,
+        };
+        !name.starts_with('-')
+            && !BUILT_IN.contains(&name)
+            && !Path::new(name).exists()
+            && find(name).is_some()
+            // Otherwise, it's the value of an option, e.g. "-t foo".
+            && Opt::clap().get_matches_from_safe(&args[..i]).is_ok()
+    })
+}
+
+/// Replace this process with the plugin for subcommand `name`, given `args`.
+pub(crate) fn run(
+    name: &str,
+    args: &[OsString],
+    baseline_dir: &Path,
+    options: &CompareOpt,
+) -> Result<(), anyhow::Error> {
+    let exe = find(name).ok_or_else(|| {
+        anyhow!(
+            "Unknown subcommand \"{}\", and there's no {}{} on the PATH",
+            name,
+            PREFIX,
+            name
+        )
+    })?;
+    let mut command = Command::new(&exe);
+    command.args(args).env(
+        "HYPERDRIVE_CHECKS_OPTIONS",
+        options_json(baseline_dir, options),
+    );
+    if let Ok(me) = std::env::current_exe() {
+        command.env("HYPERDRIVE_CHECKS", me);
+    }
+    // Only returns if the plugin couldn't be run.
+    let e = command.exec();
+    Err(anyhow!("Couldn't run {:?}: {}", exe, e))
+}
+
+/// The options that plugins (and reports) are likely to need, as a JSON
+/// object. `baseline_dir` is the baseline compared against.
+pub(crate) fn options_json(baseline_dir: &Path, options: &CompareOpt) -> String {
+    let string = |s: &str| json_string(s);
+    let path = |p: &Path| json_string(&p.display().to_string());
+    let optional = |s: Option<String>| s.unwrap_or_else(|| "null".to_string());
+    let fields = [
+        ("version", string(env!("CARGO_PKG_VERSION"))),
+        ("baseline_dir", path(baseline_dir)),
+        ("preset", optional(options.preset.map(|p| string(p.name)))),
+        ("tolerance", options.tolerance.to_string()),
+        (
+            "rel_tolerance",
+            optional(options.rel_tolerance.map(|r| r.to_string())),
+        ),
+        ("ulps", optional(options.ulps.map(|u| u.to_string()))),
+        (
+            "imag_tolerance",
+            optional(options.imag_tolerance.map(|t| t.to_string())),
+        ),
+        (
+            "max_sigma",
+            optional(options.max_sigma.map(|m| m.to_string())),
+        ),
+        ("quiet", options.quiet.to_string()),
+        (
+            "outputs",
+            format!(
+                "[{}]",
+                options
+                    .outputs
+                    .iter()
+                    .map(|o| string(o))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ),
+        ("bands", optional(options.bands.map(|b| b.to_string()))),
+        (
+            "fine_channels",
+            optional(options.fine_channels.map(|f| f.to_string())),
+        ),
+        ("floats_per_channel", options.floats_per_channel.to_string()),
+        (
+            "timesteps",
+            optional(options.timesteps.map(|t| t.to_string())),
+        ),
+        ("obsid", optional(options.obsid.map(|o| o.to_string()))),
+        ("metafits", optional(options.metafits.as_deref().map(path))),
+        (
+            "history",
+            optional(options.reports.history.as_deref().map(path)),
+        ),
+        (
+            "report",
+            optional(options.reports.report.as_deref().map(path)),
+        ),
+        (
+            "hyperdrive_version",
+            optional(options.reports.hyperdrive_version.as_deref().map(string)),
+        ),
+    ];
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(k, v)| format!("{}: {}", json_string(k), v))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
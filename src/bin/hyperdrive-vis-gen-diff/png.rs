@@ -0,0 +1,204 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A minimal PNG writer, for heatmaps. See
+//! <https://www.w3.org/TR/png/> and RFC 1950/1951 (zlib and deflate).
+//!
+//! Heatmaps are blocks of flat colour, so each row is filtered to zeros
+//! where it can be (against the row above, or the pixel to the left), and
+//! runs of repeated bytes are deflated with fixed Huffman codes. That's
+//! nowhere near as good as zlib in general, but fine for these.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+
+/// Write an 8-bit RGB image of `width` by `height` pixels, given row by row
+/// from the top, to `path`.
+pub(crate) fn write_rgb(
+    path: &Path,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> Result<(), anyhow::Error> {
+    let stride = width * 3;
+    assert_eq!(pixels.len(), stride * height);
+    let mut filtered = Vec::with_capacity((stride + 1) * height);
+    for y in 0..height {
+        let row = &pixels[y * stride..(y + 1) * stride];
+        if y > 0 && row == &pixels[(y - 1) * stride..y * stride] {
+            // "Up": the same as the row above, so all zeros.
+            filtered.push(2);
+            filtered.extend(std::iter::repeat_n(0, stride));
+        } else {
+            // "Sub": the difference from the pixel to the left.
+            filtered.push(1);
+            filtered.extend((0..stride).map(|x| {
+                let left = if x >= 3 { row[x - 3] } else { 0 };
+                row[x].wrapping_sub(left)
+            }));
+        }
+    }
+
+    let mut header = vec![];
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // 8-bit RGB, deflate, filtered per row, not interlaced.
+    header.extend([8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut png, b"IHDR", &header);
+    chunk(&mut png, b"IDAT", &zlib(&filtered));
+    chunk(&mut png, b"IEND", &[]);
+    std::fs::write(path, png).map_err(|e| anyhow!("Couldn't write {:?}: {}", path, e))
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    png.extend(crc32(&[kind.as_slice(), data].concat()).to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// Bits written least significant first, as deflate packs them.
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    acc: u32,
+    n: u32,
+}
+
+impl Bits {
+    fn write(&mut self, value: u32, n: u32) {
+        self.acc |= value << self.n;
+        self.n += n;
+        while self.n >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.n -= 8;
+        }
+    }
+
+    /// Huffman codes are packed most significant bit first.
+    fn code(&mut self, code: u32, n: u32) {
+        self.write(code.reverse_bits() >> (32 - n), n);
+    }
+
+    /// A literal byte or length symbol (0-285) with the fixed Huffman codes.
+    fn symbol(&mut self, s: u32) {
+        match s {
+            0..=143 => self.code(0x30 + s, 8),
+            144..=255 => self.code(0x190 + s - 144, 9),
+            256..=279 => self.code(s - 256, 7),
+            _ => self.code(0xC0 + s - 280, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.n > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+/// The length symbols of deflate: each one's first length, and number of
+/// extra bits.
+const LENGTHS: [(u32, u32); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// `data` as a zlib stream of one fixed-Huffman deflate block, in which runs of
+/// a repeated byte are copies from a distance of 1.
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits::default();
+    // The final block, with fixed Huffman codes.
+    bits.write(1, 1);
+    bits.write(1, 2);
+    let mut i = 0;
+    while i < data.len() {
+        let run = if i > 0 {
+            data[i..]
+                .iter()
+                .take(258)
+                .take_while(|&&b| b == data[i - 1])
+                .count()
+        } else {
+            0
+        };
+        if run < 3 {
+            bits.symbol(data[i] as u32);
+            i += 1;
+            continue;
+        }
+        let s = LENGTHS
+            .iter()
+            .rposition(|&(first, _)| first as usize <= run)
+            .expect("runs are at least 3 long");
+        let (first, extra) = LENGTHS[s];
+        bits.symbol(257 + s as u32);
+        bits.write(run as u32 - first, extra);
+        // Distance code 0, a distance of 1.
+        bits.code(0, 5);
+        i += run;
+    }
+    bits.symbol(256);
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(bits.finish());
+    zlib.extend(adler32(data).to_be_bytes());
+    zlib
+}
@@ -0,0 +1,292 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Named bundles of settings, so that scripts don't each carry their own
+//! slightly different magic numbers.
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::NanPolicy;
+use structopt::clap::ArgMatches;
+
+use crate::CompareOpt;
+
+/// A named set of defaults. Anything given explicitly on the command line
+/// wins.
+#[derive(Debug)]
+pub(crate) struct Preset {
+    pub(crate) name: &'static str,
+    tolerance: f32,
+    /// If set, compare in units of the estimated noise instead (see
+    /// --max-sigma).
+    max_sigma: Option<f32>,
+    /// If set, floats also pass within these (see --rel-tolerance and
+    /// --imag-tolerance).
+    rel_tolerance: Option<f32>,
+    imag_tolerance: Option<f32>,
+    /// If set, a file only fails if more than this fraction of its floats
+    /// exceed the tolerance (see --max-frac-exceeding).
+    max_frac_exceeding: Option<f64>,
+    /// Which NaNs fail (see --allow-nan and --nan-equal).
+    nan: NanPolicy,
+    /// Why the preset's numbers are what they are, for reports.
+    pub(crate) rationale: &'static str,
+}
+
+pub(crate) const PRESETS: &[Preset] = &[
+    Preset {
+        name: "strict",
+        tolerance: 1e-6,
+        max_sigma: None,
+        rel_tolerance: None,
+        imag_tolerance: None,
+        max_frac_exceeding: None,
+        nan: NanPolicy::Fail,
+        rationale: "The same build on the same hardware should agree almost exactly.",
+    },
+    Preset {
+        name: "gpu",
+        tolerance: 5e-3,
+        max_sigma: None,
+        rel_tolerance: None,
+        imag_tolerance: None,
+        max_frac_exceeding: Some(1e-5),
+        nan: NanPolicy::Fail,
+        rationale: "CUDA's fast single-precision intrinsics (e.g. __sinf) trade accuracy for speed, so GPU outputs drift from CPU baselines by more than rounding alone: the tolerance is loose, and up to 1 in 100,000 floats, where an intrinsic put a lone float much further off, may exceed it.",
+    },
+    Preset {
+        name: "gpu-vs-cpu",
+        tolerance: 1e-4,
+        max_sigma: None,
+        rel_tolerance: Some(1e-4),
+        imag_tolerance: Some(5e-4),
+        max_frac_exceeding: None,
+        nan: NanPolicy::Fail,
+        rationale: "The GPU sums visibilities in a different order to the CPU, so single-precision rounding differences grow with the visibilities themselves: a float passes within 1e-4 of the baseline relatively, or within an absolute floor of 1e-4 for small values. Imaginary parts are often near zero, where relative differences are meaningless but rounding errors are still the size of those in the whole visibility, so they get a floor of 5e-4.",
+    },
+    Preset {
+        name: "nightly",
+        tolerance: 1e-3,
+        max_sigma: Some(0.1),
+        rel_tolerance: None,
+        imag_tolerance: None,
+        max_frac_exceeding: None,
+        nan: NanPolicy::Equal,
+        rationale: "Nightly runs on real data; differences only matter relative to the noise. Flagged visibilities are NaN in both files, so only NaNs in one file but not the other fail.",
+    },
+    Preset {
+        name: "acceptance",
+        tolerance: 1e-4,
+        max_sigma: None,
+        rel_tolerance: None,
+        imag_tolerance: None,
+        max_frac_exceeding: None,
+        nan: NanPolicy::Fail,
+        rationale: "Outputs attached to sign-off documents are held to a tight tolerance.",
+    },
+];
+
+/// The options that each option a preset can set conflicts with, as clap
+/// checks when they're given explicitly (see [Opt]). A preset's settings
+/// aren't arguments, so clap can't check them.
+const CONFLICTS: &[(&str, &[&str])] = &[
+    ("allow-nan", &["coordinator"]),
+    ("nan-equal", &["allow-nan", "coordinator"]),
+    (
+        "max-sigma",
+        &[
+            "rel-tolerance",
+            "ulps",
+            "imag-tolerance",
+            "config",
+            "complex",
+            "max-frac-exceeding",
+            "chi2-window",
+            "tolerances",
+        ],
+    ),
+    (
+        "rel-tolerance",
+        &["max-sigma", "chi2-window", "tolerances", "coordinator"],
+    ),
+    (
+        "imag-tolerance",
+        &[
+            "complex",
+            "max-sigma",
+            "chi2-window",
+            "tolerances",
+            "coordinator",
+        ],
+    ),
+    (
+        "max-frac-exceeding",
+        &[
+            "rel-tolerance",
+            "ulps",
+            "config",
+            "complex",
+            "max-sigma",
+            "chi2-window",
+            "coordinator",
+        ],
+    ),
+];
+
+pub(crate) fn parse_preset(s: &str) -> Result<&'static Preset, String> {
+    PRESETS.iter().find(|p| p.name == s).ok_or_else(|| {
+        let names: Vec<&str> = PRESETS.iter().map(|p| p.name).collect();
+        format!(
+            "Unknown preset \"{}\"; expected one of {}",
+            s,
+            names.join(", ")
+        )
+    })
+}
+
+impl Preset {
+    /// Fill in whatever wasn't given explicitly in `matches` from this
+    /// preset. Fails if the preset sets an option that conflicts with one that
+    /// was given.
+    pub(crate) fn apply(
+        &self,
+        options: &mut CompareOpt,
+        matches: &ArgMatches,
+    ) -> Result<(), anyhow::Error> {
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        let any_given = |names: &[&str]| names.iter().any(|&n| given(n));
+        if !given("tolerance") {
+            options.tolerance = self.tolerance;
+        }
+        if !any_given(&["allow-nan", "nan-equal"]) {
+            options.allow_nan = self.nan == NanPolicy::Allow;
+            options.nan_equal = self.nan == NanPolicy::Equal;
+        }
+        self.fill_in_comparison(options, &given);
+
+        let set = [
+            ("allow-nan", options.allow_nan),
+            ("nan-equal", options.nan_equal),
+            ("max-sigma", options.max_sigma.is_some()),
+            ("rel-tolerance", options.rel_tolerance.is_some()),
+            ("imag-tolerance", options.imag_tolerance.is_some()),
+            ("max-frac-exceeding", options.max_frac_exceeding.is_some()),
+        ];
+        for (option, conflicts) in CONFLICTS {
+            let by_preset = set.iter().any(|(o, s)| o == option && *s) && !given(option);
+            if let Some(conflict) = conflicts.iter().find(|&&c| by_preset && given(c)) {
+                bail!(
+                    "The {} preset sets --{}, which can't be used with --{}",
+                    self.name,
+                    option,
+                    conflict
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in how to compare the floats from this preset, unless it was given
+    /// explicitly.
+    fn fill_in_comparison(&self, options: &mut CompareOpt, given: &dyn Fn(&str) -> bool) {
+        let any_given = |names: &[&str]| names.iter().any(|&n| given(n));
+        // Choosing how to compare explicitly overrides the preset's choice. Any
+        // tolerance for the floats means comparing them against it rather than
+        // the noise, and any other way of comparing means none of the preset's
+        // ways apply.
+        if any_given(&[
+            "max-sigma",
+            "chi2-window",
+            "tolerances",
+            "complex",
+            "config",
+            "coordinator",
+        ]) {
+            return;
+        }
+        if !any_given(&[
+            "tolerance",
+            "rel-tolerance",
+            "ulps",
+            "imag-tolerance",
+            "max-frac-exceeding",
+        ]) {
+            options.max_sigma = self.max_sigma;
+        }
+        if options.max_sigma.is_some() {
+            return;
+        }
+        if !any_given(&["rel-tolerance", "max-frac-exceeding"]) {
+            options.rel_tolerance = self.rel_tolerance;
+        }
+        if !given("imag-tolerance") {
+            options.imag_tolerance = self.imag_tolerance;
+        }
+        // Neither works with relative or ULP tolerances.
+        if !any_given(&["max-frac-exceeding", "rel-tolerance", "ulps"]) {
+            options.max_frac_exceeding = self.max_frac_exceeding;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opt;
+    use structopt::StructOpt;
+
+    /// The options given by `args`, with the preset applied.
+    fn options(args: &[&str]) -> Result<CompareOpt, anyhow::Error> {
+        let args: Vec<&str> = std::iter::once("hyperdrive-vis-gen-diff")
+            .chain(args.iter().copied())
+            .collect();
+        let matches = Opt::clap().get_matches_from_safe(args)?;
+        let mut options = Opt::from_clap(&matches).diff.compare;
+        options.preset.unwrap().apply(&mut options, &matches)?;
+        Ok(options)
+    }
+
+    #[test]
+    fn given_options_win() {
+        let nightly = options(&["--preset", "nightly"]).unwrap();
+        assert_eq!(nightly.max_sigma, Some(0.1));
+        assert!(nightly.nan_equal);
+
+        // A tolerance means comparing against it rather than the noise.
+        let tolerance = options(&["--preset", "nightly", "--tolerance", "1e-2"]).unwrap();
+        assert_eq!((tolerance.tolerance, tolerance.max_sigma), (1e-2, None));
+        let allow = options(&["--preset", "nightly", "--allow-nan"]).unwrap();
+        assert!(allow.allow_nan && !allow.nan_equal);
+
+        let gpu = options(&["--preset", "gpu-vs-cpu", "--max-frac-exceeding", "0.1"]).unwrap();
+        assert_eq!(gpu.rel_tolerance, None);
+        assert_eq!(gpu.imag_tolerance, Some(5e-4));
+        let gpu = options(&["--preset", "gpu", "--ulps", "4"]).unwrap();
+        assert_eq!(gpu.max_frac_exceeding, None);
+    }
+
+    #[test]
+    fn conflicts_with_the_preset_are_refused() {
+        let e = options(&["--preset", "nightly", "--coordinator", "0.0.0.0:7777"])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            e.contains("--nan-equal") && e.contains("--coordinator"),
+            "{}",
+            e
+        );
+        // Presets that leave the NaNs failing don't conflict.
+        assert!(options(&["--preset", "strict", "--coordinator", "0.0.0.0:7777"]).is_ok());
+    }
+
+    #[test]
+    fn rationales_are_distinct() {
+        for (i, a) in PRESETS.iter().enumerate() {
+            for b in &PRESETS[i + 1..] {
+                let first = |p: &Preset| p.rationale.split(&[',', '.', ':'][..]).next().unwrap();
+                assert_ne!(first(a), first(b), "{} and {}", a.name, b.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A progress line for comparisons of large files, which can otherwise give no
+//! feedback for minutes: how many files are done, and how much of each file
+//! being compared has been read. It's redrawn in place on stdout, so it's only
+//! shown when that's a terminal.
+
+use std::io::{IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Don't redraw more often than this.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The width of each bar, in characters.
+const BAR_WIDTH: usize = 20;
+
+pub(crate) struct Progress {
+    files: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    done: usize,
+    /// The name, bytes read and total bytes of each file being compared, in
+    /// the order they were started.
+    in_flight: Vec<(String, u64, u64)>,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    /// Progress through `files` files (or bands), unless `quiet` or stdout
+    /// isn't a terminal.
+    pub(crate) fn new(files: usize, quiet: bool) -> Option<Progress> {
+        if quiet || !std::io::stdout().is_terminal() {
+            return None;
+        }
+        Some(Progress {
+            files,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Start comparing the file `name`, of which `total` bytes will be read.
+    pub(crate) fn start(&self, name: &str, total: u64) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        state.in_flight.push((name.to_string(), 0, total));
+        self.draw(&mut state, false);
+    }
+
+    /// Another `bytes` of the file `name` have been read.
+    pub(crate) fn advance(&self, name: &str, bytes: u64) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        if let Some(file) = state.in_flight.iter_mut().find(|f| f.0 == name) {
+            file.1 = (file.1 + bytes).min(file.2);
+        }
+        self.draw(&mut state, false);
+    }
+
+    /// All of the file `name` has been read, at once.
+    pub(crate) fn read_all(&self, name: &str) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        if let Some(file) = state.in_flight.iter_mut().find(|f| f.0 == name) {
+            file.1 = file.2;
+        }
+        self.draw(&mut state, false);
+    }
+
+    /// The file `name` has been compared (or couldn't be).
+    pub(crate) fn finish(&self, name: &str) {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        state.in_flight.retain(|f| f.0 != name);
+        state.done += 1;
+        self.draw(&mut state, false);
+    }
+
+    /// Take the progress line down while `f` prints, then put it back.
+    pub(crate) fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        let mut state = self.state.lock().expect("progress lock poisoned");
+        clear();
+        let result = f();
+        self.draw(&mut state, true);
+        result
+    }
+
+    /// Take the progress line down for good.
+    pub(crate) fn clear(&self) {
+        let _state = self.state.lock().expect("progress lock poisoned");
+        clear();
+    }
+
+    fn draw(&self, state: &mut State, force: bool) {
+        if !force && state.drawn.is_some_and(|d| d.elapsed() < REDRAW_INTERVAL) {
+            return;
+        }
+        state.drawn = Some(Instant::now());
+        let mut line = format!(
+            "{} {}/{} files",
+            bar(state.done as u64, self.files as u64),
+            state.done,
+            self.files
+        );
+        if let Some((name, read, total)) = state.in_flight.first() {
+            line.push_str(&format!(
+                " | {} {} {}/{}",
+                name,
+                bar(*read, *total),
+                size(*read),
+                size(*total)
+            ));
+            if state.in_flight.len() > 1 {
+                line.push_str(&format!(" (+{} more)", state.in_flight.len() - 1));
+            }
+        }
+        let mut stdout = std::io::stdout().lock();
+        // Nothing can be done if the terminal has gone away.
+        let _ = write!(stdout, "\r\x1b[K{}", line);
+        let _ = stdout.flush();
+    }
+}
+
+fn clear() {
+    let mut stdout = std::io::stdout().lock();
+    let _ = write!(stdout, "\r\x1b[K");
+    let _ = stdout.flush();
+}
+
+fn bar(done: u64, total: u64) -> String {
+    let filled = if total == 0 {
+        BAR_WIDTH
+    } else {
+        (done as f64 / total as f64 * BAR_WIDTH as f64) as usize
+    };
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+}
+
+/// A number of bytes, in the largest unit that keeps it at least 1.
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
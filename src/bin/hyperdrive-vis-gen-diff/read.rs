@@ -0,0 +1,662 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reading files of floats, robustly and quickly on parallel filesystems.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::bail;
+
+pub(crate) use pawsey_hyperdrive_checks::bytes_to_f32s;
+
+use crate::compress::{Codec, Stream};
+use crate::history::utc_timestamp;
+
+/// Buffers, offsets and lengths of reads with O_DIRECT must be multiples of the
+/// filesystem's logical block size; this is a multiple of all common ones.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// The size of each read when reads must be chunked (for O_DIRECT or
+/// throttling) but no stripe size is known.
+const DEFAULT_CHUNK: usize = 16 * 1024 * 1024;
+
+/// The `f_type` reported by statfs for Lustre filesystems.
+#[cfg(target_os = "linux")]
+const LUSTRE_SUPER_MAGIC: u64 = 0x0BD0_0BD0;
+
+/// Reads files, retrying IO that fails because of a transient filesystem
+/// error (e.g. a Lustre metadata server hiccup), splitting reads of files on
+/// striped filesystems into concurrent, stripe-aligned chunks, and optionally
+/// bypassing the page cache. Compressed files are read through their
+/// decompressors.
+pub(crate) struct Reader {
+    /// How many times to retry before giving up.
+    pub(crate) retries: u32,
+    /// How long to wait before the first retry; this doubles with each
+    /// subsequent retry.
+    pub(crate) backoff: Duration,
+    /// If set, read in chunks of this many bytes in parallel, regardless of the
+    /// filesystem. Otherwise, the stripe size of files on Lustre is used.
+    pub(crate) stripe_size: Option<u64>,
+    /// How many concurrent reads to issue per file. If not set, the stripe
+    /// count of the file is used (bounded by the available parallelism).
+    pub(crate) threads: Option<usize>,
+    /// Bypass the page cache by reading with O_DIRECT.
+    pub(crate) direct_io: bool,
+    /// Map files into memory, rather than reading them, where possible.
+    pub(crate) mmap: bool,
+    /// Limits the rate at which all files are read, if set.
+    pub(crate) throttle: Option<Throttle>,
+    /// How many retries have been needed so far.
+    pub(crate) used: AtomicU32,
+    pub(crate) quiet: bool,
+    /// The decompressors of the compressed files being read, kept between
+    /// reads so that a file read in order is only decompressed once.
+    pub(crate) streams: Mutex<HashMap<PathBuf, Stream>>,
+}
+
+impl Reader {
+    /// Run `f`, retrying it if it fails with a transient IO error.
+    fn retry<T, F: FnMut() -> std::io::Result<T>>(
+        &self,
+        path: &Path,
+        mut f: F,
+    ) -> std::io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    attempt += 1;
+                    self.used.fetch_add(1, Ordering::Relaxed);
+                    if !self.quiet {
+                        eprintln!(
+                            "Warning: transient error reading {:?} ({}); retry {}/{} in {:?}",
+                            path, e, attempt, self.retries, backoff
+                        );
+                    }
+                    sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Work out how best to read the file at `path`.
+    pub(crate) fn layout(&self, path: &Path) -> Layout {
+        let (stripe_size, stripe_count) = match self.stripe_size {
+            Some(s) => (Some(s), None),
+            None if is_lustre(path) => lustre_layout(path),
+            None => (None, None),
+        };
+        let available = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let threads = self
+            .threads
+            .unwrap_or_else(|| stripe_count.unwrap_or(available).min(available))
+            .max(1);
+        Layout {
+            stripe_size,
+            threads,
+        }
+    }
+
+    /// Read the whole file at `path`.
+    pub(crate) fn read_bytes(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        if Codec::of(path).is_some() {
+            return Stream::open(path)?.read_to_end();
+        }
+        let layout = self.layout(path);
+        self.retry(path, || {
+            let mut file = open(path, self.direct_io)?;
+            let stripe = match layout.stripe() {
+                Some(stripe) => stripe,
+                _ if self.direct_io || self.throttle.is_some() => DEFAULT_CHUNK,
+                // Nothing special required; just read the whole thing.
+                _ => {
+                    let mut bytes = vec![];
+                    file.read_to_end(&mut bytes)?;
+                    return Ok(bytes);
+                }
+            };
+            let len = file.metadata()?.len() as usize;
+            let align = if self.direct_io {
+                DIRECT_IO_ALIGNMENT
+            } else {
+                1
+            };
+            read_chunked(
+                &file,
+                0,
+                len,
+                round_up(stripe, align),
+                align,
+                layout.threads,
+                self.throttle.as_ref(),
+            )
+        })
+    }
+
+    /// Read `len` bytes from `offset` in the file at `path`, laid out as
+    /// `layout`, failing if there aren't that many.
+    pub(crate) fn read_range(
+        &self,
+        path: &Path,
+        layout: &Layout,
+        offset: u64,
+        len: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        if Codec::of(path).is_some() {
+            let mut bytes = vec![0; len];
+            let read = self.read_decompressed(path, offset, &mut bytes)?;
+            if read != len {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("expected to read {} bytes, but read {}", len, read),
+                ));
+            }
+            return Ok(bytes);
+        }
+        // O_DIRECT reads whole blocks, so it's only used for aligned ranges;
+        // the last range of a file is usually read through the page cache.
+        let align = if self.direct_io
+            && offset.is_multiple_of(DIRECT_IO_ALIGNMENT as u64)
+            && len.is_multiple_of(DIRECT_IO_ALIGNMENT)
+        {
+            DIRECT_IO_ALIGNMENT
+        } else {
+            1
+        };
+        self.retry(path, || {
+            let file = open(path, align > 1)?;
+            let stripe = layout.stripe().unwrap_or(DEFAULT_CHUNK);
+            read_chunked(
+                &file,
+                offset,
+                len,
+                round_up(stripe, align),
+                align,
+                layout.threads,
+                self.throttle.as_ref(),
+            )
+        })
+    }
+
+    /// Read only the given blocks of `block_bytes` bytes from the file at
+    /// `path`, concatenated. The last block of the file may be short.
+    pub(crate) fn read_blocks(
+        &self,
+        path: &Path,
+        blocks: &[u64],
+        block_bytes: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        if Codec::of(path).is_some() {
+            let mut bytes = vec![0; blocks.len() * block_bytes];
+            let mut total = 0;
+            for &block in blocks {
+                let buf = &mut bytes[total..total + block_bytes];
+                total += self.read_decompressed(path, block * block_bytes as u64, buf)?;
+            }
+            bytes.truncate(total);
+            return Ok(bytes);
+        }
+        // As with ranges, O_DIRECT is only used if the blocks are aligned.
+        let align = if self.direct_io && block_bytes.is_multiple_of(DIRECT_IO_ALIGNMENT) {
+            DIRECT_IO_ALIGNMENT
+        } else {
+            1
+        };
+        self.retry(path, || {
+            let file = open(path, align > 1)?;
+            let mut bytes = vec![0; blocks.len() * block_bytes + align];
+            let start = bytes.as_ptr().align_offset(align);
+            let mut total = 0;
+            for &block in blocks {
+                let buf = &mut bytes[start + total..start + total + block_bytes];
+                if let Some(throttle) = &self.throttle {
+                    throttle.acquire(buf.len());
+                }
+                total += read_full_at(&file, buf, block * block_bytes as u64)?;
+            }
+            bytes.truncate(start + total);
+            bytes.drain(..start);
+            Ok(bytes)
+        })
+    }
+
+    /// Fill `buf` from `offset` in the decompressed data of the file at
+    /// `path`, stopping early only at the end of it. Returns the number of
+    /// bytes read. Reading from where the last read stopped is cheapest; going
+    /// backwards means decompressing from the start again.
+    fn read_decompressed(
+        &self,
+        path: &Path,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        let stream = self.streams.lock().unwrap().remove(path);
+        let mut stream = match stream {
+            Some(s) if s.position <= offset => s,
+            _ => Stream::open(path)?,
+        };
+        stream.skip_to(offset)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(buf.len());
+        }
+        let read = stream.read_full(buf)?;
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), stream);
+        Ok(read)
+    }
+
+    /// Map the whole file at `path` into memory, if asked to and it isn't
+    /// compressed.
+    pub(crate) fn map(&self, path: &Path) -> std::io::Result<Option<Mapped>> {
+        if !self.mmap || Codec::of(path).is_some() {
+            return Ok(None);
+        }
+        self.retry(path, || Mapped::new(path)).map(Some)
+    }
+
+    /// Finish decompressing the file at `path`, if it was being read, failing
+    /// if the decompressor found it corrupt.
+    pub(crate) fn close(&self, path: &Path) -> std::io::Result<()> {
+        let stream = self.streams.lock().unwrap().remove(path);
+        match stream {
+            Some(s) => s.finish().map(drop),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A file mapped read-only into memory, unmapped when dropped.
+pub(crate) struct Mapped {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only, so it can be read from any thread.
+unsafe impl Send for Mapped {}
+unsafe impl Sync for Mapped {}
+
+impl Mapped {
+    fn new(path: &Path) -> std::io::Result<Mapped> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // mmap refuses empty mappings.
+            return Ok(Mapped {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // Safe: a fresh mapping of an open file, checked for failure.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Only a hint to read ahead, so failure doesn't matter.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapped { ptr, len })
+    }
+
+    /// `len` bytes from `offset`, failing if there aren't that many.
+    pub(crate) fn bytes(&self, offset: u64, len: usize) -> std::io::Result<&[u8]> {
+        let offset = offset as usize;
+        if offset + len > self.len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "expected to read {} bytes, but read {}",
+                    len,
+                    self.len.saturating_sub(offset)
+                ),
+            ));
+        }
+        if len == 0 {
+            return Ok(&[]);
+        }
+        // Safe: within the mapping, which lives as long as self.
+        Ok(unsafe { std::slice::from_raw_parts((self.ptr as *const u8).add(offset), len) })
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+/// `bytes` as f32s in this machine's byte order, without copying, if they're
+/// aligned for it.
+pub(crate) fn as_f32s(bytes: &[u8]) -> Option<&[f32]> {
+    // Safe: every bit pattern is a valid f32.
+    match unsafe { bytes.align_to::<f32>() } {
+        (&[], floats, &[]) => Some(floats),
+        _ => None,
+    }
+}
+
+/// How a file is laid out on its filesystem.
+pub(crate) struct Layout {
+    stripe_size: Option<u64>,
+    /// How many concurrent reads to issue.
+    threads: usize,
+}
+
+impl Layout {
+    /// The size of the chunks to read concurrently, if that's worth doing.
+    fn stripe(&self) -> Option<usize> {
+        match self.stripe_size {
+            Some(stripe) if self.threads > 1 && stripe > 0 => Some(stripe as usize),
+            _ => None,
+        }
+    }
+}
+
+/// A file's size and modification time, to tell whether it changed while it
+/// was being read.
+#[derive(PartialEq)]
+pub(crate) struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    pub(crate) fn of(path: &Path) -> Result<Stamp, anyhow::Error> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    /// Fail if the file at `path`, stamped with `self` before it was read, has
+    /// changed since, e.g. because a hyperdrive job is still writing it.
+    pub(crate) fn check_unchanged(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let now = Stamp::of(path)?;
+        if now != *self {
+            bail!(
+                "{:?} changed during comparison (from {} to {}); is a hyperdrive job still writing to it?",
+                path,
+                self,
+                now
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Stamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} bytes", self.len)?;
+        if let Some(modified) = self.modified {
+            write!(f, " modified {}", utc_timestamp(modified))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read as much of the file at `path` as can be read, stopping at the first
+/// error, to find out how much of a corrupt file is still usable.
+pub(crate) fn readable_prefix(path: &Path) -> Vec<u8> {
+    let mut bytes = vec![];
+    if let Ok(mut file) = File::open(path) {
+        let mut buf = vec![0; DEFAULT_CHUNK];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => bytes.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(_) => break,
+            }
+        }
+    }
+    bytes
+}
+
+/// Caps the aggregate rate of reads (over all threads and files) by making
+/// readers wait until their reads fit inside the budget.
+pub(crate) struct Throttle {
+    /// Bytes per second.
+    rate: u64,
+    /// When throttling started, and how many bytes have been read since.
+    state: Mutex<(Instant, u64)>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            rate: bytes_per_second.max(1),
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Block until reading another `bytes` bytes doesn't exceed the rate.
+    fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().expect("throttle lock poisoned");
+            let (start, read) = *state;
+            // Don't let time spent idle (e.g. comparing rather than reading)
+            // build up a burst allowance bigger than a second of reading.
+            let elapsed = start.elapsed();
+            let allowed_since_start = elapsed.as_secs_f64() * self.rate as f64;
+            let (start, read) = if allowed_since_start - read as f64 > self.rate as f64 {
+                (Instant::now() - Duration::from_secs(1), 0)
+            } else {
+                (start, read)
+            };
+            let read = read + bytes as u64;
+            *state = (start, read);
+            let due = Duration::from_secs_f64(read as f64 / self.rate as f64);
+            due.checked_sub(start.elapsed())
+        };
+        if let Some(wait) = wait {
+            sleep(wait);
+        }
+    }
+}
+
+/// Parse a read rate like "500M" or "1.5G/s" (bytes per second); see
+/// [parse_size].
+pub(crate) fn parse_rate(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    parse_size(trimmed.strip_suffix("/s").unwrap_or(trimmed))
+}
+
+/// Parse a size like "4M" or "1.5G" (bytes, with binary prefixes K, M, G and
+/// T). An optional trailing "B" is ignored.
+pub(crate) fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let trimmed = trimmed.strip_suffix(&['B', 'b'][..]).unwrap_or(trimmed);
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('K') | Some('k') => (&trimmed[..trimmed.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&trimmed[..trimmed.len() - 1], 1 << 40),
+        _ => (trimmed, 1),
+    };
+    let number: f64 = crate::parse_number(number)
+        .map_err(|e| format!("Couldn't parse \"{}\" as a size, e.g. \"500M\": {}", s, e))?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(format!("\"{}\" must be positive", s));
+    }
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Is this an error that a filesystem might recover from by itself?
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::ESTALE))
+}
+
+fn round_up(n: usize, multiple: usize) -> usize {
+    n.div_ceil(multiple) * multiple
+}
+
+#[cfg(target_os = "linux")]
+fn open(path: &Path, direct_io: bool) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    if direct_io {
+        options.custom_flags(libc::O_DIRECT);
+    }
+    options.open(path).map_err(|e| {
+        if direct_io && e.raw_os_error() == Some(libc::EINVAL) {
+            std::io::Error::new(
+                e.kind(),
+                format!("the filesystem doesn't support O_DIRECT ({})", e),
+            )
+        } else {
+            e
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(path: &Path, direct_io: bool) -> std::io::Result<File> {
+    if direct_io {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "O_DIRECT is only supported on Linux",
+        ));
+    }
+    File::open(path)
+}
+
+/// Read `len` bytes of `file` from `offset` in chunks of `chunk` bytes with `threads`
+/// concurrent readers. Chunks are dealt out to the readers in turn, so that
+/// with stripe-sized chunks the readers are spread over the storage targets
+/// holding the file. The buffer read into is aligned to `align` bytes (and the
+/// chunks are a multiple of it), as O_DIRECT requires.
+fn read_chunked(
+    file: &File,
+    offset: u64,
+    len: usize,
+    chunk: usize,
+    align: usize,
+    threads: usize,
+    throttle: Option<&Throttle>,
+) -> std::io::Result<Vec<u8>> {
+    let padded = round_up(len, align);
+    let mut bytes = vec![0; padded + align];
+    let start = bytes.as_ptr().align_offset(align);
+
+    let mut work: Vec<Vec<(u64, &mut [u8])>> = (0..threads).map(|_| vec![]).collect();
+    for (i, c) in bytes[start..start + padded].chunks_mut(chunk).enumerate() {
+        work[i % threads].push((offset + (i * chunk) as u64, c));
+    }
+
+    let total = std::thread::scope(|s| {
+        let handles: Vec<_> = work
+            .into_iter()
+            .map(|chunks| {
+                s.spawn(move || -> std::io::Result<usize> {
+                    let mut total = 0;
+                    for (offset, c) in chunks {
+                        if let Some(throttle) = throttle {
+                            throttle.acquire(c.len());
+                        }
+                        total += read_full_at(file, c, offset)?;
+                    }
+                    Ok(total)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("reader thread panicked"))
+            .sum::<std::io::Result<usize>>()
+    })?;
+    if total != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("expected to read {} bytes, but read {}", len, total),
+        ));
+    }
+
+    bytes.truncate(start + len);
+    bytes.drain(..start);
+    Ok(bytes)
+}
+
+/// Fill `buf` from `offset` in `file`, stopping early only at the end of the
+/// file. Returns the number of bytes read.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read_at(&mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(target_os = "linux")]
+fn is_lustre(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    // Safe: c_path is a valid, NUL-terminated string and buf is a valid
+    // statfs struct for the kernel to fill.
+    let ret = unsafe { libc::statfs(c_path.as_ptr(), &mut buf) };
+    ret == 0 && buf.f_type as u64 == LUSTRE_SUPER_MAGIC
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_lustre(_path: &Path) -> bool {
+    false
+}
+
+/// Ask `lfs` for the (stripe size, stripe count) of a file on Lustre.
+fn lustre_layout(path: &Path) -> (Option<u64>, Option<usize>) {
+    let query = |flag: &str| -> Option<u64> {
+        let output = Command::new("lfs")
+            .arg("getstripe")
+            .arg(flag)
+            .arg(path)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .find_map(|s| s.parse().ok())
+    };
+    (query("-S"), query("-c").map(|c| c as usize))
+}
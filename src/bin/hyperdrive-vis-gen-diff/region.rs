@@ -0,0 +1,152 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Restrict the comparison to a slice of each file (or band), e.g. to bisect
+//! a difference that's only in the first timestep. Slices are given as ranges
+//! of floats, or (knowing the layout of the data) of timesteps or fine
+//! channels; the floats outside them are left out like excluded channels.
+
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::bail;
+
+use crate::channels::Channels;
+use crate::extract;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Axis {
+    Floats,
+    Timesteps,
+    Channels,
+}
+
+impl Axis {
+    fn name(self) -> &'static str {
+        match self {
+            Axis::Floats => "floats",
+            Axis::Timesteps => "timesteps",
+            Axis::Channels => "channels",
+        }
+    }
+}
+
+/// A range of one axis, as given with --range.
+#[derive(Clone, Debug)]
+pub(crate) struct Slice {
+    pub(crate) axis: Axis,
+    pub(crate) range: Range<usize>,
+}
+
+impl fmt::Display for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}..{}",
+            self.axis.name(),
+            self.range.start,
+            self.range.end
+        )
+    }
+}
+
+/// Parse a slice like "1000..2000" (floats), "timesteps=0..1" or
+/// "channels=4..8" (up to, but not including, the end).
+pub(crate) fn parse(s: &str) -> Result<Slice, String> {
+    let (axis, range) = match s.split_once('=') {
+        Some(("floats", r)) => (Axis::Floats, r),
+        Some(("timesteps", r)) => (Axis::Timesteps, r),
+        Some(("channels", r)) => (Axis::Channels, r),
+        Some((a, _)) => {
+            return Err(format!(
+                "Unknown axis \"{}\"; expected floats, timesteps or channels",
+                a
+            ))
+        }
+        None => (Axis::Floats, s),
+    };
+    Ok(Slice {
+        axis,
+        range: extract::parse_range(range)?,
+    })
+}
+
+/// The slices resolved for one file (or band) of `floats` floats.
+pub(crate) struct Region {
+    floats: Range<usize>,
+    timesteps: Option<Range<usize>>,
+    floats_per_timestep: usize,
+    channels: Option<Range<u64>>,
+    floats_per_channel: u64,
+    fine_channels: u64,
+}
+
+impl Region {
+    /// `timesteps` is the number of timesteps in the file (or band), if it's
+    /// known, and `channels` its fine channels.
+    pub(crate) fn new(
+        slices: &[Slice],
+        floats: u64,
+        timesteps: Option<u64>,
+        channels: Option<&Channels>,
+    ) -> Result<Region, anyhow::Error> {
+        let floats = floats as usize;
+        let mut region = Region {
+            floats: 0..floats,
+            timesteps: None,
+            floats_per_timestep: 0,
+            channels: None,
+            floats_per_channel: 0,
+            fine_channels: 0,
+        };
+        for slice in slices {
+            let len = match slice.axis {
+                Axis::Floats => floats,
+                Axis::Timesteps => match timesteps {
+                    Some(t) if t > 0 => {
+                        region.floats_per_timestep = floats / t as usize;
+                        t as usize
+                    }
+                    _ => bail!(
+                        "--range {} needs the number of timesteps; see --timesteps, --baselines and --metafits",
+                        slice
+                    ),
+                },
+                Axis::Channels => match channels {
+                    Some(c) if c.floats_per_channel > 0 => {
+                        region.floats_per_channel = c.floats_per_channel;
+                        region.fine_channels = c.fine_channels;
+                        c.fine_channels as usize
+                    }
+                    _ => bail!(
+                        "--range {} needs the number of fine channels; see --fine-channels and --metafits",
+                        slice
+                    ),
+                },
+            };
+            if slice.range.end > len {
+                bail!("Can't compare {}; there are only {}", slice, len);
+            }
+            let range = slice.range.clone();
+            match slice.axis {
+                Axis::Floats => region.floats = range,
+                Axis::Timesteps => region.timesteps = Some(range),
+                Axis::Channels => region.channels = Some(range.start as u64..range.end as u64),
+            }
+        }
+        Ok(region)
+    }
+
+    /// Whether float `i` of the file (or band) is in the region.
+    pub(crate) fn contains(&self, i: usize) -> bool {
+        self.floats.contains(&i)
+            && self
+                .timesteps
+                .as_ref()
+                .is_none_or(|t| t.contains(&(i / self.floats_per_timestep)))
+            && self.channels.as_ref().is_none_or(|c| {
+                c.contains(&(i as u64 / self.floats_per_channel % self.fine_channels))
+            })
+    }
+}
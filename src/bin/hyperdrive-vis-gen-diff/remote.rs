@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Baselines kept in object storage (e.g. Acacia) or on a web server, rather
+//! than on a local filesystem.
+//!
+//! The baseline's manifest.toml is downloaded first; it lists the files to
+//! download, and their sizes and XXH64 hashes, which every download must
+//! match. HTTP(S) URLs are downloaded with curl, and s3:// URLs with the AWS
+//! CLI (whose endpoint, e.g. Acacia's, is set with AWS_ENDPOINT_URL). The
+//! files are cached, keyed by the URL and the manifest, so they're only
+//! downloaded again when the baseline changes.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+
+use crate::container::cache_dir;
+use crate::hash;
+use crate::manifest::{Manifest, MANIFEST};
+
+/// Download the baseline at `url` (if it isn't cached already) and return the
+/// directory it's in.
+pub(crate) fn fetch(url: &str, quiet: bool) -> Result<PathBuf, anyhow::Error> {
+    fetch_into(url, &cache_dir("remote")?, quiet)
+}
+
+/// Like [fetch], with the cache in `cache`.
+fn fetch_into(url: &str, cache: &Path, quiet: bool) -> Result<PathBuf, anyhow::Error> {
+    let url = url.trim_end_matches('/');
+    std::fs::create_dir_all(cache)?;
+    // Download somewhere else first, so that an interrupted download is never
+    // mistaken for a complete one.
+    let partial = cache.join(format!("{:016x}.partial", hash::xxh64(url.as_bytes(), 0)));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    std::fs::create_dir_all(&partial)?;
+
+    download(&format!("{}/{}", url, MANIFEST), &partial.join(MANIFEST))?;
+    let manifest = Manifest::read(&partial)?
+        .ok_or_else(|| anyhow!("Couldn't download {}/{}", url, MANIFEST))?;
+    let text = std::fs::read(partial.join(MANIFEST))?;
+    let key = [url.as_bytes(), b"\0", &text].concat();
+    let dir = cache.join(format!("{:016x}", hash::xxh64(&key, 0)));
+    if dir.is_dir() {
+        std::fs::remove_dir_all(&partial)?;
+        if !quiet {
+            println!("Using the cached copy of {} in {}", url, dir.display());
+        }
+        return Ok(dir);
+    }
+
+    if !quiet {
+        println!(
+            "Downloading {} files from {} ...",
+            manifest.files.len(),
+            url
+        );
+    }
+    for f in &manifest.files {
+        let path = partial.join(&f.name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        download(&format!("{}/{}", url, f.name.display()), &path)?;
+        let (bytes, xxh64) = hash::xxh64_file(&path)?;
+        if (bytes, xxh64) != (f.bytes, f.xxh64) {
+            bail!(
+                "{}/{} has {} bytes with XXH64 {:016x}, but its manifest says {} bytes with XXH64 {:016x}",
+                url,
+                f.name.display(),
+                bytes,
+                xxh64,
+                f.bytes,
+                f.xxh64
+            );
+        }
+    }
+    std::fs::rename(&partial, &dir)?;
+    Ok(dir)
+}
+
+/// The schemes of the URLs that baselines can be fetched from, and the program
+/// each is downloaded with.
+pub(crate) const SCHEMES: &[(&str, &str)] = &[("https", "curl"), ("http", "curl"), ("s3", "aws")];
+
+/// Download `url` to the file `to`.
+fn download(url: &str, to: &Path) -> Result<(), anyhow::Error> {
+    let mut command = if url.starts_with("s3://") {
+        let mut c = Command::new("aws");
+        c.args(["s3", "cp", "--only-show-errors", url]).arg(to);
+        c
+    } else if url.starts_with("https://") || url.starts_with("http://") {
+        let mut c = Command::new("curl");
+        c.args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--output",
+        ])
+        .arg(to)
+        .arg(url);
+        c
+    } else {
+        bail!("Expected an https:// or s3:// URL, not \"{}\"", url);
+    };
+    let output = command
+        .output()
+        .map_err(|e| anyhow!("Couldn't run {:?}: {}", command.get_program(), e))?;
+    if !output.status.success() {
+        bail!(
+            "Couldn't download {}:\n{}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{prelude::*, BufReader};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::manifest::ManifestFile;
+
+    /// The files served, by path, and the paths asked for.
+    type Served = Arc<Mutex<(HashMap<String, Vec<u8>>, Vec<String>)>>;
+
+    /// Serve `served` over HTTP on another thread, returning its URL.
+    fn serve(served: Served) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let path = request.split(' ').nth(1).unwrap_or("").to_string();
+                let mut s = served.lock().unwrap();
+                let response = match s.0.get(&path) {
+                    Some(body) => [
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", body.len()).as_bytes(),
+                        b"Connection: close\r\n\r\n",
+                        body,
+                    ]
+                    .concat(),
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                s.1.push(path);
+                let _ = stream.write_all(&response);
+            }
+        });
+        url
+    }
+
+    /// A manifest listing `band01.bin` with `data`, as it would be written.
+    fn manifest_of(data: &[u8], dir: &Path) -> Vec<u8> {
+        let manifest = Manifest {
+            hyperdrive_version: "0.2.0".to_string(),
+            files: vec![ManifestFile {
+                name: PathBuf::from("band01.bin"),
+                bytes: data.len() as u64,
+                xxh64: hash::xxh64(data, 0),
+            }],
+            ..Manifest::default()
+        };
+        manifest.write(dir).unwrap();
+        std::fs::read(dir.join(MANIFEST)).unwrap()
+    }
+
+    #[test]
+    fn baselines_are_downloaded_once() {
+        let cache = std::env::temp_dir().join(format!("remote-{}", std::process::id()));
+        std::fs::create_dir_all(&cache).unwrap();
+        let data = b"\0\0\x80\x3f".to_vec();
+        let served: Served = Default::default();
+        served.lock().unwrap().0.extend([
+            (
+                "/base/manifest.toml".to_string(),
+                manifest_of(&data, &cache),
+            ),
+            ("/base/band01.bin".to_string(), data.clone()),
+        ]);
+        let url = serve(Arc::clone(&served));
+
+        let dir = fetch_into(&format!("{}/base/", url), &cache, true).unwrap();
+        assert_eq!(std::fs::read(dir.join("band01.bin")).unwrap(), data);
+        // Only the manifest is downloaded again while it's unchanged.
+        let again = fetch_into(&format!("{}/base", url), &cache, true).unwrap();
+        assert_eq!(again, dir);
+        assert_eq!(
+            served.lock().unwrap().1,
+            [
+                "/base/manifest.toml",
+                "/base/band01.bin",
+                "/base/manifest.toml"
+            ]
+        );
+
+        // A damaged download is refused, and not cached.
+        served.lock().unwrap().0.extend([
+            ("/bad/manifest.toml".to_string(), manifest_of(&data, &cache)),
+            ("/bad/band01.bin".to_string(), b"\0\0\0\0".to_vec()),
+        ]);
+        let e = fetch_into(&format!("{}/bad", url), &cache, true).unwrap_err();
+        assert!(e.to_string().contains("but its manifest says"), "{}", e);
+        let e = fetch_into(&format!("{}/missing", url), &cache, true).unwrap_err();
+        assert!(e.to_string().starts_with("Couldn't download"), "{}", e);
+        std::fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn only_known_schemes_are_downloaded() {
+        let e = download("ftp://host/manifest.toml", Path::new("manifest.toml")).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Expected an https:// or s3:// URL, not \"ftp://host/manifest.toml\""
+        );
+    }
+}
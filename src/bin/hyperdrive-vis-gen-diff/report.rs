@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Reports stamped with where their results came from, and optionally signed
+//! with minisign, so that a report attached to a sign-off document can be
+//! checked for tampering (`minisign -V -p key.pub -m report.txt`).
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::Dtype;
+
+use crate::environment::Environment;
+use crate::manifest::Manifest;
+use crate::{hash, Differences, FilePair};
+
+/// Everything needed to reproduce (or distrust) a report.
+pub(crate) struct Provenance {
+    pub(crate) timestamp: String,
+    pub(crate) hyperdrive_version: String,
+    pub(crate) host: String,
+    pub(crate) obsid: Option<u64>,
+    /// The XXH64 hash of the baseline manifest; see [manifest_hash].
+    pub(crate) baseline_manifest: u64,
+    /// The XXH64 hash of the command-line arguments.
+    pub(crate) config: u64,
+    /// Where the baseline came from, if it has a manifest.
+    pub(crate) baseline: Option<Manifest>,
+    pub(crate) environment: Environment,
+}
+
+impl Provenance {
+    fn render(&self) -> String {
+        let mut rendered = format!(
+            "checker: {} {}\nhyperdrive: {}\nhost: {}\ntime: {}\nobsid: {}\nbaseline manifest xxh64: {:016x}\nconfig xxh64: {:016x}\n",
+            env!("CARGO_BIN_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            self.hyperdrive_version,
+            self.host,
+            self.timestamp,
+            self.obsid.map_or("unknown".to_string(), |o| o.to_string()),
+            self.baseline_manifest,
+            self.config
+        );
+        if let Some(m) = &self.baseline {
+            for (key, value) in m.fields() {
+                rendered.push_str(&format!("baseline {}: {}\n", key, value));
+            }
+        }
+        for (key, value) in self.environment.fields() {
+            rendered.push_str(&format!("{}: {}\n", key.replace('_', " "), value));
+        }
+        rendered
+    }
+}
+
+/// Hash a manifest of the baseline data compared: one line per file (or band)
+/// with its name, size in bytes and XXH64 hash (or "-" if it wasn't fully
+/// read, e.g. when sampling).
+pub(crate) fn manifest_hash(
+    pairs: &[FilePair],
+    diffs: &[Differences],
+    dtype: Dtype,
+) -> Result<u64, anyhow::Error> {
+    let mut manifest = String::new();
+    for (pair, d) in pairs.iter().zip(diffs.iter()) {
+        let size = pair.bytes(&pair.baseline, dtype)?;
+        let hash = match d.hashes {
+            Some((_, b)) => format!("{:016x}", b),
+            None => "-".to_string(),
+        };
+        manifest.push_str(&format!("{}\t{}\t{}\n", pair.name.display(), size, hash));
+    }
+    Ok(hash::xxh64(manifest.as_bytes(), 0))
+}
+
+/// Hash the command-line arguments (other than the executable's path).
+pub(crate) fn config_hash() -> u64 {
+    let mut args = String::new();
+    for arg in std::env::args_os().skip(1) {
+        args.push_str(&arg.to_string_lossy());
+        args.push('\0');
+    }
+    hash::xxh64(args.as_bytes(), 0)
+}
+
+/// Write the provenance, then `body`, to `path`.
+pub(crate) fn write(path: &Path, provenance: &Provenance, body: &str) -> Result<(), anyhow::Error> {
+    std::fs::write(path, format!("{}\n{}", provenance.render(), body))
+        .map_err(|e| anyhow!("Couldn't write report {:?}: {}", path, e))
+}
+
+/// Sign the report at `path` with the minisign secret key `key`, creating
+/// `<path>.minisig` next to it. minisign asks for the key's password, if it
+/// has one.
+pub(crate) fn sign(path: &Path, key: &Path) -> Result<(), anyhow::Error> {
+    let status = Command::new("minisign")
+        .arg("-S")
+        .arg("-s")
+        .arg(key)
+        .arg("-m")
+        .arg(path)
+        .status()
+        .map_err(|e| anyhow!("Couldn't run minisign to sign {:?}: {}", path, e))?;
+    if !status.success() {
+        bail!("minisign failed to sign {:?} ({})", path, status);
+    }
+    Ok(())
+}
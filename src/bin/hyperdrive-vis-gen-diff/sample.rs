@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Compare a random (but reproducible) subset of each file, for fast screening
+//! of huge datasets.
+//!
+//! Files are split into blocks of [BLOCK_LEN] floats, and each block is
+//! compared with probability `fraction`. Whole blocks are sampled because
+//! scattered single-float reads would be far slower than reading everything.
+
+use std::path::Path;
+
+/// The number of floats in a block.
+pub(crate) const BLOCK_LEN: usize = 4096;
+
+pub(crate) struct Sampling {
+    /// The fraction of blocks to compare, in (0, 1].
+    pub(crate) fraction: f64,
+    pub(crate) seed: u64,
+}
+
+impl Sampling {
+    /// Choose which of the `total_blocks` blocks of the file called `name` to
+    /// compare. The choice depends only on the seed and the name, so repeated
+    /// runs (and the current and baseline files) sample the same blocks. At
+    /// least one block is always chosen.
+    pub(crate) fn choose_blocks(&self, name: &Path, total_blocks: u64) -> Vec<u64> {
+        let mut rng = SplitMix64(self.seed ^ fnv1a(name.to_string_lossy().as_bytes()));
+        let mut blocks: Vec<u64> = (0..total_blocks)
+            .filter(|_| rng.next_f64() < self.fraction)
+            .collect();
+        if blocks.is_empty() && total_blocks > 0 {
+            blocks.push(rng.next_u64() % total_blocks);
+        }
+        blocks
+    }
+}
+
+/// If none of `n` randomly-sampled blocks exceeded the tolerance, then with 95%
+/// confidence, fewer than this fraction of all blocks would.
+pub(crate) fn upper_bound_95(n: u64) -> f64 {
+    if n == 0 {
+        1.0
+    } else {
+        1.0 - 0.05f64.powf(1.0 / n as f64)
+    }
+}
+
+pub(crate) fn parse_fraction(s: &str) -> Result<f64, String> {
+    let f: f64 = crate::parse_number(s)?;
+    if f > 0.0 && f <= 1.0 {
+        Ok(f)
+    } else {
+        Err(format!("The sample fraction must be in (0, 1], not {}", s))
+    }
+}
+
+/// A small, fast PRNG; see <https://prng.di.unimi.it/splitmix64.c>.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform float in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
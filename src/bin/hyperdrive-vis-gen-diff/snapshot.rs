@@ -0,0 +1,194 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Promote the hyperdrive outputs in the PWD to be the baseline.
+//!
+//! Every file is copied next to its destination first, and only renamed into
+//! place once all of them have been copied (and verified), so that a failed
+//! snapshot leaves the old baseline as it was. Files may be stored compressed,
+//! in which case the manifest describes the compressed files.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::{glob_files, sort_naturally, Dtype, Endian};
+
+use crate::compress::{self, Codec};
+use crate::manifest::{Manifest, ManifestFile};
+use crate::perf::Timing;
+use crate::{email, hash, history};
+
+/// How many bytes to copy at a time.
+const CHUNK: usize = 1 << 22;
+
+/// The hyperdrive (and CUDA) that made the files being promoted.
+pub(crate) struct Producer {
+    pub(crate) hyperdrive_version: String,
+    pub(crate) hyperdrive_commit: Option<String>,
+    pub(crate) cuda_version: Option<String>,
+    /// How long hyperdrive took to make the files, if known.
+    pub(crate) timings: Vec<Timing>,
+}
+
+/// Copy the files in the PWD matching `patterns` into the baseline directory
+/// `dir`, replacing (or removing) the files there matching them, and write a
+/// manifest. If `verify` is set, every file must be a whole, non-zero number
+/// of finite values of that type and byte order. If `compress` is set, the
+/// files are stored compressed with it.
+pub(crate) fn snapshot(
+    dir: &Path,
+    patterns: &[String],
+    verify: Option<(Dtype, Endian)>,
+    compress: Option<Codec>,
+    producer: Producer,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let glob_all = |dir: &Path, extensions: &[&str]| -> Result<Vec<PathBuf>, anyhow::Error> {
+        let mut files = vec![];
+        for p in patterns {
+            files.append(&mut glob_files(dir, p)?);
+            for e in extensions {
+                files.append(&mut glob_files(dir, &format!("{}.{}", p, e))?);
+            }
+        }
+        sort_naturally(&mut files);
+        Ok(files)
+    };
+    let names = glob_all(Path::new("."), &[])?;
+    if names.is_empty() {
+        bail!("PWD does not have any {} files!", patterns.join(" or "));
+    }
+    std::fs::create_dir_all(dir).map_err(|e| anyhow!("Couldn't create {:?}: {}", dir, e))?;
+
+    // The names the files are stored under.
+    let stored: Vec<PathBuf> = names
+        .iter()
+        .map(|name| match compress {
+            Some(codec) => PathBuf::from(format!("{}.{}", name.display(), codec.extension())),
+            None => name.clone(),
+        })
+        .collect();
+    // Beside where the file will be, which may be in a subdirectory.
+    let partial = |name: &Path| {
+        let file_name = name.file_name().unwrap_or_default().to_string_lossy();
+        dir.join(name)
+            .with_file_name(format!(".{}.partial", file_name))
+    };
+    let mut files = vec![];
+    for (name, file) in names.iter().zip(&stored) {
+        if let Some(parent) = dir.join(name).parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("Couldn't create {:?}: {}", parent, e))?;
+        }
+        let copied = copy(name, &partial(name), verify).and_then(|hashed| match compress {
+            Some(codec) => {
+                compress::compress(codec, &partial(name), &partial(file))?;
+                hash::xxh64_file(&partial(file))
+            }
+            None => Ok(hashed),
+        });
+        match copied {
+            Ok((bytes, xxh64)) => files.push(ManifestFile {
+                name: file.clone(),
+                bytes,
+                xxh64,
+            }),
+            Err(e) => {
+                for name in names.iter().chain(&stored) {
+                    let _ = std::fs::remove_file(partial(name));
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    for stale in glob_all(dir, compress::EXTENSIONS)?
+        .iter()
+        .filter(|f| !stored.contains(f))
+    {
+        if !quiet {
+            println!("Removing {:?}; it isn't in the PWD", dir.join(stale));
+        }
+        std::fs::remove_file(dir.join(stale))?;
+    }
+    for file in &stored {
+        std::fs::rename(partial(file), dir.join(file))?;
+        if !quiet {
+            println!("Copied {:?} to {}", file, dir.display());
+        }
+    }
+
+    Manifest {
+        created: history::utc_timestamp(SystemTime::now()),
+        host: email::hostname(),
+        source: std::env::current_dir()?,
+        hyperdrive_version: producer.hyperdrive_version,
+        hyperdrive_commit: producer.hyperdrive_commit,
+        cuda_version: producer.cuda_version,
+        files,
+        timings: producer.timings,
+    }
+    .write(dir)
+}
+
+/// Copy `from` to `to`, returning the number of bytes copied and their XXH64
+/// hash.
+fn copy(
+    from: &Path,
+    to: &Path,
+    verify: Option<(Dtype, Endian)>,
+) -> Result<(u64, u64), anyhow::Error> {
+    let mut src = File::open(from).map_err(|e| anyhow!("Couldn't open {:?}: {}", from, e))?;
+    let mut dst = File::create(to).map_err(|e| anyhow!("Couldn't create {:?}: {}", to, e))?;
+    let mut hasher = hash::Xxh64::new(0);
+    let mut buf = vec![0; CHUNK];
+    // The bytes at the start of `buf` left over from the last read, which
+    // don't make up a whole value.
+    let size = verify.map_or(1, |(dtype, _)| dtype.size());
+    let mut carry = 0;
+    let mut bytes = 0;
+    loop {
+        let n = src.read(&mut buf[carry..])?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[carry..carry + n]);
+        dst.write_all(&buf[carry..carry + n])?;
+        let filled = carry + n;
+        let whole = filled / size * size;
+        if let Some((dtype, endian)) = verify {
+            for (i, v) in dtype.to_f64s(&buf[..whole], endian).into_iter().enumerate() {
+                if !v.is_finite() {
+                    bail!(
+                        "{:?} isn't valid: value {} is {}",
+                        from,
+                        (bytes - carry as u64) / size as u64 + i as u64,
+                        v
+                    );
+                }
+            }
+        }
+        buf.copy_within(whole..filled, 0);
+        carry = filled - whole;
+        bytes += n as u64;
+    }
+    if let Some((dtype, _)) = verify {
+        if bytes == 0 {
+            bail!("{:?} isn't valid: it's empty", from);
+        }
+        if carry != 0 {
+            bail!(
+                "{:?} isn't valid: its {} bytes aren't a whole number of {} values",
+                from,
+                bytes,
+                dtype.name()
+            );
+        }
+    }
+    dst.sync_all()?;
+    Ok((bytes, hasher.finish()))
+}
@@ -0,0 +1,379 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Running a battery of checks (e.g. one per observation, every night) from a
+//! suite file, rather than gluing them together with a shell script. A suite
+//! is a TOML file like
+//!
+//! ```toml
+//! # Options for every case, as given on the command line.
+//! args = "--fine-channels 32 --exclude-edge-channels 2"
+//! # Who to tell if any case fails.
+//! webhook = "https://hooks.slack.com/services/T000/B000/XXXX"
+//! email_to = "a@example.org, b@example.org"
+//! smtp_server = "smtp.example.org"
+//!
+//! [[cases]]
+//! name = "1065880128"
+//! dir = "/scratch/mwa/1065880128"
+//! baseline = "/scratch/baselines/1065880128"
+//! outputs = "hyperdrive_band??.bin"
+//! tolerance = 1e-3
+//! ```
+//!
+//! Each case runs this executable in `dir`, so that it's checked exactly as
+//! it would be by hand. Only `dir` is required: `baseline` defaults to the
+//! usual "baseline" inside `dir`; `outputs` (comma-separated patterns),
+//! `tolerance`, `rel_tolerance` and `config` are passed on as the options of
+//! the same names; and `args` are any other options for the case. Relative
+//! paths are relative to the suite file. Only this subset of TOML is read.
+//!
+//! If any case fails, the summary table and the worst offenders are posted to
+//! `webhook` (Slack or Mattermost) and emailed to `email_to` (through
+//! `smtp_server`, from `email_from`), if given.
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail};
+use pawsey_hyperdrive_checks::exit_code;
+
+use crate::manifest::unquote;
+use crate::notify::Notify;
+use crate::{email, history, junit};
+
+/// The number of failed cases listed as the worst offenders.
+const WORST_OFFENDERS: usize = 5;
+
+/// A check in a suite.
+#[derive(Debug, Default)]
+struct Case {
+    name: String,
+    dir: PathBuf,
+    baseline: Option<PathBuf>,
+    outputs: Option<String>,
+    tolerance: Option<String>,
+    rel_tolerance: Option<String>,
+    config: Option<PathBuf>,
+    args: Vec<String>,
+}
+
+impl Case {
+    /// The command-line arguments for the check, given the arguments
+    /// common to every case.
+    fn args(&self, common: &[String]) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(b) = &self.baseline {
+            args.push(b.display().to_string());
+        }
+        let options = [
+            ("--outputs", self.outputs.clone()),
+            ("--tolerance", self.tolerance.clone()),
+            ("--rel-tolerance", self.rel_tolerance.clone()),
+            (
+                "--config",
+                self.config.as_ref().map(|c| c.display().to_string()),
+            ),
+        ];
+        for (option, value) in options {
+            if let Some(value) = value {
+                args.push(format!("{}={}", option, value));
+            }
+        }
+        args.extend(common.iter().cloned());
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// The outcome of a case.
+struct Outcome {
+    /// "pass", "FAIL" or "ERROR".
+    status: &'static str,
+    time: Duration,
+    /// The largest difference and the file (or band) with it, from the
+    /// check's summary line, if it got that far.
+    max_diff: Option<f64>,
+    worst: Option<String>,
+}
+
+/// Everything in a suite file.
+#[derive(Debug, Default)]
+struct Suite {
+    /// The arguments common to every case.
+    common: Vec<String>,
+    notify: Notify,
+    cases: Vec<Case>,
+}
+
+/// Run every case of the suite at `path`, print a summary of them and, if
+/// `junit` is given, write it there as a JUnit report. Exits with -1 if any
+/// case failed.
+pub(crate) fn run(path: &Path, junit: Option<&Path>, quiet: bool) -> Result<(), anyhow::Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Couldn't read suite {:?}: {}", path, e))?;
+    // Cases are run in their own directories, so their paths must be
+    // absolute.
+    let root = std::env::current_dir()?.join(path.parent().unwrap_or_else(|| Path::new(".")));
+    let Suite {
+        common,
+        notify,
+        cases,
+    } = parse(&text, &root).map_err(|e| anyhow!("Suite {:?}: {}", path, e))?;
+    if cases.is_empty() {
+        bail!("Suite {:?} doesn't have any [[cases]]", path);
+    }
+    let exe = std::env::current_exe()?;
+
+    let mut outcomes = vec![];
+    for case in &cases {
+        if !quiet {
+            println!("=== {} ({}) ===", case.name, case.dir.display());
+        }
+        let started = Instant::now();
+        let mut summary = None;
+        let status = check(&exe, case, &common, quiet, &mut summary);
+        let status = match status {
+            Ok(s) if s.success() => "pass",
+            // The exit code for differences that are too large.
+            Ok(s) if s.code() == Some(exit_code::TOLERANCE_EXCEEDED) => "FAIL",
+            Ok(_) => "ERROR",
+            Err(e) => {
+                if !quiet {
+                    println!("Couldn't run the check in {:?}: {}", case.dir, e);
+                }
+                "ERROR"
+            }
+        };
+        let field = |key| summary.as_deref().and_then(|s| summary_field(s, key));
+        outcomes.push(Outcome {
+            status,
+            time: started.elapsed(),
+            max_diff: field("max_diff").and_then(|d| d.parse().ok()),
+            worst: field("worst"),
+        });
+    }
+
+    let passes = outcomes.iter().filter(|o| o.status == "pass").count();
+    let width = cases.iter().map(|c| c.name.len()).max().unwrap_or(0).max(4);
+    let mut table = format!(
+        "{:<w$} {:>6} {:>10} {:>14}  {}\n",
+        "case",
+        "result",
+        "time (s)",
+        "max diff",
+        "worst",
+        w = width
+    );
+    for (case, outcome) in cases.iter().zip(&outcomes) {
+        table.push_str(&format!(
+            "{:<w$} {:>6} {:>10.1} {:>14}  {}\n",
+            case.name,
+            outcome.status,
+            outcome.time.as_secs_f64(),
+            outcome
+                .max_diff
+                .map_or("-".to_string(), |d| format!("{:e}", d)),
+            outcome.worst.as_deref().unwrap_or("-"),
+            w = width
+        ));
+    }
+    table.push_str(&format!("{} of {} cases passed\n", passes, cases.len()));
+    if !quiet {
+        println!();
+        print!("{}", table);
+    }
+
+    if passes < cases.len() && !notify.is_empty() {
+        let mut body = format!(
+            "The hyperdrive checks in {} failed on {}.\n\n{}",
+            path.display(),
+            email::hostname(),
+            table
+        );
+        let mut failed: Vec<(&Case, &Outcome)> = cases
+            .iter()
+            .zip(&outcomes)
+            .filter(|(_, o)| o.status != "pass")
+            .collect();
+        // Cases that couldn't be checked at all first, then the largest
+        // differences.
+        failed.sort_by(|(_, a), (_, b)| {
+            let key = |o: &Outcome| o.max_diff.unwrap_or(f64::INFINITY);
+            key(b).total_cmp(&key(a))
+        });
+        body.push_str("\nWorst offenders:\n");
+        for (case, outcome) in failed.iter().take(WORST_OFFENDERS) {
+            body.push_str(&format!(
+                "  {}: {}",
+                case.name,
+                match outcome.max_diff {
+                    Some(d) => format!("max diff {:e}", d),
+                    None => "couldn't be checked".to_string(),
+                }
+            ));
+            if let Some(worst) = &outcome.worst {
+                body.push_str(&format!(" in {}", worst));
+            }
+            body.push('\n');
+        }
+        let subject = format!(
+            "hyperdrive checks FAILED: {} of {} cases in {}",
+            cases.len() - passes,
+            cases.len(),
+            path.display()
+        );
+        notify.send(&subject, &body);
+    }
+
+    if let Some(junit_file) = junit {
+        let junit_cases: Vec<junit::Case> = cases
+            .iter()
+            .zip(&outcomes)
+            .map(|(case, outcome)| junit::Case {
+                name: case.name.clone(),
+                status: match outcome.status {
+                    // Reported as an error, like a corrupt file.
+                    "ERROR" => "CORRUPT",
+                    s => s,
+                },
+                message: format!("in {}", case.dir.display()),
+                time: outcome.time,
+            })
+            .collect();
+        junit::write(
+            junit_file,
+            &path.display().to_string(),
+            &history::utc_timestamp(SystemTime::now()),
+            &[],
+            &junit_cases,
+            false,
+        )?;
+    }
+
+    if passes < cases.len() {
+        if !quiet {
+            println!("Some cases failed; exiting with code 1.");
+        }
+        std::process::exit(exit_code::TOLERANCE_EXCEEDED);
+    }
+    Ok(())
+}
+
+/// Run the check of `case`, passing its output on unless `quiet`, and keep
+/// its summary line (see `HYPERDRIVE-CHECKS` in the README).
+fn check(
+    exe: &Path,
+    case: &Case,
+    common: &[String],
+    quiet: bool,
+    summary: &mut Option<String>,
+) -> Result<std::process::ExitStatus, anyhow::Error> {
+    // The check isn't run with --quiet, so that it still prints its summary
+    // line.
+    let mut child = Command::new(exe)
+        .args(case.args(common))
+        .current_dir(&case.dir)
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Guaranteed to be present, as it was piped.
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        if line.starts_with("HYPERDRIVE-CHECKS ") {
+            *summary = Some(line.clone());
+        }
+        if !quiet {
+            println!("{}", line);
+        }
+    }
+    Ok(child.wait()?)
+}
+
+/// The value of `key` in a summary line like "HYPERDRIVE-CHECKS result=FAIL
+/// max_diff=3.2e-3 worst=hyperdrive_band07.bin", unquoted if need be.
+fn summary_field(line: &str, key: &str) -> Option<String> {
+    let start = line.find(&format!(" {}=", key))? + key.len() + 2;
+    let rest = &line[start..];
+    match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut value = String::new();
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => return Some(value),
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            None
+        }
+        None => Some(rest.split_whitespace().next().unwrap_or("").to_string()),
+    }
+}
+
+/// Parse a suite file.
+fn parse(text: &str, root: &Path) -> Result<Suite, String> {
+    let mut suite = Suite::default();
+    let cases = &mut suite.cases;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[cases]]" {
+            cases.push(Case::default());
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+            None => return Err(format!("line {}: expected KEY = VALUE", i + 1)),
+        };
+        let string = || unquote(value).ok_or(format!("line {}: expected a string", i + 1));
+        let path = || string().map(|s| root.join(s));
+        let number = || {
+            value
+                .parse::<f64>()
+                .map(|_| value.to_string())
+                .map_err(|_| format!("line {}: expected a number, not {}", i + 1, value))
+        };
+        let words = || string().map(|s| s.split_whitespace().map(|w| w.to_string()).collect());
+        let notify = &mut suite.notify;
+        match (cases.last_mut(), key) {
+            (None, "args") => suite.common = words()?,
+            (None, "webhook") => notify.webhook = Some(string()?),
+            (None, "email_to") => {
+                notify.email_to = string()?
+                    .split(',')
+                    .map(|a| a.trim().to_string())
+                    .filter(|a| !a.is_empty())
+                    .collect()
+            }
+            (None, "smtp_server") => notify.smtp_server = Some(string()?),
+            (None, "email_from") => notify.email_from = Some(string()?),
+            (Some(c), "name") => c.name = string()?,
+            (Some(c), "dir") => c.dir = path()?,
+            (Some(c), "baseline") => c.baseline = Some(path()?),
+            (Some(c), "outputs") => c.outputs = Some(string()?),
+            (Some(c), "tolerance") => c.tolerance = Some(number()?),
+            (Some(c), "rel_tolerance") => c.rel_tolerance = Some(number()?),
+            (Some(c), "config") => c.config = Some(path()?),
+            (Some(c), "args") => c.args = words()?,
+            _ => return Err(format!("line {}: unknown key \"{}\"", i + 1, key)),
+        }
+    }
+    if !suite.notify.email_to.is_empty() && suite.notify.smtp_server.is_none() {
+        return Err("email_to needs an smtp_server".to_string());
+    }
+    for (n, case) in suite.cases.iter_mut().enumerate() {
+        if case.dir.as_os_str().is_empty() {
+            return Err(format!("case {} has no dir", n + 1));
+        }
+        if case.name.is_empty() {
+            case.name = case.dir.display().to_string();
+        }
+    }
+    Ok(suite)
+}
@@ -0,0 +1,67 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! How the residuals evolve over an observation. Errors that grow with time
+//! (e.g. from precession or epoch regressions) are smeared out by per-file
+//! maxima, but stand out in the trend of the per-timestep residual RMS.
+
+/// The trend of the per-timestep residual RMS.
+pub(crate) struct Trend {
+    pub(crate) mean: f64,
+    /// The least-squares slope of the RMS, per timestep.
+    pub(crate) slope: f64,
+    /// How much the fitted RMS changes over the observation, relative to the
+    /// mean RMS.
+    pub(crate) growth: f64,
+    /// The standard deviation of the RMS, relative to the mean RMS.
+    pub(crate) relative_std: f64,
+}
+
+/// The residual RMS of each of `timesteps` equally sized timesteps (the slowest
+/// varying axis of the data). Floats for which `skip` is true are left out.
+pub(crate) fn rms_per_timestep<F: Fn(usize) -> bool>(
+    current: &[f32],
+    baseline: &[f32],
+    timesteps: usize,
+    skip: F,
+) -> Vec<f64> {
+    let per_timestep = current.len() / timesteps;
+    (0..timesteps)
+        .map(|t| {
+            let (sum, n) = (t * per_timestep..(t + 1) * per_timestep)
+                .filter(|&i| !skip(i))
+                .fold((0.0, 0), |(sum, n), i| {
+                    let r = (current[i] - baseline[i]) as f64;
+                    (sum + r * r, n + 1)
+                });
+            if n == 0 {
+                0.0
+            } else {
+                (sum / n as f64).sqrt()
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn trend(rms: &[f64]) -> Trend {
+    let n = rms.len() as f64;
+    let mean = rms.iter().sum::<f64>() / n;
+    let t_mean = (n - 1.0) / 2.0;
+    let (cov, var) = rms
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(cov, var), (t, r)| {
+            let dt = t as f64 - t_mean;
+            (cov + dt * (r - mean), var + dt * dt)
+        });
+    let slope = if var == 0.0 { 0.0 } else { cov / var };
+    let std = (rms.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n).sqrt();
+    let relative = |x: f64| if mean == 0.0 { 0.0 } else { x / mean };
+    Trend {
+        mean,
+        slope,
+        growth: relative(slope * (n - 1.0)),
+        relative_std: relative(std),
+    }
+}
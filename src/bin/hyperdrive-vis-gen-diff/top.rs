@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Where the largest differences are, so that a failure can be traced to a
+//! baseline, channel or polarisation rather than just a number.
+//!
+//! Data are in timestep, baseline, fine channel order; each fine channel holds
+//! its polarisations, each a real and imaginary float (and a weight, with
+//! --weights).
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// One of the largest differences.
+pub(crate) struct Worst {
+    /// The index of the float in the data compared.
+    pub(crate) index: u64,
+    pub(crate) current: f32,
+    pub(crate) baseline: f32,
+}
+
+/// Orders differences so that the heap's smallest is the first to go.
+struct ByDiff(f32, u64);
+
+impl ByDiff {
+    fn new(current: f32, baseline: f32, index: u64) -> ByDiff {
+        // NaNs are the worst differences of all.
+        let diff = (current - baseline).abs();
+        ByDiff(if diff.is_nan() { f32::INFINITY } else { diff }, index)
+    }
+}
+
+impl PartialEq for ByDiff {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for ByDiff {}
+
+impl PartialOrd for ByDiff {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByDiff {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Earlier floats win ties.
+        self.0
+            .total_cmp(&other.0)
+            .then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+/// The `n` largest absolute differences between `current` and `baseline`,
+/// largest first. Floats for which `skip` is true are left out.
+pub(crate) fn worst<F: Fn(usize) -> bool>(
+    current: &[f32],
+    baseline: &[f32],
+    n: usize,
+    skip: F,
+) -> Vec<Worst> {
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for (i, (c, b)) in current.iter().zip(baseline).enumerate() {
+        if skip(i) {
+            continue;
+        }
+        heap.push(Reverse(ByDiff::new(*c, *b, i as u64)));
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+    let mut worst: Vec<ByDiff> = heap.into_iter().map(|Reverse(d)| d).collect();
+    worst.sort_by(|a, b| b.cmp(a));
+    worst
+        .into_iter()
+        .map(|ByDiff(_, i)| Worst {
+            index: i,
+            current: current[i as usize],
+            baseline: baseline[i as usize],
+        })
+        .collect()
+}
+
+/// Combine the largest differences of two parts of the same data (with
+/// indices into all of it), keeping the `n` largest.
+pub(crate) fn merge(mut a: Vec<Worst>, b: Vec<Worst>, n: usize) -> Vec<Worst> {
+    a.extend(b);
+    let key = |w: &Worst| ByDiff::new(w.current, w.baseline, w.index);
+    a.sort_by_key(|w| Reverse(key(w)));
+    a.truncate(n);
+    a
+}
+
+/// As much as is known of how the data are laid out.
+pub(crate) struct Layout<'a> {
+    pub(crate) fine_channels: Option<u64>,
+    pub(crate) floats_per_channel: u64,
+    pub(crate) weights: bool,
+    /// The number of baselines per timestep, if known.
+    pub(crate) baselines: Option<u64>,
+    /// The names of the tiles, to name the tiles of each baseline.
+    pub(crate) tile_names: Option<&'a [String]>,
+    /// The centre frequency of each fine channel, in Hz, if known.
+    pub(crate) frequencies: Option<Vec<f64>>,
+}
+
+impl Layout<'_> {
+    /// Describe where the float at `index` (within its file, or band with
+    /// --bands) is.
+    pub(crate) fn describe(&self, index: u64) -> String {
+        let fine_channels = match self.fine_channels {
+            Some(f) if f > 0 && self.floats_per_channel > 0 => f,
+            _ => return String::new(),
+        };
+        let floats_per_pol = if self.weights { 3 } else { 2 };
+        let component = index % self.floats_per_channel;
+        let channel = (index / self.floats_per_channel) % fine_channels;
+        let row = index / (self.floats_per_channel * fine_channels);
+        let mut out = match self.baselines.filter(|&b| b > 0) {
+            Some(baselines) => {
+                let baseline = row % baselines;
+                let tiles = self.tile_names.and_then(|names| {
+                    let (a, b) = tile_pair(names.len() as u64, baselines, baseline)?;
+                    Some(format!(
+                        " (tiles {}-{})",
+                        names[a as usize], names[b as usize]
+                    ))
+                });
+                format!(
+                    "timestep {}, baseline {}{}",
+                    row / baselines,
+                    baseline,
+                    tiles.unwrap_or_default()
+                )
+            }
+            None => format!("row {}", row),
+        };
+        let frequency = match self
+            .frequencies
+            .as_ref()
+            .and_then(|f| f.get(channel as usize))
+        {
+            Some(f) => format!(" ({:.3} MHz)", f / 1e6),
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            ", channel {}{}, pol {}, {}",
+            channel,
+            frequency,
+            component / floats_per_pol,
+            ["re", "im", "weight"][(component % floats_per_pol) as usize]
+        ));
+        out
+    }
+}
+
+/// The tiles of baseline `baseline`, if there are `baselines` baselines of
+/// `tiles` tiles, ordered by tile pair. Baselines include autocorrelations if
+/// there are enough of them.
+fn tile_pair(tiles: u64, baselines: u64, baseline: u64) -> Option<(u64, u64)> {
+    let autos = if baselines == tiles * (tiles + 1) / 2 {
+        true
+    } else if baselines == tiles * tiles.saturating_sub(1) / 2 {
+        false
+    } else {
+        return None;
+    };
+    let mut remaining = baseline;
+    for a in 0..tiles {
+        let first = if autos { a } else { a + 1 };
+        let row = tiles - first;
+        if remaining < row {
+            return Some((a, first + remaining));
+        }
+        remaining -= row;
+    }
+    None
+}
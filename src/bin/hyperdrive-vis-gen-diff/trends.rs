@@ -0,0 +1,119 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The trends of the maximum differences recorded by past runs (with --csv or
+//! --history). A regression that creeps in a little at a time passes every
+//! night until the night it doesn't; a maximum difference that keeps growing
+//! over the last few runs is worth looking at while it's still under the
+//! tolerance.
+
+use std::path::Path;
+
+use crate::history;
+use crate::temporal;
+
+/// The maximum differences of a file (or band), or of every file, over the
+/// runs, oldest first.
+struct Series {
+    name: String,
+    max_diffs: Vec<f32>,
+    /// The verdict of the latest run.
+    verdict: String,
+}
+
+/// Print the trend of the maximum difference of each file (or band) in the
+/// --csv file at `path`, or of each run's in the --history file at `path`,
+/// over the `last` runs. Returns whether any of them grew by more than `creep`
+/// (as a fraction of its mean) over those runs, having been recorded for at
+/// least 3 runs.
+pub(crate) fn print_trends(
+    path: &Path,
+    last: usize,
+    creep: f64,
+    quiet: bool,
+) -> Result<bool, anyhow::Error> {
+    let mut series: Vec<((Option<u64>, String), Series)> = vec![];
+    let mut push = |obsid: Option<u64>, name: &str, max_diff: f32, verdict: &str| {
+        let key = (obsid, name.to_string());
+        let i = match series.iter().position(|(k, _)| *k == key) {
+            Some(i) => i,
+            None => {
+                series.push((
+                    key,
+                    Series {
+                        name: name.to_string(),
+                        max_diffs: vec![],
+                        verdict: String::new(),
+                    },
+                ));
+                series.len() - 1
+            }
+        };
+        series[i].1.max_diffs.push(max_diff);
+        series[i].1.verdict = verdict.to_string();
+    };
+    if history::is_files_csv(path)? {
+        for row in history::read_files(path)? {
+            push(row.obsid, &row.file, row.max_diff, &row.verdict);
+        }
+    } else {
+        for row in history::read(path)? {
+            let verdict = if row.passed { "pass" } else { "fail" };
+            push(row.obsid, "every file", row.max_diff, verdict);
+        }
+    }
+    // Only name the observations if there's more than one.
+    let several = series.iter().any(|((o, _), _)| *o != series[0].0 .0);
+    for ((obsid, _), s) in &mut series {
+        if let (true, Some(o)) = (several, obsid) {
+            s.name = format!("{} (obsid {})", s.name, o);
+        }
+    }
+
+    if !quiet {
+        println!(
+            "{:<30} {:>5} {:>12} {:>12} {:>12} {:>8} {:>7}",
+            "file", "runs", "first", "latest", "largest", "growth", "result"
+        );
+    }
+    let mut creeping = vec![];
+    for (_, s) in &series {
+        let window = &s.max_diffs[s.max_diffs.len().saturating_sub(last)..];
+        let trend = temporal::trend(&window.iter().map(|&d| d as f64).collect::<Vec<_>>());
+        let crept = window.len() >= 3 && trend.growth > creep;
+        if crept {
+            creeping.push(s.name.as_str());
+        }
+        if !quiet {
+            println!(
+                "{:<30} {:>5} {:>12e} {:>12e} {:>12e} {:>7.0}% {:>7}{}",
+                s.name,
+                window.len(),
+                window[0],
+                window[window.len() - 1],
+                window.iter().copied().fold(0.0, f32::max),
+                trend.growth * 100.0,
+                s.verdict,
+                if crept { " CREEPING" } else { "" }
+            );
+        }
+    }
+    if !quiet {
+        if creeping.is_empty() {
+            println!(
+                "No maximum difference grew by more than {}% over the last {} runs.",
+                creep * 100.0,
+                last
+            );
+        } else {
+            println!(
+                "Maximum differences growing by more than {}% over the last {} runs: {}",
+                creep * 100.0,
+                last,
+                creeping.join(", ")
+            );
+        }
+    }
+    Ok(!creeping.is_empty())
+}
@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Checking that files really hold values of the declared type and byte
+//! order, without comparing them against anything (--validate-only). Values of
+//! the wrong type or byte order usually decode as NaNs, infinities, or absurdly
+//! large or small numbers.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::{Dtype, Endian, Error};
+
+use crate::compress;
+use crate::read::Reader;
+
+/// Files are decoded this many bytes at a time (rounded down to whole values).
+const CHUNK: usize = 1 << 24;
+/// Non-zero values further than this many orders of magnitude from 1 are
+/// implausible for visibilities.
+const IMPLAUSIBLE: i32 = 30;
+
+/// What decoding a file found.
+#[derive(Default)]
+struct Validity {
+    values: u64,
+    nans: u64,
+    infinities: u64,
+    implausible: u64,
+    /// Bytes at the end that don't make up a whole value.
+    stray_bytes: u64,
+}
+
+/// Decode every one of `files`, reporting each, and fail with every problem
+/// found at once. NaNs are only a problem if `allow_nan` isn't set.
+pub(crate) fn validate_all(
+    files: &[PathBuf],
+    reader: &Reader,
+    dtype: Dtype,
+    endian: Endian,
+    allow_nan: bool,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let mut problems = vec![];
+    for path in files {
+        let v = validate(path, reader, dtype, endian)?;
+        let mut found = vec![];
+        if v.values == 0 && v.stray_bytes == 0 {
+            found.push("it's empty".to_string());
+        }
+        if v.stray_bytes > 0 {
+            found.push(format!(
+                "it ends with {} stray bytes, so it was probably truncated mid-write",
+                v.stray_bytes
+            ));
+        }
+        if v.nans > 0 && !allow_nan {
+            found.push(format!("{} values are NaN", v.nans));
+        }
+        if v.infinities > 0 {
+            found.push(format!("{} values are infinite", v.infinities));
+        }
+        if v.implausible > 0 {
+            found.push(format!(
+                "{} values are smaller than 1e-{} or larger than 1e{}; is --dtype or --endian wrong?",
+                v.implausible, IMPLAUSIBLE, IMPLAUSIBLE
+            ));
+        }
+        if !quiet {
+            println!(
+                "{:?}: {} {} values{}",
+                path,
+                v.values,
+                dtype.name(),
+                if found.is_empty() { "" } else { "; INVALID" }
+            );
+        }
+        for f in found {
+            problems.push(format!("{:?}: {}", path, f));
+        }
+    }
+    if !problems.is_empty() {
+        bail!(Error::Shape(format!(
+            "{} problem(s) found decoding the files as {} values:\n  {}",
+            problems.len(),
+            dtype.name(),
+            problems.join("\n  ")
+        )));
+    }
+    if !quiet {
+        println!(
+            "All {} files are valid {} values; nothing was compared.",
+            files.len(),
+            dtype.name()
+        );
+    }
+    Ok(())
+}
+
+fn validate(path: &Path, reader: &Reader, dtype: Dtype, endian: Endian) -> Result<Validity, Error> {
+    let size = dtype.size() as u64;
+    let len = compress::len(path).map_err(|e| Error::reading(path, e))?;
+    let mut v = Validity {
+        stray_bytes: len % size,
+        ..Validity::default()
+    };
+    let whole = len - v.stray_bytes;
+    let layout = reader.layout(path);
+    let chunk = (CHUNK as u64 / size * size).max(size);
+    let mut offset = 0;
+    while offset < whole {
+        let n = chunk.min(whole - offset);
+        let bytes = reader
+            .read_range(path, &layout, offset, n as usize)
+            .map_err(|e| Error::reading(path, e))?;
+        for value in dtype.to_f64s(&bytes, endian) {
+            if value.is_nan() {
+                v.nans += 1;
+            } else if value.is_infinite() {
+                v.infinities += 1;
+            } else if value != 0.0 && value.abs().log10().abs() > IMPLAUSIBLE as f64 {
+                v.implausible += 1;
+            }
+        }
+        offset += n;
+    }
+    reader.close(path).map_err(|e| Error::reading(path, e))?;
+    v.values = whole / size;
+    Ok(v)
+}
@@ -0,0 +1,772 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Judge the differences found between every pair of files, given the known
+//! failures, quarantine and hyperdrive's timings, then write the reports and
+//! print the verdict.
+
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use pawsey_hyperdrive_checks::exit_code;
+
+use crate::exempt::{Quarantine, Xfail};
+use crate::manifest::Manifest;
+use crate::{
+    badge, config, email, environment, exempt, history, json, junit, perf, plugin, print_summary,
+    report, sample, summary_value, CompareOpt, Differences, FilePair, Timings,
+};
+
+/// The differences found between every pair of files.
+pub(crate) struct Outcome<'a> {
+    pub(crate) pairs: &'a [FilePair],
+    pub(crate) diffs: &'a [Differences],
+    /// The largest differences of all of the files.
+    pub(crate) overall: Differences,
+    pub(crate) obsid: Option<u64>,
+    /// How many transient IO errors were retried (see --io-retries).
+    pub(crate) io_retries: u32,
+}
+
+/// Whether an [Outcome] passes, and why not.
+pub(crate) struct Verdict {
+    /// The result of each file, e.g. "pass" or "XFAIL".
+    pub(crate) statuses: Vec<&'static str>,
+    pub(crate) failed: bool,
+    /// The stages of hyperdrive slower than --max-slowdown allows.
+    pub(crate) regressed: Vec<String>,
+    /// How many known failures passed.
+    pub(crate) xpasses: usize,
+    /// The name of the file with the largest difference.
+    pub(crate) worst: String,
+}
+
+/// The files (or bands) whose failures don't count: the known failures (of
+/// --xfail and the --config file) and the --quarantine.
+pub(crate) struct Exemptions<'a> {
+    xfail: &'a [Xfail],
+    /// The known failures of the --config file.
+    expected: Vec<Xfail>,
+    quarantine: Vec<Quarantine>,
+}
+
+impl<'a> Exemptions<'a> {
+    pub(crate) fn read(options: &'a CompareOpt) -> Result<Exemptions<'a>, anyhow::Error> {
+        Ok(Exemptions {
+            xfail: &options.xfail,
+            expected: match &options.config {
+                Some(path) => config::read_expected_failures(path)?,
+                None => vec![],
+            },
+            quarantine: match &options.quarantine {
+                Some(path) => exempt::read_quarantine(path)?,
+                None => vec![],
+            },
+        })
+    }
+
+    /// Warn of the exemptions that have expired, and so count again.
+    fn warn_expired(&self, now: SystemTime) {
+        for x in self.expected.iter().filter(|x| x.expired(now)) {
+            eprintln!(
+                "Warning: the expected failure of {} expired on {}; its files count again",
+                x.pattern(),
+                x.expires.as_ref().map_or("", |(date, _)| date.as_str())
+            );
+        }
+        for q in self.quarantine.iter().filter(|q| q.expired(now)) {
+            eprintln!(
+                "Warning: the quarantine of {} expired on {}; its files count again",
+                q.pattern(),
+                q.expires
+            );
+        }
+    }
+
+    fn xfail(&self, pair: &FilePair, now: SystemTime) -> Option<&Xfail> {
+        self.xfail
+            .iter()
+            .chain(&self.expected)
+            .find(|x| !x.expired(now) && x.matches(&pair.name))
+    }
+
+    fn quarantined(&self, pair: &FilePair, now: SystemTime) -> Option<&Quarantine> {
+        self.quarantine
+            .iter()
+            .find(|q| !q.expired(now) && q.matches(&pair.name))
+    }
+}
+
+/// Print the statistics of the differences of all of the files.
+pub(crate) fn print_statistics(overall: &Differences, options: &CompareOpt) {
+    if options.quiet {
+        return;
+    }
+    println!("Maximum difference: {}", overall.max_abs);
+    if options.per_float_tolerances() {
+        println!("Floats outside every tolerance: {}", overall.violations);
+        if overall.violations > 0 {
+            // Every violating float breaks all of the tolerances, but say
+            // which ones the worst differences break most.
+            let mut violated = vec![];
+            if overall.max_scaled > options.tolerance {
+                violated.push(format!(
+                    "absolute ({:e} > {:e})",
+                    overall.max_scaled, options.tolerance
+                ));
+            }
+            if let Some(r) = options.rel_tolerance.filter(|&r| overall.max_rel > r) {
+                violated.push(format!("relative ({:e} > {:e})", overall.max_rel, r));
+            }
+            if let Some(u) = options.ulps.filter(|&u| overall.max_ulps > u as u64) {
+                violated.push(format!("ULPs ({} > {})", overall.max_ulps, u));
+            }
+            println!("Tolerances violated: {}", violated.join(", "));
+        }
+    }
+    // Distributed workers only send back the largest differences.
+    if overall.samples > 0 {
+        println!(
+            "Mean difference: {:e}; RMS: {:e}{}; std dev: {:e}",
+            overall.mean_signed(),
+            overall.rms(),
+            options
+                .max_rms
+                .map_or(String::new(), |m| format!(" (limit {:e})", m)),
+            overall.std_dev()
+        );
+        println!(
+            "Largest median: {:e}; 95th percentile: {:e}; 99th percentile: {:e}",
+            overall.median, overall.p95, overall.p99
+        );
+        println!(
+            "Fraction of floats above tolerance: {:e}{}",
+            overall.frac_exceeding(),
+            options
+                .max_frac_exceeding
+                .map_or(String::new(), |f| format!(" (limit {:e})", f))
+        );
+    }
+    if overall.skipped > 0 {
+        println!(
+            "Floats left out as flagged or excluded: {}",
+            overall.skipped
+        );
+    }
+    if overall.current_nans + overall.baseline_nans > 0 {
+        println!(
+            "NaNs: {} current, {} baseline; {} failing",
+            overall.current_nans, overall.baseline_nans, overall.nan_failures
+        );
+    }
+    if options.complex {
+        println!(
+            "Maximum amplitude difference: {} (tolerance {})",
+            overall.max_amp, options.tolerance
+        );
+        println!(
+            "Maximum phase difference: {} degrees (tolerance {})",
+            overall.max_phase, options.phase_tolerance
+        );
+    }
+    if options.max_sigma.is_some() {
+        println!(
+            "Maximum difference in units of noise: {} sigma",
+            overall.max_sigmas
+        );
+    }
+    if options.timesteps.is_some() {
+        println!(
+            "Largest growth of the residual RMS over the observation: {:+.1}%",
+            100.0 * overall.rms_growth
+        );
+    }
+    if options.weights_dir.is_some() {
+        println!(
+            "Largest weighted residual RMS: {:e}{}",
+            overall.weighted_rms,
+            options
+                .max_weighted_rms
+                .map_or(String::new(), |m| format!(" (limit {:e})", m))
+        );
+    }
+    if let Some((low, high)) = options.chi2_window {
+        println!(
+            "Reduced chi-squared: {} (must be between {} and {})",
+            overall.reduced_chi2(),
+            low,
+            high
+        );
+    }
+    if let Some((statistic, p_value)) = overall.ks {
+        println!(
+            "Kolmogorov-Smirnov test of the value distributions: smallest p-value {:e} (statistic {:e}){}",
+            p_value,
+            statistic,
+            options
+                .ks_threshold
+                .map_or(String::new(), |t| format!(" (limit {:e})", t))
+        );
+    }
+}
+
+/// Decide whether the outcome passes. `sampled` is whether only some blocks
+/// of the files were compared, and `manifest` that of the baseline, if it has
+/// one, for the timings to compare hyperdrive's against.
+pub(crate) fn judge(
+    outcome: &Outcome,
+    exemptions: &Exemptions,
+    manifest: Option<&Manifest>,
+    sampled: bool,
+    options: &CompareOpt,
+) -> Result<Verdict, anyhow::Error> {
+    let now = SystemTime::now();
+    exemptions.warn_expired(now);
+    let files = || outcome.pairs.iter().zip(outcome.diffs.iter());
+    for (pair, d) in files() {
+        match exemptions.xfail(pair, now) {
+            Some(x) if options.failed(d) && !options.quiet => {
+                println!("Known failure of {:?} ({})", pair.name, x.reason)
+            }
+            Some(x) if !options.failed(d) => eprintln!(
+                "{}: {:?} passed, but was expected to fail ({}); is {} stale?",
+                if options.strict_xfail {
+                    "Error"
+                } else {
+                    "Warning"
+                },
+                pair.name,
+                x.reason,
+                x.pattern()
+            ),
+            _ => (),
+        }
+        if let Some(q) = exemptions.quarantined(pair, now) {
+            if !options.quiet {
+                println!(
+                    "{:?} is quarantined until {}; it {} but doesn't count",
+                    pair.name,
+                    q.expires,
+                    if options.failed(d) {
+                        "failed"
+                    } else {
+                        "passed"
+                    }
+                );
+            }
+        }
+    }
+    let xpasses = files()
+        .filter(|(pair, d)| !options.failed(d) && exemptions.xfail(pair, now).is_some())
+        .count();
+    let counted: Vec<&Differences> = files()
+        .filter(|(pair, _)| {
+            exemptions.xfail(pair, now).is_none() && exemptions.quarantined(pair, now).is_none()
+        })
+        .map(|(_, d)| d)
+        .collect();
+    let too_large =
+        counted.iter().any(|d| options.failed(d)) || options.strict_xfail && xpasses > 0;
+    if !options.tolerances.is_empty() && !options.quiet {
+        print_tolerances(&counted, options);
+    }
+    if sampled && !options.quiet {
+        print_sampling(&outcome.overall, too_large);
+    }
+    let regressed = slowdowns(manifest, options)?;
+
+    let statuses = files()
+        .map(
+            |(pair, d)| match (options.failed(d), exemptions.xfail(pair, now).is_some()) {
+                _ if exemptions.quarantined(pair, now).is_some() => "QUAR",
+                _ if d.corrupt => "CORRUPT",
+                (true, false) => "FAIL",
+                (true, true) => "XFAIL",
+                (false, true) => "XPASS",
+                (false, false) => "pass",
+            },
+        )
+        .collect();
+    let worst = files()
+        .fold(
+            None,
+            |worst: Option<(&FilePair, &Differences)>, (p, d)| match worst {
+                Some((_, w)) if w.max_abs >= d.max_abs => worst,
+                _ => Some((p, d)),
+            },
+        )
+        .map(|(p, _)| p.name.display().to_string())
+        .unwrap_or_default();
+    Ok(Verdict {
+        statuses,
+        // A slow hyperdrive fails the check like a large difference does.
+        failed: too_large || !regressed.is_empty(),
+        regressed,
+        xpasses,
+        worst,
+    })
+}
+
+/// Print whether the comparison would pass at each of the --tolerances, given
+/// the differences of the files that count.
+fn print_tolerances(counted: &[&Differences], options: &CompareOpt) {
+    println!(
+        "{:>14} {:>14} {:>6}",
+        "tolerance", "failing files", "result"
+    );
+    for &tolerance in &options.tolerances {
+        let failing = counted
+            .iter()
+            .filter(|d| options.failed_at(d, tolerance))
+            .count();
+        println!(
+            "{:>14e} {:>14} {:>6}",
+            tolerance,
+            format!("{} of {}", failing, counted.len()),
+            if failing > 0 { "FAIL" } else { "pass" }
+        );
+    }
+}
+
+/// Print how confident the verdict of a sampled comparison is.
+fn print_sampling(overall: &Differences, too_large: bool) {
+    if overall.sampled_blocks == overall.total_blocks {
+        println!(
+            "Sampled all {} blocks; the verdict is exact.",
+            overall.total_blocks
+        );
+    } else if too_large {
+        println!(
+            "Sampled {} of {} blocks; a difference too large was found, so the full comparison would fail too.",
+            overall.sampled_blocks, overall.total_blocks
+        );
+    } else {
+        println!(
+            "Sampled {} of {} blocks; with 95% confidence, fewer than {:.3}% of all blocks would fail.",
+            overall.sampled_blocks,
+            overall.total_blocks,
+            100.0 * sample::upper_bound_95(overall.sampled_blocks)
+        );
+    }
+}
+
+/// Compare hyperdrive's timings against the baseline's, printing them, and
+/// return the stages slower than --max-slowdown allows.
+fn slowdowns(
+    manifest: Option<&Manifest>,
+    options: &CompareOpt,
+) -> Result<Vec<String>, anyhow::Error> {
+    let timings = perf::timings(&options.hyperdrive_log, options.wall_time)?;
+    let slowdowns = match manifest {
+        Some(m) => perf::compare(&timings, &m.timings),
+        None => vec![],
+    };
+    let regressed: Vec<String> = slowdowns
+        .iter()
+        .filter(|s| options.max_slowdown.is_some_and(|max| s.percent() > max))
+        .map(|s| s.stage.clone())
+        .collect();
+    if !timings.is_empty() && !options.quiet {
+        if slowdowns.is_empty() {
+            println!("The baseline has no timings to compare hyperdrive's against.");
+        }
+        for s in &slowdowns {
+            println!(
+                "{}: {:.3} s (baseline {:.3} s, {:+.1}%){}",
+                s.stage,
+                s.current,
+                s.baseline,
+                s.percent(),
+                if regressed.contains(&s.stage) {
+                    " TOO SLOW"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+    Ok(regressed)
+}
+
+/// Write the reports asked for (history, CSV, badge, text, JUnit, email and
+/// JSON), returning how long they took. `baseline_dir` is the baseline
+/// compared against, `manifest` its manifest, if it has one, and `sign_key`
+/// the key to sign the report with, if any.
+pub(crate) fn write_reports(
+    outcome: &Outcome,
+    verdict: &Verdict,
+    baseline_dir: &Path,
+    manifest: Option<Manifest>,
+    sign_key: Option<&Path>,
+    options: &CompareOpt,
+) -> Result<Duration, anyhow::Error> {
+    let reports = &options.reports;
+    let hyperdrive_version = || {
+        reports
+            .hyperdrive_version
+            .clone()
+            .or_else(environment::hyperdrive_version)
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    let files = || outcome.pairs.iter().zip(outcome.diffs.iter());
+
+    let started = Instant::now();
+    if let Some(history_file) = &reports.history {
+        let row = history::HistoryRow {
+            timestamp: history::utc_timestamp(SystemTime::now()),
+            hyperdrive_version: hyperdrive_version(),
+            worst_file: verdict.worst.clone(),
+            max_diff: outcome.overall.max_abs,
+            passed: !verdict.failed,
+            obsid: outcome.obsid,
+        };
+        history::append(history_file, &row)?;
+    }
+    if let Some(csv) = &reports.csv {
+        let names: Vec<String> = outcome
+            .pairs
+            .iter()
+            .map(|p| p.name.display().to_string())
+            .collect();
+        let rows: Vec<history::FileRow> = outcome
+            .diffs
+            .iter()
+            .zip(&verdict.statuses)
+            .zip(&names)
+            .map(|((d, status), name)| history::FileRow {
+                file: name,
+                max_diff: d.max_abs,
+                rms: d.rms() as f32,
+                verdict: status.to_lowercase(),
+            })
+            .collect();
+        history::append_files(
+            csv,
+            &history::utc_timestamp(SystemTime::now()),
+            &hyperdrive_version(),
+            outcome.obsid,
+            &rows,
+        )?;
+    }
+
+    if let Some(badge_file) = &reports.badge {
+        badge::write_badge(badge_file, &reports.badge_label, !verdict.failed)?;
+    }
+
+    let provenance = if reports.report.is_some()
+        || reports.json.is_some()
+        || reports.format == "json"
+    {
+        Some(report::Provenance {
+            timestamp: history::utc_timestamp(SystemTime::now()),
+            hyperdrive_version: hyperdrive_version(),
+            host: email::hostname(),
+            obsid: outcome.obsid,
+            baseline_manifest: report::manifest_hash(outcome.pairs, outcome.diffs, options.dtype)?,
+            config: report::config_hash(),
+            baseline: manifest,
+            environment: environment::Environment::capture(),
+        })
+    } else {
+        None
+    };
+    if let (Some(report_file), Some(provenance)) = (&reports.report, &provenance) {
+        let mut body = String::new();
+        if let Some(preset) = options.preset {
+            body.push_str(&format!("Preset {}: {}\n\n", preset.name, preset.rationale));
+        }
+        body.push_str(&format!(
+            "{}\nResult: {}\n",
+            summary(outcome, verdict, options),
+            if verdict.failed { "FAILED" } else { "passed" }
+        ));
+        if outcome.io_retries > 0 {
+            body.push_str(&format!(
+                "Transient IO errors retried: {}\n",
+                outcome.io_retries
+            ));
+        }
+        report::write(report_file, provenance, &body)?;
+        if let Some(key) = sign_key {
+            report::sign(report_file, key)?;
+        }
+    }
+
+    if let Some(junit_file) = &reports.junit {
+        let cases: Vec<junit::Case> = files()
+            .zip(&verdict.statuses)
+            .map(|((pair, d), status)| junit::Case {
+                name: pair.name.display().to_string(),
+                status,
+                message: match options.max_sigma {
+                    Some(max_sigma) => format!(
+                        "max diff {:e} ({} sigma), limit {} sigma",
+                        d.max_abs, d.max_sigmas, max_sigma
+                    ),
+                    None => format!(
+                        "max diff {:e}, tolerance {:e}",
+                        d.max_abs, options.tolerance
+                    ),
+                },
+                time: d.timings.total(),
+            })
+            .collect();
+        let mut properties = vec![
+            ("tolerance", format!("{:e}", options.tolerance)),
+            ("hyperdrive_version", hyperdrive_version()),
+        ];
+        if let Some(max_sigma) = options.max_sigma {
+            properties.push(("max_sigma", max_sigma.to_string()));
+        }
+        if let Some(obsid) = outcome.obsid {
+            properties.push(("obsid", obsid.to_string()));
+        }
+        junit::write(
+            junit_file,
+            env!("CARGO_BIN_NAME"),
+            &history::utc_timestamp(SystemTime::now()),
+            &properties,
+            &cases,
+            options.strict_xfail,
+        )?;
+    }
+
+    if verdict.failed && !reports.email_to.is_empty() {
+        email_failure(outcome, verdict, options)?;
+    }
+    // The JSON report is written last, so that it can say how long the others
+    // took.
+    let took = started.elapsed();
+    if let Some(provenance) = &provenance {
+        let rendered = json::render(&json::Run {
+            provenance,
+            dtype: options.dtype,
+            options: plugin::options_json(baseline_dir, options),
+            passed: !verdict.failed,
+            worst: &verdict.worst,
+            overall: &outcome.overall,
+            pairs: outcome.pairs,
+            diffs: outcome.diffs,
+            statuses: &verdict.statuses,
+            io_retries: outcome.io_retries,
+            reports: took,
+            preset: options.preset,
+        })?;
+        if let Some(json_file) = &reports.json {
+            std::fs::write(json_file, &rendered)
+                .map_err(|e| anyhow!("Couldn't write {:?}: {}", json_file, e))?;
+        }
+        if reports.format == "json" {
+            print!("{}", rendered);
+        }
+    }
+    Ok(started.elapsed())
+}
+
+/// A table of every file's result, for the email and report.
+fn summary(outcome: &Outcome, verdict: &Verdict, options: &CompareOpt) -> String {
+    let mut table = format!(
+        "{:<30} {:>14} {:>7} {:>16} {:>16}\n",
+        "file", "max diff", "result", "current xxh3", "baseline xxh3"
+    );
+    for ((pair, d), status) in outcome
+        .pairs
+        .iter()
+        .zip(outcome.diffs.iter())
+        .zip(&verdict.statuses)
+    {
+        let (current, baseline) = match d.hashes {
+            Some((c, b)) => (format!("{:016x}", c), format!("{:016x}", b)),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        table.push_str(&format!(
+            "{:<30} {:>14e} {:>7} {:>16} {:>16}\n",
+            pair.name.display(),
+            d.max_abs,
+            status,
+            current,
+            baseline
+        ));
+    }
+    table.push_str(&format!(
+        "\nMaximum difference: {:e}\n",
+        outcome.overall.max_abs
+    ));
+    match options.max_sigma {
+        Some(max_sigma) => table.push_str(&format!(
+            "Maximum difference in units of noise: {} sigma (limit {})\n",
+            outcome.overall.max_sigmas, max_sigma
+        )),
+        None => {
+            table.push_str(&format!("Tolerance: {:e}\n", options.tolerance));
+            if let Some(r) = options.rel_tolerance {
+                table.push_str(&format!("Relative tolerance: {:e}\n", r));
+            }
+            if let Some(u) = options.ulps {
+                table.push_str(&format!("ULP tolerance: {}\n", u));
+            }
+        }
+    }
+    table
+}
+
+/// Email the summary of a failed comparison to --email-to. Failing to send it
+/// is only warned about, as it shouldn't hide the result of the comparison.
+fn email_failure(
+    outcome: &Outcome,
+    verdict: &Verdict,
+    options: &CompareOpt,
+) -> Result<(), anyhow::Error> {
+    let reports = &options.reports;
+    let mut body = format!(
+        "hyperdrive checks failed on {} in {}.\n",
+        email::hostname(),
+        std::env::current_dir()?.display()
+    );
+    if let Some(obsid) = outcome.obsid {
+        body.push_str(&format!("Observation: {}\n", obsid));
+    }
+    body.push('\n');
+    body.push_str(&summary(outcome, verdict, options));
+
+    let from = reports
+        .email_from
+        .clone()
+        .unwrap_or_else(|| format!("hyperdrive-checks@{}", email::hostname()));
+    let subject = format!(
+        "hyperdrive checks FAILED{}: max diff {:e} ({})",
+        outcome
+            .obsid
+            .map(|o| format!(" for {}", o))
+            .unwrap_or_default(),
+        outcome.overall.max_abs,
+        verdict.worst
+    );
+    if let Err(e) = email::send(&email::Email {
+        // Guaranteed to be present by clap.
+        server: reports.smtp_server.as_deref().unwrap(),
+        from: &from,
+        to: &reports.email_to,
+        subject: &subject,
+        body: &body,
+    }) {
+        eprintln!("Warning: couldn't send email: {}", e);
+    }
+    Ok(())
+}
+
+/// Print how long each file took to compare, and the reports to write.
+pub(crate) fn print_timings(outcome: &Outcome, reports: Duration) {
+    println!(
+        "{:<30} {:>11} {:>11} {:>11} {:>11}",
+        "file", "io (s)", "decode (s)", "metrics (s)", "total (s)"
+    );
+    let row = |name: &str, t: &Timings| {
+        println!(
+            "{:<30} {:>11.3} {:>11.3} {:>11.3} {:>11.3}",
+            name,
+            t.io.as_secs_f64(),
+            t.decode.as_secs_f64(),
+            t.metrics.as_secs_f64(),
+            t.total().as_secs_f64()
+        )
+    };
+    for (pair, d) in outcome.pairs.iter().zip(outcome.diffs.iter()) {
+        row(&pair.name.display().to_string(), &d.timings);
+    }
+    row("overall", &outcome.overall.timings);
+    println!("Writing reports: {:.3} s", reports.as_secs_f64());
+}
+
+/// The code to exit with if the verdict is a failure. Files that couldn't be
+/// compared, or only in part, say more than their differences.
+pub(crate) fn exit_code(overall: &Differences) -> i32 {
+    match overall.unreadable {
+        Some(code) => code,
+        None if overall.corrupt => exit_code::SHAPE_MISMATCH,
+        None => exit_code::TOLERANCE_EXCEEDED,
+    }
+}
+
+/// Print why the comparison failed (if it did), then the summary line.
+pub(crate) fn print_verdict(outcome: &Outcome, verdict: &Verdict, options: &CompareOpt) {
+    let overall = &outcome.overall;
+    if verdict.failed && !options.quiet {
+        if !verdict.regressed.is_empty() {
+            println!(
+                "Slower than --max-slowdown allows: {}.",
+                verdict.regressed.join(", ")
+            );
+        }
+        if overall.corrupt {
+            println!("Some files are corrupt; only their valid parts were compared.");
+        }
+        if overall.unreadable.is_some() {
+            println!("Some files couldn't be compared at all; see above.");
+        }
+        if overall.nan_failures > 0 {
+            println!("Some files have NaNs; see --allow-nan and --nan-equal.");
+        }
+        if let (Some(t), Some((_, p))) = (options.ks_threshold, overall.ks) {
+            if p < t {
+                println!("Some files' values aren't distributed like the baseline's; see --ks-threshold.");
+            }
+        }
+        if options.strict_xfail && verdict.xpasses > 0 {
+            println!("Known failures passed, and --strict-xfail is set.");
+        }
+        println!(
+            "Difference is too large; exiting with code {}.",
+            exit_code(overall)
+        );
+    }
+    if overall.size_mismatches > 0 && !options.quiet {
+        println!(
+            "{} files have different sizes; only their overlap was compared.",
+            overall.size_mismatches
+        );
+    }
+
+    // Last, so that scripts scanning logs can find the verdict easily.
+    let mut line = format!(
+        "HYPERDRIVE-CHECKS result={} max_diff={:e} worst={} files={} tolerance={:e}",
+        if verdict.failed { "FAIL" } else { "PASS" },
+        overall.max_abs,
+        summary_value(&verdict.worst),
+        outcome.pairs.len(),
+        options.tolerance
+    );
+    if overall.size_mismatches > 0 {
+        line.push_str(&format!(" size_mismatches={}", overall.size_mismatches));
+    }
+    if verdict.xpasses > 0 {
+        line.push_str(&format!(" xpass={}", verdict.xpasses));
+    }
+    if let Some((_, p_value)) = overall.ks {
+        line.push_str(&format!(" ks_p_value={:e}", p_value));
+    }
+    if let Some(max_sigma) = options.max_sigma {
+        line.push_str(&format!(" max_sigma={}", max_sigma));
+    }
+    if let Some(r) = options.rel_tolerance {
+        line.push_str(&format!(" rel_tolerance={:e}", r));
+    }
+    if let Some(t) = options.imag_tolerance {
+        line.push_str(&format!(" imag_tolerance={:e}", t));
+    }
+    if let Some(u) = options.ulps {
+        line.push_str(&format!(" ulps={}", u));
+    }
+    if options.complex {
+        line.push_str(&format!(
+            " max_amp_diff={:e} max_phase_diff={:e} phase_tolerance={}",
+            overall.max_amp, overall.max_phase, options.phase_tolerance
+        ));
+    }
+    if let Some(obsid) = outcome.obsid {
+        line.push_str(&format!(" obsid={}", obsid));
+    }
+    print_summary(&line, options.reports.format == "json");
+}
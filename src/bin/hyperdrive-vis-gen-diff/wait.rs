@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Waiting for hyperdrive to finish writing its outputs (--wait-for-files),
+//! e.g. when running as a dependent job of the job producing them.
+
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use pawsey_hyperdrive_checks::{baseline_files, Error};
+
+use crate::compress;
+
+/// Parse a duration like "2h", "90m", "1h30m", "45s" or "45" (seconds), or as
+/// humantime and the like write them, e.g. "3.2s", "350 ms" or "2s 345ms 12us
+/// 3ns".
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let too_long = || format!("The duration \"{}\" is too long", s);
+    let compact: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut rest = compact.as_str();
+    if rest.is_empty() {
+        return Err("Empty duration".to_string());
+    }
+    let mut total = Duration::default();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, unit_start) = rest.split_at(digits);
+        let unit_len = unit_start
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(unit_start.len());
+        let (unit, after) = unit_start.split_at(unit_len);
+        if number.is_empty() {
+            return Err(format!(
+                "Missing number before \"{}\" in duration \"{}\"",
+                unit, s
+            ));
+        }
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("Invalid number \"{}\" in duration \"{}\"", number, s))?;
+        let multiplier = match unit {
+            "h" => 3600.0,
+            "m" | "min" => 60.0,
+            // A number without a unit is in seconds.
+            "s" | "sec" | "" => 1.0,
+            "ms" => 1e-3,
+            "us" | "µs" => 1e-6,
+            "ns" => 1e-9,
+            _ => return Err(format!("Invalid unit \"{}\" in duration \"{}\"", unit, s)),
+        };
+        let term = Duration::try_from_secs_f64(number * multiplier).map_err(|_| too_long())?;
+        total = total.checked_add(term).ok_or_else(too_long)?;
+        rest = after;
+    }
+    Ok(total)
+}
+
+/// Block until every file in `baseline` matching `patterns` (compressed or
+/// not, as the comparison finds them) also exists in `current`, and the sizes
+/// of those files have stopped changing between two polls (i.e. the producer
+/// is no longer writing to them). Fails if `timeout` elapses first.
+pub(crate) fn wait_for_files(
+    baseline: &Path,
+    current: &Path,
+    patterns: &[String],
+    timeout: Option<Duration>,
+    poll_interval: Duration,
+    quiet: bool,
+) -> Result<(), anyhow::Error> {
+    let expected: Vec<PathBuf> = baseline_files(baseline, patterns, compress::EXTENSIONS)?
+        .iter()
+        .map(|name| current.join(name))
+        .collect();
+    if expected.is_empty() {
+        bail!(Error::Missing(format!(
+            "{} does not have any {} files to wait for!",
+            baseline.display(),
+            patterns.join(" or ")
+        )));
+    }
+
+    let start = Instant::now();
+    let mut previous_sizes: Option<Vec<u64>> = None;
+    let mut announced = false;
+    loop {
+        let sizes: Option<Vec<u64>> = expected
+            .iter()
+            .map(|p| std::fs::metadata(p).ok().map(|m| m.len()))
+            .collect();
+        match (&sizes, &previous_sizes) {
+            (Some(s), Some(prev)) if s == prev => return Ok(()),
+            _ => (),
+        }
+
+        if let Some(t) = timeout {
+            if start.elapsed() >= t {
+                let missing: Vec<&PathBuf> = expected.iter().filter(|p| !p.exists()).collect();
+                if missing.is_empty() {
+                    bail!(Error::Missing(format!(
+                        "Timed out after {:?} waiting for output files to finish being written",
+                        t
+                    )));
+                } else {
+                    bail!(Error::Missing(format!(
+                        "Timed out after {:?} waiting for output files; still missing {:?}",
+                        t, missing
+                    )));
+                }
+            }
+        }
+
+        if !quiet && sizes.is_none() && !announced {
+            println!("Waiting for {} output files to appear ...", expected.len());
+            announced = true;
+        }
+        previous_sizes = sizes;
+        sleep(poll_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        for &(s, secs) in &[
+            ("45", 45.0),
+            ("45s", 45.0),
+            ("90m", 5400.0),
+            ("1h30m", 5400.0),
+            ("3.2s", 3.2),
+            ("350 ms", 0.35),
+            ("2s 345ms 12us 3ns", 2.345_012_003),
+        ] {
+            assert_eq!(
+                parse_duration(s),
+                Ok(Duration::from_secs_f64(secs)),
+                "{}",
+                s
+            );
+        }
+        for s in &["", "h", "1x", "1.2.3s", "1e400h"] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn compressed_baselines_are_waited_for() {
+        let root = std::env::temp_dir().join(format!("wait-{}", std::process::id()));
+        let (baseline, current) = (root.join("baseline"), root.join("current"));
+        std::fs::create_dir_all(&baseline).unwrap();
+        std::fs::create_dir_all(&current).unwrap();
+        std::fs::write(baseline.join("hyperdrive_band01.bin.zst"), b"").unwrap();
+        std::fs::write(baseline.join("hyperdrive_band02.bin"), b"").unwrap();
+        let patterns = ["hyperdrive_band*.bin".to_string()];
+        let wait = |timeout| {
+            wait_for_files(
+                &baseline,
+                &current,
+                &patterns,
+                Some(timeout),
+                Duration::from_millis(1),
+                true,
+            )
+        };
+
+        // Both names are expected in the current directory, not the PWD.
+        std::fs::write(current.join("hyperdrive_band02.bin"), b"").unwrap();
+        let e = wait(Duration::from_millis(20)).unwrap_err().to_string();
+        assert!(e.contains("hyperdrive_band01.bin\""), "{}", e);
+        std::fs::write(current.join("hyperdrive_band01.bin"), b"").unwrap();
+        let waited = wait(Duration::from_secs(10));
+        let nothing = wait_for_files(
+            &current,
+            &baseline,
+            &["*.uvfits".to_string()],
+            None,
+            Duration::from_millis(1),
+            true,
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+        waited.unwrap();
+        assert!(nothing.is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Rerun the comparison whenever the outputs in the PWD are rewritten, e.g.
+//! while hacking on hyperdrive and rerunning simulate-vis over and over, and
+//! print only what changed since the last run.
+//!
+//! The outputs are polled for changes to their sizes and modification times;
+//! a run starts once they've stopped changing. Each run is this executable run
+//! again with the same options (less --watch), writing its results with --csv.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use pawsey_hyperdrive_checks::{glob_files, sort_naturally, DEFAULT_OUTPUTS};
+
+use crate::{history, DiffOpt};
+
+/// How often to look at the outputs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The size and modification time of each output, to see when they change.
+type Signature = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+/// The maximum difference and verdict of each file (or band), by name.
+type Results = HashMap<String, (f32, String)>;
+
+/// Compare, then compare again every time the outputs change, until killed.
+/// `args` are the command-line arguments this executable was run with.
+pub(crate) fn watch(options: &DiffOpt, args: &[OsString]) -> Result<(), anyhow::Error> {
+    let patterns: Vec<String> = if options.compare.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        options.compare.outputs.clone()
+    };
+    let exe = std::env::current_exe()?;
+    let csv = std::env::temp_dir().join(format!(
+        "hyperdrive-checks-watch.{}.csv",
+        std::process::id()
+    ));
+    let mut child_args: Vec<OsString> = args[1..]
+        .iter()
+        .filter(|a| *a != "--watch")
+        .cloned()
+        .collect();
+    child_args.extend(["--quiet".into(), "--csv".into(), csv.clone().into()]);
+
+    let mut previous: Option<Results> = None;
+    let mut compared: Option<Signature> = None;
+    let mut last_seen: Option<Signature> = None;
+    for run in 1.. {
+        if run > 1 && !options.compare.quiet {
+            println!("Watching for changes to {} ...", patterns.join(" or "));
+        }
+        // Compare straight away the first time, then once the outputs have
+        // been rewritten and stopped changing.
+        loop {
+            let seen = signature(&patterns)?;
+            let ready = match &compared {
+                None => true,
+                Some(c) => *c != seen && last_seen.as_ref() == Some(&seen),
+            };
+            last_seen = Some(seen);
+            if ready {
+                break;
+            }
+            sleep(POLL_INTERVAL);
+        }
+        compared = last_seen.clone();
+
+        let _ = std::fs::remove_file(&csv);
+        let status = Command::new(&exe)
+            .args(&child_args)
+            .stdout(Stdio::null())
+            .status()?;
+        let when = history::utc_timestamp(SystemTime::now());
+        let results = match status.code() {
+            Some(0) | Some(1) if csv.exists() => read(&csv)?,
+            code => {
+                println!(
+                    "Run {} ({}): couldn't compare (exit code {}); see above",
+                    run,
+                    when,
+                    code.map_or("none".to_string(), |c| c.to_string())
+                );
+                continue;
+            }
+        };
+        let _ = std::fs::remove_file(&csv);
+        print!(
+            "{}",
+            report(run, &when, status.success(), previous.as_ref(), &results)
+        );
+        previous = Some(results);
+    }
+    Ok(())
+}
+
+fn signature(patterns: &[String]) -> Result<Signature, anyhow::Error> {
+    let mut files = vec![];
+    for p in patterns {
+        files.append(&mut glob_files(std::path::Path::new("."), p)?);
+    }
+    sort_naturally(&mut files);
+    Ok(files
+        .into_iter()
+        .map(|f| {
+            let metadata = std::fs::metadata(&f).ok();
+            let len = metadata.as_ref().map_or(0, |m| m.len());
+            let modified = metadata.and_then(|m| m.modified().ok());
+            (f, len, modified)
+        })
+        .collect())
+}
+
+fn read(csv: &std::path::Path) -> Result<Results, anyhow::Error> {
+    Ok(history::read_files(csv)?
+        .into_iter()
+        .map(|f| (f.file, (f.max_diff, f.verdict)))
+        .collect())
+}
+
+/// The results of a run to print: all of them the first time, then only those
+/// that changed.
+fn report(
+    run: usize,
+    when: &str,
+    passed: bool,
+    previous: Option<&Results>,
+    results: &Results,
+) -> String {
+    let mut out = String::new();
+    let mut names: Vec<&String> = results.keys().collect();
+    names.sort();
+    let (worst, max_diff) = results
+        .iter()
+        .max_by(|a, b| a.1 .0.total_cmp(&b.1 .0))
+        .map_or(("-", 0.0), |(name, (d, _))| (name.as_str(), *d));
+    let _ = writeln!(
+        out,
+        "Run {} ({}): {}; max diff {:e} in {} ({} files)",
+        run,
+        when,
+        if passed { "PASS" } else { "FAIL" },
+        max_diff,
+        worst,
+        results.len()
+    );
+    let mut changes = 0;
+    for name in names {
+        let (d, v) = &results[name];
+        match previous.map(|p| p.get(name)) {
+            None => {
+                let _ = writeln!(out, "  {}: max diff {:e} ({})", name, d, v);
+            }
+            Some(None) => {
+                let _ = writeln!(out, "  {}: new, max diff {:e} ({})", name, d, v);
+            }
+            Some(Some((pd, pv))) if pd != d || pv != v => {
+                let verdict = if pv != v {
+                    format!("{} -> {}", pv, v)
+                } else {
+                    v.clone()
+                };
+                let _ = writeln!(
+                    out,
+                    "  {}: max diff {:e} -> {:e} ({})",
+                    name, pd, d, verdict
+                );
+            }
+            Some(Some(_)) => continue,
+        }
+        changes += 1;
+    }
+    if let Some(previous) = previous {
+        let mut gone: Vec<&String> = previous
+            .keys()
+            .filter(|n| !results.contains_key(*n))
+            .collect();
+        gone.sort();
+        for name in &gone {
+            let _ = writeln!(out, "  {}: no longer compared", name);
+        }
+        if changes == 0 && gone.is_empty() {
+            let _ = writeln!(out, "  No change since the last run");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(files: &[(&str, f32, &str)]) -> Results {
+        files
+            .iter()
+            .map(|(name, d, v)| (name.to_string(), (*d, v.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn only_changes_are_reported_after_the_first_run() {
+        let first = results(&[("band02.bin", 0.0, "pass"), ("band01.bin", 0.5, "fail")]);
+        assert_eq!(
+            report(1, "then", false, None, &first),
+            "Run 1 (then): FAIL; max diff 5e-1 in band01.bin (2 files)\n  \
+             band01.bin: max diff 5e-1 (fail)\n  \
+             band02.bin: max diff 0e0 (pass)\n"
+        );
+        assert_eq!(
+            report(2, "now", false, Some(&first), &first),
+            "Run 2 (now): FAIL; max diff 5e-1 in band01.bin (2 files)\n  \
+             No change since the last run\n"
+        );
+
+        let second = results(&[("band01.bin", 0.0, "pass"), ("band03.bin", 1e-3, "pass")]);
+        assert_eq!(
+            report(3, "now", true, Some(&first), &second),
+            "Run 3 (now): PASS; max diff 1e-3 in band03.bin (2 files)\n  \
+             band01.bin: max diff 5e-1 -> 0e0 (fail -> pass)\n  \
+             band03.bin: new, max diff 1e-3 (pass)\n  \
+             band02.bin: no longer compared\n"
+        );
+        assert_eq!(
+            report(4, "now", false, None, &Results::new()),
+            "Run 4 (now): FAIL; max diff 0e0 in - (0 files)\n"
+        );
+    }
+}
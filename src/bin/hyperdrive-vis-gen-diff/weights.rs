@@ -0,0 +1,61 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The weighted residual RMS, using the visibility weights hyperdrive can
+//! write alongside its outputs. Heavily down-weighted samples (e.g. at the
+//! edges of coarse bands) barely count, so they can't dominate the result the
+//! way they do the unweighted statistics.
+//!
+//! A weight file holds one little-endian float per complex visibility (i.e.
+//! per pair of data floats), in the same order as the data. Samples with a
+//! weight of zero or less are flagged and left out.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+
+use crate::read::bytes_to_f32s;
+
+/// Find the weight file for coarse band `band` (counting from 1) in `dir`, e.g.
+/// "hyperdrive_band01_weights.bin".
+pub(crate) fn find(dir: &Path, band: u64) -> Result<PathBuf, anyhow::Error> {
+    let pattern = format!("{}/*band{:02}_weights.bin", dir.display(), band);
+    let mut matches: Vec<PathBuf> = glob::glob(&pattern)?.filter_map(Result::ok).collect();
+    match matches.len() {
+        0 => bail!("Couldn't find a weight file matching {}", pattern),
+        1 => Ok(matches.remove(0)),
+        _ => bail!("Several weight files match {}: {:?}", pattern, matches),
+    }
+}
+
+pub(crate) fn read(path: &Path) -> Result<Vec<f32>, anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    Ok(bytes_to_f32s(&bytes, path)?)
+}
+
+/// The weighted RMS of the residuals, sqrt(sum w·|d|² / sum w) over complex
+/// visibilities d, where `weights` has one weight per pair of floats of
+/// `current` and `baseline`. Floats for which `skip` is true are left out.
+pub(crate) fn weighted_rms<F: Fn(usize) -> bool>(
+    current: &[f32],
+    baseline: &[f32],
+    weights: &[f32],
+    skip: F,
+) -> f64 {
+    let (sum, total_weight) = weights.iter().enumerate().filter(|(_, &w)| w > 0.0).fold(
+        (0.0, 0.0),
+        |(sum, total_weight), (v, &w)| {
+            let d2: f64 = (2 * v..2 * v + 2)
+                .filter(|&i| !skip(i))
+                .map(|i| ((current[i] - baseline[i]) as f64).powi(2))
+                .sum();
+            (sum + w as f64 * d2, total_weight + w as f64)
+        },
+    );
+    if total_weight == 0.0 {
+        0.0
+    } else {
+        (sum / total_weight).sqrt()
+    }
+}
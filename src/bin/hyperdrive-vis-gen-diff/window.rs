@@ -0,0 +1,115 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Compare outputs against several historical baselines at once, to tell
+//! whether the outputs have drifted from all of them or whether only the newest
+//! baseline is odd.
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+
+use crate::read::Reader;
+use crate::{
+    band_file_pairs, compare_pair, preflight, split_bands, DiffOpt, Differences, Verbosity,
+};
+
+/// Compare the outputs against each baseline in the window and report how
+/// well they agree with each. Returns the baseline the outputs are then
+/// compared against as usual (with its reports and verdict): the newest that
+/// they agree with, or the newest of all if they agree with none, so that the
+/// run only fails if no baseline in the window agrees.
+pub(crate) fn choose_baseline(
+    options: &DiffOpt,
+    reader: &Reader,
+) -> Result<PathBuf, anyhow::Error> {
+    // Guaranteed to be present by the caller.
+    let dir = options.baseline_window.as_ref().unwrap();
+    let mut baselines: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_dir())
+        .collect();
+    baselines.sort();
+    if baselines.is_empty() {
+        bail!("{:?} doesn't contain any baseline directories!", dir);
+    }
+    let window = &baselines[baselines.len().saturating_sub(options.window.max(1))..];
+
+    let comparison = options.compare.comparison()?;
+    let mut results: Vec<(&PathBuf, Differences, bool)> = vec![];
+    for (i, baseline) in window.iter().enumerate() {
+        let pairs = band_file_pairs(options, baseline, i == 0)?;
+        preflight(&pairs, &options.compare)?;
+        let pairs = match options.compare.bands {
+            Some(bands) => split_bands(pairs, bands, options.compare.dtype)?,
+            None => pairs,
+        };
+        let mut overall = Differences::default();
+        for pair in &pairs {
+            // Nothing is printed when quiet.
+            overall = overall.max(compare_pair(
+                pair,
+                &comparison,
+                reader,
+                Verbosity::Quiet,
+                &mut String::new(),
+            )?);
+        }
+        results.push((baseline, overall, options.compare.failed(&overall)));
+    }
+
+    if !options.compare.quiet {
+        println!(
+            "Compared against the newest {} of {} baselines in {:?}:",
+            window.len(),
+            baselines.len(),
+            dir
+        );
+        println!("{:<30} {:>14} {:>6}", "baseline", "max diff", "result");
+        for (baseline, d, failed) in &results {
+            println!(
+                "{:<30} {:>14e} {:>6}",
+                baseline.file_name().unwrap_or_default().to_string_lossy(),
+                d.max_abs,
+                if *failed { "FAIL" } else { "pass" }
+            );
+        }
+    }
+
+    let by_diff = |a: &&(&PathBuf, Differences, bool), b: &&(&PathBuf, Differences, bool)| {
+        a.1.max_abs
+            .partial_cmp(&b.1.max_abs)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    };
+    // There's at least one baseline, so these exist.
+    let best = results.iter().min_by(by_diff).unwrap();
+    let worst = results.iter().max_by(by_diff).unwrap();
+    let newest_failed = results.last().unwrap().2;
+    let passes = results.iter().filter(|r| !r.2).count();
+    if !options.compare.quiet {
+        println!("Best agreement: {:?} ({:e})", best.0, best.1.max_abs);
+        println!("Worst agreement: {:?} ({:e})", worst.0, worst.1.max_abs);
+        if passes == results.len() {
+            println!("The outputs agree with every baseline in the window.");
+        } else if passes == 0 {
+            println!("The outputs have drifted from every baseline in the window.");
+        } else if newest_failed {
+            println!(
+                "The outputs disagree with the newest baseline, but agree with an older one; the newest baseline may be the odd one out."
+            );
+        } else {
+            println!("The outputs agree with the newest baseline, but not with some older ones.");
+        }
+    }
+
+    let (chosen, ..) = results
+        .iter()
+        .rev()
+        .find(|r| !r.2)
+        .unwrap_or_else(|| results.last().unwrap());
+    if !options.compare.quiet {
+        println!("Comparing against {:?}:", chosen);
+    }
+    Ok(chosen.to_path_buf())
+}
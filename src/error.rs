@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! What can go wrong, in the categories that scripts running the checks need
+//! to tell apart, and the exit code of each.
+
+use std::fmt;
+use std::path::Path;
+
+/// The exit codes of the checks.
+pub mod exit_code {
+    /// Everything was within tolerance.
+    pub const PASS: i32 = 0;
+    /// Differences were too large (or otherwise failed the check).
+    pub const TOLERANCE_EXCEEDED: i32 = 1;
+    /// Files were missing from the outputs or the baseline.
+    pub const MISSING_FILES: i32 = 2;
+    /// Files were corrupt, or didn't have the size or shape expected.
+    pub const SHAPE_MISMATCH: i32 = 3;
+    /// Anything else: something couldn't be read, written or parsed.
+    pub const ERROR: i32 = 4;
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    /// Files are missing from the outputs or the baseline.
+    Missing(String),
+    /// Data don't have the size or shape expected, e.g. a file was truncated,
+    /// or two files have different numbers of values.
+    Shape(String),
+    /// Something couldn't be read or parsed.
+    Io(String),
+}
+
+impl Error {
+    /// The error of reading `path`; a missing file is [Error::Missing].
+    pub fn reading(path: &Path, e: std::io::Error) -> Error {
+        let message = format!("Couldn't read {:?}: {}", path, e);
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::Missing(message)
+        } else {
+            Error::Io(message)
+        }
+    }
+
+    /// The same error, with `context` in front of its message.
+    pub fn context(self, context: impl fmt::Display) -> Error {
+        match self {
+            Error::Missing(m) => Error::Missing(format!("{}: {}", context, m)),
+            Error::Shape(m) => Error::Shape(format!("{}: {}", context, m)),
+            Error::Io(m) => Error::Io(format!("{}: {}", context, m)),
+        }
+    }
+
+    /// The same kind of error, with another message.
+    pub fn with_message(self, message: String) -> Error {
+        match self {
+            Error::Missing(_) => Error::Missing(message),
+            Error::Shape(_) => Error::Shape(message),
+            Error::Io(_) => Error::Io(message),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Missing(_) => exit_code::MISSING_FILES,
+            Error::Shape(_) => exit_code::SHAPE_MISMATCH,
+            Error::Io(_) => exit_code::ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Missing(m) | Error::Shape(m) | Error::Io(m) => f.write_str(m),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e.to_string())
+    }
+}
+
+/// The exit code for an error with this chain of causes (e.g. from anyhow's
+/// `Error::chain`): that of the first [Error] in it, or [exit_code::ERROR] if
+/// there isn't one.
+pub fn exit_code_of<'a>(
+    chain: impl IntoIterator<Item = &'a (dyn std::error::Error + 'static)>,
+) -> i32 {
+    chain
+        .into_iter()
+        .find_map(|e| e.downcast_ref::<Error>())
+        .map_or(exit_code::ERROR, Error::exit_code)
+}
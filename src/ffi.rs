@@ -0,0 +1,329 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! A C interface to [compare_dirs] and [compare_files], for harnesses that
+//! aren't written in Rust; python/hyperdrive_checks.py wraps it with ctypes.
+//!
+//! Options are given as lines of "name=value", named like the options of
+//! hyperdrive-vis-gen-diff:
+//!
+//! ```text
+//! tolerance=1e-3
+//! rel-tolerance=1e-5
+//! ulps=16
+//! nan=equal
+//! max-sigma=5
+//! dtype=f64
+//! endian=big
+//! allow-subset=true
+//! output=hyperdrive_band??.bin
+//! rule=*band01.bin,tolerance=1e-2,nan=allow
+//! ```
+//!
+//! `output` and `rule` may be given more than once. Reports are returned as
+//! JSON, with null for differences that aren't finite, or as
+//! `{"error": ..., "exit_code": ...}` if the comparison couldn't be done. The
+//! returned strings must be freed with [hyperdrive_checks_free].
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::{
+    compare_dirs, compare_files, exit_code, CompareOptions, ComparisonReport, Error, FileReport,
+    MaxDifferences, Rule,
+};
+
+/// Compare the file `current` against the file `baseline`, returning a
+/// [FileReport] as JSON.
+///
+/// # Safety
+///
+/// Every argument must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hyperdrive_checks_compare_files(
+    baseline: *const c_char,
+    current: *const c_char,
+    options: *const c_char,
+) -> *mut c_char {
+    respond(baseline, current, options, |b, c, o| {
+        compare_files(b, c, o).map(|r| file_json(&r))
+    })
+}
+
+/// Compare the hyperdrive outputs in the directory `current` against those in
+/// the directory `baseline`, returning a [ComparisonReport] as JSON.
+///
+/// # Safety
+///
+/// Every argument must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn hyperdrive_checks_compare_dirs(
+    baseline: *const c_char,
+    current: *const c_char,
+    options: *const c_char,
+) -> *mut c_char {
+    respond(baseline, current, options, |b, c, o| {
+        compare_dirs(b, c, o).map(|r| comparison_json(&r))
+    })
+}
+
+/// Free a string returned by this interface.
+///
+/// # Safety
+///
+/// `s` must have been returned by this interface (or be null), and not
+/// already freed.
+#[no_mangle]
+pub unsafe extern "C" fn hyperdrive_checks_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+unsafe fn respond(
+    baseline: *const c_char,
+    current: *const c_char,
+    options: *const c_char,
+    compare: impl FnOnce(&Path, &Path, &CompareOptions) -> Result<String, Error>,
+) -> *mut c_char {
+    let string = |s: *const c_char| CStr::from_ptr(s).to_string_lossy().into_owned();
+    let (baseline, current, options) = (string(baseline), string(current), string(options));
+    let json = match parse_options(&options) {
+        Ok(opts) => compare(Path::new(&baseline), Path::new(&current), &opts)
+            .unwrap_or_else(|e| error_json(&e.to_string(), e.exit_code())),
+        Err(e) => error_json(&e, exit_code::ERROR),
+    };
+    // The JSON escapes control characters, so can't contain a NUL.
+    CString::new(json).unwrap().into_raw()
+}
+
+fn parse_options(options: &str) -> Result<CompareOptions, String> {
+    let mut opts = CompareOptions::default();
+    for line in options.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (name, value) = split_option(line)?;
+        match name {
+            "output" => opts.outputs.push(value.to_string()),
+            "rule" => {
+                let mut fields = value.split(',');
+                let pattern = fields.next().unwrap_or_default();
+                let mut rule = Rule::new(
+                    glob::Pattern::new(pattern)
+                        .map_err(|e| format!("Invalid pattern \"{}\": {}", pattern, e))?,
+                );
+                for field in fields {
+                    let (name, value) = split_option(field)?;
+                    match name {
+                        "tolerance" => rule.tolerance = Some(parse(name, value)?),
+                        "rel-tolerance" => rule.rel_tolerance = Some(parse(name, value)?),
+                        "ulps" => rule.ulps = Some(parse(name, value)?),
+                        "nan" => rule.nan = Some(value.parse()?),
+                        _ => return Err(format!("Unknown rule option \"{}\"", name)),
+                    }
+                }
+                opts.rules.push(rule);
+            }
+            "tolerance" => opts.tolerance = parse(name, value)?,
+            "rel-tolerance" => opts.rel_tolerance = Some(parse(name, value)?),
+            "ulps" => opts.ulps = Some(parse(name, value)?),
+            "nan" => opts.nan = value.parse()?,
+            "max-sigma" => opts.max_sigma = Some(parse(name, value)?),
+            "dtype" => opts.dtype = value.parse()?,
+            "endian" => opts.endian = value.parse()?,
+            "allow-subset" => opts.allow_subset = parse(name, value)?,
+            _ => return Err(format!("Unknown option \"{}\"", name)),
+        }
+    }
+    Ok(opts)
+}
+
+fn split_option(s: &str) -> Result<(&str, &str), String> {
+    let i = s
+        .find('=')
+        .ok_or_else(|| format!("Expected \"name=value\", not \"{}\"", s))?;
+    Ok((s[..i].trim(), s[i + 1..].trim()))
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {} \"{}\"", name, value))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn number(f: f32) -> String {
+    if f.is_finite() {
+        format!("{:e}", f)
+    } else {
+        "null".to_string()
+    }
+}
+
+fn differences_json(d: &MaxDifferences) -> String {
+    format!(
+        "{{\"abs\": {}, \"rel\": {}, \"ulps\": {}, \"sigmas\": {}, \"violations\": {}, \"nan_failures\": {}}}",
+        number(d.abs),
+        number(d.rel),
+        d.ulps,
+        number(d.sigmas),
+        d.violations,
+        d.nan_failures
+    )
+}
+
+fn file_json(r: &FileReport) -> String {
+    format!(
+        "{{\"name\": {}, \"differences\": {}, \"passed\": {}}}",
+        json_string(&r.name.to_string_lossy()),
+        differences_json(&r.differences),
+        r.passed
+    )
+}
+
+fn comparison_json(r: &ComparisonReport) -> String {
+    let files: Vec<String> = r.files.iter().map(file_json).collect();
+    format!(
+        "{{\"files\": [{}], \"differences\": {}, \"worst\": {}, \"passed\": {}}}",
+        files.join(", "),
+        differences_json(&r.differences),
+        json_string(&r.worst.to_string_lossy()),
+        r.passed
+    )
+}
+
+fn error_json(message: &str, exit_code: i32) -> String {
+    format!(
+        "{{\"error\": {}, \"exit_code\": {}}}",
+        json_string(message),
+        exit_code
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(
+        f: unsafe extern "C" fn(*const c_char, *const c_char, *const c_char) -> *mut c_char,
+        baseline: &Path,
+        current: &Path,
+        options: &str,
+    ) -> String {
+        let c = |s: &str| CString::new(s).unwrap();
+        let (b, cur, o) = (
+            c(&baseline.to_string_lossy()),
+            c(&current.to_string_lossy()),
+            c(options),
+        );
+        unsafe {
+            let json = f(b.as_ptr(), cur.as_ptr(), o.as_ptr());
+            let s = CStr::from_ptr(json).to_str().unwrap().to_string();
+            hyperdrive_checks_free(json);
+            s
+        }
+    }
+
+    #[test]
+    fn reports_are_returned_as_json() {
+        let root = std::env::temp_dir().join(format!("ffi-{}", std::process::id()));
+        let (baseline, current) = (root.join("baseline"), root.join("current"));
+        std::fs::create_dir_all(&baseline).unwrap();
+        std::fs::create_dir_all(&current).unwrap();
+        let floats = |fs: &[f32]| fs.iter().flat_map(|f| f.to_le_bytes()).collect::<Vec<_>>();
+        for (name, b, c) in [
+            ("hyperdrive_band01.bin", [1.0, 2.0], [1.0, 2.25]),
+            ("hyperdrive_band02.bin", [0.0, 1.0], [0.5, 1.0]),
+        ] {
+            std::fs::write(baseline.join(name), floats(&b)).unwrap();
+            std::fs::write(current.join(name), floats(&c)).unwrap();
+        }
+        let band01 = |dir: &Path| dir.join("hyperdrive_band01.bin");
+
+        let file = call(
+            hyperdrive_checks_compare_files,
+            &band01(&baseline),
+            &band01(&current),
+            "tolerance=0.5\n",
+        );
+        let dirs = call(
+            hyperdrive_checks_compare_dirs,
+            &baseline,
+            &current,
+            "tolerance=0.1\nrule=*band01.bin, tolerance=0.3",
+        );
+        let missing = call(
+            hyperdrive_checks_compare_dirs,
+            &baseline,
+            &root.join("nowhere"),
+            "",
+        );
+        let bad = call(
+            hyperdrive_checks_compare_dirs,
+            &baseline,
+            &current,
+            "nan=sometimes",
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(
+            file.ends_with(
+                "\"differences\": {\"abs\": 2.5e-1, \"rel\": 1.25e-1, \"ulps\": 1048576, \
+             \"sigmas\": 0e0, \"violations\": 0, \"nan_failures\": 0}, \"passed\": true}"
+            ),
+            "{}",
+            file
+        );
+        // band02's relative difference is infinite, as its baseline float is
+        // zero.
+        assert!(dirs.contains("\"rel\": null"), "{}", dirs);
+        assert!(
+            dirs.contains("\"worst\": \"hyperdrive_band02.bin\""),
+            "{}",
+            dirs
+        );
+        assert!(dirs.ends_with("\"passed\": false}"), "{}", dirs);
+        assert!(dirs.contains("\"violations\": 0, \"nan_failures\": 0}, \"passed\": true}"));
+        assert!(missing.starts_with("{\"error\": \""), "{}", missing);
+        assert!(missing.ends_with(&format!("\"exit_code\": {}}}", exit_code::MISSING_FILES)));
+        assert!(bad.contains("Unknown NaN policy"), "{}", bad);
+        assert!(bad.ends_with(&format!("\"exit_code\": {}}}", exit_code::ERROR)));
+    }
+
+    #[test]
+    fn options_are_named_like_the_checkers() {
+        let opts = parse_options(
+            "tolerance=1e-2\n rel-tolerance = 1e-5\nulps=16\nnan=equal\nmax-sigma=5\n\
+             dtype=f64\nendian=big\nallow-subset=true\noutput=a*.bin\noutput=b.bin\n\
+             rule=*01.bin,ulps=4,nan=allow\n",
+        )
+        .unwrap();
+        assert_eq!(opts.tolerance, 1e-2);
+        assert_eq!(opts.rel_tolerance, Some(1e-5));
+        assert_eq!(opts.ulps, Some(16));
+        assert_eq!(opts.max_sigma, Some(5.0));
+        assert_eq!(opts.dtype, crate::Dtype::F64);
+        assert_eq!(opts.endian, crate::Endian::Big);
+        assert!(opts.allow_subset);
+        assert_eq!(opts.outputs, ["a*.bin", "b.bin"]);
+        let rule = &opts.tolerances(Path::new("band01.bin"));
+        assert_eq!((rule.tolerance, rule.ulps), (1e-2, Some(4)));
+        for bad in &["tolerance", "tolerance=big", "colour=red", "rule=*,size=2"] {
+            assert!(parse_options(bad).is_err(), "{}", bad);
+        }
+    }
+}
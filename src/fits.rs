@@ -0,0 +1,451 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Just enough of FITS to read the files hyperdrive writes: headers, images
+//! and binary tables (and random groups, for uvfits). There's no writing, no
+//! compression and no variable-length arrays.
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::Error;
+
+/// FITS files are made of blocks of this many bytes.
+const BLOCK: usize = 2880;
+/// Header cards are this many bytes.
+const CARD: usize = 80;
+
+/// A FITS file, read whole.
+pub struct Fits {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    pub hdus: Vec<Hdu>,
+}
+
+/// A header and data unit.
+pub struct Hdu {
+    /// The header's keywords and their values, in order. String values are
+    /// unquoted; comments are dropped.
+    pub header: Vec<(String, String)>,
+    data_start: usize,
+    data_len: usize,
+}
+
+impl Fits {
+    pub fn read(path: &Path) -> Result<Fits, Error> {
+        let bytes = std::fs::read(path).map_err(|e| Error::reading(path, e))?;
+        if !Fits::is_fits(&bytes) {
+            return Err(Error::Io(format!("{:?} isn't a FITS file", path)));
+        }
+        let mut hdus = vec![];
+        let mut start = 0;
+        while start < bytes.len() {
+            let (hdu, next) = parse_hdu(&bytes, start)
+                .map_err(|e| Error::Io(format!("{:?} is malformed: {}", path, e)))?;
+            hdus.push(hdu);
+            start = next;
+        }
+        Ok(Fits {
+            path: path.to_path_buf(),
+            bytes,
+            hdus,
+        })
+    }
+
+    /// Do these bytes (the start of a file will do) look like FITS?
+    pub fn is_fits(bytes: &[u8]) -> bool {
+        bytes.starts_with(b"SIMPLE  =")
+    }
+
+    /// The extension named `extname`.
+    pub fn hdu(&self, extname: &str) -> Result<&Hdu, Error> {
+        self.hdus
+            .iter()
+            .find(|h| h.value("EXTNAME") == Some(extname))
+            .ok_or_else(|| Error::Io(format!("{:?} doesn't have a {} HDU", self.path, extname)))
+    }
+
+    pub fn data(&self, hdu: &Hdu) -> &[u8] {
+        &self.bytes[hdu.data_start..hdu.data_start + hdu.data_len]
+    }
+
+    /// The values of an image HDU as f64s, with the first axis varying
+    /// fastest.
+    pub fn image(&self, hdu: &Hdu) -> Result<Vec<f64>, Error> {
+        let data = self.data(hdu);
+        let scale = hdu.float("BSCALE").unwrap_or(1.0);
+        let zero = hdu.float("BZERO").unwrap_or(0.0);
+        let values: Vec<f64> = match hdu.int("BITPIX") {
+            Some(-64) => data.chunks_exact(8).map(BigEndian::read_f64).collect(),
+            Some(-32) => data
+                .chunks_exact(4)
+                .map(|v| BigEndian::read_f32(v) as f64)
+                .collect(),
+            Some(32) => data
+                .chunks_exact(4)
+                .map(|v| BigEndian::read_i32(v) as f64)
+                .collect(),
+            Some(16) => data
+                .chunks_exact(2)
+                .map(|v| BigEndian::read_i16(v) as f64)
+                .collect(),
+            Some(8) => data.iter().map(|&v| v as f64).collect(),
+            b => {
+                return Err(Error::Io(format!(
+                    "{:?} has an image with unsupported BITPIX {:?}",
+                    self.path, b
+                )))
+            }
+        };
+        Ok(if scale == 1.0 && zero == 0.0 {
+            values
+        } else {
+            values.into_iter().map(|v| v * scale + zero).collect()
+        })
+    }
+
+    /// The binary table in `hdu`.
+    pub fn table<'a>(&'a self, hdu: &'a Hdu) -> Result<Table<'a>, Error> {
+        if hdu.value("XTENSION") != Some("BINTABLE") {
+            return Err(Error::Io(format!(
+                "{:?}: {:?} isn't a binary table",
+                self.path,
+                hdu.value("EXTNAME")
+            )));
+        }
+        let fields = hdu.int("TFIELDS").unwrap_or(0) as usize;
+        let mut columns = vec![];
+        let mut offset = 0;
+        for n in 1..=fields {
+            let form = hdu
+                .value(&format!("TFORM{}", n))
+                .ok_or_else(|| Error::Io(format!("{:?}: column {} has no TFORM", self.path, n)))?;
+            let digits = form
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(form.len());
+            let repeat: usize = if digits == 0 {
+                1
+            } else {
+                form[..digits].parse().unwrap_or(1)
+            };
+            let kind = form[digits..].chars().next().unwrap_or(' ');
+            let width = match kind {
+                'L' | 'B' | 'A' => repeat,
+                'I' => 2 * repeat,
+                'J' | 'E' => 4 * repeat,
+                'K' | 'D' | 'C' => 8 * repeat,
+                'M' => 16 * repeat,
+                // Bits, rounded up to bytes.
+                'X' => repeat.div_ceil(8),
+                _ => {
+                    return Err(Error::Io(format!(
+                        "{:?}: column {} has unsupported TFORM {}",
+                        self.path, n, form
+                    )))
+                }
+            };
+            columns.push(Column {
+                name: hdu.value(&format!("TTYPE{}", n)).unwrap_or("").to_string(),
+                kind,
+                repeat,
+                offset,
+            });
+            offset += width;
+        }
+        let row_len = hdu.int("NAXIS1").unwrap_or(0) as usize;
+        if offset > row_len {
+            return Err(Error::Io(format!(
+                "{:?}: the columns of {:?} are wider than its rows",
+                self.path,
+                hdu.value("EXTNAME")
+            )));
+        }
+        Ok(Table {
+            columns,
+            row_len,
+            rows: hdu.int("NAXIS2").unwrap_or(0) as usize,
+            data: self.data(hdu),
+        })
+    }
+}
+
+impl Hdu {
+    /// The value of the header keyword `key`, if it's there.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.header
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn int(&self, key: &str) -> Option<i64> {
+        self.value(key)?.parse().ok()
+    }
+
+    pub fn float(&self, key: &str) -> Option<f64> {
+        // FITS allows Fortran-style exponents.
+        self.value(key)?.replace('D', "E").parse().ok()
+    }
+
+    /// The lengths of the axes, first (fastest varying) first.
+    pub fn axes(&self) -> Vec<usize> {
+        let naxis = self.int("NAXIS").unwrap_or(0);
+        (1..=naxis)
+            .map(|n| self.int(&format!("NAXIS{}", n)).unwrap_or(0) as usize)
+            .collect()
+    }
+}
+
+/// A column of a binary table.
+struct Column {
+    name: String,
+    /// The TFORM type code, e.g. 'D'.
+    kind: char,
+    repeat: usize,
+    /// The byte offset of the column in a row.
+    offset: usize,
+}
+
+/// A binary table.
+pub struct Table<'a> {
+    columns: Vec<Column>,
+    row_len: usize,
+    pub rows: usize,
+    data: &'a [u8],
+}
+
+impl Table<'_> {
+    fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    fn cells<'b>(&'b self, c: &'b Column) -> impl Iterator<Item = &'b [u8]> + 'b {
+        (0..self.rows).map(move |r| &self.data[r * self.row_len + c.offset..])
+    }
+
+    /// The first number in each row of the numeric column `name`, if there is
+    /// such a column.
+    pub fn f64s(&self, name: &str) -> Option<Vec<f64>> {
+        Some(self.f64_rows(name)?.into_iter().map(|r| r[0]).collect())
+    }
+
+    /// All of the numbers in each row of the numeric column `name` (e.g. the
+    /// three coordinates of a position), if there is such a column.
+    pub fn f64_rows(&self, name: &str) -> Option<Vec<Vec<f64>>> {
+        let c = self.column(name).filter(|c| c.repeat > 0)?;
+        let (size, read): (usize, fn(&[u8]) -> f64) = match c.kind {
+            'D' => (8, BigEndian::read_f64),
+            'E' => (4, |b| BigEndian::read_f32(b) as f64),
+            'K' => (8, |b| BigEndian::read_i64(b) as f64),
+            'J' => (4, |b| BigEndian::read_i32(b) as f64),
+            'I' => (2, |b| BigEndian::read_i16(b) as f64),
+            'B' => (1, |b| b[0] as f64),
+            _ => return None,
+        };
+        Some(
+            self.cells(c)
+                .map(|b| (0..c.repeat).map(|i| read(&b[i * size..])).collect())
+                .collect(),
+        )
+    }
+
+    /// Each row of the character column `name`, without trailing spaces or
+    /// NULs, if there is such a column.
+    pub fn strings(&self, name: &str) -> Option<Vec<String>> {
+        let c = self.column(name).filter(|c| c.kind == 'A')?;
+        Some(
+            self.cells(c)
+                .map(|b| {
+                    String::from_utf8_lossy(&b[..c.repeat])
+                        .trim_end_matches(&[' ', '\0'][..])
+                        .to_string()
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Parse the HDU starting at `start`, returning it and where the next starts.
+fn parse_hdu(bytes: &[u8], start: usize) -> Result<(Hdu, usize), String> {
+    let mut header = vec![];
+    let mut pos = start;
+    loop {
+        let card = bytes
+            .get(pos..pos + CARD)
+            .ok_or_else(|| "a header has no END".to_string())?;
+        pos += CARD;
+        let card = String::from_utf8_lossy(card);
+        let key = card[..8].trim_end();
+        if key == "END" {
+            break;
+        }
+        if &card[8..10] != "= " {
+            // COMMENT, HISTORY, blank and the like.
+            continue;
+        }
+        header.push((key.to_string(), parse_value(&card[10..])));
+    }
+    let data_start = pos.div_ceil(BLOCK) * BLOCK;
+    let hdu = Hdu {
+        header,
+        data_start,
+        data_len: 0,
+    };
+
+    let bitpix = hdu.int("BITPIX").ok_or("a header has no BITPIX")?;
+    let axes = hdu.axes();
+    // Random groups don't count their first axis, which is 0.
+    let groups = hdu.value("GROUPS") == Some("T") && axes.first() == Some(&0);
+    let elements = if axes.is_empty() {
+        0
+    } else {
+        let product: usize = axes.iter().skip(groups as usize).product();
+        let pcount = hdu.int("PCOUNT").unwrap_or(0) as usize;
+        let gcount = hdu.int("GCOUNT").unwrap_or(1) as usize;
+        gcount * (pcount + product)
+    };
+    let data_len = elements * (bitpix.unsigned_abs() as usize / 8);
+    if data_start + data_len > bytes.len() {
+        return Err(format!(
+            "an HDU's data runs {} bytes past the end of the file",
+            data_start + data_len - bytes.len()
+        ));
+    }
+    let next = (data_start + data_len).div_ceil(BLOCK) * BLOCK;
+    Ok((
+        Hdu { data_len, ..hdu },
+        next.min(bytes.len()).max(data_start),
+    ))
+}
+
+/// The value of a header card, without its comment, and unquoted if it's a
+/// string.
+fn parse_value(s: &str) -> String {
+    let s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('\'') {
+        // Quotes inside strings are doubled.
+        let mut value = String::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if chars.peek() == Some(&'\'') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            value.push(c);
+        }
+        return value.trim_end().to_string();
+    }
+    s.split('/').next().unwrap_or("").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A header of `cards`, padded to whole blocks.
+    fn header(cards: &[&str]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = cards
+            .iter()
+            .chain(&["END"])
+            .flat_map(|c| format!("{:<80}", c).into_bytes())
+            .collect();
+        bytes.resize(bytes.len().div_ceil(BLOCK) * BLOCK, b' ');
+        bytes
+    }
+
+    /// Data, padded to whole blocks.
+    fn data(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes.resize(bytes.len().div_ceil(BLOCK) * BLOCK, 0);
+        bytes
+    }
+
+    fn write(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.fits", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn image_and_table_round_trip() {
+        let image = [1.5f32, -2.25, 0.0, 1e30, -1e-30, 7.0];
+        let mut bytes = header(&[
+            "SIMPLE  =                    T",
+            "BITPIX  =                  -32",
+            "NAXIS   =                    2",
+            "NAXIS1  =                    3",
+            "NAXIS2  =                    2",
+            "OBJECT  = 'it''s a test'         / a comment",
+            "EXPOSURE=               1.5D+2",
+        ]);
+        bytes.extend(data(image.iter().flat_map(|f| f.to_be_bytes()).collect()));
+        bytes.extend(header(&[
+            "XTENSION= 'BINTABLE'",
+            "BITPIX  =                    8",
+            "NAXIS   =                    2",
+            "NAXIS1  =                   30",
+            "NAXIS2  =                    2",
+            "PCOUNT  =                    0",
+            "GCOUNT  =                    1",
+            "TFIELDS =                    3",
+            "TTYPE1  = 'NAME    '",
+            "TFORM1  = '4A      '",
+            "TTYPE2  = 'POSITION'",
+            "TFORM2  = '3D      '",
+            "TTYPE3  = 'INDEX   '",
+            "TFORM3  = 'I       '",
+            "EXTNAME = 'TILES   '",
+        ]));
+        let rows: [(&[u8; 4], [f64; 3], i16); 2] = [
+            (b"T1  ", [1.0, -2.0, 3.5], 7),
+            (b"T22\0", [0.0, 1e-3, -4e6], -1),
+        ];
+        let mut table = vec![];
+        for (name, position, index) in &rows {
+            table.extend_from_slice(&name[..]);
+            table.extend(position.iter().flat_map(|p| p.to_be_bytes()));
+            table.extend(index.to_be_bytes());
+        }
+        bytes.extend(data(table));
+        let path = write("fits-round-trip", &bytes);
+
+        let fits = Fits::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(fits.hdus.len(), 2);
+        let primary = &fits.hdus[0];
+        assert_eq!(primary.axes(), vec![3, 2]);
+        assert_eq!(primary.value("OBJECT"), Some("it's a test"));
+        assert_eq!(primary.float("EXPOSURE"), Some(150.0));
+        let read: Vec<f64> = image.iter().map(|&f| f as f64).collect();
+        assert_eq!(fits.image(primary).unwrap(), read);
+
+        let table = fits.table(fits.hdu("TILES").unwrap()).unwrap();
+        assert_eq!(table.rows, 2);
+        assert_eq!(table.strings("NAME").unwrap(), vec!["T1", "T22"]);
+        assert_eq!(
+            table.f64_rows("POSITION").unwrap(),
+            vec![vec![1.0, -2.0, 3.5], vec![0.0, 1e-3, -4e6]]
+        );
+        assert_eq!(table.f64s("INDEX").unwrap(), vec![7.0, -1.0]);
+        assert!(table.f64s("NOPE").is_none());
+    }
+
+    #[test]
+    fn truncated_data_is_malformed() {
+        let mut bytes = header(&[
+            "SIMPLE  =                    T",
+            "BITPIX  =                  -64",
+            "NAXIS   =                    1",
+            "NAXIS1  =                 1000",
+        ]);
+        bytes.extend(vec![0; 100]);
+        let path = write("fits-truncated", &bytes);
+        let result = Fits::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Error::Io(e)) if e.contains("past the end")));
+    }
+}
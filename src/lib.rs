@@ -0,0 +1,686 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The core of hyperdrive-vis-gen-diff, for use in other regression harnesses:
+//! finding hyperdrive outputs, reading them as floats and finding the largest
+//! differences between them.
+//!
+//! [compare_dirs] compares the hyperdrive outputs in one directory against
+//! those in a baseline directory, like running hyperdrive-vis-gen-diff with
+//! the tolerances of [CompareOptions], but returns a [ComparisonReport] rather
+//! than printing it; [compare_files] does the same for a single pair of files.
+//! Both are also available to C and Python through [ffi].
+
+mod error;
+pub mod ffi;
+pub mod fits;
+pub mod solutions;
+mod tolerance;
+
+pub use error::{exit_code, exit_code_of, Error};
+pub use tolerance::{
+    abs_difference, estimate_noise, tolerances, ulp_distance, FloatDifference, Judgement,
+    NanPolicy, Rule, Tolerances,
+};
+
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NativeEndian};
+
+/// The files that hyperdrive writes by default: one per coarse band, numbered
+/// with (at least) two digits.
+pub const DEFAULT_OUTPUTS: &[&str] = &["hyperdrive_band??.bin", "hyperdrive_band???.bin"];
+
+/// How to compare directories.
+#[derive(Clone, Debug)]
+pub struct CompareOptions {
+    /// A file fails if the absolute difference between two of its floats is
+    /// bigger than this, unless they're within one of the other tolerances.
+    pub tolerance: f32,
+    /// Floats whose difference is at most this fraction of the baseline float
+    /// also pass.
+    pub rel_tolerance: Option<f32>,
+    /// Floats at most this many units in the last place apart also pass.
+    pub ulps: Option<u32>,
+    /// Which NaNs fail.
+    pub nan: NanPolicy,
+    /// Tolerances for particular files, overriding those above.
+    pub rules: Vec<Rule>,
+    /// Instead of the tolerances, a file fails if its largest absolute
+    /// difference is more than this many times the estimated noise of the
+    /// baseline (see [estimate_noise]). NaNs are still judged by `nan`.
+    pub max_sigma: Option<f32>,
+    /// The names of the files to compare; glob patterns are allowed. If empty,
+    /// [DEFAULT_OUTPUTS] is used.
+    pub outputs: Vec<String>,
+    /// The type of the values in the files.
+    pub dtype: Dtype,
+    /// The byte order of the values in the files.
+    pub endian: Endian,
+    /// Don't fail if files in the baseline are missing from the directory
+    /// being checked, for intentionally partial runs.
+    pub allow_subset: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions {
+            tolerance: 0.001,
+            rel_tolerance: None,
+            ulps: None,
+            nan: NanPolicy::Fail,
+            rules: vec![],
+            max_sigma: None,
+            outputs: vec![],
+            dtype: Dtype::F32,
+            endian: Endian::Little,
+            allow_subset: false,
+        }
+    }
+}
+
+impl CompareOptions {
+    /// The tolerances of a file (or band) called `name`.
+    pub fn tolerances(&self, name: &Path) -> Tolerances {
+        let defaults = Tolerances {
+            tolerance: self.tolerance,
+            rel_tolerance: self.rel_tolerance,
+            ulps: self.ulps,
+            nan: self.nan,
+        };
+        tolerances(&self.rules, name, defaults).unwrap_or(defaults)
+    }
+}
+
+/// The type of the values in a file. Whatever the type, values are compared as
+/// f32s, so doubles are rounded to single precision first.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dtype {
+    /// What hyperdrive writes.
+    #[default]
+    F32,
+    F64,
+    I16,
+    I32,
+}
+
+impl Dtype {
+    /// The names accepted by [Dtype::from_str].
+    pub const NAMES: &'static [&'static str] = &["f32", "f64", "i16", "i32"];
+
+    /// The size of a value in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            Dtype::F32 | Dtype::I32 => 4,
+            Dtype::F64 => 8,
+            Dtype::I16 => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dtype::F32 => "f32",
+            Dtype::F64 => "f64",
+            Dtype::I16 => "i16",
+            Dtype::I32 => "i32",
+        }
+    }
+
+    /// Decode values of this type and byte order read from `path` as the f32s
+    /// they're compared as.
+    pub fn to_f32s(self, bytes: &[u8], endian: Endian, path: &Path) -> Result<Vec<f32>, Error> {
+        if !bytes.len().is_multiple_of(self.size()) {
+            return Err(Error::Shape(format!(
+                "An invalid number of bytes were read from {:?}. Does this file really contain {} values?",
+                path,
+                self.name()
+            )));
+        }
+        Ok(match endian {
+            Endian::Little => self.decode::<LittleEndian>(bytes),
+            Endian::Big => self.decode::<BigEndian>(bytes),
+            Endian::Native => self.decode::<NativeEndian>(bytes),
+        })
+    }
+
+    fn decode<E: ByteOrder>(self, bytes: &[u8]) -> Vec<f32> {
+        let values = bytes.chunks_exact(self.size());
+        match self {
+            Dtype::F32 => {
+                let mut data = vec![0.0; bytes.len() / 4];
+                E::read_f32_into(bytes, &mut data);
+                data
+            }
+            Dtype::F64 => values.map(|v| E::read_f64(v) as f32).collect(),
+            Dtype::I16 => values.map(|v| E::read_i16(v) as f32).collect(),
+            Dtype::I32 => values.map(|v| E::read_i32(v) as f32).collect(),
+        }
+    }
+
+    /// Decode the whole values in `bytes` exactly.
+    pub fn to_f64s(self, bytes: &[u8], endian: Endian) -> Vec<f64> {
+        match endian {
+            Endian::Little => self.decode_exact::<LittleEndian>(bytes),
+            Endian::Big => self.decode_exact::<BigEndian>(bytes),
+            Endian::Native => self.decode_exact::<NativeEndian>(bytes),
+        }
+    }
+
+    fn decode_exact<E: ByteOrder>(self, bytes: &[u8]) -> Vec<f64> {
+        bytes
+            .chunks_exact(self.size())
+            .map(|v| match self {
+                Dtype::F32 => E::read_f32(v) as f64,
+                Dtype::F64 => E::read_f64(v),
+                Dtype::I16 => E::read_i16(v) as f64,
+                Dtype::I32 => E::read_i32(v) as f64,
+            })
+            .collect()
+    }
+}
+
+/// The byte order of the values in a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Endian {
+    /// What hyperdrive writes on x86.
+    #[default]
+    Little,
+    Big,
+    /// Whatever this machine uses.
+    Native,
+}
+
+impl Endian {
+    /// The names accepted by [Endian::from_str].
+    pub const NAMES: &'static [&'static str] = &["little", "big", "native"];
+
+    /// Whether values in this byte order can be used as they are on this
+    /// machine.
+    pub fn is_native(self) -> bool {
+        match self {
+            Endian::Little => cfg!(target_endian = "little"),
+            Endian::Big => cfg!(target_endian = "big"),
+            Endian::Native => true,
+        }
+    }
+}
+
+impl FromStr for Endian {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endian, String> {
+        match s {
+            "little" => Ok(Endian::Little),
+            "big" => Ok(Endian::Big),
+            "native" => Ok(Endian::Native),
+            _ => Err(format!(
+                "Unknown byte order \"{}\"; expected one of {}",
+                s,
+                Endian::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl FromStr for Dtype {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dtype, String> {
+        match s {
+            "f32" => Ok(Dtype::F32),
+            "f64" => Ok(Dtype::F64),
+            "i16" => Ok(Dtype::I16),
+            "i32" => Ok(Dtype::I32),
+            _ => Err(format!(
+                "Unknown type \"{}\"; expected one of {}",
+                s,
+                Dtype::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// The largest differences between the floats of two files.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MaxDifferences {
+    /// The largest absolute difference.
+    pub abs: f32,
+    /// The largest absolute difference relative to the magnitude of the
+    /// baseline float. Infinite if a baseline float is zero but the current
+    /// float isn't.
+    pub rel: f32,
+    /// The largest distance in units in the last place.
+    pub ulps: u64,
+    /// The largest absolute difference in units of the estimated noise of the
+    /// baseline. Only calculated with [CompareOptions::max_sigma].
+    pub sigmas: f32,
+    /// The number of pairs of floats outside all of the tolerances.
+    pub violations: u64,
+    /// The number of pairs of floats that fail the NaN policy. Pairs with a
+    /// NaN are otherwise left out of the differences.
+    pub nan_failures: u64,
+}
+
+impl MaxDifferences {
+    /// Whether every pair of floats passed.
+    pub fn passed(&self) -> bool {
+        self.violations == 0 && self.nan_failures == 0
+    }
+
+    fn max(self, other: MaxDifferences) -> MaxDifferences {
+        MaxDifferences {
+            abs: self.abs.max(other.abs),
+            rel: self.rel.max(other.rel),
+            ulps: self.ulps.max(other.ulps),
+            sigmas: self.sigmas.max(other.sigmas),
+            violations: self.violations + other.violations,
+            nan_failures: self.nan_failures + other.nan_failures,
+        }
+    }
+}
+
+/// The result of comparing one file.
+#[derive(Clone, Debug)]
+pub struct FileReport {
+    /// The file's name, relative to the directories compared.
+    pub name: PathBuf,
+    pub differences: MaxDifferences,
+    pub passed: bool,
+}
+
+/// The result of comparing two directories.
+#[derive(Clone, Debug)]
+pub struct ComparisonReport {
+    /// Every file compared, in name order.
+    pub files: Vec<FileReport>,
+    /// The largest differences over all files.
+    pub differences: MaxDifferences,
+    /// The file with the largest absolute difference.
+    pub worst: PathBuf,
+    /// Whether every file passed.
+    pub passed: bool,
+}
+
+/// Compare each of the hyperdrive outputs in `current` against the file of
+/// the same name in `baseline`.
+pub fn compare_dirs(
+    baseline: &Path,
+    current: &Path,
+    opts: &CompareOptions,
+) -> Result<ComparisonReport, Error> {
+    let patterns = if opts.outputs.is_empty() {
+        DEFAULT_OUTPUTS.iter().map(|p| p.to_string()).collect()
+    } else {
+        opts.outputs.clone()
+    };
+    let mut files = vec![];
+    for name in matching_files(baseline, current, &patterns, opts.allow_subset)? {
+        let report = compare_files(&baseline.join(&name), &current.join(&name), opts)?;
+        files.push(FileReport { name, ..report });
+    }
+    let worst = files
+        .iter()
+        .fold(&files[0], |w, f| {
+            if f.differences.abs > w.differences.abs {
+                f
+            } else {
+                w
+            }
+        })
+        .name
+        .clone();
+    Ok(ComparisonReport {
+        differences: files
+            .iter()
+            .fold(MaxDifferences::default(), |acc, f| acc.max(f.differences)),
+        passed: files.iter().all(|f| f.passed),
+        worst,
+        files,
+    })
+}
+
+/// Compare the file `current` against the file `baseline`, like a single file
+/// of [compare_dirs] and like hyperdrive-vis-gen-diff: every option but
+/// `outputs` and `allow_subset` applies, with `rules` matched against the file
+/// name of `current`. The report is named after `current`.
+pub fn compare_files(
+    baseline: &Path,
+    current: &Path,
+    opts: &CompareOptions,
+) -> Result<FileReport, Error> {
+    let name = current.file_name().map_or(current, Path::new);
+    let (current_data, baseline_data) = (
+        read_values(current, opts.dtype, opts.endian)?,
+        read_values(baseline, opts.dtype, opts.endian)?,
+    );
+    let mut differences = max_differences(&current_data, &baseline_data, &opts.tolerances(name))
+        .map_err(|e| e.context(format!("{:?}", current)))?;
+    let passed = differences.nan_failures == 0
+        && match opts.max_sigma {
+            Some(max_sigma) => {
+                let noise = estimate_noise(&baseline_data);
+                differences.sigmas = if differences.abs == 0.0 {
+                    0.0
+                } else {
+                    differences.abs / noise
+                };
+                differences.sigmas <= max_sigma
+            }
+            None => differences.violations == 0,
+        };
+    Ok(FileReport {
+        name: current.to_path_buf(),
+        passed,
+        differences,
+    })
+}
+
+/// Compare paths the way people number files: runs of digits are compared as
+/// numbers, so "band9" comes before "band10". Paths that only differ in
+/// leading zeros are ordered as strings, so that only equal paths are equal.
+pub fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    let (mut x, mut y) = (a.as_ref(), b.as_ref());
+    loop {
+        let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (xd, yd) = (digits(x), digits(y));
+        let ordering = if xd > 0 && yd > 0 {
+            let (xn, yn) = (
+                x[..xd].trim_start_matches('0'),
+                y[..yd].trim_start_matches('0'),
+            );
+            // With the leading zeros gone, longer numbers are bigger.
+            xn.len().cmp(&yn.len()).then_with(|| xn.cmp(yn))
+        } else {
+            match (x.chars().next(), y.chars().next()) {
+                (Some(c), Some(d)) => c.cmp(&d),
+                (c, d) => c.cmp(&d),
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        if x.is_empty() {
+            return a.cmp(&b);
+        }
+        let skip = |s: &str, digits: usize| {
+            if digits > 0 {
+                digits
+            } else {
+                s.chars().next().map_or(0, char::len_utf8)
+            }
+        };
+        x = &x[skip(x, xd)..];
+        y = &y[skip(y, yd)..];
+    }
+}
+
+/// Sort `paths` with [natural_cmp] and remove duplicates.
+pub fn sort_naturally(paths: &mut Vec<PathBuf>) {
+    paths.sort_by(|a, b| natural_cmp(a, b));
+    paths.dedup();
+}
+
+/// The paths (relative to `dir`) of the files in `dir` matching `pattern`,
+/// which may match in subdirectories, e.g. "*/hyperdrive_band??.bin". They're
+/// sorted naturally (see [natural_cmp]) so that files are always processed
+/// (and reported) in the same order.
+pub fn glob_files(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let dir_str = dir
+        .to_str()
+        .ok_or_else(|| Error::Io(format!("The directory {:?} contained invalid unicode", dir)))?;
+    let mut files = vec![];
+    let paths = glob::glob(&format!("{}/{}", glob::Pattern::escape(dir_str), pattern))
+        .map_err(|e| Error::Io(format!("Bad pattern \"{}\": {}", pattern, e)))?;
+    for path in paths {
+        let path = path.map_err(|e| Error::Io(e.to_string()))?;
+        // glob drops a leading "./".
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        if relative.file_name().is_some() {
+            files.push(relative.to_path_buf());
+        }
+    }
+    sort_naturally(&mut files);
+    Ok(files)
+}
+
+/// The names of the files in `current` matching any of `patterns`, all of
+/// which must also be in `baseline`. Unless `allow_subset`, every such file in
+/// `baseline` must also be in `current`, so that a run that silently wrote
+/// fewer files fails.
+pub fn matching_files(
+    baseline: &Path,
+    current: &Path,
+    patterns: &[String],
+    allow_subset: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    matching_files_with(baseline, current, patterns, &[], allow_subset)
+}
+
+/// Like [matching_files], but the files in `baseline` may instead have one of
+/// `extensions` appended, e.g. "hyperdrive_band01.bin.zst" when compressed.
+pub fn matching_files_with(
+    baseline: &Path,
+    current: &Path,
+    patterns: &[String],
+    extensions: &[&str],
+    allow_subset: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    if !baseline.is_dir() {
+        return Err(Error::Missing(format!(
+            "Directory {:?} does not exist! This should contain baseline hyperdrive binary files.",
+            baseline
+        )));
+    };
+    let glob_all = |dir| -> Result<Vec<PathBuf>, Error> {
+        let mut files = vec![];
+        for p in patterns {
+            files.append(&mut glob_files(dir, p)?);
+        }
+        sort_naturally(&mut files);
+        Ok(files)
+    };
+    let dir = if current == Path::new(".") {
+        "PWD".to_string()
+    } else {
+        format!("{:?}", current)
+    };
+    let present_files = glob_all(current)?;
+    if present_files.is_empty() {
+        return Err(Error::Missing(format!(
+            "{} does not have any {} files!",
+            dir,
+            patterns.join(" or ")
+        )));
+    }
+    let baseline_files = baseline_files(baseline, patterns, extensions)?;
+    for p in &present_files {
+        if baseline_files
+            .binary_search_by(|b| natural_cmp(b, p))
+            .is_err()
+        {
+            return Err(Error::Missing(format!(
+                "{:?} is missing from {}!",
+                p,
+                baseline.display()
+            )));
+        }
+    }
+    if !allow_subset {
+        let missing: Vec<PathBuf> = baseline_files
+            .into_iter()
+            .filter(|b| {
+                present_files
+                    .binary_search_by(|p| natural_cmp(p, b))
+                    .is_err()
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Missing(format!(
+                "{} has {} file(s) missing from {}: {}",
+                baseline.display(),
+                missing.len(),
+                dir,
+                missing
+                    .iter()
+                    .map(|m| m.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+    }
+    Ok(present_files)
+}
+
+/// The names of the files in `baseline` matching any of `patterns`, including
+/// those stored with one of `extensions` appended (which is left off), sorted
+/// naturally.
+pub fn baseline_files(
+    baseline: &Path,
+    patterns: &[String],
+    extensions: &[&str],
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for p in patterns {
+        files.append(&mut glob_files(baseline, p)?);
+        for e in extensions {
+            for f in glob_files(baseline, &format!("{}.{}", p, e))? {
+                files.push(f.with_extension(""));
+            }
+        }
+    }
+    sort_naturally(&mut files);
+    files.dedup();
+    Ok(files)
+}
+
+/// Read the file at `path` as little-endian floats.
+pub fn read_f32s(path: &Path) -> Result<Vec<f32>, Error> {
+    let bytes = std::fs::read(path).map_err(|e| Error::reading(path, e))?;
+    bytes_to_f32s(&bytes, path)
+}
+
+/// Read the file at `path` as values of type `dtype` and byte order `endian`,
+/// as f32s.
+pub fn read_values(path: &Path, dtype: Dtype, endian: Endian) -> Result<Vec<f32>, Error> {
+    let bytes = std::fs::read(path).map_err(|e| Error::reading(path, e))?;
+    dtype.to_f32s(&bytes, endian, path)
+}
+
+/// Decode little-endian floats read from `path`.
+pub fn bytes_to_f32s(bytes: &[u8], path: &Path) -> Result<Vec<f32>, Error> {
+    Dtype::F32.to_f32s(bytes, Endian::Little, path)
+}
+
+/// The largest differences between `current` and `baseline`, which must be
+/// the same (non-zero) length, and how many pairs of floats fail `tolerances`.
+pub fn max_differences(
+    current: &[f32],
+    baseline: &[f32],
+    tolerances: &Tolerances,
+) -> Result<MaxDifferences, Error> {
+    if current.is_empty() || baseline.is_empty() {
+        return Err(Error::Shape("There isn't any data to compare".to_string()));
+    }
+    if current.len() != baseline.len() {
+        return Err(Error::Shape(format!(
+            "There are {} current floats, but {} baseline floats",
+            current.len(),
+            baseline.len()
+        )));
+    }
+    Ok(current
+        .iter()
+        .zip(baseline)
+        .fold(MaxDifferences::default(), |acc, (&c, &b)| {
+            acc.max(match tolerances.judge(c, b, tolerances.tolerance) {
+                // f32::max ignores NaNs, so they're counted instead.
+                Judgement::Nan { fails } => MaxDifferences {
+                    nan_failures: fails as u64,
+                    ..MaxDifferences::default()
+                },
+                Judgement::Compared { difference, passed } => MaxDifferences {
+                    abs: difference.abs,
+                    rel: difference.rel,
+                    ulps: difference.ulps,
+                    sigmas: 0.0,
+                    violations: !passed as u64,
+                    nan_failures: 0,
+                },
+            })
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globbed_paths_are_relative_to_the_directory() {
+        // Brackets would be a pattern if the directory weren't escaped.
+        let dir = std::env::temp_dir().join(format!("glob-[1]-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["band10.bin", "band9.bin", "sub/band1.bin"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let found = (
+            glob_files(&dir, "*.bin"),
+            glob_files(&dir, "sub/*.bin"),
+            glob_files(&dir, "*/band?.bin"),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(found.0.unwrap(), paths(&["band9.bin", "band10.bin"]));
+        assert_eq!(found.1.unwrap(), paths(&["sub/band1.bin"]));
+        assert_eq!(found.2.unwrap(), paths(&["sub/band1.bin"]));
+    }
+
+    #[test]
+    fn numbers_in_paths_are_ordered_as_numbers() {
+        let mut paths: Vec<PathBuf> = [
+            "hyperdrive_band10.bin",
+            "hyperdrive_band9.bin",
+            "hyperdrive_band010.bin",
+            "hyperdrive_band100.bin",
+            "hyperdrive_band01.bin",
+            "hyperdrive_band1.bin",
+            "hyperdrive_band.bin",
+            "b/band2.bin",
+            "a10/band1.bin",
+            "a9/band3.bin",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        paths.sort_by(|a, b| natural_cmp(a, b));
+        let sorted: Vec<&str> = paths.iter().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "a9/band3.bin",
+                "a10/band1.bin",
+                "b/band2.bin",
+                "hyperdrive_band.bin",
+                "hyperdrive_band01.bin",
+                "hyperdrive_band1.bin",
+                "hyperdrive_band9.bin",
+                "hyperdrive_band010.bin",
+                "hyperdrive_band10.bin",
+                "hyperdrive_band100.bin",
+            ]
+        );
+
+        // Only equal paths are equal, however long their numbers.
+        let cmp = |a: &str, b: &str| natural_cmp(Path::new(a), Path::new(b));
+        assert_eq!(cmp("band01", "band01"), Ordering::Equal);
+        assert_ne!(cmp("band01", "band1"), Ordering::Equal);
+        assert_eq!(
+            cmp("run99999999999999999999999", "run100000000000000000000000"),
+            Ordering::Less
+        );
+        assert_eq!(cmp("bänd2", "bänd10"), Ordering::Less);
+    }
+}
@@ -0,0 +1,477 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Compare calibration solutions the way they are plotted: as per-tile,
+//! per-channel amplitudes and phases of each Jones matrix element, after
+//! referencing every tile to a reference tile. This turns "the plots look
+//! different" into concrete numbers.
+//!
+//! Solutions can also be applied to visibilities, so that uncalibrated outputs
+//! can be compared against calibrated ones.
+//!
+//! Both hyperdrive's FITS solutions and the "MWAOCAL" binary solutions of
+//! André Offringa's tools (which hyperdrive can also write) are understood.
+//! Only the FITS files name their tiles and the frequencies of their
+//! channels, so only they can be compared when their tiles or channels
+//! differ.
+
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::fits::Fits;
+use crate::Error;
+
+const MAGIC: &[u8] = b"MWAOCAL\0";
+const HEADER_LEN: usize = 8 + 6 * 4 + 2 * 8;
+pub const POLS: [&str; 4] = ["XX", "XY", "YX", "YY"];
+
+#[derive(Clone, Copy)]
+struct Complex(f64, f64);
+
+impl Complex {
+    fn mul(self, o: Complex) -> Complex {
+        Complex(self.0 * o.0 - self.1 * o.1, self.0 * o.1 + self.1 * o.0)
+    }
+
+    fn add(self, o: Complex) -> Complex {
+        Complex(self.0 + o.0, self.1 + o.1)
+    }
+
+    fn sub(self, o: Complex) -> Complex {
+        Complex(self.0 - o.0, self.1 - o.1)
+    }
+
+    fn div(self, o: Complex) -> Complex {
+        let d = o.0 * o.0 + o.1 * o.1;
+        Complex(
+            (self.0 * o.0 + self.1 * o.1) / d,
+            (self.1 * o.0 - self.0 * o.1) / d,
+        )
+    }
+
+    fn neg(self) -> Complex {
+        Complex(-self.0, -self.1)
+    }
+
+    fn conj(self) -> Complex {
+        Complex(self.0, -self.1)
+    }
+}
+
+/// A 2x2 complex matrix, [XX, XY, YX, YY].
+type Jones = [Complex; 4];
+
+fn mul(a: &Jones, b: &Jones) -> Jones {
+    [
+        a[0].mul(b[0]).add(a[1].mul(b[2])),
+        a[0].mul(b[1]).add(a[1].mul(b[3])),
+        a[2].mul(b[0]).add(a[3].mul(b[2])),
+        a[2].mul(b[1]).add(a[3].mul(b[3])),
+    ]
+}
+
+/// The conjugate transpose.
+fn herm(j: &Jones) -> Jones {
+    [j[0].conj(), j[2].conj(), j[1].conj(), j[3].conj()]
+}
+
+fn inv(j: &Jones) -> Jones {
+    let det = j[0].mul(j[3]).sub(j[1].mul(j[2]));
+    [
+        j[3].div(det),
+        j[1].neg().div(det),
+        j[2].neg().div(det),
+        j[0].div(det),
+    ]
+}
+
+pub struct Solutions {
+    intervals: usize,
+    tiles: usize,
+    channels: usize,
+    /// Indexed by interval, then tile, then channel.
+    jones: Vec<Jones>,
+    /// The names of the tiles, if known.
+    tile_names: Option<Vec<String>>,
+    /// The centre frequencies of the channels in Hz, if known.
+    frequencies: Option<Vec<f64>>,
+}
+
+impl Solutions {
+    fn get(&self, interval: usize, tile: usize, channel: usize) -> &Jones {
+        &self.jones[(interval * self.tiles + tile) * self.channels + channel]
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Are all of a tile's solutions NaN, i.e. is the tile flagged?
+    fn flagged(&self, tile: usize) -> bool {
+        (0..self.intervals)
+            .all(|i| (0..self.channels).all(|c| self.get(i, tile, c).iter().all(|j| j.0.is_nan())))
+    }
+
+    /// The name of a tile, or its index if the names aren't known.
+    fn tile_name(&self, tile: usize) -> String {
+        match &self.tile_names {
+            Some(names) => names[tile].clone(),
+            None => tile.to_string(),
+        }
+    }
+
+    /// Calibrate visibilities in place. `data` is in timestep, baseline, fine
+    /// channel order, each visibility being 4 complex polarisations (8
+    /// floats). Baselines are ordered by tile pair ((0, 1), (0, 2), ...,
+    /// including (0, 0) etc. if `autos`) and the fine channels are those of
+    /// the solutions from `first_channel`. The solution intervals are spread
+    /// evenly over the timesteps.
+    pub fn apply(
+        &self,
+        data: &mut [f32],
+        fine_channels: usize,
+        first_channel: usize,
+        autos: bool,
+    ) -> Result<(), Error> {
+        if first_channel + fine_channels > self.channels {
+            return Err(Error::Shape(format!(
+                "The solutions have {} channels, so they don't cover channels {} to {}",
+                self.channels,
+                first_channel,
+                first_channel + fine_channels - 1
+            )));
+        }
+        let pairs: Vec<(usize, usize)> = (0..self.tiles)
+            .flat_map(|i| (i..self.tiles).map(move |j| (i, j)))
+            .filter(|(i, j)| autos || i != j)
+            .collect();
+        let timestep_len = pairs.len() * fine_channels * 8;
+        if timestep_len == 0 || !data.len().is_multiple_of(timestep_len) {
+            return Err(Error::Shape(format!(
+                "{} floats aren't a whole number of timesteps of {} baselines ({} tiles) and {} fine channels",
+                data.len(),
+                pairs.len(),
+                self.tiles,
+                fine_channels
+            )));
+        }
+        let timesteps = data.len() / timestep_len;
+        // Calibrated = J_i^-1 V J_j^-H.
+        let inverses: Vec<Jones> = self.jones.iter().map(inv).collect();
+        let inverse = |interval: usize, tile: usize, channel: usize| {
+            &inverses[(interval * self.tiles + tile) * self.channels + channel]
+        };
+
+        for (n, vis) in data.chunks_exact_mut(8).enumerate() {
+            let channel = n % fine_channels + first_channel;
+            let (i, j) = pairs[n / fine_channels % pairs.len()];
+            let timestep = n / (fine_channels * pairs.len());
+            let interval = timestep * self.intervals / timesteps;
+            let v = [
+                Complex(vis[0] as f64, vis[1] as f64),
+                Complex(vis[2] as f64, vis[3] as f64),
+                Complex(vis[4] as f64, vis[5] as f64),
+                Complex(vis[6] as f64, vis[7] as f64),
+            ];
+            let c = mul(
+                &mul(inverse(interval, i, channel), &v),
+                &herm(inverse(interval, j, channel)),
+            );
+            for (pol, c) in c.iter().enumerate() {
+                vis[2 * pol] = c.0 as f32;
+                vis[2 * pol + 1] = c.1 as f32;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read a FITS or binary solutions file.
+pub fn read(path: &Path) -> Result<Solutions, Error> {
+    let bytes = std::fs::read(path).map_err(|e| Error::reading(path, e))?;
+    if Fits::is_fits(&bytes) {
+        drop(bytes);
+        return read_fits(path);
+    }
+    if bytes.len() < HEADER_LEN || &bytes[..8] != MAGIC {
+        return Err(Error::Io(format!(
+            "{:?} isn't a FITS or MWAOCAL binary solutions file",
+            path
+        )));
+    }
+    let u32_at = |i: usize| LittleEndian::read_u32(&bytes[8 + 4 * i..]) as usize;
+    let (intervals, tiles, channels, pols) = (u32_at(2), u32_at(3), u32_at(4), u32_at(5));
+    if pols != 4 {
+        return Err(Error::Shape(format!(
+            "{:?} has {} polarisations; expected 4",
+            path, pols
+        )));
+    }
+    let n = intervals * tiles * channels;
+    if bytes.len() != HEADER_LEN + n * 4 * 16 {
+        return Err(Error::Shape(format!(
+            "{:?} is {} bytes, but its header describes {} bytes",
+            path,
+            bytes.len(),
+            HEADER_LEN + n * 4 * 16
+        )));
+    }
+    let mut floats = vec![0.0; n * 8];
+    LittleEndian::read_f64_into(&bytes[HEADER_LEN..], &mut floats);
+    let jones = floats
+        .chunks_exact(8)
+        .map(|f| {
+            [
+                Complex(f[0], f[1]),
+                Complex(f[2], f[3]),
+                Complex(f[4], f[5]),
+                Complex(f[6], f[7]),
+            ]
+        })
+        .collect();
+    Ok(Solutions {
+        intervals,
+        tiles,
+        channels,
+        jones,
+        tile_names: None,
+        frequencies: None,
+    })
+}
+
+/// Read hyperdrive's FITS solutions: a SOLUTIONS image of (real, imaginary)
+/// pairs of the 4 Jones elements by channel, tile and timeblock, with the
+/// tiles named in the TILES table and the channels' frequencies in the
+/// CHANBLOCKS table.
+fn read_fits(path: &Path) -> Result<Solutions, Error> {
+    let fits = Fits::read(path)?;
+    let hdu = fits.hdu("SOLUTIONS")?;
+    let (channels, tiles, intervals) = match hdu.axes().as_slice() {
+        [8, c, t, i] => (*c, *t, *i),
+        axes => {
+            return Err(Error::Shape(format!(
+                "{:?}'s SOLUTIONS have axes {:?}; expected 8 floats by channel, tile and timeblock",
+                path, axes
+            )))
+        }
+    };
+    let jones = fits
+        .image(hdu)?
+        .chunks_exact(8)
+        .map(|f| {
+            [
+                Complex(f[0], f[1]),
+                Complex(f[2], f[3]),
+                Complex(f[4], f[5]),
+                Complex(f[6], f[7]),
+            ]
+        })
+        .collect();
+    let tile_names = match fits.hdu("TILES") {
+        Ok(hdu) => fits.table(hdu)?.strings("TileName"),
+        Err(_) => None,
+    }
+    .filter(|names| names.len() == tiles);
+    let frequencies = match fits.hdu("CHANBLOCKS") {
+        Ok(hdu) => fits.table(hdu)?.f64s("Freq"),
+        Err(_) => None,
+    }
+    // Channels of flagged chanblocks may not have a frequency.
+    .filter(|freqs| freqs.len() == channels && freqs.iter().all(|f| f.is_finite()));
+    Ok(Solutions {
+        intervals,
+        tiles,
+        channels,
+        jones,
+        tile_names,
+        frequencies,
+    })
+}
+
+/// The largest differences between the amplitudes and phases of two sets of
+/// solutions, per polarisation.
+#[derive(Default)]
+pub struct SolutionDiffs {
+    pub max_amp: [f64; 4],
+    /// In degrees, wrapped into [0, 180].
+    pub max_phase: [f64; 4],
+    /// How many values are flagged (NaN) in one set of solutions, but not the
+    /// other, in tiles flagged in neither.
+    pub flag_mismatches: usize,
+    /// Tiles flagged (all NaN) in both sets of solutions, which aren't
+    /// compared.
+    pub flagged_tiles: Vec<String>,
+    /// Tiles flagged in only one set of solutions.
+    pub tile_flag_mismatches: Vec<String>,
+    /// Tiles and channels (by frequency, in Hz) in only one set of solutions.
+    pub only_in_a: Vec<String>,
+    pub only_in_b: Vec<String>,
+}
+
+impl SolutionDiffs {
+    /// Do the solutions differ in anything other than their values?
+    pub fn mismatched(&self) -> bool {
+        self.flag_mismatches > 0
+            || !self.tile_flag_mismatches.is_empty()
+            || !self.only_in_a.is_empty()
+            || !self.only_in_b.is_empty()
+    }
+}
+
+/// Pair up the indices of things in `a` and `b` with equal keys, and list the
+/// keys of those in only one of them.
+fn align<K: PartialEq + ToString>(
+    a: &[K],
+    b: &[K],
+) -> (Vec<(usize, usize)>, Vec<String>, Vec<String>) {
+    let pairs = a
+        .iter()
+        .enumerate()
+        .filter_map(|(i, k)| b.iter().position(|l| l == k).map(|j| (i, j)))
+        .collect();
+    let missing = |x: &[K], y: &[K]| {
+        x.iter()
+            .filter(|k| !y.contains(k))
+            .map(|k| k.to_string())
+            .collect()
+    };
+    (pairs, missing(a, b), missing(b, a))
+}
+
+/// Compare two sets of solutions. Tiles are matched by name and channels by
+/// frequency if both sets name them, and otherwise by index. If `ref_tile` (an
+/// index into `a`'s tiles) is given, every tile's solutions are first
+/// multiplied by the inverse of that tile's (as when plotting, so that phases
+/// are relative to it).
+pub fn compare(
+    a: &Solutions,
+    b: &Solutions,
+    ref_tile: Option<usize>,
+) -> Result<SolutionDiffs, Error> {
+    if a.intervals != b.intervals {
+        return Err(Error::Shape(format!(
+            "The solutions have different numbers of timeblocks: {} vs. {}",
+            a.intervals, b.intervals
+        )));
+    }
+    let mut diffs = SolutionDiffs::default();
+    let indices = |n: usize| (0..n).collect::<Vec<_>>();
+    let (tiles, only_a, only_b) = match (&a.tile_names, &b.tile_names) {
+        (Some(x), Some(y)) => align(x, y),
+        _ if a.tiles == b.tiles => align(&indices(a.tiles), &indices(b.tiles)),
+        _ => {
+            return Err(Error::Shape(format!(
+            "The solutions have {} and {} tiles, and they aren't named, so they can't be matched",
+            a.tiles,
+            b.tiles
+        )))
+        }
+    };
+    diffs
+        .only_in_a
+        .extend(only_a.into_iter().map(|t| format!("tile {}", t)));
+    diffs
+        .only_in_b
+        .extend(only_b.into_iter().map(|t| format!("tile {}", t)));
+    let (channels, only_a, only_b) = match (&a.frequencies, &b.frequencies) {
+        (Some(x), Some(y)) => {
+            // Frequencies to the nearest Hz.
+            let hz = |f: &Vec<f64>| f.iter().map(|f| f.round() as i64).collect::<Vec<_>>();
+            align(&hz(x), &hz(y))
+        }
+        _ if a.channels == b.channels => align(&indices(a.channels), &indices(b.channels)),
+        _ => return Err(Error::Shape(format!(
+            "The solutions have {} and {} channels, and their frequencies aren't known, so they can't be matched",
+            a.channels,
+            b.channels
+        ))),
+    };
+    diffs
+        .only_in_a
+        .extend(only_a.into_iter().map(|c| format!("channel at {} Hz", c)));
+    diffs
+        .only_in_b
+        .extend(only_b.into_iter().map(|c| format!("channel at {} Hz", c)));
+
+    let ref_tile = match ref_tile {
+        Some(r) if r >= a.tiles => {
+            return Err(Error::Io(format!(
+                "Reference tile {} doesn't exist; there are {} tiles",
+                r, a.tiles
+            )))
+        }
+        Some(r) => match tiles.iter().find(|(i, _)| *i == r) {
+            Some(&(_, rb)) if !a.flagged(r) && !b.flagged(rb) => Some((r, rb)),
+            Some(_) => {
+                return Err(Error::Io(format!(
+                    "Reference tile {} is flagged, so it can't be a reference",
+                    a.tile_name(r)
+                )))
+            }
+            None => {
+                return Err(Error::Io(format!(
+                    "Reference tile {} is only in one set of solutions",
+                    a.tile_name(r)
+                )))
+            }
+        },
+        None => None,
+    };
+
+    for &(ta, tb) in &tiles {
+        match (a.flagged(ta), b.flagged(tb)) {
+            (true, true) => {
+                diffs.flagged_tiles.push(a.tile_name(ta));
+                continue;
+            }
+            (false, false) => (),
+            _ => {
+                diffs.tile_flag_mismatches.push(a.tile_name(ta));
+                continue;
+            }
+        }
+        for interval in 0..a.intervals {
+            for &(ca, cb) in &channels {
+                let referenced =
+                    |s: &Solutions, tile: usize, r: Option<usize>, channel: usize| match r {
+                        Some(r) => mul(
+                            s.get(interval, tile, channel),
+                            &inv(s.get(interval, r, channel)),
+                        ),
+                        None => *s.get(interval, tile, channel),
+                    };
+                let (ja, jb) = (
+                    referenced(a, ta, ref_tile.map(|r| r.0), ca),
+                    referenced(b, tb, ref_tile.map(|r| r.1), cb),
+                );
+                for pol in 0..4 {
+                    let (amp_a, amp_b) = (ja[pol].0.hypot(ja[pol].1), jb[pol].0.hypot(jb[pol].1));
+                    match (amp_a.is_nan(), amp_b.is_nan()) {
+                        (true, true) => continue,
+                        (false, false) => (),
+                        _ => {
+                            diffs.flag_mismatches += 1;
+                            continue;
+                        }
+                    }
+                    let phase = |c: Complex| c.1.atan2(c.0).to_degrees();
+                    let mut dphase = (phase(ja[pol]) - phase(jb[pol])).abs() % 360.0;
+                    if dphase > 180.0 {
+                        dphase = 360.0 - dphase;
+                    }
+                    diffs.max_amp[pol] = diffs.max_amp[pol].max((amp_a - amp_b).abs());
+                    diffs.max_phase[pol] = diffs.max_phase[pol].max(dphase);
+                }
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+/// The tile used as the reference if none is given, matching hyperdrive's
+/// plots: the last one that isn't flagged.
+pub fn default_ref_tile(s: &Solutions) -> Option<usize> {
+    (0..s.tiles).rev().find(|&t| !s.flagged(t))
+}
@@ -0,0 +1,305 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! How a pair of floats is judged. Everything that compares floats, the
+//! library and every executable, uses these, so that they all agree on what
+//! passes.
+
+use std::cmp::Ordering;
+use std::path::Path;
+use std::str::FromStr;
+
+use glob::Pattern;
+
+/// The tolerances a file (or band) is held to.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerances {
+    /// The largest acceptable absolute difference.
+    pub tolerance: f32,
+    /// Floats within either of these also pass.
+    pub rel_tolerance: Option<f32>,
+    pub ulps: Option<u32>,
+    /// Which NaNs fail.
+    pub nan: NanPolicy,
+}
+
+/// How a pair of floats compares.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Judgement {
+    /// Either float is NaN, so there's no difference; whether that fails the
+    /// NaN policy.
+    Nan { fails: bool },
+    /// How much the floats differ, and whether they're within any of the
+    /// tolerances.
+    Compared {
+        difference: FloatDifference,
+        passed: bool,
+    },
+}
+
+/// How much two floats that aren't NaN differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FloatDifference {
+    /// See [abs_difference].
+    pub abs: f32,
+    /// The absolute difference relative to the magnitude of the baseline
+    /// float. Infinite if the baseline float is zero but the current float
+    /// isn't.
+    pub rel: f32,
+    /// See [ulp_distance].
+    pub ulps: u64,
+}
+
+impl FloatDifference {
+    pub fn of(current: f32, baseline: f32) -> FloatDifference {
+        let abs = abs_difference(current, baseline);
+        FloatDifference {
+            abs,
+            rel: if abs == 0.0 {
+                0.0
+            } else {
+                abs / baseline.abs()
+            },
+            ulps: ulp_distance(current, baseline),
+        }
+    }
+}
+
+impl Tolerances {
+    /// Only an absolute tolerance, failing any NaN.
+    pub fn absolute(tolerance: f32) -> Tolerances {
+        Tolerances {
+            tolerance,
+            rel_tolerance: None,
+            ulps: None,
+            nan: NanPolicy::Fail,
+        }
+    }
+
+    /// Compare `current` with `baseline`, holding them to `absolute` rather
+    /// than the absolute tolerance (e.g. a tolerance scaled for the float's
+    /// place in the data). A float passes if it's within any of the
+    /// tolerances.
+    pub fn judge(&self, current: f32, baseline: f32, absolute: f32) -> Judgement {
+        if current.is_nan() || baseline.is_nan() {
+            return Judgement::Nan {
+                fails: self.nan.fails(current.is_nan(), baseline.is_nan()),
+            };
+        }
+        let d = FloatDifference::of(current, baseline);
+        Judgement::Compared {
+            difference: d,
+            passed: d.abs <= absolute
+                || self.rel_tolerance.is_some_and(|r| d.rel <= r)
+                || self.ulps.is_some_and(|u| d.ulps <= u as u64),
+        }
+    }
+}
+
+/// The tolerances for the files matching `pattern`; those it doesn't give are
+/// left to the defaults.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub tolerance: Option<f32>,
+    pub rel_tolerance: Option<f32>,
+    pub ulps: Option<u32>,
+    pub nan: Option<NanPolicy>,
+}
+
+impl Rule {
+    pub fn new(pattern: Pattern) -> Rule {
+        Rule {
+            pattern,
+            tolerance: None,
+            rel_tolerance: None,
+            ulps: None,
+            nan: None,
+        }
+    }
+}
+
+/// The tolerances for the file (or band) called `name`: those of the first
+/// rule matching it, if any, filled in from `defaults`.
+pub fn tolerances(rules: &[Rule], name: &Path, defaults: Tolerances) -> Option<Tolerances> {
+    let rule = rules.iter().find(|r| r.pattern.matches_path(name))?;
+    Some(Tolerances {
+        tolerance: rule.tolerance.unwrap_or(defaults.tolerance),
+        rel_tolerance: rule.rel_tolerance.or(defaults.rel_tolerance),
+        ulps: rule.ulps.or(defaults.ulps),
+        nan: rule.nan.unwrap_or(defaults.nan),
+    })
+}
+
+/// Which NaNs fail.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NanPolicy {
+    /// Any NaN, in either file.
+    #[default]
+    Fail,
+    /// NaNs in only one of the files.
+    Equal,
+    /// None.
+    Allow,
+}
+
+impl NanPolicy {
+    /// The names accepted by [NanPolicy::from_str].
+    pub const NAMES: &'static [&'static str] = &["fail", "equal", "allow"];
+
+    /// Does a pair of floats, either of which may be NaN, fail?
+    pub fn fails(self, current_nan: bool, baseline_nan: bool) -> bool {
+        match self {
+            NanPolicy::Fail => current_nan || baseline_nan,
+            NanPolicy::Equal => current_nan != baseline_nan,
+            NanPolicy::Allow => false,
+        }
+    }
+}
+
+impl FromStr for NanPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NanPolicy, String> {
+        match s {
+            "fail" => Ok(NanPolicy::Fail),
+            "equal" => Ok(NanPolicy::Equal),
+            "allow" => Ok(NanPolicy::Allow),
+            _ => Err(format!(
+                "Unknown NaN policy \"{}\"; expected one of {}",
+                s,
+                NanPolicy::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// The absolute difference between two floats that aren't NaN. Equal
+/// infinities are equal.
+pub fn abs_difference(a: f32, b: f32) -> f32 {
+    if a == b {
+        0.0
+    } else {
+        (a - b).abs()
+    }
+}
+
+/// The number of representable floats between `a` and `b`, i.e. how many
+/// units in the last place they differ by. The largest possible value if
+/// either is NaN.
+pub fn ulp_distance(a: f32, b: f32) -> u64 {
+    if a.is_nan() || b.is_nan() {
+        return u64::MAX;
+    }
+    // Map the bits onto integers that are ordered like the floats, with both
+    // zeros at 0.
+    let ordered = |f: f32| {
+        let bits = f.to_bits() as i32;
+        if bits < 0 {
+            -((bits & i32::MAX) as i64)
+        } else {
+            bits as i64
+        }
+    };
+    (ordered(a) - ordered(b)).unsigned_abs()
+}
+
+/// Estimate the thermal noise (standard deviation) of visibilities made of
+/// interleaved real and imaginary floats. The imaginary parts are used, as they
+/// carry comparatively little sky signal; differencing consecutive imaginary
+/// values removes what signal remains, and the median absolute deviation makes
+/// the estimate robust against RFI and other outliers.
+pub fn estimate_noise(data: &[f32]) -> f32 {
+    let imag: Vec<f32> = data.iter().skip(1).step_by(2).copied().collect();
+    let mut abs_diffs: Vec<f32> = imag.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    if abs_diffs.is_empty() {
+        return 0.0;
+    }
+    let mid = abs_diffs.len() / 2;
+    let (_, median, _) =
+        abs_diffs.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    // 1.4826 converts a median absolute deviation into a standard deviation
+    // for Gaussian noise, and the difference of two samples has sqrt(2) times
+    // the noise of one.
+    *median * 1.4826 / std::f32::consts::SQRT_2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ulp_distance_edge_cases() {
+        let smallest = f32::from_bits(1);
+        // Both zeros are the same float.
+        assert_eq!(ulp_distance(0.0, -0.0), 0);
+        assert_eq!(ulp_distance(0.0, smallest), 1);
+        assert_eq!(ulp_distance(-smallest, smallest), 2);
+        // Subnormals run into the normal floats without a gap.
+        assert_eq!(
+            ulp_distance(f32::MIN_POSITIVE, f32::from_bits(0x007F_FFFF)),
+            1
+        );
+        assert_eq!(ulp_distance(1.0, 1.0 + f32::EPSILON), 1);
+        assert_eq!(ulp_distance(f32::MAX, f32::INFINITY), 1);
+        assert_eq!(
+            ulp_distance(f32::NEG_INFINITY, f32::INFINITY),
+            2 * f32::INFINITY.to_bits() as u64
+        );
+        assert_eq!(ulp_distance(f32::NAN, 1.0), u64::MAX);
+        assert_eq!(ulp_distance(1.0, f32::NAN), u64::MAX);
+        assert_eq!(ulp_distance(f32::NAN, f32::NAN), u64::MAX);
+    }
+
+    #[test]
+    fn judging() {
+        let t = Tolerances {
+            ulps: Some(1),
+            ..Tolerances::absolute(0.0)
+        };
+        let passed = |current, baseline| match t.judge(current, baseline, t.tolerance) {
+            Judgement::Compared { passed, .. } => passed,
+            Judgement::Nan { .. } => panic!("{} and {} aren't NaN", current, baseline),
+        };
+        assert!(passed(0.0, -0.0));
+        assert!(passed(f32::INFINITY, f32::INFINITY));
+        assert!(passed(f32::MAX, f32::INFINITY));
+        assert!(!passed(f32::NEG_INFINITY, f32::INFINITY));
+        assert!(!passed(1.0, 1.0 + 2.0 * f32::EPSILON));
+
+        let d = FloatDifference::of(1.0, 0.0);
+        assert_eq!((d.abs, d.rel), (1.0, f32::INFINITY));
+        assert_eq!(FloatDifference::of(0.0, 0.0), FloatDifference::default());
+    }
+
+    #[test]
+    fn nan_policies() {
+        for &(nan, fails) in &[
+            (NanPolicy::Fail, [true, true, true]),
+            (NanPolicy::Equal, [false, true, true]),
+            (NanPolicy::Allow, [false, false, false]),
+        ] {
+            let t = Tolerances {
+                nan,
+                ..Tolerances::absolute(1.0)
+            };
+            for (&(current, baseline), &fails) in
+                [(f32::NAN, f32::NAN), (f32::NAN, 1.0), (1.0, f32::NAN)]
+                    .iter()
+                    .zip(&fails)
+            {
+                assert_eq!(
+                    t.judge(current, baseline, 1.0),
+                    Judgement::Nan { fails },
+                    "{:?}: {} and {}",
+                    nan,
+                    current,
+                    baseline
+                );
+            }
+        }
+        assert_eq!("equal".parse(), Ok(NanPolicy::Equal));
+        assert!("sometimes".parse::<NanPolicy>().is_err());
+    }
+}
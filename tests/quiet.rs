@@ -0,0 +1,78 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! --quiet leaves only the summary line, which needs the output of the whole
+//! executable to check.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A directory with an output file and a baseline that differs by 0.5.
+fn outputs(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("quiet-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(dir.join("baseline")).unwrap();
+    let floats = |fs: &[f32]| -> Vec<u8> { fs.iter().flat_map(|f| f.to_le_bytes()).collect() };
+    std::fs::write(dir.join("hyperdrive_band01.bin"), floats(&[1.0, 2.0])).unwrap();
+    std::fs::write(
+        dir.join("baseline").join("hyperdrive_band01.bin"),
+        floats(&[1.0, 2.5]),
+    )
+    .unwrap();
+    dir
+}
+
+fn run(name: &str, args: &[&str]) -> (i32, String, String) {
+    let dir = outputs(name);
+    let output = Command::new(env!("CARGO_BIN_EXE_hyperdrive-vis-gen-diff"))
+        .args(args)
+        .arg("baseline")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn only_the_summary_line_is_printed() {
+    let (code, stdout, stderr) = run("pass", &["--quiet", "--tolerance", "1"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(
+        stdout.starts_with("HYPERDRIVE-CHECKS result=PASS "),
+        "{}",
+        stdout
+    );
+    assert_eq!(stderr, "");
+
+    let (code, stdout, _) = run("fail", &["--quiet", "--tolerance", "0.1"]);
+    assert_eq!(code, 1);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(
+        stdout.starts_with("HYPERDRIVE-CHECKS result=FAIL "),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn the_summary_line_leaves_stdout_to_the_json_report() {
+    let (code, stdout, stderr) = run("json", &["--quiet", "--format", "json", "--tolerance", "1"]);
+    assert_eq!(code, 0);
+    assert!(
+        stdout.starts_with('{') && stdout.trim_end().ends_with('}'),
+        "{}",
+        stdout
+    );
+    assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+    assert!(
+        stderr.starts_with("HYPERDRIVE-CHECKS result=PASS "),
+        "{}",
+        stderr
+    );
+}